@@ -1,4 +1,5 @@
-blocked_hosts = {
+-- Session files can override the list with `settings = { hosts = [...] }`
+blocked_hosts = settings and settings.hosts or {
 	"reddit.com"
 }
 
@@ -10,8 +11,8 @@ function on_packet(eth_frame)
 
 	if ip:protocol() == "Tcp" then
 		local tcp = ip:tcp()
-		if tcp:is_tls() then
-			local tls = tcp:tls()
+		local tls = tcp:tls()
+		if tls ~= nil then
 			local hello = tls:client_hello()
 			-- Check whether the TLS packet is ClientHello
 			if hello ~= nil then
@@ -0,0 +1,32 @@
+-- harpy test examples/block.lua
+
+-- A TLS record carrying a ClientHello for `sni`
+local function client_hello(sni)
+	local server_name = string.pack(">s2", string.pack(">B s2", 0, sni))
+	local extensions = string.pack(">s2", string.pack(">I2 s2", 0, server_name))
+	local body = string.pack(">I2", 0x0303) .. string.rep("\0", 32) .. string.pack(">s1 s2 s1", "", "\x13\x01", "\0") .. extensions
+	return string.pack(">B I2 s2", 0x16, 0x0301, string.pack(">B s3", 1, body))
+end
+
+test("drops ClientHellos to reddit.com", function()
+	local verdict = script():on_packet(fixture.tcp{ dst = "151.101.1.140", payload = client_hello("www.reddit.com") })
+	assert_eq(verdict, "drop")
+end)
+
+test("forwards ClientHellos to other hosts unchanged", function()
+	local frame = fixture.tcp{ dst = "93.184.215.14", payload = client_hello("example.com") }
+	local verdict, result = script():on_packet(frame)
+	assert_eq(verdict, "continue")
+	assert_eq(result, frame)
+end)
+
+test("blocks the hosts from its settings", function()
+	local s = script{ hosts = { "example.com" } }
+	assert_eq(s:on_packet(fixture.tcp{ payload = client_hello("example.com") }), "drop")
+	assert_eq(s:on_packet(fixture.tcp{ payload = client_hello("www.reddit.com") }), "continue")
+end)
+
+test("ignores other traffic", function()
+	assert_eq(script():on_packet(fixture.udp{ dst_port = 53 }), "continue")
+	assert_eq(script():on_packet(fixture.ethernet{ type = 0x0806 }), "continue")
+end)
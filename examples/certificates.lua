@@ -0,0 +1,34 @@
+-- Keeps an inventory of the services and certificates seen on the network.
+-- Certificates are only visible for TLS 1.2 and older, TLS 1.3 encrypts them.
+seen = {}
+
+function on_packet(eth_frame)
+	local ip = eth_frame:ipv4()
+	if ip == nil then
+		return
+	end
+
+	local tcp = ip:tcp()
+	if tcp == nil then
+		return
+	end
+
+	local service = ip:src() .. ":" .. tcp:src_port()
+	-- A certificate chain usually spans several TCP packets and shares them with the ServerHello
+	for _, tls in ipairs(tcp:tls_records()) do
+		local hello = tls:server_hello()
+		if hello ~= nil then
+			print(service .. " speaks " .. hello:version() .. " using " .. hello:ciphersuite() .. " (ALPN: " .. (hello:alpn() or "none") .. ")")
+		end
+
+		for _, cert in ipairs(tls:certificates()) do
+			if seen[cert:fingerprint()] == nil then
+				seen[cert:fingerprint()] = true
+				print(service .. " presented " .. cert:subject())
+				print("\tissued by " .. cert:issuer())
+				print("\tvalid until " .. os.date("%Y-%m-%d", cert:not_after()))
+				print("\tnames: " .. table.concat(cert:sans(), ", "))
+			end
+		end
+	end
+end
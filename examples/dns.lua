@@ -1,8 +1,27 @@
+-- Host name whose DNS responses are exchanged
+HOST = "27justin.dev"
+
+-- Encodes a host name as DNS labels, f.e. "27justin.dev" becomes "\x0827justin\x03dev\x00"
+function encode_name(name)
+	local labels = binary("")
+	for label in name:gmatch("[^.]+") do
+		labels = labels .. binary.pack("s1", label)
+	end
+	return labels .. binary.pack("B", 0)
+end
+
 function mock_dns_response(original_payload)
-	-- Following is a raw DNS response packet.
-	-- The first two bytes are exchanged with the original type as they are the response ID
-	local response = { original_payload:get(0), original_payload:get(1), 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x32, 0x37, 0x6a, 0x75, 0x73, 0x74, 0x69, 0x6e, 0x03, 0x64, 0x65, 0x76, 0x00, 0x00, 0x01, 0x00, 0x01, 0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x51, 0x80, 0x00, 0x04,--[[ The following 4 bytes (0x63,...) are the new IP address, in decimal this corresponds to 99.99.99.99 ]] 0x63, 0x63, 0x63, 0x63, 0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00 }
-	return binary(response)
+	-- The response has to carry the ID of the original response
+	local id = binary.unpack(">H", original_payload)
+	-- Flags: QR, RD and RA set, one question, one answer and one additional record
+	local header = binary.pack(">H [1 4 1 1 1] [1 3 4] HHHH", id, 1, 0, 0, 0, 1, 1, 0, 0, 1, 1, 0, 1)
+	-- Type A, class IN
+	local question = encode_name(HOST) .. binary.pack(">HH", 1, 1)
+	-- The answer refers to the name in the question (0xc00c), the 4 bytes are the new IP address 99.99.99.99
+	local answer = binary.pack(">H HH I s2", 0xc00c, 1, 1, 86400, binary({ 99, 99, 99, 99 }))
+	-- EDNS OPT record
+	local opt = binary.pack(">B HH I H", 0, 41, 1232, 0, 0)
+	return header .. question .. answer .. opt
 end
 
 function on_packet(eth_frame)
@@ -19,7 +38,7 @@ function on_packet(eth_frame)
 	if udp:src_port() == 53 then
 		local payload = udp:payload()
 		-- Only change the response if it contains some predetermined hostname
-		if payload:contains(binary("27justin")) then
+		if payload:contains(encode_name(HOST)) then
 			print("Exchanging DNS response")
 			local response = mock_dns_response(payload)
 			udp:payload(response)
@@ -29,4 +48,3 @@ function on_packet(eth_frame)
 		return eth_frame
 	end
 end
-
@@ -0,0 +1,29 @@
+-- Identifies the applications talking on the network through their JA3/JA4 fingerprints.
+-- Every fingerprint is printed once per host.
+seen = {}
+
+function on_packet(eth_frame)
+	local ip = eth_frame:ipv4()
+	if ip == nil then
+		return
+	end
+
+	local tcp = ip:tcp()
+	local tls = tcp and tcp:tls()
+	if tls == nil then
+		return
+	end
+
+	local hello = tls:client_hello()
+	if hello == nil then
+		return
+	end
+
+	local key = ip:src() .. " " .. hello:ja4()
+	if seen[key] == nil then
+		seen[key] = true
+		print(ip:src() .. " -> " .. (hello:sni() or ip:dst()))
+		print("\tJA3: " .. hello:ja3_hash())
+		print("\tJA4: " .. hello:ja4())
+	end
+end
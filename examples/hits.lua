@@ -11,8 +11,8 @@ function on_packet(eth_frame)
 
 	if ip:protocol() == "Tcp" then
 		local tcp = ip:tcp()
-		if tcp:is_tls() then
-			local tls = tcp:tls()
+		local tls = tcp:tls()
+		if tls ~= nil then
 			local hello = tls:client_hello()
 			-- Check whether the TLS packet is ClientHello
 			if hello ~= nil then
@@ -1,8 +1,15 @@
+-- With --fast-path, only HTTPS and QUIC reach the script, the kernel forwards everything else
+harpy.intercept({ protocol = "tcp", port = 443 })
+harpy.intercept({ protocol = "udp", port = 443 })
+
 function dump_sni(hello, ip)
 	print("[" .. os.date("%H:%M:%S") .. "]: " .. ip:src() .. " is visiting " .. hello:sni())
+	-- Also written to the --events output, if any
+	harpy.emit("sni", { src = ip:src(), dst = ip:dst(), sni = hello:sni(), transport = "tcp" })
 end
 function dump_quic_sni(hello, ip)
 	print("QUIC:: [" .. os.date("%H:%M:%S") .. "]: " .. ip:src() .. " is visiting " .. hello:sni())
+	harpy.emit("sni", { src = ip:src(), dst = ip:dst(), sni = hello:sni(), transport = "quic" })
 end
 
 
@@ -11,8 +18,8 @@ function on_packet(eth_frame)
 	if ip then
 		if ip:protocol() == "Tcp" then
 			local tcp = ip:tcp()
-			if tcp:is_tls() then
-				local tls = tcp:tls()
+			local tls = tcp:tls()
+			if tls ~= nil then
 				local client_hello = tls:client_hello()
 				if client_hello ~= nil then
 					dump_sni(client_hello, ip)
@@ -22,11 +29,12 @@ function on_packet(eth_frame)
 			local udp = ip:udp()
 			if udp:is_quic() then
 				local quic = udp:quic()
-				--[[local client_hello = quic:client_hello()
-				if client_hello ~= nil then
-					dump_quic_sni(client_hello, ip)
-				end]]
-
+				if quic ~= nil then
+					local client_hello = quic:client_hello()
+					if client_hello ~= nil and client_hello:sni() ~= nil then
+						dump_quic_sni(client_hello, ip)
+					end
+				end
 			end
 		end
 	end
@@ -1,4 +1,4 @@
-use std::{net::{Ipv4Addr, IpAddr}, sync::Arc};
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
 
 use pnet::{
     datalink::{MacAddr, NetworkInterface},
@@ -10,22 +10,34 @@ use pnet::{
     }
 };
 
-use crate::sink::Sink;
+use crate::{sink::Sink, error::HarpyError};
 
 pub struct ARPController {
-    interface: NetworkInterface,
+    mac: MacAddr,
+    ip: Ipv4Addr,
     spoof_table: Vec<Ipv4Addr>,
     sink: Arc<Sink>
 }
 
 
 impl ARPController {
-    pub fn new(interface: NetworkInterface, sink: Arc<Sink>) -> ARPController {
-        ARPController {
-            interface: interface.clone(),
+    /// Fails if the interface lacks the MAC or IPv4 address needed to send ARP packets
+    pub fn new(interface: &NetworkInterface, sink: Arc<Sink>) -> Result<ARPController, HarpyError> {
+        Ok(ARPController {
+            mac: interface.mac.filter(|mac| !mac.is_zero()).ok_or_else(|| HarpyError::NoMacAddress(interface.name.clone()))?,
+            ip: crate::util::interface_ipv4(interface).ok_or_else(|| HarpyError::NoIpv4Address(interface.name.clone()))?,
             spoof_table: Default::default(),
             sink
-        }
+        })
+    }
+
+    /// The MAC address of the interface
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+    /// The IPv4 address of the interface
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
     }
 
     pub fn spoof_table(&self) -> &Vec<Ipv4Addr> {
@@ -62,14 +74,17 @@ impl ARPController {
         EthernetPacket::owned(ethernet_packet.packet().to_vec()).unwrap()
     }
 
-    pub fn spoof(&mut self, source: Ipv4Addr, source_mac: MacAddr, target: Ipv4Addr, target_mac: MacAddr) -> () {
+    pub fn spoof(&mut self, source: Ipv4Addr, source_mac: MacAddr, target: Ipv4Addr, target_mac: MacAddr) {
         let packet = ARPController::build_arp_packet(source_mac, target_mac, source_mac, source, target_mac, target, ArpOperations::Reply);
-        self.spoof_table.push(target);
+        if !self.spoof_table.contains(&target) {
+            self.spoof_table.push(target);
+        }
         self.sink.send(packet);
+        crate::metrics::METRICS.arp_replies_sent.inc();
     }
 
     pub fn resolve_mac(&self, ip: &Ipv4Addr) -> Option<MacAddr> {
-        let arp = ARPController::build_arp_packet(self.interface.mac.unwrap(), MacAddr::broadcast(), self.interface.mac.unwrap(), match self.interface.ips[0].ip() { IpAddr::V4(ip) => ip, _ => { return None; }}, MacAddr::zero(), *ip, ArpOperations::Request);
+        let arp = ARPController::build_arp_packet(self.mac, MacAddr::broadcast(), self.mac, self.ip, MacAddr::zero(), *ip, ArpOperations::Request);
         debug!("Trying to resolve MAC for IP: {}", ip);
         self.sink.send(EthernetPacket::owned(arp.packet().to_vec()).unwrap());
         let listener = self.sink.add_rx();
@@ -97,7 +112,35 @@ impl ARPController {
         }
     }
 
+    /// Resolves the MACs of several hosts at once, hosts that didn't reply within `timeout` are
+    /// missing from the result
+    pub fn resolve_macs(&self, ips: &[Ipv4Addr], timeout: std::time::Duration) -> HashMap<Ipv4Addr, MacAddr> {
+        let request = |ip: Ipv4Addr| ARPController::build_arp_packet(self.mac, MacAddr::broadcast(), self.mac, self.ip, MacAddr::zero(), ip, ArpOperations::Request);
+        let listener = self.sink.add_rx();
+        let mut resolved = HashMap::new();
+
+        let start = std::time::Instant::now();
+        let mut last: Option<std::time::Instant> = None;
+        while resolved.len() < ips.len() && start.elapsed() < timeout {
+            if last.is_none_or(|last| last.elapsed().as_millis() > 1250) {
+                for ip in ips.iter().filter(|ip| !resolved.contains_key(*ip)) {
+                    debug!("Trying to resolve MAC for IP: {}", ip);
+                    self.sink.send(request(*ip));
+                }
+                last = Some(std::time::Instant::now());
+            }
 
+            let packet = listener.recv().unwrap();
+            if packet.get_ethertype() == EtherTypes::Arp {
+                if let Some(arp_packet) = ArpPacket::new(packet.payload()) {
+                    if arp_packet.get_operation() == ArpOperations::Reply && ips.contains(&arp_packet.get_sender_proto_addr()) {
+                        resolved.insert(arp_packet.get_sender_proto_addr(), arp_packet.get_sender_hw_addr());
+                    }
+                }
+            }
+        }
+        resolved
+    }
 }
 
 
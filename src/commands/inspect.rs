@@ -1,53 +1,76 @@
 use std::{sync::Arc};
-use pnet::{
-    datalink::{self, NetworkInterface},
+use pnet::packet::{ethernet::EtherTypes, ipv4::Ipv4Packet};
+use serde_json::json;
+use harpy::{
+    encap::Encapsulation,
+    fragment::{self, Reassembler, Reassembly},
+    metrics::METRICS,
+    error::HarpyError,
+    sink::Sink,
+    tui::Dashboard,
+    util,
+    engine::HarpyEngine
 };
-use crate::{Commands, sink::Sink, engine::{HarpyEngine, types::LuaEthernetPacket}};
+use crate::Commands;
 
 
-pub(crate) fn run(args: crate::Args) {
-    match args.command {
-        Commands::Inspect {
-            interface,
-            file
-        } => {
-            let interface = datalink::interfaces().into_iter().filter(|iface: &NetworkInterface| iface.name == interface).next().unwrap_or_else(|| panic!("No such interface: {}", interface));
-            let harpy = HarpyEngine::new();
+pub(crate) fn run(args: crate::Args) -> Result<(), HarpyError> {
+    if let Commands::Inspect {
+        interface,
+        file,
+        events,
+        metrics,
+        sandbox
+    } = args.command {
+        let interface = util::find_interface(&interface)?;
+        let harpy = match sandbox.into_config() {
+            Some(config) => HarpyEngine::sandboxed(config.into_sandbox()?),
+            None => HarpyEngine::new()
+        };
+        let events = super::open_events(events.as_deref())?;
+        super::serve_metrics(metrics)?;
+        harpy.set_events(events.clone());
 
-            let sink = Arc::new(match Sink::new(&interface) { Ok(sink) => sink, Err(e) => { error!("Couldn't start network socket: {:#?}", e); std::process::exit(1); } });
+        let sink = Arc::new(Sink::new(&interface)?);
 
-            harpy.context(|ctx| {
-                ctx.globals().set("MTU", sink.mtu())?;
-                ctx.globals().set("harpy_mode", "inspect")
-            }).unwrap();
-            harpy.run_file(file).unwrap_or_else(|e| error!("Error in lua-script: {}", e));
+        harpy.context(|ctx| {
+            ctx.globals().set("MTU", sink.mtu())?;
+            ctx.globals().set("harpy_mode", "inspect")
+        }).unwrap();
+        harpy.run_file(file.clone()).inspect_err(|e| {
+            events.emit("script_error", json!({ "script": file, "function": null, "error": e.to_string() }));
+        })?;
 
-            let rx_channel = sink.add_rx();
-            let clone = sink.clone();
-            std::thread::spawn(move || {
-                let sink = clone.clone();
-                sink.run()
-            });
+        let rx_channel = sink.add_rx();
+        let clone = sink.clone();
+        std::thread::spawn(move || {
+            let sink = clone.clone();
+            sink.run()
+        });
 
-            loop {
-                let packet = rx_channel.recv().unwrap();
-                let start = std::time::Instant::now();
-                harpy.context(|ctx| {
-                    let packet: LuaEthernetPacket = (&packet).into();
-                    let g = ctx.globals();
-
-                    if let Ok(on_packet) = g.get::<_, rlua::Function>("on_packet") {
-                        on_packet.call::<LuaEthernetPacket, Option<LuaEthernetPacket>>(packet)
-                            .unwrap_or_else(|e| { error!("on_packet: {}", e); None });
-                    }
-                });
-                trace!("lua - Packet processed in {}ms", start.elapsed().as_millis());
-
-
-            }
-        },
-        _ => {}
+        let mut reassembler = Reassembler::default();
+        loop {
+            let packet = rx_channel.recv().unwrap();
+            // The script sees fragmented datagrams once they're complete
+            let encapsulation = Encapsulation::parse(&packet);
+            let fragment = match encapsulation.ethertype {
+                EtherTypes::Ipv4 => Ipv4Packet::new(encapsulation.payload(&packet))
+                    .filter(fragment::is_fragment)
+                    .map(|ipv4| reassembler.push(&ipv4, std::time::Instant::now())),
+                _ => None
+            };
+            let packet = match fragment {
+                Some(Reassembly::Complete(datagram)) => encapsulation.with_payload(&packet, &datagram),
+                Some(Reassembly::Incomplete | Reassembly::Dropped) => continue,
+                None => packet
+            };
+            let start = std::time::Instant::now();
+            // Inspect only looks at frames, the result of the script is ignored and replies aren't sent
+            super::on_packet(&harpy, &packet, &file, &events, &Dashboard::default());
+            harpy.take_replies();
+            METRICS.lua_latency.observe(start.elapsed());
+            trace!("lua - Packet processed in {}ms", start.elapsed().as_millis());
+        }
     }
+    Ok(())
 }
-
-
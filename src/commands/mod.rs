@@ -1,3 +1,77 @@
 pub mod spoof;
 pub mod inspect;
+pub mod replay;
+pub mod test;
 
+
+use std::path::Path;
+use pnet::packet::ethernet::EthernetPacket;
+use serde_json::json;
+use harpy::{
+    engine::{sandbox::{BudgetViolation, OverBudgetPolicy}, CallbackError, EngineResult, HarpyEngine},
+    events::Events,
+    error::HarpyError,
+    metrics::METRICS,
+    tui::Dashboard
+};
+
+/// Opens the `--events` output
+pub(crate) fn open_events(target: Option<&str>) -> Result<Events, HarpyError> {
+    target.map_or_else(|| Ok(Events::default()), Events::open).map_err(HarpyError::Events)
+}
+
+/// Starts the `--metrics` endpoint
+pub(crate) fn serve_metrics(addr: Option<std::net::SocketAddr>) -> Result<(), HarpyError> {
+    addr.map_or(Ok(()), harpy::metrics::serve).map_err(HarpyError::Metrics)
+}
+
+/// Passes a frame to the `on_packet` function of a script, see [`verdict`]
+pub(crate) fn on_packet(harpy: &HarpyEngine, packet: &EthernetPacket<'static>, file: &Path, events: &Events, dashboard: &Dashboard) -> EngineResult {
+    verdict(harpy, "on_packet", harpy.on_packet(packet), file, events, dashboard)
+}
+
+/// Passes an ARP request and the spoofed reply to the `on_arp_reply` function of a script, see [`verdict`]
+pub(crate) fn on_arp_reply(harpy: &HarpyEngine, request: &EthernetPacket<'static>, reply: &EthernetPacket<'static>, file: &Path, events: &Events, dashboard: &Dashboard) -> EngineResult {
+    verdict(harpy, "on_arp_reply", harpy.on_arp_reply(request, reply), file, events, dashboard)
+}
+
+/// The verdict of a script's `function`. Errors are reported and leave the frame as it is,
+/// callbacks exceeding their budget are handled according to the sandbox's policy.
+fn verdict(harpy: &HarpyEngine, function: &str, result: Result<EngineResult, CallbackError>, file: &Path, events: &Events, dashboard: &Dashboard) -> EngineResult {
+    match result {
+        Ok(result) => result,
+        Err(CallbackError::Script(e)) => {
+            error!("{}: {}", function, e);
+            events.emit("script_error", json!({ "script": file, "function": function, "error": e.to_string() }));
+            dashboard.script_error(&format!("{}: {}", function, e));
+            EngineResult::Continue
+        },
+        Err(CallbackError::OverBudget(violation)) => {
+            let policy = harpy.sandbox().map_or(OverBudgetPolicy::Forward, |sandbox| sandbox.policy);
+            warn!("{}: {} exceeded its {} budget, {}", file.display(), function, violation.as_str(), match policy {
+                OverBudgetPolicy::Forward => "forwarding the frame",
+                OverBudgetPolicy::Drop => "dropping the frame",
+                OverBudgetPolicy::Disable => "disabling the script"
+            });
+            events.emit("script_over_budget", json!({
+                "script": file,
+                "function": function,
+                "budget": violation.as_str(),
+                "policy": policy.as_str()
+            }));
+            dashboard.script_error(&format!("{}: {} exceeded its {} budget", file.display(), function, violation.as_str()));
+            match violation {
+                BudgetViolation::Time => METRICS.scripts_over_time_budget.inc(),
+                BudgetViolation::Memory => METRICS.scripts_over_memory_budget.inc()
+            }
+            match policy {
+                OverBudgetPolicy::Forward => EngineResult::Continue,
+                OverBudgetPolicy::Drop => EngineResult::Drop,
+                OverBudgetPolicy::Disable => {
+                    harpy.disable();
+                    EngineResult::Continue
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fs::File, io::BufReader, path::Path, sync::Arc, time::{Duration, Instant}};
+use pnet::packet::{ethernet::EthernetPacket, Packet};
+use harpy::{
+    config::ConfigError,
+    engine::{EngineResult, HarpyEngine},
+    error::HarpyError,
+    pcap::{PcapReader, LINKTYPE_ETHERNET},
+    replay::{Pacing, RewriteRules},
+    sink::Sink,
+    tui::Dashboard,
+    util
+};
+use serde_json::json;
+use crate::Commands;
+
+fn open_capture(capture: &Path) -> Result<PcapReader<BufReader<File>>, HarpyError> {
+    let reader = PcapReader::open(capture).map_err(|e| HarpyError::Capture(capture.to_owned(), e))?;
+    if reader.link_type() != LINKTYPE_ETHERNET {
+        return Err(HarpyError::Capture(capture.to_owned(), std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("link type {} isn't supported, only Ethernet captures are", reader.link_type()))));
+    }
+    Ok(reader)
+}
+
+pub(crate) fn run(args: crate::Args) -> Result<(), HarpyError> {
+    if let Commands::Replay {
+        capture,
+        interface,
+        speed,
+        topspeed,
+        loops,
+        rewrite_mac,
+        rewrite_ip,
+        file,
+        events,
+        sandbox
+    } = args.command {
+        let pacing = match topspeed {
+            true => Pacing::Topspeed,
+            false if speed.is_finite() && speed > 0.0 => Pacing::Original { speed },
+            false => return Err(ConfigError::Invalid(format!("--speed has to be a positive number, got {}", speed)).into())
+        };
+        let mut rules = RewriteRules::default();
+        for rule in rewrite_mac.iter() {
+            rules.add_mac(rule).map_err(ConfigError::Invalid)?;
+        }
+        for rule in rewrite_ip.iter() {
+            rules.add_ip(rule).map_err(ConfigError::Invalid)?;
+        }
+        let sandbox = sandbox.into_config().map(|config| config.into_sandbox()).transpose()?;
+        // Fail before the socket is opened
+        open_capture(&capture)?;
+
+        let interface = util::find_interface(&interface)?;
+        let events = super::open_events(events.as_deref())?;
+        let sink = Arc::new(Sink::new(&interface)?);
+
+        let script = match file {
+            Some(file) => {
+                let harpy = sandbox.map_or_else(HarpyEngine::new, HarpyEngine::sandboxed);
+                harpy.set_events(events.clone());
+                harpy.context(|ctx| {
+                    ctx.globals().set("MTU", sink.mtu())?;
+                    ctx.globals().set("harpy_mode", "replay")
+                }).map_err(|e| HarpyError::from_lua(file.clone(), e))?;
+                harpy.run_file(file.clone()).inspect_err(|e| {
+                    events.emit("script_error", json!({ "script": file, "function": null, "error": e.to_string() }));
+                })?;
+                Some((harpy, file))
+            },
+            None => None
+        };
+
+        // Received frames aren't needed, but have to be taken off the bus
+        let rx_channel = sink.add_rx();
+        std::thread::spawn(move || while rx_channel.recv().is_ok() {});
+        let clone = sink.clone();
+        std::thread::spawn(move || {
+            clone.run()
+        });
+
+        let (mut sent, mut dropped) = (0usize, 0usize);
+        for _ in 0..loops {
+            let reader = open_capture(&capture)?;
+            let start = Instant::now();
+            let mut first = None;
+            for record in reader {
+                let record = record.map_err(|e| HarpyError::Capture(capture.clone(), e))?;
+                let first = *first.get_or_insert(record.timestamp);
+                let due = start + pacing.offset(record.timestamp.saturating_sub(first));
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+
+                let Some(frame) = EthernetPacket::owned(record.data) else { continue };
+                let frame = rules.rewrite(frame);
+                let frame = match &script {
+                    Some((harpy, file)) => {
+                        let result = super::on_packet(harpy, &frame, file, &events, &Dashboard::default());
+                        // The recorded senders aren't there to receive replies
+                        harpy.take_replies();
+                        match result {
+                            EngineResult::Continue => frame,
+                            EngineResult::Drop => {
+                                dropped += 1;
+                                continue;
+                            },
+                            EngineResult::Tamper(tampered) => tampered
+                        }
+                    },
+                    None => frame
+                };
+                trace!("Replaying {} bytes", frame.packet().len());
+                sink.send(frame);
+                sent += 1;
+            }
+        }
+        // The sink sends from its own thread, wait for it to catch up
+        while sink.pending() > 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        info!("Replayed {} frames, {} dropped by the script", sent, dropped);
+    }
+    Ok(())
+}
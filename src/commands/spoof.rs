@@ -1,176 +1,247 @@
-use std::{net::{IpAddr, Ipv4Addr}, sync::Arc};
+use std::net::Ipv4Addr;
 use pnet::{
-    datalink::{self, NetworkInterface},
-    packet::{
-        ethernet::{MutableEthernetPacket, EtherTypes},
-        arp::{ArpPacket},
-        Packet,
-        PacketSize
-    }
+    datalink::NetworkInterface,
+    packet::{ethernet::EthernetPacket, ip::IpNextHeaderProtocols, Packet}
 };
-use crate::{util, Commands, sink::{Sink}, arp::ARPController, engine::{HarpyEngine, EngineResult, types::LuaEthernetPacket}};
-
-pub(crate) fn run(args: crate::Args) {
-    match args.command {
-        Commands::Spoof {
-            target,
-            gateway,
-            interface,
-            file,
-            all
-        } => {
-            let interface = datalink::interfaces().into_iter().filter(|iface: &NetworkInterface| iface.name == interface).next().unwrap_or_else(|| panic!("No such interface: {}", interface));
-            let primary_ip = match interface.ips[0].ip() { IpAddr::V4(ip) => ip, _ => panic!("IPv4 address expected") };
-            let target: Ipv4Addr = target.parse().unwrap_or_else(|_| panic!("Invalid IP address: {}", target));
-            let gateway: Ipv4Addr = match gateway
-                                .map_or_else(|| util::get_gateway_for(&interface), |s| str::parse(&s).ok())
-                                .unwrap_or_else(|| panic!("No gateway could be determined for: {}", interface.name)) {
-                                    IpAddr::V4(ip) => ip,
-                                    _ => panic!("Expected IPv4 gateway")
-                                };
-
-
-            let sink = Arc::new(match Sink::new(&interface) { Ok(sink) => sink, Err(e) => { error!("Couldn't start network socket: {:#?}", e); std::process::exit(1); } });
+use serde_json::json;
+use harpy::{
+    config::{Filter, Overrides, ScriptConfig, Session, SessionConfig},
+    downgrade::{QuicBlocker, QUIC_PORT},
+    engine::{sandbox::Sandbox, HarpyEngine, EngineResult},
+    error::HarpyError,
+    events::Events,
+    impair::{Impairments, Selector},
+    metrics::METRICS,
+    tui::Dashboard,
+    util,
+    Frame, MitmSession, PacketHandler, StopHandle, Verdict
+};
+use crate::Commands;
+
+/// Runs the session's Lua scripts. Every script runs in its own engine, so their globals don't
+/// collide, frames are passed through them in the order they are declared in.
+struct ScriptHandler {
+    scripts: Vec<ScriptConfig>,
+    engines: Vec<HarpyEngine>,
+    filter: Filter,
+    sandbox: Option<Sandbox>,
+    mtu: u32,
+    events: Events,
+    impairments: Impairments,
+    dashboard: Dashboard
+}
 
-            let harpy = HarpyEngine::new();
+impl ScriptHandler {
+    fn load_engines(&self) -> Result<Vec<HarpyEngine>, HarpyError> {
+        self.scripts.iter().map(|script| {
+            let harpy = self.sandbox.map_or_else(HarpyEngine::new, HarpyEngine::sandboxed);
+            harpy.set_events(self.events.clone());
+            harpy.set_impairments(self.impairments.clone());
             harpy.context(|ctx| {
-                ctx.globals().set("MTU", sink.mtu())?;
+                ctx.globals().set("MTU", self.mtu)?;
                 ctx.globals().set("harpy_mode", "spoof")
-            }).unwrap();
-            if let Some(ref file) = file {
-                harpy.run_file(file.to_owned()).unwrap();
-            }
-
-            let rx_channel = sink.add_rx();
-            let clone = sink.clone();
-            std::thread::spawn(move || {
-                let sink = clone.clone();
-                sink.run()
-            });
-
-            let mut arp = ARPController::new(interface.clone(), sink.clone());
-
-            let gateway_mac = arp.resolve_mac(&gateway).unwrap_or_else(|| panic!("Could not resolve MAC for gateway: {}", gateway));
-            let target_mac = arp.resolve_mac(&target).unwrap_or_else(|| panic!("Could not resolve MAC for target: {}", target));
-            info!("Gateway MAC: {} -> {}", gateway, gateway_mac);
-            info!("Target MAC: {} -> {}", target, target_mac);
-
-            // Spoof both the gateway and the target
-            arp.spoof(gateway, interface.mac.unwrap(), target, target_mac);
-            arp.spoof(target, interface.mac.unwrap(), gateway, gateway_mac);
-            info!("Spoofed ARP entries for {} and {}", gateway, target);
-
-            'network: loop {
-                let mut packet = rx_channel.recv().unwrap();
+            }).and_then(|_| harpy.set_settings(&script.settings))
+                .map_err(|e| HarpyError::from_lua(script.file.clone(), e))?;
+            harpy.run_file(script.file.to_owned()).inspect_err(|e| {
+                self.events.emit("script_error", json!({ "script": script.file, "function": null, "error": e.to_string() }));
+            })?;
+            Ok(harpy)
+        }).collect()
+    }
+}
 
-                // Check if the packet is an ARP request for the target
-                // If it is, spoof the ARP reply
-                if packet.get_ethertype() == EtherTypes::Arp {
-                    if let Some(arp_packet) = ArpPacket::new(packet.payload()) {
-                        let target_ip = arp_packet.get_target_proto_addr();
-                        if arp.spoof_table().contains(&target_ip)
-                            && (arp_packet.get_sender_proto_addr() == gateway
-                                || arp_packet.get_sender_proto_addr() == target) {
-                            //&& arp_packet.get_sender_proto_addr() != primary_ip {
-                            debug!("{} is requesting {}, spoofing...", arp_packet.get_sender_proto_addr(), target_ip);
-                            let arp_response = ARPController::build_arp_packet(
-                                interface.mac.unwrap(),
-                                arp_packet.get_sender_hw_addr(),
-                                interface.mac.unwrap(),
-                                target_ip,
-                                arp_packet.get_sender_hw_addr(),
-                                arp_packet.get_sender_proto_addr(),
-                                pnet::packet::arp::ArpOperations::Reply
-                            );
-                            sink.send(arp_response);
-                            std::thread::sleep(std::time::Duration::from_millis(150));
-                            let arp_response = ARPController::build_arp_packet(
-                                interface.mac.unwrap(),
-                                arp_packet.get_sender_hw_addr(),
-                                interface.mac.unwrap(),
-                                target_ip,
-                                arp_packet.get_sender_hw_addr(),
-                                arp_packet.get_sender_proto_addr(),
-                                pnet::packet::arp::ArpOperations::Reply
-                            );
-                            sink.send(arp_response);
-                        }
-                    }
+impl ScriptHandler {
+    fn reload(&mut self) {
+        if self.dashboard.take_reload() {
+            match self.load_engines() {
+                Ok(reloaded) => {
+                    self.engines = reloaded;
+                    info!("Reloaded lua-scripts");
+                },
+                Err(e) => {
+                    error!("{}, keeping the previous scripts", e);
+                    self.dashboard.script_error(&e.to_string());
                 }
+            }
+        }
+    }
+}
 
+impl PacketHandler for ScriptHandler {
+    fn on_packet(&mut self, frame: &Frame) -> Verdict {
+        self.reload();
+        if !self.filter.matches(&frame.ipv4) {
+            return Verdict::Forward;
+        }
+
+        let (source_ip, target_ip) = (frame.source(), frame.destination());
+        let start = std::time::Instant::now();
+        let size = frame.packet.packet().len();
+        let mut tampered: Option<EthernetPacket<'static>> = None;
+        for (harpy, script) in self.engines.iter().zip(self.scripts.iter()) {
+            let result = super::on_packet(harpy, tampered.as_ref().unwrap_or(frame.packet), &script.file, &self.events, &self.dashboard);
+            // Replies go back to where the frame came from, whatever happens to the frame
+            for datagram in harpy.take_replies() {
+                frame.reply(&datagram);
+            }
+            match result {
+                EngineResult::Continue => (),
+                EngineResult::Drop => {
+                    METRICS.lua_latency.observe(start.elapsed());
+                    METRICS.packets_dropped.inc();
+                    self.events.emit("packet_dropped", json!({
+                        "src": source_ip.to_string(),
+                        "dst": target_ip.to_string(),
+                        "size": size
+                    }));
+                    return Verdict::Drop;
+                },
+                EngineResult::Tamper(modified) => tampered = Some(modified)
+            }
+        }
+        METRICS.lua_latency.observe(start.elapsed());
+        trace!("lua - Packet processed in {}ms", start.elapsed().as_millis());
+        match tampered {
+            Some(packet) => {
+                METRICS.packets_tampered.inc();
+                self.events.emit("packet_tampered", json!({
+                    "src": source_ip.to_string(),
+                    "dst": target_ip.to_string(),
+                    "size": size,
+                    "tampered_size": packet.packet().len()
+                }));
+                Verdict::Replace(packet)
+            },
+            None => Verdict::Forward
+        }
+    }
 
-                if packet.get_ethertype() == EtherTypes::Ipv4 {
-                    // Check if IPv4 Destination == gateway and IPv4 source == target, then forward it
-                    // Check if IPv4 Destination == target and IPv4 source == gateway, then forward it
-                    let payload = packet.payload();
-                    let source_ip: Ipv4Addr = Ipv4Addr::from([payload[12],payload[13],payload[14], payload[15]]).try_into().unwrap();
-                    let target_ip: Ipv4Addr = Ipv4Addr::from([payload[16],payload[17],payload[18], payload[19]]).try_into().unwrap();
-                    trace!("{} -> {}", source_ip, target_ip);
-                    //let target_ip: Ipv4Addr = packet.payload()[16..20].try_into().unwrap();
-
-                    //let is_targeted = (packet.get_source() == target_mac && target_ip != primary_ip) || (packet.get_source() == gateway_mac && source_ip == target);
-                    let is_targeted = source_ip == target || target_ip == target && target_ip != primary_ip;
-                    if file.is_some() && (is_targeted || all){
-                        let start = std::time::Instant::now();
-                        let status = harpy.context(|ctx| {
-                            let packet: LuaEthernetPacket = (&packet).into();
-                            let g = ctx.globals();
-
-                            if let Ok(on_packet) = g.get::<_, rlua::Function>("on_packet") {
-                                let result = on_packet.call::<LuaEthernetPacket, Option<LuaEthernetPacket>>(packet)
-                                    .unwrap_or_else(|e| { error!("on_packet: {}", e); None });
-                                if let Some(b) = result {
-                                    if b.dropped() {
-                                        return EngineResult::Drop;
-                                    }else if b.tampered() {
-                                        return EngineResult::Tamper(b.into());
-
-                                    }
-                                }
-                            }
-                            EngineResult::Continue
-                        });
-                        match status {
-                            EngineResult::Continue => (),
-                            EngineResult::Drop => continue 'network,
-                            EngineResult::Tamper(tampered) => {
-                                packet = tampered;
-                            }
-                        }
-                        trace!("lua - Packet processed in {}ms", start.elapsed().as_millis());
-                    }
-
-                    let ipv4 = pnet::packet::ipv4::Ipv4Packet::new(packet.payload()).unwrap();
-                    if packet.get_source() == target_mac && ipv4.get_destination() != primary_ip {
-                        trace!("[Target -> Gateway] Rerouting {} bytes of data", ipv4.packet_size());
-
-                        let mut ethernet_buffer: Vec<u8> = Vec::new();
-                        ethernet_buffer.extend_from_slice(packet.packet());
-                        let mut ethernet_packet = MutableEthernetPacket::owned(ethernet_buffer).unwrap();
-                        ethernet_packet.set_source(interface.mac.unwrap());
-                        ethernet_packet.set_destination(gateway_mac);
-                        ethernet_packet.set_ethertype(EtherTypes::Ipv4);
-
-                        sink.send(ethernet_packet.consume_to_immutable());
-                    }else if packet.get_source() == gateway_mac && ipv4.get_destination() == target {
-                        trace!("[Gateway -> Target] Rerouting {} bytes of data", ipv4.packet_size());
-
-                        let mut ethernet_buffer: Vec<u8> = Vec::new();
-                        ethernet_buffer.extend_from_slice(packet.packet());
-                        let mut ethernet_packet = MutableEthernetPacket::owned(ethernet_buffer).unwrap();
-                        ethernet_packet.set_source(interface.mac.unwrap());
-                        ethernet_packet.set_destination(target_mac);
-                        ethernet_packet.set_ethertype(EtherTypes::Ipv4);
-
-                        sink.send(ethernet_packet.consume_to_immutable());
-                    }
-                }
+    fn on_arp_reply(&mut self, request: &EthernetPacket<'static>, reply: &EthernetPacket<'static>) -> Verdict {
+        self.reload();
+        let mut tampered: Option<EthernetPacket<'static>> = None;
+        for (harpy, script) in self.engines.iter().zip(self.scripts.iter()) {
+            let result = super::on_arp_reply(harpy, request, tampered.as_ref().unwrap_or(reply), &script.file, &self.events, &self.dashboard);
+            // There's no IPv4 sender to reply to
+            harpy.take_replies();
+            match result {
+                EngineResult::Continue => (),
+                EngineResult::Drop => return Verdict::Drop,
+                EngineResult::Tamper(modified) => tampered = Some(modified)
             }
-        },
-        _ => {}
+        }
+        tampered.map_or(Verdict::Forward, Verdict::Replace)
     }
 }
 
+pub(crate) fn run(args: crate::Args) -> Result<(), HarpyError> {
+    if let Commands::Spoof {
+        config,
+        target,
+        gateway,
+        interface,
+        file,
+        all,
+        block_quic,
+        events,
+        metrics,
+        tui,
+        repoison_interval,
+        fast_path,
+        fragment_timeout,
+        fragment_overlap,
+        mss_headroom,
+        sandbox,
+        impairment
+    } = args.command {
+        let overrides = Overrides {
+            interface, gateway, target, file, all, block_quic, events, metrics, tui, repoison_interval, fast_path,
+            fragment_timeout, fragment_overlap, mss_headroom,
+            sandbox: sandbox.into_config(),
+            impairment: impairment.into_config()
+        };
+        let session = match config {
+            Some(config) => SessionConfig::load(&config)?,
+            None => SessionConfig::default()
+        }.into_session(overrides)?;
+
+        let interface = util::find_interface(&session.interface)?;
+        let gateway = session.gateway
+            .or_else(|| util::default_gateway(&interface))
+            .ok_or_else(|| HarpyError::NoGateway(interface.name.clone()))?;
+
+        let stop = StopHandle::default();
+        let dashboard = match session.tui {
+            true => Dashboard::start(format!("{} via {} on {}", match session.targets.as_slice() {
+                [target] => target.to_string(),
+                targets => format!("{} targets", targets.len())
+            }, gateway, interface.name), stop.clone()),
+            false => {
+                pretty_env_logger::init();
+                Dashboard::default()
+            }
+        };
+        let result = spoof(session, interface, gateway, &dashboard, stop);
+        dashboard.stop();
+        return result;
+    }
+    Ok(())
+}
 
+fn spoof(session: Session, interface: NetworkInterface, gateway: Ipv4Addr, dashboard: &Dashboard, stop: StopHandle) -> Result<(), HarpyError> {
+    let mtu = util::interface_mtu(&interface)?;
+    let events = super::open_events(session.events.as_deref())?;
+    super::serve_metrics(session.metrics)?;
 
-
+    // Flows of impairment rules and QUIC have to reach harpy, the scripts add their own
+    let mut intercepted: Vec<Selector> = session.impairments.iter().map(|(selector, _)| *selector).collect();
+    if session.block_quic.is_some() {
+        intercepted.push(Selector { protocol: Some(IpNextHeaderProtocols::Udp), port: Some(QUIC_PORT), ..Default::default() });
+    }
+    // Always set up, scripts may add rules at runtime
+    let impairments = Impairments::new(session.impairments);
+    let mut builder = MitmSession::builder(interface)
+        .gateway(gateway)
+        .impairments(impairments.clone())
+        .targets(session.targets.iter().copied())
+        .capture_all(session.all)
+        .fragments(session.fragment_timeout, session.fragment_overlap)
+        .mss_headroom(session.mss_headroom)
+        .observer(events.clone())
+        .observer(dashboard.clone())
+        .stop_handle(stop);
+    if let Some(interval) = session.repoison_interval {
+        builder = builder.repoison_interval(interval);
+    }
+    if let Some(mode) = session.block_quic {
+        builder = builder.handler(QuicBlocker::new(mode).events(events.clone()));
+    }
+    if !session.scripts.is_empty() {
+        let mut scripts = ScriptHandler {
+            scripts: session.scripts,
+            engines: Vec::new(),
+            filter: session.filter,
+            sandbox: session.sandbox,
+            mtu,
+            events,
+            impairments,
+            dashboard: dashboard.clone()
+        };
+        scripts.engines = scripts.load_engines()?;
+        for harpy in scripts.engines.iter() {
+            // Scripts that don't declare their interest see what the filter lets through
+            match harpy.intercepts() {
+                declared if declared.is_empty() => intercepted.extend(scripts.filter.selectors()),
+                declared => intercepted.extend(declared)
+            }
+        }
+        builder = builder.handler(scripts);
+    }
+    if session.fast_path {
+        if intercepted.contains(&Selector::default()) {
+            warn!("Every flow is intercepted, the kernel fast path won't forward anything");
+        }
+        builder = builder.fast_path(intercepted);
+    }
+    // Pausing and drop-all apply to whatever the other handlers let through
+    builder.handler(dashboard.clone()).build()?.run()
+}
@@ -0,0 +1,37 @@
+use harpy::{error::HarpyError, testing::{self, ReportFormat}};
+use crate::Commands;
+
+pub(crate) fn run(args: crate::Args) -> Result<(), HarpyError> {
+    if let Commands::Test {
+        script,
+        spec,
+        format,
+        output,
+        sandbox
+    } = args.command {
+        if !script.is_file() {
+            return Err(HarpyError::ScriptRead(script, std::io::ErrorKind::NotFound.into()));
+        }
+        let spec = spec.unwrap_or_else(|| {
+            let stem = script.file_stem().unwrap_or_default().to_string_lossy();
+            script.with_file_name(format!("{}_test.lua", stem))
+        });
+        let sandbox = sandbox.into_config().map(|config| config.into_sandbox()).transpose()?;
+
+        let outcomes = testing::run(&script, &spec, sandbox)?;
+        let report = match format {
+            ReportFormat::Tap => testing::tap(&outcomes),
+            ReportFormat::Junit => testing::junit(&script.display().to_string(), &outcomes)
+        };
+        match output {
+            Some(file) => std::fs::write(&file, report).map_err(|e| HarpyError::Report(file, e))?,
+            None => print!("{}", report)
+        }
+
+        let failed = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+        if failed > 0 {
+            return Err(HarpyError::TestsFailed { failed, total: outcomes.len() });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,538 @@
+use std::{net::{Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, time::Duration};
+use pnet::{
+    ipnetwork::Ipv4Network,
+    packet::{
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::Ipv4Packet,
+        tcp::TcpPacket,
+        udp::UdpPacket,
+        Packet
+    }
+};
+use serde::Deserialize;
+
+use crate::{downgrade::QuicBlockMode, engine::sandbox::{OverBudgetPolicy, Sandbox}, impair::{self, Profile, Selector}, fragment::OverlapPolicy};
+
+/// Upper bound for the number of hosts a session may spoof, guards against `/8` typos
+pub const MAX_TARGETS: usize = 1024;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The configuration parsed, but doesn't describe a usable session
+    Invalid(String)
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A spoof session as written in a `--config` file, every field is optional so command line flags
+/// can fill in the rest
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub interface: Option<String>,
+    pub gateway: Option<Ipv4Addr>,
+    /// IPv4 addresses or CIDR ranges
+    pub targets: Vec<String>,
+    /// IPv4 addresses or CIDR ranges removed from `targets`
+    pub exclude: Vec<String>,
+    /// Seconds between unsolicited spoofed ARP replies
+    pub repoison_interval: Option<u64>,
+    pub all: bool,
+    pub block_quic: Option<QuicBlockMode>,
+    pub tui: bool,
+    pub scripts: Vec<ScriptConfig>,
+    pub output: OutputConfig,
+    pub filter: FilterConfig,
+    /// Runs the scripts in a sandbox, if present
+    pub sandbox: Option<SandboxConfig>,
+    /// Impairs forwarded frames, the first matching entry applies
+    pub impairment: Vec<ImpairmentConfig>,
+    /// Let the kernel forward every flow harpy doesn't intercept
+    pub fast_path: bool,
+    pub fragments: FragmentConfig,
+    /// Bytes scripts may add to TCP segments, the MSS of handshakes is clamped to leave room
+    pub mss_headroom: Option<u16>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub file: PathBuf,
+    /// Exposed to the script as the global `settings` table
+    #[serde(default)]
+    pub settings: toml::value::Table
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub events: Option<String>,
+    pub metrics: Option<SocketAddr>
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// `tcp`, `udp` or `icmp`
+    pub protocols: Vec<String>,
+    /// TCP/UDP ports, matching either the source or destination port
+    pub ports: Vec<u16>
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FragmentConfig {
+    /// Seconds to wait for the missing fragments of a datagram
+    pub timeout: Option<u64>,
+    pub overlap: Option<OverlapPolicy>
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Milliseconds a callback may take
+    pub time_budget: Option<u64>,
+    /// MiB a script may allocate
+    pub memory_limit: Option<u64>,
+    pub over_budget: Option<OverBudgetPolicy>
+}
+
+impl SandboxConfig {
+    /// Values set in `other` take precedence
+    pub fn merge(self, other: SandboxConfig) -> SandboxConfig {
+        SandboxConfig {
+            time_budget: other.time_budget.or(self.time_budget),
+            memory_limit: other.memory_limit.or(self.memory_limit),
+            over_budget: other.over_budget.or(self.over_budget)
+        }
+    }
+
+    pub fn into_sandbox(self) -> Result<Sandbox, ConfigError> {
+        let default = Sandbox::default();
+        let time_budget = match self.time_budget {
+            Some(0) => return Err(ConfigError::Invalid("`sandbox.time_budget` has to be at least one millisecond".to_string())),
+            time_budget => time_budget.map_or(default.time_budget, Duration::from_millis)
+        };
+        let memory_limit = match self.memory_limit {
+            Some(0) => return Err(ConfigError::Invalid("`sandbox.memory_limit` has to be at least one MiB".to_string())),
+            Some(mib) => usize::try_from(mib).ok().and_then(|mib| mib.checked_mul(1024 * 1024))
+                .ok_or_else(|| ConfigError::Invalid(format!("`sandbox.memory_limit` of {} MiB is too large", mib)))?,
+            None => default.memory_limit
+        };
+        Ok(Sandbox { time_budget, memory_limit, policy: self.over_budget.unwrap_or(default.policy) })
+    }
+}
+
+/// Network impairment of the frames matching `target`, `protocol` and `port`. The fields are shared
+/// with `harpy.impair()`, an entry without any impairment removes the rule of its selector there.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImpairmentConfig {
+    /// IPv4 address or CIDR range, matching either the source or destination address
+    pub target: Option<String>,
+    /// `tcp`, `udp` or `icmp`
+    pub protocol: Option<String>,
+    /// TCP/UDP port, matching either the source or destination port
+    pub port: Option<u16>,
+    /// Milliseconds
+    pub delay: Option<u64>,
+    pub jitter: Option<u64>,
+    /// Percentages of frames
+    pub loss: Option<f64>,
+    pub duplicate: Option<f64>,
+    pub reorder: Option<f64>,
+    /// Bandwidth, f.e. `512kbit` or `10mbit`
+    pub rate: Option<String>
+}
+
+/// Flows selected by `target`, `protocol` and `port`, as passed to `harpy.intercept()`
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectorConfig {
+    /// IPv4 address or CIDR range, matching either the source or destination address
+    pub target: Option<String>,
+    /// `tcp`, `udp` or `icmp`
+    pub protocol: Option<String>,
+    /// TCP/UDP port, matching either the source or destination port
+    pub port: Option<u16>
+}
+
+impl SelectorConfig {
+    /// `field` names the table the selector was read from in errors
+    pub fn into_selector(self, field: &str) -> Result<Selector, ConfigError> {
+        Ok(Selector {
+            host: self.target.as_deref().map(|target| parse_network(target, &format!("{}.target", field))).transpose()?,
+            protocol: self.protocol.as_deref().map(|protocol| parse_protocol(protocol, &format!("{}.protocol", field))).transpose()?,
+            port: self.port
+        })
+    }
+}
+
+impl ImpairmentConfig {
+    /// The rule's selector and profile, no profile if no impairment is set
+    pub fn into_rule(self) -> Result<(Selector, Option<Profile>), ConfigError> {
+        let selector = SelectorConfig { target: self.target, protocol: self.protocol, port: self.port }.into_selector("impairment")?;
+        let percentage = |value: Option<f64>, field: &str| match value {
+            Some(percent) if !(0.0..=100.0).contains(&percent) =>
+                Err(ConfigError::Invalid(format!("`impairment.{}` of {} isn't a percentage between 0 and 100", field, percent))),
+            percent => Ok(percent.unwrap_or(0.0))
+        };
+        let rate = self.rate.as_deref().map(|rate| impair::parse_rate(rate)
+            .ok_or_else(|| ConfigError::Invalid(format!("`{}` in `impairment.rate` isn't a bandwidth, f.e. 512kbit or 10mbit", rate))))
+            .transpose()?;
+        let profile = Profile {
+            delay: Duration::from_millis(self.delay.unwrap_or(0)),
+            jitter: Duration::from_millis(self.jitter.unwrap_or(0)),
+            loss: percentage(self.loss, "loss")?,
+            duplicate: percentage(self.duplicate, "duplicate")?,
+            reorder: percentage(self.reorder, "reorder")?,
+            rate
+        };
+        Ok((selector, (profile != Profile::default()).then_some(profile)))
+    }
+}
+
+/// Restricts which packets are passed to the scripts, an empty filter matches everything
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Filter {
+    pub protocols: Vec<IpNextHeaderProtocol>,
+    pub ports: Vec<u16>
+}
+
+impl Filter {
+    pub fn matches(&self, ipv4: &Ipv4Packet) -> bool {
+        let protocol = ipv4.get_next_level_protocol();
+        if !self.protocols.is_empty() && !self.protocols.contains(&protocol) {
+            return false;
+        }
+        if self.ports.is_empty() {
+            return true;
+        }
+        let ports = match protocol {
+            IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4.payload()).map(|tcp| (tcp.get_source(), tcp.get_destination())),
+            IpNextHeaderProtocols::Udp => UdpPacket::new(ipv4.payload()).map(|udp| (udp.get_source(), udp.get_destination())),
+            _ => None
+        };
+        ports.is_some_and(|(source, destination)| self.ports.contains(&source) || self.ports.contains(&destination))
+    }
+
+    /// The filter as selectors, one per protocol and port
+    pub fn selectors(&self) -> Vec<Selector> {
+        let protocols: Vec<_> = match self.protocols.is_empty() {
+            true => vec![None],
+            false => self.protocols.iter().copied().map(Some).collect()
+        };
+        let ports: Vec<_> = match self.ports.is_empty() {
+            true => vec![None],
+            false => self.ports.iter().copied().map(Some).collect()
+        };
+        protocols.iter()
+            .flat_map(|&protocol| ports.iter().map(move |&port| Selector { host: None, protocol, port }))
+            .collect()
+    }
+}
+
+/// Command line flags of `harpy spoof`, these take precedence over the configuration file
+#[derive(Debug, Default)]
+pub struct Overrides {
+    pub interface: Option<String>,
+    pub gateway: Option<Ipv4Addr>,
+    pub target: Option<String>,
+    pub file: Option<PathBuf>,
+    pub all: bool,
+    pub block_quic: Option<QuicBlockMode>,
+    pub events: Option<String>,
+    pub metrics: Option<SocketAddr>,
+    pub tui: bool,
+    pub repoison_interval: Option<u64>,
+    /// Enables the sandbox, the set values override those of the file
+    pub sandbox: Option<SandboxConfig>,
+    /// Impairment of all forwarded frames, applies after the rules of the file
+    pub impairment: Option<ImpairmentConfig>,
+    pub fast_path: bool,
+    pub fragment_timeout: Option<u64>,
+    pub fragment_overlap: Option<OverlapPolicy>,
+    pub mss_headroom: Option<u16>
+}
+
+/// A validated spoof session
+#[derive(Debug)]
+pub struct Session {
+    pub interface: String,
+    pub gateway: Option<Ipv4Addr>,
+    pub targets: Vec<Ipv4Addr>,
+    pub repoison_interval: Option<Duration>,
+    pub all: bool,
+    pub block_quic: Option<QuicBlockMode>,
+    pub tui: bool,
+    pub scripts: Vec<ScriptConfig>,
+    pub events: Option<String>,
+    pub metrics: Option<SocketAddr>,
+    pub filter: Filter,
+    pub sandbox: Option<Sandbox>,
+    pub impairments: Vec<(Selector, Profile)>,
+    pub fast_path: bool,
+    pub fragment_timeout: Duration,
+    pub fragment_overlap: OverlapPolicy,
+    pub mss_headroom: u16
+}
+
+impl SessionConfig {
+    /// Reads a configuration file, relative script paths are resolved against its directory
+    pub fn load(path: &Path) -> Result<SessionConfig, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let mut config: SessionConfig = toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_owned(), e))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for script in config.scripts.iter_mut() {
+            if script.file.is_relative() {
+                script.file = base.join(&script.file);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Applies the command line flags and validates the result
+    pub fn into_session(self, overrides: Overrides) -> Result<Session, ConfigError> {
+        let interface = overrides.interface.or(self.interface)
+            .ok_or_else(|| ConfigError::Invalid("no interface given, set `interface` or pass --interface".to_string()))?;
+
+        let targets = match overrides.target {
+            Some(target) => vec![target],
+            None => self.targets
+        };
+        if targets.is_empty() {
+            return Err(ConfigError::Invalid("no targets given, set `targets` or pass --target".to_string()));
+        }
+        let excluded = self.exclude.iter()
+            .map(|exclusion| parse_network(exclusion, "exclude"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let gateway = overrides.gateway.or(self.gateway);
+        let mut hosts: Vec<Ipv4Addr> = Vec::new();
+        for target in targets.iter() {
+            for host in hosts_of(parse_network(target, "targets")?) {
+                if !excluded.iter().any(|network| network.contains(host)) && Some(host) != gateway && !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+                if hosts.len() > MAX_TARGETS {
+                    return Err(ConfigError::Invalid(format!("more than {} targets, narrow down `targets`", MAX_TARGETS)));
+                }
+            }
+        }
+        if hosts.is_empty() {
+            return Err(ConfigError::Invalid("every target is excluded".to_string()));
+        }
+
+        let repoison_interval = match overrides.repoison_interval.or(self.repoison_interval) {
+            Some(0) => return Err(ConfigError::Invalid("`repoison_interval` has to be at least one second".to_string())),
+            interval => interval.map(Duration::from_secs)
+        };
+
+        let scripts = match overrides.file {
+            Some(file) => vec![ScriptConfig { file, settings: Default::default() }],
+            None => self.scripts
+        };
+        if let Some(script) = scripts.iter().find(|script| !script.file.is_file()) {
+            return Err(ConfigError::Invalid(format!("script {} doesn't exist", script.file.display())));
+        }
+
+        let protocols = self.filter.protocols.iter()
+            .map(|protocol| parse_protocol(protocol, "filter.protocols"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sandbox = match (self.sandbox, overrides.sandbox) {
+            (None, None) => None,
+            (config, flags) => Some(config.unwrap_or_default().merge(flags.unwrap_or_default()).into_sandbox()?)
+        };
+
+        let fragment_timeout = match overrides.fragment_timeout.or(self.fragments.timeout) {
+            Some(0) => return Err(ConfigError::Invalid("`fragments.timeout` has to be at least one second".to_string())),
+            timeout => Duration::from_secs(timeout.unwrap_or(30))
+        };
+
+        let mut impairments = Vec::new();
+        for impairment in self.impairment.into_iter().chain(overrides.impairment) {
+            if let (selector, Some(profile)) = impairment.into_rule()? {
+                impairments.push((selector, profile));
+            }
+        }
+
+        Ok(Session {
+            interface,
+            gateway,
+            targets: hosts,
+            repoison_interval,
+            all: overrides.all || self.all,
+            block_quic: overrides.block_quic.or(self.block_quic),
+            tui: overrides.tui || self.tui,
+            scripts,
+            events: overrides.events.or(self.output.events),
+            metrics: overrides.metrics.or(self.output.metrics),
+            filter: Filter { protocols, ports: self.filter.ports },
+            sandbox,
+            impairments,
+            fast_path: overrides.fast_path || self.fast_path,
+            fragment_timeout,
+            fragment_overlap: overrides.fragment_overlap.or(self.fragments.overlap).unwrap_or(OverlapPolicy::Drop),
+            mss_headroom: overrides.mss_headroom.or(self.mss_headroom).unwrap_or(0)
+        })
+    }
+}
+
+fn parse_network(value: &str, field: &str) -> Result<Ipv4Network, ConfigError> {
+    value.parse::<Ipv4Network>()
+        .map_err(|_| ConfigError::Invalid(format!("`{}` in `{}` is neither an IPv4 address nor a CIDR range", value, field)))
+}
+
+fn parse_protocol(value: &str, field: &str) -> Result<IpNextHeaderProtocol, ConfigError> {
+    match value.to_ascii_lowercase().as_str() {
+        "tcp" => Ok(IpNextHeaderProtocols::Tcp),
+        "udp" => Ok(IpNextHeaderProtocols::Udp),
+        "icmp" => Ok(IpNextHeaderProtocols::Icmp),
+        _ => Err(ConfigError::Invalid(format!("unknown protocol `{}` in `{}`, expected tcp, udp or icmp", value, field)))
+    }
+}
+
+/// Hosts of a network, without its network and broadcast address
+fn hosts_of(network: Ipv4Network) -> impl Iterator<Item = Ipv4Addr> {
+    let skip_edges = network.prefix() < 31;
+    network.iter().filter(move |&host| !skip_edges || (host != network.network() && host != network.broadcast()))
+}
+
+
+#[test]
+fn test_session_config() {
+    let config: SessionConfig = toml::from_str(r#"
+        interface = "enp7s0"
+        gateway = "192.168.0.1"
+        targets = ["192.168.0.0/29", "192.168.0.53"]
+        exclude = ["192.168.0.4/31"]
+        repoison_interval = 10
+        block_quic = "reject"
+        mss_headroom = 100
+
+        [output]
+        metrics = "127.0.0.1:9100"
+
+        [filter]
+        protocols = ["udp"]
+        ports = [53]
+
+        [fragments]
+        overlap = "first"
+
+        [[impairment]]
+        target = "192.168.0.53"
+        protocol = "tcp"
+        port = 443
+        delay = 200
+        jitter = 50
+        loss = 2.5
+        rate = "1mbit"
+
+        [[impairment]]
+        target = "192.168.0.2"
+    "#).unwrap();
+    let session = config.into_session(Overrides {
+        events: Some("-".to_string()),
+        impairment: Some(ImpairmentConfig { delay: Some(10), ..Default::default() }),
+        ..Default::default()
+    }).unwrap();
+
+    assert_eq!(session.interface, "enp7s0");
+    assert_eq!(session.targets, [2, 3, 6, 53].map(|host| Ipv4Addr::new(192, 168, 0, host)));
+    assert_eq!(session.repoison_interval, Some(Duration::from_secs(10)));
+    assert_eq!(session.block_quic, Some(QuicBlockMode::Reject));
+    assert_eq!(session.events.as_deref(), Some("-"));
+    assert_eq!(session.metrics, Some("127.0.0.1:9100".parse().unwrap()));
+    assert_eq!(session.filter.protocols, [IpNextHeaderProtocols::Udp]);
+    assert_eq!(session.filter.selectors(), [Selector { host: None, protocol: Some(IpNextHeaderProtocols::Udp), port: Some(53) }]);
+    assert!(!session.fast_path);
+    assert_eq!((session.fragment_timeout, session.fragment_overlap), (Duration::from_secs(30), OverlapPolicy::First));
+    assert_eq!(session.mss_headroom, 100);
+    // The entry without impairment is skipped, the flags apply to everything else
+    assert_eq!(session.impairments, [
+        (Selector { host: Some("192.168.0.53/32".parse().unwrap()), protocol: Some(IpNextHeaderProtocols::Tcp), port: Some(443) }, Profile {
+            delay: Duration::from_millis(200),
+            jitter: Duration::from_millis(50),
+            loss: 2.5,
+            rate: Some(1_000_000),
+            ..Default::default()
+        }),
+        (Selector::default(), Profile { delay: Duration::from_millis(10), ..Default::default() })
+    ]);
+
+    let session = SessionConfig { interface: Some("eth0".to_string()), targets: vec!["10.0.0.5".to_string()], ..Default::default() }
+        .into_session(Overrides { target: Some("10.0.0.7".to_string()), ..Default::default() })
+        .unwrap();
+    assert_eq!(session.targets, [Ipv4Addr::new(10, 0, 0, 7)]);
+    assert_eq!(session.sandbox, None);
+
+    let session = SessionConfig {
+        interface: Some("eth0".to_string()),
+        targets: vec!["10.0.0.5".to_string()],
+        sandbox: Some(SandboxConfig { time_budget: Some(20), over_budget: Some(OverBudgetPolicy::Drop), ..Default::default() }),
+        ..Default::default()
+    }.into_session(Overrides {
+        sandbox: Some(SandboxConfig { over_budget: Some(OverBudgetPolicy::Disable), ..Default::default() }),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(session.sandbox, Some(Sandbox {
+        time_budget: Duration::from_millis(20),
+        memory_limit: Sandbox::default().memory_limit,
+        policy: OverBudgetPolicy::Disable
+    }));
+}
+
+#[test]
+fn test_session_config_validation() {
+    let invalid = |config: &str| match toml::from_str::<SessionConfig>(config) {
+        Ok(config) => config.into_session(Overrides::default()).unwrap_err().to_string(),
+        Err(e) => e.to_string()
+    };
+
+    assert!(invalid(r#"targets = ["10.0.0.1"]"#).contains("no interface given"));
+    assert!(invalid(r#"interface = "eth0""#).contains("no targets given"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.300"]"#).contains("`10.0.0.300` in `targets`"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        exclude = ["10.0.0.0/24"]"#).contains("every target is excluded"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.0/8"]"#).contains("more than 1024 targets"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        repoison_interval = 0"#).contains("at least one second"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        [filter]
+        protocols = ["sctp"]"#).contains("unknown protocol `sctp`"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        [[scripts]]
+        file = "does-not-exist.lua""#).contains("doesn't exist"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        [sandbox]
+        time_budget = 0"#).contains("at least one millisecond"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        [[impairment]]
+        loss = 150"#).contains("`impairment.loss` of 150"));
+    assert!(invalid(r#"interface = "eth0"
+        targets = ["10.0.0.1"]
+        [[impairment]]
+        rate = "fast""#).contains("`fast` in `impairment.rate`"));
+    assert!(invalid(r#"interfaces = "eth0""#).contains("unknown field `interfaces`"));
+}
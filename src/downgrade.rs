@@ -0,0 +1,230 @@
+use std::{collections::{HashMap, HashSet}, net::Ipv4Addr, time::{Duration, Instant}};
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    tcp::{TcpPacket, TcpFlags},
+    udp::UdpPacket,
+    Packet
+};
+
+use serde_json::json;
+
+use crate::{events::Events, icmp, metrics::METRICS, quic::{self, Header, PacketType}, session::{Frame, PacketHandler, Verdict}};
+
+/// UDP port of the blocked QUIC traffic, the one HTTP/3 uses
+pub const QUIC_PORT: u16 = 443;
+
+/// How QUIC traffic is blocked to force clients back to TCP/TLS
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuicBlockMode {
+    /// Silently drop QUIC datagrams, clients fall back once their handshake times out
+    Drop,
+    /// Answer QUIC datagrams with ICMP port unreachable, clients fall back immediately
+    Reject
+}
+
+/// Fallback statistics of a single client
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HostStats {
+    /// Number of QUIC datagrams blocked
+    pub blocked: u64,
+    /// Servers the client tried to reach over QUIC
+    pub quic_servers: HashSet<Ipv4Addr>,
+    /// Servers the client connected to over TCP after its QUIC traffic was blocked
+    pub fallbacks: HashSet<Ipv4Addr>
+}
+
+/// Tracks which clients fell back to TCP after their QUIC traffic to a server was blocked
+pub struct DowngradeStats {
+    hosts: HashMap<Ipv4Addr, HostStats>,
+    report_interval: Duration,
+    last_report: Instant,
+    changed: bool
+}
+
+impl Default for DowngradeStats {
+    fn default() -> Self {
+        DowngradeStats {
+            hosts: Default::default(),
+            report_interval: Duration::from_secs(60),
+            last_report: Instant::now(),
+            changed: false
+        }
+    }
+}
+
+impl DowngradeStats {
+    pub fn hosts(&self) -> &HashMap<Ipv4Addr, HostStats> {
+        &self.hosts
+    }
+
+    /// Records a blocked QUIC datagram the client sent to the server
+    pub fn record_blocked(&mut self, client: Ipv4Addr, server: Ipv4Addr) {
+        let host = self.hosts.entry(client).or_default();
+        host.blocked += 1;
+        METRICS.quic_blocked.inc();
+        if host.quic_servers.insert(server) {
+            debug!("Blocking QUIC from {} to {}", client, server);
+        }
+        self.changed = true;
+    }
+
+    /// Records a TCP SYN, returns true if it confirms a fallback of the client to TCP
+    pub fn record_tcp_syn(&mut self, client: Ipv4Addr, server: Ipv4Addr) -> bool {
+        let host = match self.hosts.get_mut(&client) {
+            Some(host) if host.quic_servers.contains(&server) => host,
+            _ => return false
+        };
+        if !host.fallbacks.insert(server) {
+            return false;
+        }
+        info!("{} fell back to TCP for {}", client, server);
+        METRICS.quic_fallbacks.inc();
+        self.changed = true;
+        true
+    }
+
+    /// Logs a per-host summary and emits it as `quic_downgrade` events, at most once per report
+    /// interval and only if something changed
+    pub fn report(&mut self, events: &Events) {
+        if !self.changed || self.last_report.elapsed() < self.report_interval {
+            return;
+        }
+        self.changed = false;
+        self.last_report = Instant::now();
+        for (client, host) in self.hosts.iter() {
+            info!("QUIC downgrade {}: {} datagrams blocked, {}/{} servers fell back to TCP",
+                client, host.blocked, host.fallbacks.len(), host.quic_servers.len());
+            events.emit("quic_downgrade", json!({
+                "client": client.to_string(),
+                "blocked": host.blocked,
+                "quic_servers": host.quic_servers.len(),
+                "fallbacks": host.fallbacks.len()
+            }));
+        }
+    }
+}
+
+/// Blocks QUIC datagrams passing through a session and tracks the clients' fallback to TCP
+pub struct QuicBlocker {
+    mode: QuicBlockMode,
+    stats: DowngradeStats,
+    events: Events
+}
+
+impl QuicBlocker {
+    pub fn new(mode: QuicBlockMode) -> QuicBlocker {
+        QuicBlocker { mode, stats: DowngradeStats::default(), events: Events::default() }
+    }
+    /// Emits `quic_fallback` for every confirmed fallback and periodic `quic_downgrade` summaries
+    pub fn events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+    pub fn stats(&self) -> &DowngradeStats {
+        &self.stats
+    }
+}
+
+/// Whether `udp` carries a handshake packet of QUIC v1 or v2 to or from [`QUIC_PORT`]. Other
+/// long headers are too easily mistaken for DNS, games or VPNs, a DNS ID from 0xf000 already
+/// reads as one. Short headers carry no version at all, so connections established before the
+/// session keep working until they close.
+fn is_quic_handshake(udp: &UdpPacket) -> bool {
+    if udp.get_source() != QUIC_PORT && udp.get_destination() != QUIC_PORT {
+        return false;
+    }
+    match quic::parse_datagram(udp.payload(), 0) {
+        Ok(packets) => matches!(packets.first().map(|packet| &packet.header), Some(Header::Long(header))
+            if matches!(header.version, quic::QUIC_V1 | quic::QUIC_V2)
+                && matches!(header.packet_type, PacketType::Initial | PacketType::ZeroRtt | PacketType::Handshake)),
+        Err(_) => false
+    }
+}
+
+impl PacketHandler for QuicBlocker {
+    fn on_packet(&mut self, frame: &Frame) -> Verdict {
+        let (source_ip, target_ip) = (frame.source(), frame.destination());
+        let mut verdict = Verdict::Forward;
+        match frame.ipv4.get_next_level_protocol() {
+            IpNextHeaderProtocols::Udp => {
+                if let Some(udp) = UdpPacket::new(frame.ipv4.payload()).filter(is_quic_handshake) {
+                    // The client is the side using the ephemeral (higher) port
+                    if udp.get_source() > udp.get_destination() {
+                        self.stats.record_blocked(source_ip, target_ip);
+                    } else {
+                        self.stats.record_blocked(target_ip, source_ip);
+                    }
+                    if self.mode == QuicBlockMode::Reject {
+                        frame.reply(&icmp::port_unreachable(&frame.ipv4));
+                    }
+                    verdict = Verdict::Drop;
+                }
+            },
+            IpNextHeaderProtocols::Tcp => {
+                if let Some(tcp) = TcpPacket::new(frame.ipv4.payload()) {
+                    if tcp.get_flags() & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN
+                        && self.stats.record_tcp_syn(source_ip, target_ip) {
+                        self.events.emit("quic_fallback", json!({ "client": source_ip.to_string(), "server": target_ip.to_string() }));
+                    }
+                }
+            },
+            _ => ()
+        }
+        self.stats.report(&self.events);
+        verdict
+    }
+}
+
+
+#[test]
+fn test_fallback_tracking() {
+    let client = Ipv4Addr::new(192, 168, 0, 38);
+    let server = Ipv4Addr::new(1, 1, 1, 1);
+    let mut stats = DowngradeStats::default();
+
+    assert!(!stats.record_tcp_syn(client, server));
+    stats.record_blocked(client, server);
+    stats.record_blocked(client, server);
+    assert!(!stats.record_tcp_syn(client, Ipv4Addr::new(8, 8, 8, 8)));
+    assert!(stats.record_tcp_syn(client, server));
+    // Further connections don't count as another fallback
+    assert!(!stats.record_tcp_syn(client, server));
+
+    let host = &stats.hosts()[&client];
+    assert_eq!(host.blocked, 2);
+    assert_eq!(host.fallbacks.len(), 1);
+
+    let path = std::env::temp_dir().join(format!("harpy-downgrade-{}.jsonl", std::process::id()));
+    stats.report_interval = Duration::ZERO;
+    stats.report(&Events::open(path.to_str().unwrap()).unwrap());
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let event = serde_json::from_str::<serde_json::Value>(&contents).unwrap();
+    assert_eq!(event["type"], "quic_downgrade");
+    assert_eq!(event["client"], "192.168.0.38");
+    assert_eq!(event["blocked"], 2);
+    assert_eq!(event["fallbacks"], 1);
+}
+
+#[test]
+fn test_quic_detection() {
+    use hex_literal::hex;
+    let udp = |source: u16, destination: u16, payload: &[u8]| {
+        let mut datagram = [&source.to_be_bytes()[..], &destination.to_be_bytes(), &(8 + payload.len() as u16).to_be_bytes(), &[0, 0]].concat();
+        datagram.extend(payload);
+        UdpPacket::owned(datagram).unwrap()
+    };
+    // Handshake packet of QUIC v1
+    let handshake = hex!("e000000001 04 01020304 00 03 aabbcc");
+    assert!(is_quic_handshake(&udp(50000, 443, &handshake)));
+    assert!(is_quic_handshake(&udp(443, 50000, &handshake)));
+    assert!(!is_quic_handshake(&udp(50000, 8443, &handshake)));
+    assert!(!is_quic_handshake(&udp(50000, 443, &[])));
+
+    // DNS query for example.com with an ID of 0xf012, its first byte reads as a long header
+    let query = hex!("f012 0100 0001 0000 0000 0000 07 6578616d706c65 03 636f6d 00 0001 0001");
+    assert!(!is_quic_handshake(&udp(50000, 53, &query)));
+    assert!(!is_quic_handshake(&udp(50000, 443, &query)));
+}
@@ -0,0 +1,332 @@
+//! Encapsulations between the Ethernet header and the network layer: 802.1Q VLAN tags, 802.1ad
+//! QinQ stacks, PPPoE sessions (RFC 2516) and MPLS label stacks (RFC 3032). Harpy looks for the
+//! packet inside, and keeps the tags when it forwards or modifies the frame.
+
+use pnet::packet::{
+    ethernet::{EtherType, EtherTypes, EthernetPacket},
+    Packet
+};
+
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_QINQ: u16 = 0x88a8;
+/// Used for QinQ before 802.1ad, some switches still do
+pub const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+pub const ETHERTYPE_PPPOE_SESSION: u16 = 0x8864;
+pub const ETHERTYPE_MPLS: u16 = 0x8847;
+pub const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+
+// https://www.iana.org/assignments/ppp-numbers
+const PPP_IPV4: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+const PPP_MPLS: u16 = 0x0281;
+
+/// Version and type of PPPoE headers (RFC 2516)
+const PPPOE_VERSION_TYPE: u8 = 0x11;
+/// Tags parsed before giving up on a frame
+const MAX_TAGS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    /// 802.1Q tag, or an 802.1ad service tag of a QinQ stack, told apart by `tpid`
+    Vlan { tpid: u16, pcp: u8, dei: bool, vid: u16 },
+    /// PPPoE session header, followed by the PPP protocol
+    Pppoe { session: u16 },
+    /// MPLS label stack entry, the bottom of stack bit is set on the last one
+    Mpls { label: u32, tc: u8, ttl: u8 }
+}
+
+impl Tag {
+    /// Returns the name scripts know the tag by
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tag::Vlan { .. } => "vlan",
+            Tag::Pppoe { .. } => "pppoe",
+            Tag::Mpls { .. } => "mpls"
+        }
+    }
+
+    /// Bytes the tag takes in the frame
+    pub fn header_len(&self) -> usize {
+        match self {
+            Tag::Vlan { .. } | Tag::Mpls { .. } => 4,
+            Tag::Pppoe { .. } => 8
+        }
+    }
+
+    /// The EtherType announcing the tag
+    fn ethertype(&self) -> u16 {
+        match self {
+            Tag::Vlan { tpid, .. } => *tpid,
+            Tag::Pppoe { .. } => ETHERTYPE_PPPOE_SESSION,
+            Tag::Mpls { .. } => ETHERTYPE_MPLS
+        }
+    }
+}
+
+/// The tags of a frame and the packet they carry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encapsulation {
+    /// Outermost first
+    pub tags: Vec<Tag>,
+    /// Type of the encapsulated packet
+    pub ethertype: EtherType,
+    /// Offset of the encapsulated packet in the frame
+    pub offset: usize
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Guesses the packet following a label stack from its version, MPLS doesn't announce it
+fn after_labels(data: &[u8]) -> Option<EtherType> {
+    match data.first()? >> 4 {
+        4 => Some(EtherTypes::Ipv4),
+        6 => Some(EtherTypes::Ipv6),
+        _ => None
+    }
+}
+
+impl Encapsulation {
+    /// Walks the tags of `frame`. Parsing stops at the first header harpy doesn't know or that
+    /// is cut short, `ethertype` and `offset` then point at it.
+    pub fn parse(frame: &EthernetPacket) -> Encapsulation {
+        let data = frame.packet();
+        let mut tags = Vec::new();
+        let mut ethertype = frame.get_ethertype().0;
+        let mut offset = EthernetPacket::minimum_packet_size();
+        while tags.len() < MAX_TAGS {
+            match ethertype {
+                ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY => {
+                    let (Some(tci), Some(next)) = (read_u16(data, offset), read_u16(data, offset + 2)) else {
+                        break;
+                    };
+                    tags.push(Tag::Vlan { tpid: ethertype, pcp: (tci >> 13) as u8, dei: tci & 0x1000 != 0, vid: tci & 0x0fff });
+                    ethertype = next;
+                    offset += 4;
+                },
+                ETHERTYPE_PPPOE_SESSION => {
+                    // Only the session stage carries packets, with a code of 0
+                    if data.get(offset..offset + 2) != Some(&[PPPOE_VERSION_TYPE, 0][..]) {
+                        break;
+                    }
+                    let (Some(session), Some(protocol)) = (read_u16(data, offset + 2), read_u16(data, offset + 6)) else {
+                        break;
+                    };
+                    let next = match protocol {
+                        PPP_IPV4 => EtherTypes::Ipv4.0,
+                        PPP_IPV6 => EtherTypes::Ipv6.0,
+                        PPP_MPLS => ETHERTYPE_MPLS,
+                        // LCP, IPCP and the like
+                        _ => break
+                    };
+                    tags.push(Tag::Pppoe { session });
+                    ethertype = next;
+                    offset += 8;
+                },
+                ETHERTYPE_MPLS | ETHERTYPE_MPLS_MULTICAST => {
+                    let mut labels = Vec::new();
+                    let mut end = offset;
+                    let bottom = loop {
+                        let Some(entry) = data.get(end..end + 4) else {
+                            break false;
+                        };
+                        let entry = u32::from_be_bytes(entry.try_into().unwrap());
+                        labels.push(Tag::Mpls { label: entry >> 12, tc: ((entry >> 9) & 0x7) as u8, ttl: entry as u8 });
+                        end += 4;
+                        if entry & 0x100 != 0 {
+                            break true;
+                        }
+                        if tags.len() + labels.len() >= MAX_TAGS {
+                            break false;
+                        }
+                    };
+                    // Pseudowires and the like are left alone, together with their labels
+                    let next = match bottom {
+                        true => after_labels(&data[end..]),
+                        false => None
+                    };
+                    let Some(next) = next else {
+                        break;
+                    };
+                    tags.extend(labels);
+                    ethertype = next.0;
+                    offset = end;
+                    break;
+                },
+                _ => break
+            }
+        }
+        Encapsulation { tags, ethertype: EtherType(ethertype), offset }
+    }
+
+    /// The encapsulated packet
+    pub fn payload<'a>(&self, frame: &'a EthernetPacket) -> &'a [u8] {
+        &frame.packet()[self.offset..]
+    }
+
+    /// Bytes the tags take from the MTU. VLAN tags don't, links carrying them allow larger frames.
+    pub fn overhead(&self) -> usize {
+        self.tags.iter().filter(|tag| !matches!(tag, Tag::Vlan { .. })).map(Tag::header_len).sum()
+    }
+
+    /// Returns `frame` carrying `payload` instead, with the same addresses and tags
+    pub fn with_payload(&self, frame: &EthernetPacket, payload: &[u8]) -> EthernetPacket<'static> {
+        let mut buf = [&frame.packet()[..self.offset], payload].concat();
+        set_lengths(&self.tags, &mut buf);
+        EthernetPacket::owned(buf).unwrap()
+    }
+}
+
+/// Updates the length of PPPoE headers to the bytes following them
+fn set_lengths(tags: &[Tag], frame: &mut [u8]) {
+    let mut offset = EthernetPacket::minimum_packet_size();
+    for tag in tags {
+        if let Tag::Pppoe { .. } = tag {
+            let length = (frame.len() - offset - 6) as u16;
+            frame[offset + 4..offset + 6].copy_from_slice(&length.to_be_bytes());
+        }
+        offset += tag.header_len();
+    }
+}
+
+/// Writes the field announcing `next` after `previous`
+fn announce(previous: Option<&Tag>, next: u16, out: &mut Vec<u8>) -> Result<(), String> {
+    match previous {
+        None | Some(Tag::Vlan { .. }) => out.extend(next.to_be_bytes()),
+        Some(Tag::Pppoe { .. }) => out.extend(match next {
+            0x0800 => PPP_IPV4,
+            0x86dd => PPP_IPV6,
+            ETHERTYPE_MPLS => PPP_MPLS,
+            _ => return Err(format!("PPPoE can't carry EtherType {:#06x}", next))
+        }.to_be_bytes()),
+        Some(Tag::Mpls { .. }) => match next {
+            0x0800 | 0x86dd | ETHERTYPE_MPLS => (),
+            _ => return Err(format!("MPLS labels can only be followed by labels or IP, not EtherType {:#06x}", next))
+        }
+    }
+    Ok(())
+}
+
+/// Returns `frame` with its tags replaced by `tags`, the addresses and the encapsulated packet
+/// are kept.
+pub fn retag(frame: &EthernetPacket, tags: &[Tag]) -> Result<EthernetPacket<'static>, String> {
+    let encapsulation = Encapsulation::parse(frame);
+    let mut buf = frame.packet()[..12].to_vec();
+    for (index, tag) in tags.iter().enumerate() {
+        announce(index.checked_sub(1).map(|previous| &tags[previous]), tag.ethertype(), &mut buf)?;
+        match *tag {
+            Tag::Vlan { tpid, pcp, dei, vid } => {
+                if ![ETHERTYPE_VLAN, ETHERTYPE_QINQ, ETHERTYPE_QINQ_LEGACY].contains(&tpid) {
+                    return Err(format!("{:#06x} is not a VLAN TPID", tpid));
+                }
+                if pcp > 7 || vid > 0x0fff {
+                    return Err(format!("a VLAN tag can't have a PCP of {} and a VID of {}", pcp, vid));
+                }
+                buf.extend(((pcp as u16) << 13 | (dei as u16) << 12 | vid).to_be_bytes());
+            },
+            Tag::Pppoe { session } => {
+                buf.extend([PPPOE_VERSION_TYPE, 0]);
+                buf.extend(session.to_be_bytes());
+                // Set once the payload is known
+                buf.extend([0, 0]);
+            },
+            Tag::Mpls { label, tc, ttl } => {
+                if label > 0xfffff || tc > 7 {
+                    return Err(format!("an MPLS label can't be {} with a traffic class of {}", label, tc));
+                }
+                let bottom = !matches!(tags.get(index + 1), Some(Tag::Mpls { .. }));
+                buf.extend((label << 12 | (tc as u32) << 9 | (bottom as u32) << 8 | ttl as u32).to_be_bytes());
+            }
+        }
+    }
+    announce(tags.last(), encapsulation.ethertype.0, &mut buf)?;
+    buf.extend(encapsulation.payload(frame));
+    set_lengths(tags, &mut buf);
+    Ok(EthernetPacket::owned(buf).unwrap())
+}
+
+
+#[test]
+fn test_parse() {
+    use hex_literal::hex;
+    // QinQ with an outer 802.1ad tag (VID 100) and an inner 802.1Q tag (PCP 5, VID 20)
+    let frame = hex!("020000000002 020000000001 88a8 0064 8100 a014 0800 4500");
+    let frame = EthernetPacket::new(&frame).unwrap();
+    let encapsulation = Encapsulation::parse(&frame);
+    assert_eq!(encapsulation.tags, vec![
+        Tag::Vlan { tpid: ETHERTYPE_QINQ, pcp: 0, dei: false, vid: 100 },
+        Tag::Vlan { tpid: ETHERTYPE_VLAN, pcp: 5, dei: false, vid: 20 }
+    ]);
+    assert_eq!((encapsulation.ethertype, encapsulation.offset), (EtherTypes::Ipv4, 22));
+    assert_eq!(encapsulation.overhead(), 0);
+
+    // PPPoE session 0x1234 carrying IPv4
+    let frame = hex!("020000000002 020000000001 8864 1100 1234 0004 0021 4500");
+    let frame = EthernetPacket::new(&frame).unwrap();
+    let encapsulation = Encapsulation::parse(&frame);
+    assert_eq!(encapsulation.tags, vec![Tag::Pppoe { session: 0x1234 }]);
+    assert_eq!((encapsulation.ethertype, encapsulation.offset, encapsulation.overhead()), (EtherTypes::Ipv4, 22, 8));
+    let replaced = encapsulation.with_payload(&frame, &hex!("45000014"));
+    assert_eq!(replaced.packet(), hex!("020000000002 020000000001 8864 1100 1234 0006 0021 45000014"));
+
+    // LCP isn't unwrapped
+    let frame = hex!("020000000002 020000000001 8864 1100 1234 0004 c021 0101");
+    let encapsulation = Encapsulation::parse(&EthernetPacket::new(&frame).unwrap());
+    assert_eq!((encapsulation.tags.len(), encapsulation.ethertype.0, encapsulation.offset), (0, ETHERTYPE_PPPOE_SESSION, 14));
+
+    // Two labels, 16 and 17, the second at the bottom of the stack
+    let frame = hex!("020000000002 020000000001 8847 00010040 000111ff 4500");
+    let frame = EthernetPacket::new(&frame).unwrap();
+    let encapsulation = Encapsulation::parse(&frame);
+    assert_eq!(encapsulation.tags, vec![Tag::Mpls { label: 16, tc: 0, ttl: 64 }, Tag::Mpls { label: 17, tc: 0, ttl: 255 }]);
+    assert_eq!((encapsulation.ethertype, encapsulation.offset), (EtherTypes::Ipv4, 22));
+
+    // Cut short
+    let frame = hex!("020000000002 020000000001 8100 0064");
+    let encapsulation = Encapsulation::parse(&EthernetPacket::new(&frame).unwrap());
+    assert_eq!((encapsulation.tags.len(), encapsulation.ethertype.0, encapsulation.offset), (0, ETHERTYPE_VLAN, 14));
+}
+
+#[test]
+fn test_retag() {
+    use hex_literal::hex;
+    let frame = hex!("020000000002 020000000001 0800 45000014");
+    let frame = EthernetPacket::new(&frame).unwrap();
+    let tags = [Tag::Vlan { tpid: ETHERTYPE_VLAN, pcp: 3, dei: true, vid: 42 }, Tag::Pppoe { session: 1 }, Tag::Mpls { label: 16, tc: 1, ttl: 64 }];
+    let tagged = retag(&frame, &tags).unwrap();
+    assert_eq!(tagged.packet(), hex!("020000000002 020000000001 8100 702a 8864 1100 0001 000a 0281 00010340 45000014"));
+    let encapsulation = Encapsulation::parse(&tagged);
+    assert_eq!(encapsulation.tags, tags);
+    assert_eq!(encapsulation.payload(&tagged), hex!("45000014"));
+    assert_eq!(retag(&tagged, &[]).unwrap().packet(), frame.packet());
+
+    assert!(retag(&frame, &[Tag::Mpls { label: 16, tc: 0, ttl: 64 }, Tag::Vlan { tpid: ETHERTYPE_VLAN, pcp: 0, dei: false, vid: 1 }]).is_err());
+    assert!(retag(&frame, &[Tag::Vlan { tpid: 0x0800, pcp: 0, dei: false, vid: 1 }]).is_err());
+    assert!(retag(&frame, &[Tag::Vlan { tpid: ETHERTYPE_VLAN, pcp: 0, dei: false, vid: 4096 }]).is_err());
+}
+
+#[test]
+fn test_with_payload() {
+    use hex_literal::hex;
+    let frame = hex!("020000000002 020000000001 0800 45000014");
+    let frame = EthernetPacket::new(&frame).unwrap();
+    let stacks = [
+        vec![Tag::Vlan { tpid: ETHERTYPE_VLAN, pcp: 0, dei: false, vid: 10 }],
+        vec![Tag::Pppoe { session: 7 }],
+        vec![Tag::Mpls { label: 16, tc: 0, ttl: 64 }, Tag::Mpls { label: 17, tc: 0, ttl: 64 }]
+    ];
+    for tags in stacks {
+        let tagged = retag(&frame, &tags).unwrap();
+        let encapsulation = Encapsulation::parse(&tagged);
+        // A tampered packet of a different size keeps the tags, PPPoE lengths follow it
+        let replaced = encapsulation.with_payload(&tagged, &hex!("45000018 00000000"));
+        let reparsed = Encapsulation::parse(&replaced);
+        assert_eq!(reparsed, encapsulation);
+        assert_eq!(reparsed.payload(&replaced), hex!("45000018 00000000"));
+        if tags[0] == (Tag::Pppoe { session: 7 }) {
+            assert_eq!(replaced.packet()[18..20], [0, 10]);
+        }
+        assert_eq!(retag(&replaced, &[]).unwrap().packet(), hex!("020000000002 020000000001 0800 45000018 00000000"));
+    }
+}
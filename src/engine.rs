@@ -1,91 +1,322 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{cell::Cell, path::PathBuf, sync::Arc};
 use pnet::packet::ethernet::EthernetPacket;
 
-use rlua::{Lua, Result, Context, Value};
+use rlua::{Lua, Result, Context, Value, Table, Variadic};
 
 pub mod types;
+pub mod sandbox;
 use types::*;
+use sandbox::{Budget, BudgetViolation, Sandbox};
+use crate::{config::{ImpairmentConfig, SelectorConfig}, events::Events, error::HarpyError, impair::{Impairments, Selector}};
+
+/// Name of the registry value holding the engine's `Events`
+pub const EVENTS: &str = "harpy_events";
+/// Name of the registry value holding the `Impairments` adjusted by `harpy.impair`
+pub const IMPAIRMENTS: &str = "harpy_impairments";
+
+/// Name of the registry value holding the flows declared with `harpy.intercept`
+pub const INTERCEPTS: &str = "harpy_intercepts";
+
+/// Name of the registry value holding the datagrams queued by `harpy.reply`
+pub const REPLIES: &str = "harpy_replies";
+
+impl UserData for Events {}
+impl UserData for Impairments {}
+
+/// Flows a script declared interest in
+#[derive(Default)]
+struct Intercepts(Vec<Selector>);
+
+impl UserData for Intercepts {}
+
+/// IPv4 datagrams to send back to the sender of the current frame
+#[derive(Default)]
+struct Replies(Vec<Vec<u8>>);
+
+impl UserData for Replies {}
+
+/// Converts a Lua value into JSON for `harpy.emit`. Sequences become arrays, other tables objects,
+/// `LuaBinary` a hex string. Nesting is limited so self-referencing tables can't recurse forever.
+fn lua_to_json(value: Value, depth: usize) -> Result<serde_json::Value> {
+    if depth > 16 {
+        return Err(LuaError::RuntimeError("harpy.emit(): tables nested too deeply".to_string()));
+    }
+    Ok(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => b.into(),
+        Value::Integer(n) => n.into(),
+        Value::Number(n) => n.into(),
+        Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into(),
+        Value::Table(table) if table.raw_len() > 0 => table.sequence_values::<Value>()
+            .map(|value| lua_to_json(value?, depth + 1))
+            .collect::<Result<serde_json::Value>>()?,
+        Value::Table(table) => {
+            let mut object = serde_json::Map::new();
+            for pair in table.pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                let key = match key {
+                    Value::String(s) => String::from_utf8_lossy(s.as_bytes()).into_owned(),
+                    Value::Integer(n) => n.to_string(),
+                    key => return Err(LuaError::RuntimeError(format!("harpy.emit(): {} keys are not supported", key.type_name())))
+                };
+                object.insert(key, lua_to_json(value, depth + 1)?);
+            }
+            object.into()
+        },
+        Value::UserData(d) if d.is::<LuaBinary>() => crate::tls::to_hex(&d.borrow::<LuaBinary>()?.0).into(),
+        value => return Err(LuaError::RuntimeError(format!("harpy.emit(): {} values are not supported", value.type_name())))
+    })
+}
+
+/// Converts a TOML value from a session's script settings into Lua
+fn toml_to_lua<'lua>(ctx: Context<'lua>, value: &toml::Value) -> Result<Value<'lua>> {
+    Ok(match value {
+        toml::Value::String(s) => Value::String(ctx.create_string(s)?),
+        toml::Value::Integer(n) => Value::Integer(*n),
+        toml::Value::Float(n) => Value::Number(*n),
+        toml::Value::Boolean(b) => Value::Boolean(*b),
+        toml::Value::Datetime(datetime) => Value::String(ctx.create_string(&datetime.to_string())?),
+        toml::Value::Array(values) => Value::Table(ctx.create_sequence_from(
+            values.iter().map(|value| toml_to_lua(ctx, value)).collect::<Result<Vec<_>>>()?
+        )?),
+        toml::Value::Table(table) => Value::Table(ctx.create_table_from(
+            table.iter().map(|(key, value)| Ok((key.as_str(), toml_to_lua(ctx, value)?))).collect::<Result<Vec<_>>>()?
+        )?)
+    })
+}
 
 pub struct HarpyEngine {
-    lua: Lua
+    lua: Lua,
+    sandbox: Option<Sandbox>,
+    budget: Option<Arc<Budget>>,
+    disabled: Cell<bool>
+}
 
+impl Default for HarpyEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl HarpyEngine {
     pub fn empty() -> HarpyEngine {
         HarpyEngine {
-            lua: Lua::new()
+            lua: Lua::new(),
+            sandbox: None,
+            budget: None,
+            disabled: Cell::new(false)
         }
     }
     pub fn new() -> HarpyEngine {
-        let harpy = Self::empty();
+        Self::empty().with_globals()
+    }
+    /// An engine with a restricted standard library, whose callbacks are limited in time and memory
+    pub fn sandboxed(sandbox: Sandbox) -> HarpyEngine {
+        let (lua, budget) = sandbox::new_lua();
+        let harpy = HarpyEngine {
+            lua,
+            sandbox: Some(sandbox),
+            budget: Some(budget),
+            disabled: Cell::new(false)
+        }.with_globals();
+        harpy.lua.set_memory_limit(Some(sandbox.memory_limit));
+        harpy
+    }
+    /// Registers harpy's functions and tables
+    fn with_globals(self) -> HarpyEngine {
+        let harpy = self;
         harpy.lua.context(|lua_ctx| {
             let g = lua_ctx.globals();
             g.set("harpy_version", env!("CARGO_PKG_VERSION")).unwrap();
-            g.set("binary", lua_ctx.create_function::<(Value,), LuaBinary, _>(|_, (value,)| {
-                // Iterate over the table and add all values to a Vec<u8>
-                let mut buffer: Vec<u8> = Vec::new();
-
-                match value {
-                    Value::Table(table) => {
-                        for v in table.pairs::<Value, Value>() {
-                            let (_, value) = v.unwrap();
-                            match value {
-                                Value::String(s) => {
-                                    let bytes = s.as_bytes();
-                                    buffer.extend_from_slice(bytes);
-                                },
-                                Value::Integer(n) => {
-                                    if n <= 255{
-                                        buffer.push(n as u8);
-                                    }else{
-                                        for byte in n.to_ne_bytes() {
-                                            buffer.push(byte);
-                                        }
-                                    }
-                                },
-                                _ => { }
-                            }
-                        }
-                    },
-                    Value::String(string) => {
-                        let bytes = string.as_bytes();
-                        buffer.extend_from_slice(bytes);
-                    },
-                    _ => {}
-                }
-                Ok(LuaBinary(buffer))
+            lua_ctx.set_named_registry_value(TLS_REASSEMBLER, crate::tls::TlsReassembler::default()).unwrap();
+            lua_ctx.set_named_registry_value(QUIC_REASSEMBLER, crate::quic::CryptoReassembler::default()).unwrap();
+            lua_ctx.set_named_registry_value(EVENTS, Events::default()).unwrap();
+            lua_ctx.set_named_registry_value(IMPAIRMENTS, Impairments::default()).unwrap();
+            lua_ctx.set_named_registry_value(INTERCEPTS, Intercepts::default()).unwrap();
+            lua_ctx.set_named_registry_value(REPLIES, Replies::default()).unwrap();
+
+            let harpy = lua_ctx.create_table().unwrap();
+            harpy.set("emit", lua_ctx.create_function(|ctx, (event_type, fields): (String, Value)| {
+                let fields = lua_to_json(fields, 0)?;
+                let events = ctx.named_registry_value::<_, AnyUserData>(EVENTS)?;
+                events.borrow::<Events>()?.emit(&event_type, fields);
+                Ok(())
+            }).unwrap()).unwrap();
+            harpy.set("counter", lua_ctx.create_function(|_, (name, help): (String, Option<String>)| {
+                crate::metrics::METRICS.script_counter(&name, help.as_deref())
+                    .map(LuaCounter)
+                    .ok_or_else(|| LuaError::RuntimeError(format!("harpy.counter(): invalid counter name '{}'", name)))
+            }).unwrap()).unwrap();
+            harpy.set("impair", lua_ctx.create_function(|ctx, rule: Table| {
+                let rule: ImpairmentConfig = serde_json::from_value(lua_to_json(Value::Table(rule), 0)?)
+                    .map_err(|e| LuaError::RuntimeError(format!("harpy.impair(): {}", e)))?;
+                let (selector, profile) = rule.into_rule().map_err(|e| LuaError::RuntimeError(format!("harpy.impair(): {}", e)))?;
+                let impairments = ctx.named_registry_value::<_, AnyUserData>(IMPAIRMENTS)?;
+                impairments.borrow::<Impairments>()?.set(selector, profile);
+                Ok(())
+            }).unwrap()).unwrap();
+            harpy.set("intercept", lua_ctx.create_function(|ctx, selector: Table| {
+                let selector: SelectorConfig = serde_json::from_value(lua_to_json(Value::Table(selector), 0)?)
+                    .map_err(|e| LuaError::RuntimeError(format!("harpy.intercept(): {}", e)))?;
+                let selector = selector.into_selector("intercept").map_err(|e| LuaError::RuntimeError(format!("harpy.intercept(): {}", e)))?;
+                let intercepts = ctx.named_registry_value::<_, AnyUserData>(INTERCEPTS)?;
+                intercepts.borrow_mut::<Intercepts>()?.0.push(selector);
+                Ok(())
+            }).unwrap()).unwrap();
+            harpy.set("reply", lua_ctx.create_function(|ctx, datagram: AnyUserData| {
+                let datagram = datagram.borrow::<LuaIpv4Packet>()
+                    .map_err(|_| LuaError::RuntimeError("harpy.reply(): expects a LuaIpv4Packet".to_string()))?;
+                let replies = ctx.named_registry_value::<_, AnyUserData>(REPLIES)?;
+                replies.borrow_mut::<Replies>()?.0.push(datagram.0.packet().to_vec());
+                Ok(())
             }).unwrap()).unwrap();
-            g.set("binary_to_string", lua_ctx.create_function::<(Value,), String, _>(|_, (value,)| {
-                let mut buffer: Vec<u8> = Vec::new();
-                match value {
-                    Value::UserData(d)
-                        if d.is::<LuaBinary>() => {
-                        let user_data = d.borrow::<LuaBinary>().unwrap();
-                        buffer.extend_from_slice(user_data.0.as_slice());
-                    }
-                    _ => { }
+            g.set("harpy", harpy).unwrap();
+            g.set("icmp", icmp::builder(lua_ctx).unwrap()).unwrap();
+            // `binary` is a table, so it can hold helpers such as `binary.pack`, while calling it
+            // still constructs a LuaBinary
+            let binary = lua_ctx.create_table().unwrap();
+            let constructor = lua_ctx.create_function(|_, (_, value): (Table, Value)| {
+                LuaBinary::construct(value)
+            }).unwrap();
+            let metatable = lua_ctx.create_table().unwrap();
+            metatable.set("__call", constructor).unwrap();
+            binary.set_metatable(Some(metatable));
+            binary.set("pack", lua_ctx.create_function(|_, (format, values): (String, Variadic<Value>)| {
+                binary::pack(&format, values)
+            }).unwrap()).unwrap();
+            binary.set("unpack", lua_ctx.create_function(|ctx, (format, data, offset): (String, Value, Option<usize>)| {
+                binary::unpack(ctx, &format, data, offset.unwrap_or(0))
+            }).unwrap()).unwrap();
+            binary.set("from_hex", lua_ctx.create_function(|_, (string,): (String,)| {
+                Ok(crate::util::from_hex(&string).map(LuaBinary))
+            }).unwrap()).unwrap();
+            binary.set("from_base64", lua_ctx.create_function(|_, (string,): (String,)| {
+                Ok(base64::decode(string.trim()).ok().map(LuaBinary))
+            }).unwrap()).unwrap();
+            g.set("binary", binary).unwrap();
+            g.set("binary_to_string", lua_ctx.create_function(|ctx, (value,): (Value,)| {
+                // Lua strings are 8-bit clean, the bytes are passed on as they are
+                match LuaBinary::bytes_of(&value) {
+                    Some(bytes) => ctx.create_string(&bytes),
+                    None => Err(LuaError::RuntimeError("binary_to_string() expects a LuaBinary".to_string()))
                 }
-                Ok(unsafe { String::from_utf8_unchecked(buffer) })
             }).unwrap()).unwrap();
-
         });
         harpy
     }
-    pub fn context<F, R>(&self, f: F) -> R where F: FnOnce(Context<'_>) -> R {
+    pub fn sandbox(&self) -> Option<&Sandbox> {
+        self.sandbox.as_ref()
+    }
+    /// Stops `on_packet` from being called, used by the `disable` over-budget policy
+    pub fn disable(&self) {
+        self.disabled.set(true);
+    }
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.get()
+    }
+    /// Sets where events emitted through `harpy.emit` are written to
+    pub fn set_events(&self, events: Events) {
         self.lua.context(|lua_ctx| {
-            f(lua_ctx)
+            lua_ctx.set_named_registry_value(EVENTS, events).unwrap();
+        });
+    }
+    /// Sets the impairment rules `harpy.impair` adjusts
+    pub fn set_impairments(&self, impairments: Impairments) {
+        self.lua.context(|lua_ctx| {
+            lua_ctx.set_named_registry_value(IMPAIRMENTS, impairments).unwrap();
+        });
+    }
+    /// Flows the script declared interest in with `harpy.intercept`, empty if it declared none
+    pub fn intercepts(&self) -> Vec<Selector> {
+        self.lua.context(|lua_ctx| {
+            lua_ctx.named_registry_value::<_, AnyUserData>(INTERCEPTS)
+                .and_then(|intercepts| Ok(intercepts.borrow::<Intercepts>()?.0.clone()))
+                .unwrap_or_default()
+        })
+    }
+    /// Datagrams the script queued with `harpy.reply` since the last call
+    pub fn take_replies(&self) -> Vec<Vec<u8>> {
+        self.lua.context(|lua_ctx| {
+            lua_ctx.named_registry_value::<_, AnyUserData>(REPLIES)
+                .and_then(|replies| Ok(std::mem::take(&mut replies.borrow_mut::<Replies>()?.0)))
+                .unwrap_or_default()
         })
     }
-    pub fn run_file(&self, file: PathBuf) -> Result<()> {
-        let mut file = File::open(file).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
+    /// Exposes per-script settings of a session as the global `settings` table
+    pub fn set_settings(&self, settings: &toml::value::Table) -> Result<()> {
         self.lua.context(|lua_ctx| {
-            lua_ctx.load(&contents).exec()?;
-            Ok(())
+            let settings = toml_to_lua(lua_ctx, &toml::Value::Table(settings.clone()))?;
+            lua_ctx.globals().set("settings", settings)
         })
     }
+    /// Runs `f` inside the Lua state, in a sandbox it has to finish within the time budget
+    pub fn context<F, R>(&self, f: F) -> R where F: FnOnce(Context<'_>) -> R {
+        if let (Some(budget), Some(sandbox)) = (&self.budget, &self.sandbox) {
+            budget.start(sandbox.time_budget);
+        }
+        let result = self.lua.context(|lua_ctx| {
+            f(lua_ctx)
+        });
+        if let Some(budget) = &self.budget {
+            budget.finish();
+        }
+        result
+    }
+    /// Loads and runs a script, errors point to the file and, for syntax errors, the line
+    pub fn run_file(&self, file: PathBuf) -> std::result::Result<(), HarpyError> {
+        let contents = std::fs::read(&file).map_err(|e| HarpyError::ScriptRead(file.clone(), e))?;
+        self.context(|lua_ctx| {
+            lua_ctx.load(&contents).set_name(&format!("@{}", file.display()))?.exec()
+        }).map_err(|e| HarpyError::from_lua(file, e))
+    }
+    /// Passes a frame to the script's `on_packet` function, if it defines one and isn't disabled
+    pub fn on_packet(&self, packet: &EthernetPacket<'static>) -> std::result::Result<EngineResult, CallbackError> {
+        self.call_hook("on_packet", &[packet])
+    }
+    /// Passes an ARP request and harpy's spoofed reply to the script's `on_arp_reply` function,
+    /// which may drop or modify the reply
+    pub fn on_arp_reply(&self, request: &EthernetPacket<'static>, reply: &EthernetPacket<'static>) -> std::result::Result<EngineResult, CallbackError> {
+        self.call_hook("on_arp_reply", &[request, reply])
+    }
+    /// Calls the function `name` with `frames`, the verdict is read from the frame it returns
+    fn call_hook(&self, name: &str, frames: &[&EthernetPacket<'static>]) -> std::result::Result<EngineResult, CallbackError> {
+        if self.is_disabled() {
+            return Ok(EngineResult::Continue);
+        }
+        let result = self.context(|ctx| {
+            let Ok(hook) = ctx.globals().get::<_, rlua::Function>(name) else {
+                return Ok(EngineResult::Continue);
+            };
+            let frames = frames.iter().map(|&frame| frame.into()).collect::<Variadic<LuaEthernetPacket>>();
+            Ok(match hook.call::<_, Option<LuaEthernetPacket>>(frames)? {
+                Some(b) if b.dropped() => EngineResult::Drop,
+                Some(b) if b.tampered() => EngineResult::Tamper(b.into()),
+                _ => EngineResult::Continue
+            })
+        });
+        match result {
+            Ok(result) => Ok(result),
+            Err(_) if self.budget.as_ref().is_some_and(|budget| budget.exceeded()) => Err(CallbackError::OverBudget(BudgetViolation::Time)),
+            Err(e) if self.sandbox.is_some() && sandbox::is_memory_error(&e) => Err(CallbackError::OverBudget(BudgetViolation::Memory)),
+            Err(e) => Err(CallbackError::Script(e))
+        }
+    }
+}
+
+/// Why a callback didn't finish
+#[derive(Debug)]
+pub enum CallbackError {
+    Script(LuaError),
+    OverBudget(BudgetViolation)
+}
+
+impl std::fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallbackError::Script(e) => write!(f, "{}", e),
+            CallbackError::OverBudget(violation) => write!(f, "exceeded its {} budget", violation.as_str())
+        }
+    }
 }
 
 #[derive(PartialEq)]
@@ -0,0 +1,183 @@
+use std::{
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant}
+};
+use rlua::{Error as LuaError, HookTriggers, Lua, StdLib, Table};
+
+/// Instructions between two checks of the time budget
+const CHECK_INTERVAL: u32 = 1000;
+
+/// What happens to a frame whose callback exceeded its budget
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverBudgetPolicy {
+    /// Forward the frame as the script received it
+    Forward,
+    /// Drop the frame
+    Drop,
+    /// Forward the frame and stop calling the script
+    Disable
+}
+
+impl OverBudgetPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverBudgetPolicy::Forward => "forward",
+            OverBudgetPolicy::Drop => "drop",
+            OverBudgetPolicy::Disable => "disable"
+        }
+    }
+}
+
+/// Restrictions of a sandboxed engine. Sandboxed scripts can't use `io`, `package`, `debug`,
+/// `load`, `loadfile` or `dofile`, and only `os.time`, `os.clock`, `os.date` and `os.difftime`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sandbox {
+    /// Wall-clock time a single entry into Lua, a callback or loading the script, may take.
+    /// Only Lua code is interrupted, a slow Rust function called by the script runs to its end.
+    pub time_budget: Duration,
+    /// Bytes the Lua state may allocate in total
+    pub memory_limit: usize,
+    pub policy: OverBudgetPolicy
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Sandbox {
+            time_budget: Duration::from_millis(50),
+            memory_limit: 64 * 1024 * 1024,
+            policy: OverBudgetPolicy::Forward
+        }
+    }
+}
+
+/// The limit a callback ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetViolation {
+    Time,
+    Memory
+}
+
+impl BudgetViolation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetViolation::Time => "time",
+            BudgetViolation::Memory => "memory"
+        }
+    }
+}
+
+/// Deadline of the current entry into Lua, shared with the instruction hook
+#[derive(Default)]
+pub(super) struct Budget {
+    deadline: Mutex<Option<Instant>>,
+    exceeded: AtomicBool
+}
+
+impl Budget {
+    pub(super) fn start(&self, budget: Duration) {
+        *self.deadline.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + budget);
+        self.exceeded.store(false, Ordering::Relaxed);
+    }
+    pub(super) fn finish(&self) {
+        *self.deadline.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+    /// Whether the last entry into Lua was aborted for running out of time
+    pub(super) fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+/// Creates a Lua state with the restricted standard library and installs the limits
+pub(super) fn new_lua() -> (Lua, Arc<Budget>) {
+    let lua = Lua::new_with(StdLib::BASE | StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH | StdLib::OS);
+    let budget = Arc::new(Budget::default());
+    lua.context(|ctx| -> rlua::Result<()> {
+        let g = ctx.globals();
+        let os: Table = g.get("os")?;
+        let safe_os = ctx.create_table()?;
+        for name in ["time", "clock", "date", "difftime"] {
+            safe_os.set(name, os.get::<_, rlua::Value>(name)?)?;
+        }
+        g.set("os", safe_os)?;
+
+        // Errors raised by the limits have to reach harpy, `pcall` and `xpcall` pass them on
+        let exceeded_budget = budget.clone();
+        let exceeded = ctx.create_function(move |_, ()| Ok(exceeded_budget.exceeded()))?;
+        ctx.load(r#"
+            local exceeded, pcall, xpcall, error = ...
+            local function check(ok, ...)
+                if not ok and (exceeded() or ... == "not enough memory") then
+                    error(..., 0)
+                end
+                return ok, ...
+            end
+            function _G.pcall(f, ...) return check(pcall(f, ...)) end
+            function _G.xpcall(f, handler, ...) return check(xpcall(f, handler, ...)) end
+        "#).set_name("=sandbox")?.into_function()?
+            .call::<_, ()>((exceeded, g.get::<_, rlua::Function>("pcall")?, g.get::<_, rlua::Function>("xpcall")?, g.get::<_, rlua::Function>("error")?))?;
+
+        // `load` accepts precompiled bytecode, which can corrupt the interpreter
+        for name in ["load", "loadfile", "dofile"] {
+            g.set(name, rlua::Nil)?;
+        }
+        Ok(())
+    }).expect("couldn't restrict the Lua standard library");
+
+    let hook_budget = budget.clone();
+    lua.set_hook(HookTriggers { every_nth_instruction: Some(CHECK_INTERVAL), ..Default::default() }, move |_, _| {
+        let deadline = *hook_budget.deadline.lock().unwrap_or_else(|e| e.into_inner());
+        // Keep failing once exceeded, in case the script catches the error
+        if hook_budget.exceeded() || deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            hook_budget.exceeded.store(true, Ordering::Relaxed);
+            return Err(LuaError::RuntimeError("time budget exceeded".to_string()));
+        }
+        Ok(())
+    });
+    (lua, budget)
+}
+
+/// Whether a Lua error was caused by the memory limit
+pub(super) fn is_memory_error(error: &LuaError) -> bool {
+    match error {
+        LuaError::MemoryError(_) => true,
+        LuaError::CallbackError { cause, .. } => is_memory_error(cause),
+        _ => false
+    }
+}
+
+
+#[test]
+fn test_sandbox() {
+    use pnet::packet::ethernet::EthernetPacket;
+    use super::{CallbackError, EngineResult, HarpyEngine};
+
+    let sandbox = Sandbox { time_budget: Duration::from_millis(250), memory_limit: 8 * 1024 * 1024, policy: OverBudgetPolicy::Drop };
+    let harpy = HarpyEngine::sandboxed(sandbox);
+    harpy.context(|ctx| ctx.load(r#"
+        assert(io == nil and package == nil and debug == nil and load == nil and dofile == nil)
+        assert(os.execute == nil and os.remove == nil and type(os.date("%Y")) == "string")
+        function on_packet(packet)
+            if mode == "loop" then
+                while true do pcall(function() while true do end end) end
+            elseif mode == "alloc" then
+                local t = {}
+                for i = 1, 1e9 do t[i] = string.rep("x", 1024) .. i end
+            end
+        end
+    "#).exec()).unwrap();
+
+    let packet = EthernetPacket::owned(vec![0; 34]).unwrap();
+    let run = |mode: &str| {
+        harpy.context(|ctx| ctx.globals().set("mode", mode)).unwrap();
+        harpy.on_packet(&packet)
+    };
+    assert!(matches!(run("loop"), Err(CallbackError::OverBudget(BudgetViolation::Time))));
+    assert!(matches!(run("alloc"), Err(CallbackError::OverBudget(BudgetViolation::Memory))));
+    // A callback within its budget isn't affected by earlier violations
+    assert!(matches!(run("none"), Ok(EngineResult::Continue)));
+
+    harpy.disable();
+    assert!(matches!(run("loop"), Ok(EngineResult::Continue)));
+}
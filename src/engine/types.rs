@@ -1,4 +1,5 @@
-pub use pnet::{packet::{ethernet::{EthernetPacket, MutableEthernetPacket, EtherTypes}, ipv4::{Ipv4Packet, MutableIpv4Packet}, tcp::{TcpPacket, MutableTcpPacket}, udp::{UdpPacket, MutableUdpPacket}, Packet, ip::{IpNextHeaderProtocols}}};
+pub use std::net::Ipv4Addr;
+pub use pnet::{packet::{ethernet::{EthernetPacket, MutableEthernetPacket, EtherTypes}, ipv4::{Ipv4Packet, MutableIpv4Packet}, tcp::{TcpPacket, MutableTcpPacket}, udp::{UdpPacket, MutableUdpPacket}, icmp::IcmpPacket, arp::{ArpPacket, MutableArpPacket}, Packet, ip::{IpNextHeaderProtocols}}};
 pub use rlua::{Lua, UserData, UserDataMethods, Table, Value, AnyUserData, prelude::LuaError};
 pub use crate::util::Subsequence;
 
@@ -6,16 +7,22 @@ pub use ethernet::{LuaEthernetPacket};
 pub use ipv4::{LuaIpv4Packet};
 pub use tcp::{LuaTcpPacket};
 pub use udp::{LuaUdpPacket};
+pub use icmp::{LuaIcmpPacket};
+pub use arp::{LuaArpPacket};
 pub use binary::{LuaBinary};
-pub use quic::{LuaQUIC};
-pub use tls::{LuaTls, LuaClientHello};
+pub use quic::{LuaQUIC, QUIC_REASSEMBLER};
+pub use metrics::LuaCounter;
+pub use tls::{LuaTls, LuaClientHello, LuaServerHello, LuaCertificate, TLS_REASSEMBLER};
 
 pub mod ethernet;
 pub mod ipv4;
 pub mod tcp;
 pub mod udp;
+pub mod icmp;
+pub mod arp;
 pub mod binary;
 pub mod quic;
 pub mod tls;
+pub mod metrics;
 
 
@@ -0,0 +1,106 @@
+use super::*;
+use pnet::{datalink::MacAddr, packet::arp::{ArpOperation, ArpOperations}};
+
+pub struct LuaArpPacket(pub ArpPacket<'static>);
+
+fn parse_mac(mac: &str, method: &str) -> rlua::Result<MacAddr> {
+    mac.parse().map_err(|_| LuaError::RuntimeError(format!("LuaArpPacket:{}(): `{}` is not a MAC address", method, mac)))
+}
+
+fn parse_ip(ip: &str, method: &str) -> rlua::Result<Ipv4Addr> {
+    ip.parse().map_err(|_| LuaError::RuntimeError(format!("LuaArpPacket:{}(): `{}` is not an IPv4 address", method, ip)))
+}
+
+impl LuaArpPacket {
+    /// Applies `set` to a copy of the packet
+    fn update(&mut self, set: impl FnOnce(&mut MutableArpPacket)) {
+        let mut arp = MutableArpPacket::owned(self.0.packet().to_vec()).unwrap();
+        set(&mut arp);
+        self.0 = arp.consume_to_immutable();
+    }
+}
+
+impl UserData for LuaArpPacket {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
+        _methods.add_method_mut("operation", |_, this: &mut LuaArpPacket, (operation,): (Option<u16>,)| {
+            if let Some(operation) = operation {
+                this.update(|arp| arp.set_operation(ArpOperation(operation)));
+            }
+            Ok(this.0.get_operation().0)
+        });
+        _methods.add_method("is_request", |_, this: &LuaArpPacket, ()| {
+            Ok(this.0.get_operation() == ArpOperations::Request)
+        });
+        _methods.add_method("is_reply", |_, this: &LuaArpPacket, ()| {
+            Ok(this.0.get_operation() == ArpOperations::Reply)
+        });
+        _methods.add_method_mut("sender_mac", |_, this: &mut LuaArpPacket, (mac,): (Option<String>,)| {
+            if let Some(mac) = mac {
+                let mac = parse_mac(&mac, "sender_mac")?;
+                this.update(|arp| arp.set_sender_hw_addr(mac));
+            }
+            Ok(this.0.get_sender_hw_addr().to_string())
+        });
+        _methods.add_method_mut("sender_ip", |_, this: &mut LuaArpPacket, (ip,): (Option<String>,)| {
+            if let Some(ip) = ip {
+                let ip = parse_ip(&ip, "sender_ip")?;
+                this.update(|arp| arp.set_sender_proto_addr(ip));
+            }
+            Ok(this.0.get_sender_proto_addr().to_string())
+        });
+        _methods.add_method_mut("target_mac", |_, this: &mut LuaArpPacket, (mac,): (Option<String>,)| {
+            if let Some(mac) = mac {
+                let mac = parse_mac(&mac, "target_mac")?;
+                this.update(|arp| arp.set_target_hw_addr(mac));
+            }
+            Ok(this.0.get_target_hw_addr().to_string())
+        });
+        _methods.add_method_mut("target_ip", |_, this: &mut LuaArpPacket, (ip,): (Option<String>,)| {
+            if let Some(ip) = ip {
+                let ip = parse_ip(&ip, "target_ip")?;
+                this.update(|arp| arp.set_target_proto_addr(ip));
+            }
+            Ok(this.0.get_target_proto_addr().to_string())
+        });
+    }
+
+    fn get_uvalues_count(&self) -> std::os::raw::c_int {
+        1
+    }
+}
+
+
+#[test]
+fn test_arp_packet() {
+    use hex_literal::hex;
+    // Who has 192.168.0.1? Tell 192.168.0.7, once plain and once behind VLAN 10
+    let request = hex!("ffffffffffff 020000000007 0806 0001 0800 06 04 0001 020000000007 c0a80007 000000000000 c0a80001");
+    let tagged = hex!("ffffffffffff 020000000007 8100 000a 0806 0001 0800 06 04 0001 020000000007 c0a80007 000000000000 c0a80001");
+    for frame in [&request[..], &tagged[..]] {
+        let arp = LuaEthernetPacket::new(EthernetPacket::owned(frame.to_vec()).unwrap()).as_arp().unwrap();
+        assert_eq!(arp.0.get_operation(), ArpOperations::Request);
+        assert_eq!(arp.0.get_sender_proto_addr(), Ipv4Addr::new(192, 168, 0, 7));
+        assert_eq!(arp.0.get_target_proto_addr(), Ipv4Addr::new(192, 168, 0, 1));
+    }
+    // Only Ethernet and IPv4 addresses are exposed
+    let mut other = request;
+    other[19] = 16;
+    assert!(LuaEthernetPacket::new(EthernetPacket::owned(other.to_vec()).unwrap()).as_arp().is_none());
+
+    let arp = LuaEthernetPacket::new(EthernetPacket::owned(request.to_vec()).unwrap()).as_arp().unwrap();
+    Lua::new().context(|ctx| {
+        ctx.globals().set("arp", arp).unwrap();
+        ctx.load(r#"
+            assert(arp:is_request() and not arp:is_reply())
+            assert(arp:operation(2) == 2 and arp:is_reply())
+            assert(arp:sender_mac("02:00:00:00:00:99") == "02:00:00:00:00:99")
+            assert(arp:target_ip("192.168.0.8") == "192.168.0.8")
+        "#).exec().unwrap();
+        assert!(ctx.load(r#"arp:sender_mac("not a mac")"#).exec().is_err());
+        assert!(ctx.load(r#"arp:target_ip("192.168.0")"#).exec().is_err());
+
+        let arp = ctx.globals().get::<_, AnyUserData>("arp").unwrap();
+        let arp = arp.borrow::<LuaArpPacket>().unwrap();
+        assert_eq!(arp.0.packet(), hex!("0001 0800 06 04 0002 020000000099 c0a80007 000000000000 c0a80008"));
+    });
+}
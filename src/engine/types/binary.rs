@@ -1,7 +1,153 @@
 use super::*;
+use rlua::MetaMethod;
+use crate::pack::Packed;
 
 pub struct LuaBinary(pub Vec<u8>);
 
+/// Integer accessors registered as `read_<name>`/`write_<name>`, with their width and byte order
+const INTEGERS: [(&str, &str, usize, bool); 5] = [
+    ("read_u8", "write_u8", 1, false),
+    ("read_u16", "write_u16", 2, false),
+    ("read_u16_le", "write_u16_le", 2, true),
+    ("read_u32", "write_u32", 4, false),
+    ("read_u32_le", "write_u32_le", 4, true)
+];
+
+impl LuaBinary {
+    /// Converts a `LuaBinary`, a string or a table of bytes into raw bytes
+    pub fn bytes_of(value: &Value) -> Option<Vec<u8>> {
+        match value {
+            Value::UserData(d) if d.is::<LuaBinary>() => d.borrow::<LuaBinary>().ok().map(|data| data.0.clone()),
+            Value::String(s) => Some(s.as_bytes().to_vec()),
+            Value::Table(table) => table.clone().sequence_values::<u8>().collect::<rlua::Result<Vec<u8>>>().ok(),
+            _ => None
+        }
+    }
+
+    /// Backs the `binary(value)` constructor. Accepts strings, `LuaBinary` and tables of bytes,
+    /// strings and `LuaBinary`. Integers have to be bytes, wider integers need an explicit width and
+    /// byte order through `binary.pack`.
+    pub fn construct(value: Value) -> rlua::Result<LuaBinary> {
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut append = |value: Value| -> rlua::Result<()> {
+            match value {
+                Value::Integer(n) => buffer.push(u8::try_from(n).map_err(|_| LuaError::RuntimeError(
+                    format!("binary(): {} is not a byte, use binary.pack() with an explicit width and byte order, f.e. binary.pack(\">I4\", {})", n, n)))?),
+                value => buffer.extend(LuaBinary::bytes_of(&value).ok_or_else(|| LuaError::RuntimeError(
+                    format!("binary(): can't convert {} to bytes", value.type_name())))?)
+            }
+            Ok(())
+        };
+        match value {
+            Value::Table(table) => {
+                for value in table.sequence_values::<Value>() {
+                    append(value?)?;
+                }
+            },
+            Value::Nil => {},
+            value => append(value)?
+        }
+        Ok(LuaBinary(buffer))
+    }
+
+    /// Decodes the bytes as UTF-8, the error describes the first invalid sequence
+    pub fn utf8(&self) -> Result<&str, String> {
+        std::str::from_utf8(&self.0).map_err(|e| match e.error_len() {
+            Some(len) => format!("invalid UTF-8 sequence of {} bytes at offset {}", len, e.valid_up_to()),
+            None => format!("incomplete UTF-8 sequence at offset {}", e.valid_up_to())
+        })
+    }
+
+    /// Decodes the bytes as ISO 8859-1, which maps every byte to the code point of the same value
+    pub fn latin1(&self) -> String {
+        self.0.iter().map(|b| *b as char).collect()
+    }
+
+    /// Returns the offset of the first occurrence of `needle` at or after `start`
+    pub fn find(&self, needle: &[u8], start: usize) -> Option<usize> {
+        if needle.is_empty() {
+            return (start <= self.0.len()).then_some(start);
+        }
+        self.0.get(start..)?.windows(needle.len()).position(|w| w == needle).map(|i| start + i)
+    }
+
+    /// Returns the offsets of all non-overlapping occurrences of `needle`
+    pub fn find_all(&self, needle: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut start = 0;
+        while let Some(offset) = (!needle.is_empty()).then(|| self.find(needle, start)).flatten() {
+            offsets.push(offset);
+            start = offset + needle.len();
+        }
+        offsets
+    }
+
+    /// Replaces the first `max` (or all) non-overlapping occurrences of `needle`
+    pub fn replace(&self, needle: &[u8], replacement: &[u8], max: Option<usize>) -> Vec<u8> {
+        let mut result = Vec::with_capacity(self.0.len());
+        let mut last = 0;
+        for offset in self.find_all(needle).into_iter().take(max.unwrap_or(usize::MAX)) {
+            result.extend_from_slice(&self.0[last..offset]);
+            result.extend_from_slice(replacement);
+            last = offset + needle.len();
+        }
+        result.extend_from_slice(&self.0[last..]);
+        result
+    }
+
+    pub fn read(&self, offset: usize, width: usize, little_endian: bool) -> Option<u64> {
+        let bytes = self.0.get(offset..offset.checked_add(width)?)?;
+        let fold = |value: u64, byte: &u8| value << 8 | *byte as u64;
+        Some(if little_endian { bytes.iter().rev().fold(0, fold) } else { bytes.iter().fold(0, fold) })
+    }
+
+    pub fn write(&mut self, offset: usize, width: usize, little_endian: bool, value: i64) -> rlua::Result<()> {
+        if value < 0 || (value as u64) >> (width * 8) != 0 {
+            return Err(LuaError::RuntimeError(format!("{} doesn't fit into {} bytes", value, width)));
+        }
+        let len = self.0.len();
+        let bytes = offset.checked_add(width).and_then(|end| self.0.get_mut(offset..end))
+            .ok_or_else(|| LuaError::RuntimeError(format!("write of {} bytes at offset {} out of bounds (length {})", width, offset, len)))?;
+        let be = (value as u64).to_be_bytes();
+        bytes.copy_from_slice(&be[8 - width..]);
+        if little_endian {
+            bytes.reverse();
+        }
+        Ok(())
+    }
+}
+
+/// Backs `binary.pack(format, ...)`
+pub fn pack(format: &str, values: rlua::Variadic<Value>) -> rlua::Result<LuaBinary> {
+    let values = values.iter().map(|value| match value {
+        Value::Integer(n) => Ok(Packed::Int(*n)),
+        Value::Number(n) if n.fract() == 0.0 => Ok(Packed::Int(*n as i64)),
+        value => LuaBinary::bytes_of(value).map(Packed::Bytes)
+            .ok_or_else(|| LuaError::RuntimeError("binary.pack() expects integers, strings or LuaBinary".to_string()))
+    }).collect::<rlua::Result<Vec<Packed>>>()?;
+    crate::pack::pack(format, &values)
+        .map(LuaBinary)
+        .map_err(|e| LuaError::RuntimeError(format!("binary.pack(): {}", e)))
+}
+
+/// Backs `binary.unpack(format, data, offset)`, returns the unpacked values followed by the offset
+/// of the first unread byte
+pub fn unpack<'lua>(ctx: rlua::Context<'lua>, format: &str, data: Value<'lua>, offset: usize) -> rlua::Result<rlua::MultiValue<'lua>> {
+    let bytes = LuaBinary::bytes_of(&data)
+        .ok_or_else(|| LuaError::RuntimeError("binary.unpack() expects a LuaBinary or string".to_string()))?;
+    let (values, offset) = crate::pack::unpack(format, &bytes, offset)
+        .map_err(|e| LuaError::RuntimeError(format!("binary.unpack(): {}", e)))?;
+    let mut result = values.into_iter().map(|value| match value {
+        Packed::Int(n) => Ok(Value::Integer(n)),
+        Packed::Bytes(bytes) => ctx.create_userdata(LuaBinary(bytes)).map(Value::UserData)
+    }).collect::<rlua::Result<Vec<Value>>>()?;
+    result.push(Value::Integer(offset as i64));
+    Ok(rlua::MultiValue::from_vec(result))
+}
+
+fn argument(value: &Value, method: &str) -> rlua::Result<Vec<u8>> {
+    LuaBinary::bytes_of(value).ok_or_else(|| LuaError::RuntimeError(format!("LuaBinary:{}() expects a LuaBinary, string or byte table", method)))
+}
 
 impl UserData for LuaBinary {
     fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
@@ -9,35 +155,154 @@ impl UserData for LuaBinary {
             Ok(this.0.len() as u32)
         });
         _methods.add_method::<_, (usize,), Option<u8>, _>("get", |_, this: &LuaBinary, (index,)| {
-            if index < this.0.len() {
-                Ok(Some(this.0[index]))
-            } else {
-                Ok(None)
-            }
+            Ok(this.0.get(index).copied())
         });
         _methods.add_method::<_, (Value,), _, _>("contains", |_, this: &LuaBinary, (search,)| {
             // Check if the binary contains the given value
             // The given value can be a string, a table, or another userdata (LuaBinary)
-            match search {
-                Value::UserData(d) if d.is::<LuaBinary>() => {
-                    let data = d.borrow::<LuaBinary>().unwrap();
-                    return Ok(this.0.iter().subsequence(&mut data.0.iter()));
-                },
-                Value::String(s) => {
-                    let bytes = s.as_bytes();
-                    return Ok(this.0.iter().subsequence(&mut bytes.iter()));
-                },
-                Value::Table(table) => {
-                    let v = table.pairs::<Value, u8>().map(|r| r.unwrap().1).collect::<Vec<u8>>();
-                    return Ok(this.0.iter().subsequence(&mut v.iter()));
-                },
-                _ => return Ok(false)
+            match LuaBinary::bytes_of(&search) {
+                Some(needle) => Ok(this.0.iter().subsequence(&mut needle.iter())),
+                None => Ok(false)
             }
         });
+        _methods.add_method("sub", |_, this: &LuaBinary, (start, end): (usize, Option<usize>)| {
+            Ok(this.0.get(start..end.unwrap_or(this.0.len())).map(|bytes| LuaBinary(bytes.to_vec())))
+        });
+        _methods.add_method("find", |_, this: &LuaBinary, (needle, start): (Value, Option<usize>)| {
+            Ok(this.find(&argument(&needle, "find")?, start.unwrap_or(0)))
+        });
+        _methods.add_method("find_all", |_, this: &LuaBinary, (needle,): (Value,)| {
+            Ok(this.find_all(&argument(&needle, "find_all")?))
+        });
+        _methods.add_method("replace", |_, this: &LuaBinary, (needle, replacement, max): (Value, Value, Option<usize>)| {
+            let needle = argument(&needle, "replace")?;
+            if needle.is_empty() {
+                return Err(LuaError::RuntimeError("LuaBinary:replace() can't replace an empty needle".to_string()));
+            }
+            Ok(LuaBinary(this.replace(&needle, &argument(&replacement, "replace")?, max)))
+        });
+        _methods.add_method_mut("set", |_, this: &mut LuaBinary, (offset, value): (usize, Value)| {
+            let bytes = match value {
+                Value::Integer(byte) => vec![u8::try_from(byte).map_err(|_| LuaError::RuntimeError(format!("{} is not a byte", byte)))?],
+                value => argument(&value, "set")?
+            };
+            let len = this.0.len();
+            let target = offset.checked_add(bytes.len()).and_then(|end| this.0.get_mut(offset..end))
+                .ok_or_else(|| LuaError::RuntimeError(format!("set of {} bytes at offset {} out of bounds (length {})", bytes.len(), offset, len)))?;
+            target.copy_from_slice(&bytes);
+            Ok(())
+        });
+        _methods.add_method("concat", |_, this: &LuaBinary, (other,): (Value,)| {
+            Ok(LuaBinary([this.0.as_slice(), &argument(&other, "concat")?].concat()))
+        });
+        _methods.add_method("string", |ctx, this: &LuaBinary, ()| {
+            ctx.create_string(&this.0)
+        });
+        _methods.add_method("utf8", |_, this: &LuaBinary, ()| {
+            // Follows the Lua convention of returning nil and an error message on failure
+            Ok(match this.utf8() {
+                Ok(string) => (Some(string.to_string()), None),
+                Err(e) => (None, Some(e))
+            })
+        });
+        _methods.add_method("latin1", |_, this: &LuaBinary, ()| {
+            Ok(this.latin1())
+        });
+        _methods.add_method("hex", |_, this: &LuaBinary, ()| {
+            Ok(crate::tls::to_hex(&this.0))
+        });
+        _methods.add_method("base64", |_, this: &LuaBinary, ()| {
+            Ok(base64::encode(&this.0))
+        });
+        for (read, write, width, little_endian) in INTEGERS {
+            _methods.add_method(read, move |_, this: &LuaBinary, (offset,): (usize,)| {
+                Ok(this.read(offset, width, little_endian))
+            });
+            _methods.add_method_mut(write, move |_, this: &mut LuaBinary, (offset, value): (usize, i64)| {
+                this.write(offset, width, little_endian, value)
+            });
+        }
 
+        _methods.add_meta_method(MetaMethod::Len, |_, this: &LuaBinary, ()| {
+            Ok(this.0.len())
+        });
+        _methods.add_meta_function(MetaMethod::Concat, |_, (a, b): (Value, Value)| {
+            Ok(LuaBinary([argument(&a, "concat")?, argument(&b, "concat")?].concat()))
+        });
+        _methods.add_meta_method(MetaMethod::Eq, |_, this: &LuaBinary, (other,): (Value,)| {
+            Ok(LuaBinary::bytes_of(&other).is_some_and(|other| this.0 == other))
+        });
+        // Lua passes the operands in order, the LuaBinary may be either of them
+        _methods.add_meta_function(MetaMethod::Lt, |_, (a, b): (Value, Value)| {
+            Ok(argument(&a, "__lt")? < argument(&b, "__lt")?)
+        });
+        _methods.add_meta_function(MetaMethod::Le, |_, (a, b): (Value, Value)| {
+            Ok(argument(&a, "__le")? <= argument(&b, "__le")?)
+        });
     }
 
     fn get_uvalues_count(&self) -> std::os::raw::c_int {
         1
     }
 }
+
+
+#[test]
+fn test_binary_manipulation() {
+    let mut binary = LuaBinary(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n".to_vec());
+    assert_eq!(binary.find(b"\r\n", 0), Some(14));
+    assert_eq!(binary.find(b"\r\n", 15), Some(23));
+    assert_eq!(binary.find_all(b"\r\n"), vec![14, 23, 25]);
+    assert_eq!(binary.find(b"missing", 0), None);
+    assert_eq!(binary.find(b"\r\n", 100), None);
+    assert_eq!(binary.replace(b"\r\n", b"\n", Some(1)), b"GET / HTTP/1.1\nHost: a\r\n\r\n");
+    assert_eq!(binary.replace(b"1.1", b"1.0", None), b"GET / HTTP/1.0\r\nHost: a\r\n\r\n");
+
+    binary = LuaBinary(vec![0x12, 0x34, 0x56, 0x78]);
+    assert_eq!(binary.read(0, 2, false), Some(0x1234));
+    assert_eq!(binary.read(0, 2, true), Some(0x3412));
+    assert_eq!(binary.read(0, 4, false), Some(0x12345678));
+    assert_eq!(binary.read(3, 2, false), None);
+    assert_eq!(binary.read(usize::MAX, 2, false), None);
+
+    binary.write(2, 2, true, 0xbeef).unwrap();
+    assert_eq!(binary.0, vec![0x12, 0x34, 0xef, 0xbe]);
+    assert!(binary.write(3, 2, false, 1).is_err());
+    assert!(binary.write(0, 1, false, 256).is_err());
+    assert!(binary.write(0, 1, false, -1).is_err());
+
+    // Integers above 255 aren't truncated to a byte, neither on their own nor in byte tables
+    Lua::new().context(|ctx| {
+        let construct = |source: &str| LuaBinary::construct(ctx.load(source).eval::<Value>().unwrap()).map(|binary| binary.0);
+        assert_eq!(construct("{ 0x47, 'ET', 255 }").unwrap(), b"GET\xff");
+        for source in ["256", "{ 1, 256 }", "{ -1 }", "{ 1, { 0x100 } }"] {
+            assert!(matches!(construct(source), Err(LuaError::RuntimeError(_))), "{}", source);
+        }
+        assert_eq!(LuaBinary::bytes_of(&ctx.load("{ 1, 256 }").eval::<Value>().unwrap()), None);
+        ctx.globals().set("bin", LuaBinary(vec![0; 4])).unwrap();
+        assert!(ctx.load("bin:set(0, 256)").exec().is_err());
+        assert!(ctx.load("bin:set(0, { 1, 300 })").exec().is_err());
+        assert!(ctx.load("return bin:concat({ 1000 })").exec().is_err());
+    });
+}
+
+#[test]
+fn test_binary_text_decoding() {
+    assert_eq!(LuaBinary("grüße".as_bytes().to_vec()).utf8(), Ok("grüße"));
+    assert_eq!(LuaBinary(vec![0x61, 0xff, 0x62]).utf8(), Err("invalid UTF-8 sequence of 1 bytes at offset 1".to_string()));
+    assert_eq!(LuaBinary(vec![0x61, 0xc3]).utf8(), Err("incomplete UTF-8 sequence at offset 1".to_string()));
+    assert_eq!(LuaBinary(vec![0x67, 0x72, 0xfc, 0xdf, 0x65]).latin1(), "grüße");
+}
+
+#[test]
+fn test_binary_comparison() {
+    Lua::new().context(|ctx| {
+        ctx.globals().set("bin", LuaBinary(b"abd".to_vec())).unwrap();
+        ctx.load(r#"
+            assert(bin < "abe" and "abc" < bin)
+            assert(bin <= "abd" and "abd" <= bin and not ("abe" <= bin))
+            assert({ 0x61, 0x62 } < bin)
+        "#).exec().unwrap();
+        assert!(ctx.load("return bin < 1").exec().is_err());
+    });
+}
@@ -1,4 +1,5 @@
 use super::*;
+use crate::encap::{self, Encapsulation, Tag};
 
 pub struct LuaEthernetPacket(pub EthernetPacket<'static>, pub bool, pub bool);
 
@@ -18,9 +19,30 @@ impl LuaEthernetPacket {
     pub fn tampered(&self) -> bool {
         self.2
     }
+    /// The VLAN tags, PPPoE session and MPLS labels in front of the packet
+    pub fn encapsulation(&self) -> Encapsulation {
+        Encapsulation::parse(&self.0)
+    }
+    /// ARP packets resolving IPv4 addresses to MAC addresses, others can't be represented
+    pub fn as_arp(&self) -> Option<LuaArpPacket> {
+        let encapsulation = self.encapsulation();
+        if encapsulation.ethertype == EtherTypes::Arp {
+            ArpPacket::owned(encapsulation.payload(&self.0).to_vec())
+                .filter(|arp| arp.get_hw_addr_len() == 6 && arp.get_proto_addr_len() == 4)
+                .map(LuaArpPacket)
+        } else {
+            None
+        }
+    }
+    /// Replaces the encapsulated packet, keeping the Ethernet header and the tags
+    fn set_payload(&mut self, payload: &[u8]) {
+        self.0 = self.encapsulation().with_payload(&self.0, payload);
+        self.2 = true;
+    }
     pub fn as_ipv4(&self) -> Option<LuaIpv4Packet> {
-        if self.0.get_ethertype() == EtherTypes::Ipv4 {
-            Some(LuaIpv4Packet(Ipv4Packet::owned(self.0.payload().to_vec()).unwrap()))
+        let encapsulation = self.encapsulation();
+        if encapsulation.ethertype == EtherTypes::Ipv4 {
+            Ipv4Packet::owned(encapsulation.payload(&self.0).to_vec()).map(LuaIpv4Packet)
         } else {
             None
         }
@@ -33,11 +55,55 @@ impl Clone for LuaEthernetPacket {
     }
 }
 
+fn tag_to_table<'lua>(ctx: rlua::Context<'lua>, tag: &Tag) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("kind", tag.name())?;
+    match *tag {
+        Tag::Vlan { tpid, pcp, dei, vid } => {
+            table.set("tpid", tpid)?;
+            table.set("pcp", pcp)?;
+            table.set("dei", dei)?;
+            table.set("vid", vid)?;
+        },
+        Tag::Pppoe { session } => table.set("session", session)?,
+        Tag::Mpls { label, tc, ttl } => {
+            table.set("label", label)?;
+            table.set("tc", tc)?;
+            table.set("ttl", ttl)?;
+        }
+    }
+    Ok(table)
+}
+
+/// Reads a tag in the format of `LuaEthernetFrame:tags()`, `function` names the caller in errors
+pub(crate) fn tag_from_table(table: Table, function: &str) -> rlua::Result<Tag> {
+    let kind: String = table.get("kind")
+        .map_err(|_| LuaError::RuntimeError(format!("{}: tags need a `kind`", function)))?;
+    Ok(match kind.as_str() {
+        "vlan" => Tag::Vlan {
+            tpid: table.get::<_, Option<u16>>("tpid")?.unwrap_or(encap::ETHERTYPE_VLAN),
+            pcp: table.get::<_, Option<u8>>("pcp")?.unwrap_or(0),
+            dei: table.get::<_, Option<bool>>("dei")?.unwrap_or(false),
+            vid: table.get("vid")?
+        },
+        "pppoe" => Tag::Pppoe { session: table.get("session")? },
+        "mpls" => Tag::Mpls {
+            label: table.get("label")?,
+            tc: table.get::<_, Option<u8>>("tc")?.unwrap_or(0),
+            ttl: table.get::<_, Option<u8>>("ttl")?.unwrap_or(64)
+        },
+        kind => return Err(LuaError::RuntimeError(format!("{}: unknown tag `{}`", function, kind)))
+    })
+}
+
 impl UserData for LuaEthernetPacket {
     fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
         _methods.add_method("ipv4", |_, this: &LuaEthernetPacket, ()| {
             Ok(this.as_ipv4())
         });
+        _methods.add_method("arp", |_, this: &LuaEthernetPacket, ()| {
+            Ok(this.as_arp())
+        });
         _methods.add_method("src", |_, this: &LuaEthernetPacket, ()| {
             Ok(this.0.get_source().to_string())
         });
@@ -45,7 +111,18 @@ impl UserData for LuaEthernetPacket {
             Ok(this.0.get_destination().to_string())
         });
         _methods.add_method("type", |_, this: &LuaEthernetPacket, ()| {
-            Ok(this.0.get_ethertype().to_string())
+            Ok(this.encapsulation().ethertype.to_string())
+        });
+        _methods.add_method_mut("tags", |ctx, this: &mut LuaEthernetPacket, (tags,): (Option<Vec<Table>>,)| {
+            match tags {
+                Some(tags) => {
+                    let tags = tags.into_iter().map(|tag| tag_from_table(tag, "LuaEthernetFrame:tags()")).collect::<rlua::Result<Vec<_>>>()?;
+                    this.0 = encap::retag(&this.0, &tags).map_err(|e| LuaError::RuntimeError(format!("LuaEthernetFrame:tags(): {}", e)))?;
+                    this.2 = true;
+                    Ok(None)
+                },
+                None => this.encapsulation().tags.iter().map(|tag| tag_to_table(ctx, tag)).collect::<rlua::Result<Vec<_>>>().map(Some)
+            }
         });
         _methods.add_method("size", |_, this: &LuaEthernetPacket, ()| {
             Ok(this.0.packet().len())
@@ -59,22 +136,13 @@ impl UserData for LuaEthernetPacket {
         });
         _methods.add_method_mut::<_, (AnyUserData,), _, _>("payload", |_, this: &mut LuaEthernetPacket, (data,)| {
             if data.is::<LuaIpv4Packet>() {
-                let ipv4 = data.borrow::<LuaIpv4Packet>()?;
-                let packet = ipv4.0.packet();
-                let bsize = EthernetPacket::minimum_packet_size() + packet.len();
-                let mut buf = Vec::with_capacity(bsize);
-
-                buf.extend(&this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())]);
-                buf.resize(bsize, 0);
-
-                let mut eth = MutableEthernetPacket::owned(buf).unwrap();
-                eth.set_payload(packet);
-
-                this.0 = eth.consume_to_immutable();
-                this.2 = true;
-                return Ok(None);
+                this.set_payload(data.borrow::<LuaIpv4Packet>()?.0.packet());
+                Ok(None)
+            }else if data.is::<LuaArpPacket>() {
+                this.set_payload(data.borrow::<LuaArpPacket>()?.0.packet());
+                Ok(None)
             }else{
-                return Ok(Some(LuaBinary(this.0.payload().to_vec())));
+                Ok(Some(LuaBinary(this.encapsulation().payload(&this.0).to_vec())))
             }
         });
 
@@ -83,8 +151,8 @@ impl UserData for LuaEthernetPacket {
         1
     }
 }
-impl Into<EthernetPacket<'static>> for LuaEthernetPacket {
-    fn into(self) -> EthernetPacket<'static> {
-        self.0
+impl From<LuaEthernetPacket> for EthernetPacket<'static> {
+    fn from(packet: LuaEthernetPacket) -> Self {
+        packet.0
     }
 }
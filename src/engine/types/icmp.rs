@@ -0,0 +1,188 @@
+use super::*;
+use crate::icmp;
+
+pub struct LuaIcmpPacket(pub IcmpPacket<'static>);
+
+impl LuaIcmpPacket {
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<LuaIcmpPacket> {
+        // Every message has at least the 8 byte header
+        IcmpPacket::owned(bytes).filter(|icmp| icmp.packet().len() >= 8).map(LuaIcmpPacket)
+    }
+    fn icmp_type(&self) -> u8 {
+        self.0.get_icmp_type().0
+    }
+    /// The type specific second word of the header
+    fn rest_of_header(&self) -> [u8; 4] {
+        self.0.packet()[4..8].try_into().unwrap()
+    }
+    /// Identifier and sequence number of echo and timestamp messages
+    pub fn identifier(&self) -> Option<(u16, u16)> {
+        let rest = self.rest_of_header();
+        icmp::has_identifier(self.icmp_type())
+            .then(|| (u16::from_be_bytes([rest[0], rest[1]]), u16::from_be_bytes([rest[2], rest[3]])))
+    }
+    /// The datagram quoted by an error message, usually only its header and first 8 bytes
+    pub fn original(&self) -> Option<LuaIpv4Packet> {
+        if !icmp::is_error(self.icmp_type()) {
+            return None;
+        }
+        // The quote is usually cut short, its total length still counts the whole datagram
+        Ipv4Packet::owned(self.0.packet()[8..].to_vec())
+            .filter(|ipv4| ipv4.get_version() == 4 && ipv4.get_header_length() >= 5)
+            .map(LuaIpv4Packet)
+    }
+}
+
+/// Reads the original datagram passed to the error builders
+fn original_bytes(original: &AnyUserData) -> rlua::Result<Vec<u8>> {
+    let original = original.borrow::<LuaIpv4Packet>()
+        .map_err(|_| LuaError::RuntimeError("icmp: the original datagram has to be a LuaIpv4Packet".to_string()))?;
+    Ok(icmp::quote(&original.0))
+}
+
+fn parse_ip(ip: &str, function: &str) -> rlua::Result<Ipv4Addr> {
+    ip.parse().map_err(|_| LuaError::RuntimeError(format!("icmp.{}(): `{}` is not an IPv4 address", function, ip)))
+}
+
+fn bytes_option(options: &Option<Table>, name: &str, function: &str) -> rlua::Result<Vec<u8>> {
+    match options.as_ref().map(|options| options.get::<_, Value>(name)).transpose()? {
+        None | Some(Value::Nil) => Ok(Vec::new()),
+        Some(value) => LuaBinary::bytes_of(&value)
+            .ok_or_else(|| LuaError::RuntimeError(format!("icmp.{}(): `{}` has to be a LuaBinary, string or byte table", function, name)))
+    }
+}
+
+fn integer_option<'lua, T: rlua::FromLua<'lua> + Default>(options: &Option<Table<'lua>>, name: &str) -> rlua::Result<T> {
+    Ok(options.as_ref().map(|options| options.get::<_, Option<T>>(name)).transpose()?.flatten().unwrap_or_default())
+}
+
+fn echo<'lua>(icmp_type: u8, options: Option<Table<'lua>>, function: &str) -> rlua::Result<LuaIcmpPacket> {
+    let payload = bytes_option(&options, "payload", function)?;
+    let packet = icmp::echo(icmp_type, integer_option(&options, "id")?, integer_option(&options, "seq")?, &payload);
+    Ok(LuaIcmpPacket::from_bytes(packet).unwrap())
+}
+
+/// The `icmp` table, building messages with valid checksums
+pub fn builder(ctx: rlua::Context) -> rlua::Result<Table> {
+    let builder = ctx.create_table()?;
+    builder.set("echo_request", ctx.create_function(|_, (options,): (Option<Table>,)| {
+        echo(icmp::TYPE_ECHO_REQUEST, options, "echo_request")
+    })?)?;
+    builder.set("echo_reply", ctx.create_function(|_, (options,): (Option<Table>,)| {
+        echo(icmp::TYPE_ECHO_REPLY, options, "echo_reply")
+    })?)?;
+    builder.set("unreachable", ctx.create_function(|_, (original, code, mtu): (AnyUserData, Option<u8>, Option<u16>)| {
+        let mtu = mtu.unwrap_or(0).to_be_bytes();
+        let packet = icmp::build(icmp::TYPE_DESTINATION_UNREACHABLE, code.unwrap_or(icmp::CODE_PORT_UNREACHABLE), [0, 0, mtu[0], mtu[1]], &original_bytes(&original)?);
+        Ok(LuaIcmpPacket::from_bytes(packet).unwrap())
+    })?)?;
+    builder.set("time_exceeded", ctx.create_function(|_, (original, code): (AnyUserData, Option<u8>)| {
+        let packet = icmp::build(icmp::TYPE_TIME_EXCEEDED, code.unwrap_or(icmp::CODE_TTL_EXCEEDED), [0; 4], &original_bytes(&original)?);
+        Ok(LuaIcmpPacket::from_bytes(packet).unwrap())
+    })?)?;
+    builder.set("redirect", ctx.create_function(|_, (original, gateway, code): (AnyUserData, String, Option<u8>)| {
+        let gateway = parse_ip(&gateway, "redirect")?;
+        let packet = icmp::build(icmp::TYPE_REDIRECT, code.unwrap_or(icmp::CODE_REDIRECT_HOST), gateway.octets(), &original_bytes(&original)?);
+        Ok(LuaIcmpPacket::from_bytes(packet).unwrap())
+    })?)?;
+    builder.set("new", ctx.create_function(|_, (options,): (Table,)| {
+        let options = Some(options);
+        let rest = bytes_option(&options, "rest", "new")?;
+        let rest: [u8; 4] = match rest.len() {
+            0 => [0; 4],
+            _ => rest.try_into().map_err(|_| LuaError::RuntimeError("icmp.new(): `rest` has to be 4 bytes long".to_string()))?
+        };
+        let packet = icmp::build(integer_option(&options, "type")?, integer_option(&options, "code")?, rest, &bytes_option(&options, "payload", "new")?);
+        Ok(LuaIcmpPacket::from_bytes(packet).unwrap())
+    })?)?;
+    builder.set("ipv4", ctx.create_function(|_, (packet, source, destination, ttl): (AnyUserData, String, String, Option<u8>)| {
+        let packet = packet.borrow::<LuaIcmpPacket>()
+            .map_err(|_| LuaError::RuntimeError("icmp.ipv4(): expects a LuaIcmpPacket".to_string()))?;
+        let ipv4 = icmp::ipv4(parse_ip(&source, "ipv4")?, parse_ip(&destination, "ipv4")?, ttl.unwrap_or(64), packet.0.packet());
+        Ok(LuaIpv4Packet(Ipv4Packet::owned(ipv4).unwrap()))
+    })?)?;
+    Ok(builder)
+}
+
+impl UserData for LuaIcmpPacket {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
+        _methods.add_method("type", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.icmp_type())
+        });
+        _methods.add_method("code", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.0.get_icmp_code().0)
+        });
+        _methods.add_method("checksum", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.0.get_checksum())
+        });
+        _methods.add_method("size", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.0.packet().len())
+        });
+        _methods.add_method("id", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.identifier().map(|(identifier, _)| identifier))
+        });
+        _methods.add_method("seq", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.identifier().map(|(_, sequence)| sequence))
+        });
+        _methods.add_method("is_error", |_, this: &LuaIcmpPacket, ()| {
+            Ok(icmp::is_error(this.icmp_type()))
+        });
+        _methods.add_method("original", |_, this: &LuaIcmpPacket, ()| {
+            Ok(this.original())
+        });
+        _methods.add_method("mtu", |_, this: &LuaIcmpPacket, ()| {
+            let rest = this.rest_of_header();
+            Ok((this.icmp_type() == icmp::TYPE_DESTINATION_UNREACHABLE && this.0.get_icmp_code().0 == icmp::CODE_FRAGMENTATION_NEEDED)
+                .then(|| u16::from_be_bytes([rest[2], rest[3]])))
+        });
+        _methods.add_method("gateway", |_, this: &LuaIcmpPacket, ()| {
+            Ok((this.icmp_type() == icmp::TYPE_REDIRECT).then(|| Ipv4Addr::from(this.rest_of_header()).to_string()))
+        });
+        _methods.add_method_mut::<_, (Option<Value>,), _, _>("payload", |_, this: &mut LuaIcmpPacket, (payload,)| {
+            match payload {
+                Some(payload) => {
+                    let payload = LuaBinary::bytes_of(&payload)
+                        .ok_or_else(|| LuaError::RuntimeError("LuaIcmpPacket:payload() expects a LuaBinary, string or byte table".to_string()))?;
+                    let packet = icmp::build(this.icmp_type(), this.0.get_icmp_code().0, this.rest_of_header(), &payload);
+                    this.0 = IcmpPacket::owned(packet).unwrap();
+                    Ok(None)
+                },
+                None => Ok(Some(LuaBinary(this.0.packet()[8..].to_vec())))
+            }
+        });
+    }
+
+    fn get_uvalues_count(&self) -> std::os::raw::c_int {
+        1
+    }
+}
+
+
+#[test]
+fn test_icmp_packet() {
+    use hex_literal::hex;
+    let ping = LuaIcmpPacket::from_bytes(icmp::echo(icmp::TYPE_ECHO_REQUEST, 7, 3, b"ping")).unwrap();
+    assert_eq!(ping.identifier(), Some((7, 3)));
+    assert!(ping.original().is_none());
+    assert!(LuaIcmpPacket::from_bytes(vec![8, 0, 0, 0]).is_none());
+
+    // UDP datagram from 192.168.0.38:50012 to 1.1.1.1:443
+    let original = hex!("450000240000400040110000c0a8002601010101c35c01bb0010000068656c6c6f000000");
+    let reply = icmp::port_unreachable(&Ipv4Packet::new(&original).unwrap());
+    let unreachable = LuaIcmpPacket::from_bytes(Ipv4Packet::new(&reply).unwrap().payload().to_vec()).unwrap();
+    assert_eq!(unreachable.identifier(), None);
+    let quoted = unreachable.original().unwrap();
+    assert_eq!(quoted.0.get_source(), Ipv4Addr::new(192, 168, 0, 38));
+    assert_eq!(UdpPacket::new(quoted.0.payload()).unwrap().get_destination(), 443);
+
+    Lua::new().context(|ctx| {
+        ctx.globals().set("icmp", builder(ctx).unwrap()).unwrap();
+        ctx.globals().set("original", quoted).unwrap();
+        let pong = ctx.load(r#"icmp.echo_reply{ id = 7, seq = 3, payload = "ping" }"#).eval::<AnyUserData>().unwrap();
+        let pong = pong.borrow::<LuaIcmpPacket>().unwrap();
+        assert_eq!(pong.0.packet(), icmp::echo(icmp::TYPE_ECHO_REPLY, 7, 3, b"ping"));
+        let exceeded = ctx.load("icmp.time_exceeded(original)").eval::<AnyUserData>().unwrap();
+        assert_eq!(exceeded.borrow::<LuaIcmpPacket>().unwrap().0.packet()[8..], original[..28]);
+        assert!(ctx.load(r#"icmp.redirect(original, "nowhere")"#).exec().is_err());
+    });
+}
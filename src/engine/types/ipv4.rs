@@ -1,4 +1,5 @@
 use super::*;
+use pnet::packet::MutablePacket;
 
 pub struct LuaIpv4Packet(pub Ipv4Packet<'static>);
 
@@ -22,108 +23,22 @@ impl UserData for LuaIpv4Packet {
         _methods.add_method("udp", |_, this: &LuaIpv4Packet, ()| {
             Ok(this.as_udp())
         });
+        _methods.add_method("icmp", |_, this: &LuaIpv4Packet, ()| {
+            Ok(this.as_icmp())
+        });
         _methods.add_method_mut::<_, (Option<AnyUserData>,), _, _>("payload", |_, this: &mut LuaIpv4Packet, (data,)| {
-            struct PseudoHeader {
-                saddr: u32,
-                daddr: u32,
-                zero: u8,
-                protocol: u8,
-                length: u16,
-            }
-            fn make_checksum(phd: PseudoHeader, data: &[u8]) -> u16 {
-                let mut buf = Vec::with_capacity(std::mem::size_of::<PseudoHeader>() + data.len());
-                buf.resize(buf.capacity(), 0);
-                buf[0..4].copy_from_slice(&phd.saddr.to_be_bytes());
-                buf[4..8].copy_from_slice(&phd.daddr.to_be_bytes());
-                buf[8] = phd.zero;
-                buf[9] = phd.protocol;
-                buf[10..12].copy_from_slice(&phd.length.to_be_bytes());
-                buf.extend_from_slice(&data[..]);
-                crate::util::checksum(&buf)
-            }
-
             if let Some(data) = data {
                 if data.is::<LuaTcpPacket>() {
-                    let tcp = data.borrow::<LuaTcpPacket>()?;
-                    let packet = tcp.0.packet();
-
-                    let bsize = Ipv4Packet::minimum_packet_size() + packet.len();
-                    let mut buf = Vec::with_capacity(bsize);
-                    buf.extend(&this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())]);
-                    buf.resize(bsize, 0);
-
-                    let mut ipv4 = MutableIpv4Packet::owned(buf).unwrap();
-                    ipv4.set_total_length(((this.0.packet().len() - this.0.payload().len()) + tcp.0.packet().len()) as u16);
-                    ipv4.set_payload(packet);
-                    ipv4.set_checksum(0);
-                    ipv4.set_checksum(crate::util::checksum(&ipv4.packet()[0..(ipv4.packet().len() - ipv4.payload().len())]));
-                    // Calculate TCP checksum
-                    let pseudo = PseudoHeader {
-                        saddr: this.0.get_source().into(),
-                        daddr: this.0.get_destination().into(),
-                        zero: 0,
-                        protocol: IpNextHeaderProtocols::Tcp.0,
-                        length: tcp.0.packet().len() as u16,
-                    };
-
-                    let mut tcp = MutableTcpPacket::owned(ipv4.payload().to_vec()).unwrap();
-                    tcp.set_checksum(0);
-                    // Connect pseudo header and TCP packet together into one slice
-                    // and calculate checksum
-                    tcp.set_checksum(make_checksum(pseudo, tcp.packet()));
-                    ipv4.set_payload(tcp.packet());
-
-                    this.0 = ipv4.consume_to_immutable();
+                    this.set_payload(data.borrow::<LuaTcpPacket>()?.0.packet());
                     return Ok(None);
                 }else if data.is::<LuaUdpPacket>() {
-                    let udp = data.borrow::<LuaUdpPacket>()?;
-                    let packet = udp.0.packet();
-
-                    let bsize = Ipv4Packet::minimum_packet_size() + packet.len();
-                    let mut buf = Vec::with_capacity(bsize);
-                    buf.extend(&this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())]);
-                    buf.resize(bsize, 0);
-
-                    let mut ipv4 = MutableIpv4Packet::owned(buf).unwrap();
-                    ipv4.set_total_length(((this.0.packet().len() - this.0.payload().len()) + udp.0.packet().len()) as u16);
-                    ipv4.set_checksum(0);
-                    ipv4.set_checksum(crate::util::checksum(&ipv4.packet()[0..(ipv4.packet().len() - ipv4.payload().len())]));
-                    // Calculate TCP checksum
-                    let _pseudo = PseudoHeader {
-                        saddr: this.0.get_source().into(),
-                        daddr: this.0.get_destination().into(),
-                        zero: 0,
-                        protocol: IpNextHeaderProtocols::Udp.0,
-                        length: packet.len() as u16,
-                    };
-
-                    let mut udp = MutableUdpPacket::owned(packet.to_vec()).unwrap();
-                    udp.set_checksum(0);
-                    // TODO: Calculate UDP checksum
-                    // The current make_checksum function, weirdly enough, works for TCP, but not UDP
-                    // why that is, I don't know. Since both protocols use the same checksum
-                    // algorithm, I deduce that this issue might stem from the UDP data itself.
-                    // However since UDP does not require checksums, I'll leave the checksum at 0
-                    // for now.
-                    //udp.set_checksum(make_checksum(pseudo, udp.packet()));
-                    ipv4.set_payload(udp.packet());
-
-                    this.0 = ipv4.consume_to_immutable();
+                    this.set_payload(data.borrow::<LuaUdpPacket>()?.0.packet());
+                    return Ok(None);
+                }else if data.is::<LuaIcmpPacket>() {
+                    this.set_payload(data.borrow::<LuaIcmpPacket>()?.0.packet());
                     return Ok(None);
                 }else if data.is::<LuaBinary>() {
-                    let bin = data.borrow::<LuaBinary>()?;
-
-                    let bsize = Ipv4Packet::minimum_packet_size() + bin.0.len();
-                    let mut buf = Vec::with_capacity(bsize);
-                    buf.extend(&this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())]);
-                    buf.resize(bsize, 0);
-
-                    let mut ipv4 = MutableIpv4Packet::owned(buf).unwrap();
-                    ipv4.set_total_length(((this.0.packet().len() - this.0.payload().len()) + bin.0.len()) as u16);
-                    ipv4.set_payload(&bin.0);
-                    ipv4.set_checksum(0);
-                    ipv4.set_checksum(crate::util::checksum(&ipv4.packet()[0..(ipv4.packet().len() - ipv4.payload().len())]));
-                    this.0 = ipv4.consume_to_immutable();
+                    this.set_payload(&data.borrow::<LuaBinary>()?.0);
                     return Ok(None);
                 }
             }
@@ -135,16 +50,64 @@ impl UserData for LuaIpv4Packet {
     }
 }
 impl LuaIpv4Packet {
+    /// Replaces the payload, the total length and checksums are updated to match
+    pub fn set_payload(&mut self, payload: &[u8]) {
+        // Ethernet padding may follow the packet, so the header length is taken from the header
+        let header_len = (self.0.get_header_length() as usize * 4).min(self.0.packet().len());
+        let mut buf = Vec::with_capacity(header_len + payload.len());
+        buf.extend(&self.0.packet()[..header_len]);
+        buf.extend(payload);
+
+        let mut ipv4 = MutableIpv4Packet::owned(buf).unwrap();
+        ipv4.set_total_length((header_len + payload.len()) as u16);
+        self.0 = ipv4.consume_to_immutable();
+        self.update_checksums();
+    }
+    /// Recomputes the header checksum and the checksum of a TCP or UDP payload, f.e. after the
+    /// addresses were rewritten. Fragments only carry part of the segment, their payload is left as is.
+    pub fn update_checksums(&mut self) {
+        let Some(mut ipv4) = MutableIpv4Packet::owned(self.0.packet().to_vec()) else { return };
+        ipv4.set_checksum(pnet::packet::ipv4::checksum(&ipv4.to_immutable()));
+        let (source, destination) = (ipv4.get_source(), ipv4.get_destination());
+        let fragmented = ipv4.get_fragment_offset() != 0 || ipv4.get_flags() & pnet::packet::ipv4::Ipv4Flags::MoreFragments != 0;
+        match ipv4.get_next_level_protocol() {
+            _ if fragmented => (),
+            IpNextHeaderProtocols::Tcp => if let Some(mut tcp) = MutableTcpPacket::new(ipv4.payload_mut()) {
+                tcp.set_checksum(pnet::packet::tcp::ipv4_checksum(&tcp.to_immutable(), &source, &destination));
+            },
+            IpNextHeaderProtocols::Udp => if let Some(mut udp) = MutableUdpPacket::new(ipv4.payload_mut()) {
+                // A computed checksum of zero is sent as all ones, zero means no checksum
+                let checksum = pnet::packet::udp::ipv4_checksum(&udp.to_immutable(), &source, &destination);
+                udp.set_checksum(if checksum == 0 { 0xffff } else { checksum });
+            },
+            IpNextHeaderProtocols::Icmp if ipv4.payload().len() >= 4 => {
+                let icmp = ipv4.payload_mut();
+                icmp[2..4].fill(0);
+                let checksum = crate::util::checksum(icmp);
+                icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+            },
+            _ => ()
+        }
+        self.0 = ipv4.consume_to_immutable();
+    }
+    // Datagrams quoted by ICMP errors are cut short, their segments may be incomplete
     pub fn as_tcp(&self) -> Option<LuaTcpPacket> {
         if self.0.get_next_level_protocol() == IpNextHeaderProtocols::Tcp {
-            Some(LuaTcpPacket(TcpPacket::owned(self.0.payload().to_vec()).unwrap()))
+            TcpPacket::owned(self.0.payload().to_vec()).map(|tcp| LuaTcpPacket(tcp, Some((self.0.get_source(), self.0.get_destination()))))
         } else {
             None
         }
     }
     pub fn as_udp(&self) -> Option<LuaUdpPacket> {
         if self.0.get_next_level_protocol() == IpNextHeaderProtocols::Udp {
-            Some(LuaUdpPacket(UdpPacket::owned(self.0.payload().to_vec()).unwrap()))
+            UdpPacket::owned(self.0.payload().to_vec()).map(LuaUdpPacket)
+        } else {
+            None
+        }
+    }
+    pub fn as_icmp(&self) -> Option<LuaIcmpPacket> {
+        if self.0.get_next_level_protocol() == IpNextHeaderProtocols::Icmp && !crate::fragment::is_fragment(&self.0) {
+            LuaIcmpPacket::from_bytes(self.0.payload().to_vec())
         } else {
             None
         }
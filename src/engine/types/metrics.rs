@@ -0,0 +1,18 @@
+use super::*;
+use std::sync::Arc;
+use crate::metrics::ScriptCounter;
+
+/// A counter exported on the metrics endpoint as `harpy_script_<name>`
+pub struct LuaCounter(pub Arc<ScriptCounter>);
+
+impl UserData for LuaCounter {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
+        _methods.add_method("inc", |_, this: &LuaCounter, (value,): (Option<u64>,)| {
+            this.0.value.add(value.unwrap_or(1));
+            Ok(())
+        });
+        _methods.add_method("get", |_, this: &LuaCounter, ()| {
+            Ok(this.0.value.get())
+        });
+    }
+}
@@ -1,23 +1,110 @@
 use super::*;
 pub use crate::quic::QUICPacket;
+use crate::quic::{self, CryptoReassembler, Frame, Header, PacketType};
+use crate::tls::ClientHello;
 
-pub struct LuaQUIC(pub QUICPacket<'static>);
+/// Name of the registry value holding the engine's `CryptoReassembler`
+pub const QUIC_REASSEMBLER: &str = "harpy_quic_reassembler";
 
+pub struct LuaQUIC(pub QUICPacket);
+
+impl LuaQUIC {
+    /// Decrypts a client Initial packet and returns its frames
+    pub fn frames(&self) -> Option<Vec<Frame>> {
+        let (_, payload) = self.0.decrypt_initial(self.0.dst_connection_id(), true).ok()?;
+        quic::parse_frames(&payload).ok()
+    }
+
+    /// Decrypts the Initial packet and returns the ClientHello, once all CRYPTO frames carrying
+    /// it have been seen. Until then, and for server Initial packets, returns None.
+    pub fn as_client_hello(&self, ctx: rlua::Context) -> Option<LuaClientHello> {
+        if self.0.packet_type() != PacketType::Initial {
+            return None;
+        }
+        let dcid = self.0.dst_connection_id();
+        let (_, payload) = self.0.decrypt_initial(dcid, true).ok()?;
+        let frames = quic::crypto_frames(&payload).ok()?;
+        let reassembler = ctx.named_registry_value::<_, AnyUserData>(QUIC_REASSEMBLER).ok()?;
+        let handshake = reassembler.borrow_mut::<CryptoReassembler>().ok()?.feed(dcid, frames)?;
+        if handshake[0] != crate::tls::HANDSHAKE_CLIENT_HELLO {
+            return None;
+        }
+        ClientHello::parse(&handshake[4..]).map(|hello| LuaClientHello { hello, quic: true })
+    }
+}
+
+impl UserData for CryptoReassembler {}
+
+/// Converts a frame into a table with its `type` name and fields
+fn frame_table<'lua>(ctx: rlua::Context<'lua>, frame: Frame) -> rlua::Result<rlua::Table<'lua>> {
+    let table = ctx.create_table()?;
+    table.set("type", frame.name())?;
+    match frame {
+        Frame::Padding(length) => table.set("length", length)?,
+        Frame::Ack { largest, delay, .. } => {
+            table.set("largest", largest)?;
+            table.set("delay", delay)?;
+        },
+        Frame::Crypto { offset, data } => {
+            table.set("offset", offset)?;
+            table.set("data", LuaBinary(data))?;
+        },
+        Frame::Stream { stream_id, offset, fin, data } => {
+            table.set("stream_id", stream_id)?;
+            table.set("offset", offset)?;
+            table.set("fin", fin)?;
+            table.set("data", LuaBinary(data))?;
+        },
+        Frame::ConnectionClose { error_code, reason, .. } => {
+            table.set("error_code", error_code)?;
+            table.set("reason", LuaBinary(reason))?;
+        },
+        Frame::Datagram { data } => table.set("data", LuaBinary(data))?,
+        _ => {}
+    }
+    Ok(table)
+}
 
 impl UserData for LuaQUIC {
     fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
         _methods.add_method("version", |_, this: &LuaQUIC, ()| {
-            Ok(this.0.get_version().to_string())
+            Ok(this.0.version().map(|v| v.to_string()))
+        });
+        _methods.add_method("type", |_, this: &LuaQUIC, ()| {
+            Ok(this.0.packet_type().name())
+        });
+        _methods.add_method("is_long_header", |_, this: &LuaQUIC, ()| {
+            Ok(matches!(this.0.header, Header::Long(_)))
         });
         _methods.add_method("destination_cid", |_, this: &LuaQUIC, ()| {
-            Ok(String::from_utf8(this.0.get_dst_connection_id().to_vec()).unwrap_or("".to_string()))
+            Ok(crate::tls::to_hex(this.0.dst_connection_id()))
         });
         _methods.add_method("source_cid", |_, this: &LuaQUIC, ()| {
-            Ok(String::from_utf8(this.0.get_src_connection_id().to_vec()).unwrap_or("".to_string()))
+            Ok(this.0.src_connection_id().map(crate::tls::to_hex))
+        });
+        _methods.add_method("token", |_, this: &LuaQUIC, ()| {
+            Ok(match &this.0.header {
+                Header::Long(header) => Some(LuaBinary(header.token.clone())),
+                Header::Short(_) => None
+            })
+        });
+        _methods.add_method("supported_versions", |_, this: &LuaQUIC, ()| {
+            Ok(match &this.0.header {
+                Header::Long(header) => header.supported_versions.iter().map(|v| v.to_string()).collect(),
+                Header::Short(_) => Vec::new()
+            })
+        });
+        _methods.add_method("frames", |ctx, this: &LuaQUIC, ()| {
+            match this.frames() {
+                Some(frames) => Ok(Some(frames.into_iter()
+                    .map(|frame| frame_table(ctx, frame))
+                    .collect::<rlua::Result<Vec<_>>>()?)),
+                None => Ok(None)
+            }
+        });
+        _methods.add_method("client_hello", |ctx, this: &LuaQUIC, ()| {
+            Ok(this.as_client_hello(ctx))
         });
-        /*_methods.add_method("token", |_, this: &LuaQUIC, ()| {
-            Ok(String::from_utf8(this.0.get_token().to_vec()).unwrap_or("".to_string()))
-        });*/
     }
 
     fn get_uvalues_count(&self) -> std::os::raw::c_int {
@@ -1,24 +1,88 @@
 use super::*;
-use crate::tls::TlsPacket;
+use crate::tls::{self, TlsPacket, TlsReassembler};
+use crate::tcp::{self as options, TcpOption};
 
-pub struct LuaTcpPacket(pub TcpPacket<'static>);
+/// A TCP segment, along with the source and destination address of the IP packet carrying it.
+/// The addresses are needed to tell flows apart when reassembling TLS records.
+pub struct LuaTcpPacket(pub TcpPacket<'static>, pub Option<(Ipv4Addr, Ipv4Addr)>);
 
 impl LuaTcpPacket {
-    /// Check whether the packet contains TLS data
+    /// Check whether the payload starts with a TLS record
     pub fn is_tls(&self) -> bool {
         let payload = self.0.payload();
-        payload.len() >= 2
-            && (payload[0] == 0x16 || payload[0] == 0x17)
-            && payload[1] == 0x03
+        payload.len() >= tls::RECORD_HEADER_LEN && tls::is_record_header(payload)
     }
-    pub fn as_tls(&self) -> Option<LuaTls> {
-        if self.is_tls() {
-            Some(LuaTls(TlsPacket::owned(self.0.payload().to_vec())?))
-        } else {
-            None
-        }
+    /// All TLS records that are complete with this segment.
+    /// Records spanning multiple segments are stitched together per flow, given the packet
+    /// came from an IPv4 packet and the engine has a reassembler registered.
+    pub fn tls_records(&self, ctx: rlua::Context) -> Vec<LuaTls> {
+        let records = match (self.1, ctx.named_registry_value::<_, AnyUserData>(TLS_REASSEMBLER)) {
+            (Some((src, dst)), Ok(reassembler)) => {
+                let flow = (src, self.0.get_source(), dst, self.0.get_destination());
+                match reassembler.borrow_mut::<TlsReassembler>() {
+                    Ok(mut reassembler) => reassembler.feed(flow, self.0.get_sequence(), self.0.payload()),
+                    Err(_) => Vec::new()
+                }
+            },
+            _ => tls::split_records(self.0.payload()).0.into_iter().map(<[u8]>::to_vec).collect()
+        };
+        records.into_iter().filter_map(TlsPacket::owned).map(LuaTls).collect()
+    }
+    pub fn as_tls(&self, ctx: rlua::Context) -> Option<LuaTls> {
+        self.tls_records(ctx).into_iter().next()
     }
 }
+
+/// Converts an option to `{ kind = "mss", value = 1460 }`, unknown options carry their number
+/// as `kind` and their bytes as `data`
+fn option_to_table<'lua>(ctx: rlua::Context<'lua>, option: &TcpOption) -> rlua::Result<Table<'lua>> {
+    let table = ctx.create_table()?;
+    match option.name() {
+        Some(name) => table.set("kind", name)?,
+        None => table.set("kind", option.kind())?
+    }
+    match option {
+        TcpOption::Mss(mss) => table.set("value", *mss)?,
+        TcpOption::WindowScale(shift) => table.set("value", *shift)?,
+        TcpOption::Sack(blocks) => table.set("blocks", blocks.iter().map(|(left, right)| vec![*left, *right]).collect::<Vec<_>>())?,
+        TcpOption::Timestamps { value, echo } => {
+            table.set("value", *value)?;
+            table.set("echo", *echo)?;
+        },
+        TcpOption::Unknown { data, .. } => table.set("data", LuaBinary(data.clone()))?,
+        _ => ()
+    }
+    Ok(table)
+}
+
+fn option_from_table(table: Table) -> rlua::Result<TcpOption> {
+    let invalid = |message: String| LuaError::RuntimeError(format!("LuaTcpPacket:options(): {}", message));
+    Ok(match table.get::<_, Value>("kind")? {
+        Value::String(kind) => match kind.to_str()? {
+            "end" => TcpOption::End,
+            "nop" => TcpOption::Nop,
+            "mss" => TcpOption::Mss(table.get("value")?),
+            "window_scale" => TcpOption::WindowScale(table.get("value")?),
+            "sack_permitted" => TcpOption::SackPermitted,
+            "sack" => TcpOption::Sack(table.get::<_, Vec<Vec<u32>>>("blocks")?.into_iter()
+                .map(|block| match block[..] {
+                    [left, right] => Ok((left, right)),
+                    _ => Err(invalid("SACK blocks are pairs of a left and a right edge".to_string()))
+                })
+                .collect::<rlua::Result<_>>()?),
+            "timestamps" => TcpOption::Timestamps { value: table.get("value")?, echo: table.get("echo")? },
+            kind => return Err(invalid(format!("unknown option `{}`", kind)))
+        },
+        Value::Integer(kind) => TcpOption::Unknown {
+            kind: u8::try_from(kind).map_err(|_| invalid(format!("{} is not an option kind", kind)))?,
+            data: match table.get::<_, Value>("data")? {
+                Value::Nil => Vec::new(),
+                data => LuaBinary::bytes_of(&data).ok_or_else(|| invalid("`data` has to be a LuaBinary, string or byte table".to_string()))?
+            }
+        },
+        _ => return Err(invalid("options need a `kind`".to_string()))
+    })
+}
 impl UserData for LuaTcpPacket {
     fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
         _methods.add_method("src_port", |_, this: &LuaTcpPacket, ()| {
@@ -51,8 +115,26 @@ impl UserData for LuaTcpPacket {
         _methods.add_method("is_tls", |_, this: &LuaTcpPacket, ()| {
             Ok(this.is_tls())
         });
-        _methods.add_method("tls", |_, this: &LuaTcpPacket, ()| {
-            Ok(this.as_tls())
+        _methods.add_method("tls", |ctx, this: &LuaTcpPacket, ()| {
+            Ok(this.as_tls(ctx))
+        });
+        _methods.add_method("tls_records", |ctx, this: &LuaTcpPacket, ()| {
+            Ok(this.tls_records(ctx))
+        });
+        _methods.add_method_mut::<_, (Option<Vec<Table>>,), _, _>("options", |ctx, this: &mut LuaTcpPacket, (new_options,)| {
+            match new_options {
+                Some(new_options) => {
+                    let new_options = new_options.into_iter().map(option_from_table).collect::<rlua::Result<Vec<_>>>()?;
+                    this.0 = options::with_options(&this.0, &new_options)
+                        .map_err(|e| LuaError::RuntimeError(format!("LuaTcpPacket:options(): {}", e)))?;
+                    Ok(None)
+                },
+                None => {
+                    let parsed = options::parse_options(this.0.get_options_raw())
+                        .map_err(|e| LuaError::RuntimeError(format!("LuaTcpPacket:options(): {}", e)))?;
+                    Ok(Some(parsed.iter().map(|option| option_to_table(ctx, option)).collect::<rlua::Result<Vec<_>>>()?))
+                }
+            }
         });
         _methods.add_method_mut::<_, (Option<Value>,), _, _>("payload", |_, this: &mut LuaTcpPacket, (binary,)| {
             if let Some(binary) = binary {
@@ -74,7 +156,7 @@ impl UserData for LuaTcpPacket {
                         }
                     },
                     Value::Table(binary) => {
-                        let mut payload: Vec<u8> = binary.pairs::<Value, u8>().into_iter().map(|pair| pair.map(|p| p.1).unwrap_or(0)).collect::<Vec<u8>>();
+                        let payload: Vec<u8> = binary.pairs::<Value, u8>().map(|pair| pair.map(|p| p.1).unwrap_or(0)).collect::<Vec<u8>>();
 
                         let tcp_header = &this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())];
                         let bsize = tcp_header.len() + payload.len();
@@ -83,7 +165,7 @@ impl UserData for LuaTcpPacket {
                         buf.resize(bsize, 0);
 
                         let mut tcp = MutableTcpPacket::owned(buf).unwrap();
-                        tcp.set_payload(&mut payload);
+                        tcp.set_payload(&payload);
 
 
                         // NOTE: we can't compute the checksum here because we don't know the IP header which
@@ -1,31 +1,53 @@
 use super::*;
-use crate::tls::TlsPacket;
+use crate::tls::{self, TlsPacket, TlsReassembler, ClientHello, ServerHello, Certificate};
+
+/// Name of the registry value holding the engine's `TlsReassembler`
+pub const TLS_REASSEMBLER: &str = "harpy_tls_reassembler";
 
 pub struct LuaTls(pub TlsPacket<'static>);
+pub struct LuaServerHello(pub ServerHello);
+pub struct LuaCertificate(pub Certificate);
 pub struct LuaClientHello {
-    pub sni: Option<String>,
-    pub ciphersuites: Vec<rustls::CipherSuite>,
-    pub signature_schemes: Vec<rustls::SignatureScheme>
+    pub hello: ClientHello,
+    /// Whether the hello was carried in QUIC CRYPTO frames instead of TLS records
+    pub quic: bool
 }
 
 impl LuaTls {
     pub fn as_client_hello(&self) -> Option<LuaClientHello> {
-        if self.0.get_content_type() == 0x16 {
-            let mut acceptor = rustls::server::Acceptor::new().unwrap();
-            let mut p = self.0.packet();
-            if let Ok(_) = acceptor.read_tls(&mut p) {
-                if let Ok(Some(hello)) = acceptor.accept() {
-                    let hello = hello.client_hello();
-                    return Some(LuaClientHello {
-                        sni: hello.server_name().clone().map(ToOwned::to_owned),
-                        ciphersuites: hello.cipher_suites().to_vec(),
-                        signature_schemes: hello.signature_schemes().to_vec()
-                    });
-
-                }
-            }
+        if self.0.get_content_type() != 0x16 {
+            return None;
+        }
+        tls::handshakes(self.record_payload())
+            .find(|h| h.msg_type == tls::HANDSHAKE_CLIENT_HELLO)
+            .and_then(|h| ClientHello::parse(h.body))
+            .map(|hello| LuaClientHello { hello, quic: false })
+    }
+    /// Payload of the record, cut to the length announced in the record header
+    fn record_payload(&self) -> &[u8] {
+        let payload = self.0.payload();
+        &payload[..payload.len().min(self.0.get_length() as usize)]
+    }
+    pub fn as_server_hello(&self) -> Option<LuaServerHello> {
+        if self.0.get_content_type() != 0x16 {
+            return None;
         }
-        None
+        tls::handshakes(self.record_payload())
+            .find(|h| h.msg_type == tls::HANDSHAKE_SERVER_HELLO)
+            .and_then(|h| ServerHello::parse(h.body))
+            .map(LuaServerHello)
+    }
+    pub fn certificates(&self) -> Vec<LuaCertificate> {
+        if self.0.get_content_type() != 0x16 {
+            return Vec::new();
+        }
+        tls::handshakes(self.record_payload())
+            .filter(|h| h.msg_type == tls::HANDSHAKE_CERTIFICATE)
+            .filter_map(|h| tls::parse_certificate_chain(h.body))
+            .flatten()
+            .filter_map(|der| Certificate::from_der(&der))
+            .map(LuaCertificate)
+            .collect()
     }
 }
 
@@ -45,12 +67,31 @@ impl UserData for LuaTls {
         _methods.add_method("content_type", |_, this: &LuaTls, ()| {
             Ok(this.0.get_content_type().to_string())
         });
+        _methods.add_method("type", |_, this: &LuaTls, ()| {
+            Ok(tls::content_type_name(this.0.get_content_type()))
+        });
+        _methods.add_method("payload", |_, this: &LuaTls, ()| {
+            Ok(LuaBinary(this.record_payload().to_vec()))
+        });
+        _methods.add_method("alert", |_, this: &LuaTls, ()| {
+            // Alerts are only readable before the handshake finished, afterwards they're encrypted
+            match (this.0.get_content_type(), this.record_payload()) {
+                (tls::CONTENT_ALERT, [level, description]) => Ok((Some(*level), Some(*description))),
+                _ => Ok((None, None))
+            }
+        });
         _methods.add_method("size", |_, this: &LuaTls, ()| {
             Ok(this.0.payload().len())
         });
         _methods.add_method("client_hello", |_, this: &LuaTls, ()| {
             Ok(this.as_client_hello())
         });
+        _methods.add_method("server_hello", |_, this: &LuaTls, ()| {
+            Ok(this.as_server_hello())
+        });
+        _methods.add_method("certificates", |_, this: &LuaTls, ()| {
+            Ok(this.certificates())
+        });
     }
 
     fn get_uvalues_count(&self) -> std::os::raw::c_int {
@@ -61,13 +102,86 @@ impl UserData for LuaTls {
 impl UserData for LuaClientHello {
     fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
         _methods.add_method("sni", |_, this: &LuaClientHello, ()| {
-            Ok(this.sni.clone())
+            Ok(this.hello.sni())
         });
         _methods.add_method("ciphersuites", |_, this: &LuaClientHello, ()| {
-            Ok(this.ciphersuites.iter().map(|c| c.as_str().map(ToOwned::to_owned).unwrap_or("".to_owned())).collect::<Vec<String>>())
+            Ok(this.hello.cipher_suites.iter().map(|c| rustls::CipherSuite::from(*c).as_str().map(ToOwned::to_owned).unwrap_or("".to_owned())).collect::<Vec<String>>())
         });
         _methods.add_method("signature_schemes", |_, this: &LuaClientHello, ()| {
-            Ok(this.signature_schemes.iter().map(|c| c.as_str().map(ToOwned::to_owned).unwrap_or("".to_owned())).collect::<Vec<String>>())
+            Ok(this.hello.signature_algorithms().iter().map(|c| rustls::SignatureScheme::from(*c).as_str().map(ToOwned::to_owned).unwrap_or("".to_owned())).collect::<Vec<String>>())
+        });
+        _methods.add_method("extensions", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.extensions.iter().map(|e| e.extension_type).collect::<Vec<u16>>())
+        });
+        _methods.add_method("extension", |_, this: &LuaClientHello, (extension_type,): (u16,)| {
+            Ok(this.hello.extension(extension_type).map(|e| LuaBinary(e.data.clone())))
+        });
+        _methods.add_method("supported_groups", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.supported_groups())
+        });
+        _methods.add_method("ec_point_formats", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.ec_point_formats())
+        });
+        _methods.add_method("alpn", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.alpn())
+        });
+        _methods.add_method("versions", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.supported_versions())
+        });
+        _methods.add_method("ja3", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.ja3())
+        });
+        _methods.add_method("ja3_hash", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.ja3_hash())
+        });
+        _methods.add_method("ja4", |_, this: &LuaClientHello, ()| {
+            Ok(this.hello.ja4(this.quic))
+        });
+    }
+}
+
+impl UserData for TlsReassembler {}
+
+impl UserData for LuaServerHello {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
+        _methods.add_method("version", |_, this: &LuaServerHello, ()| {
+            Ok(tls::version_name(this.0.version()).to_string())
+        });
+        _methods.add_method("ciphersuite", |_, this: &LuaServerHello, ()| {
+            let suite = rustls::CipherSuite::from(this.0.cipher_suite);
+            Ok(suite.as_str().map(ToOwned::to_owned).unwrap_or(format!("0x{:04x}", this.0.cipher_suite)))
+        });
+        _methods.add_method("alpn", |_, this: &LuaServerHello, ()| {
+            Ok(this.0.alpn())
+        });
+        _methods.add_method("extensions", |_, this: &LuaServerHello, ()| {
+            Ok(this.0.extensions.iter().map(|e| e.extension_type).collect::<Vec<u16>>())
+        });
+    }
+}
+
+impl UserData for LuaCertificate {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(_methods: &mut T) {
+        _methods.add_method("subject", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.subject.clone())
+        });
+        _methods.add_method("issuer", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.issuer.clone())
+        });
+        _methods.add_method("serial", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.serial.clone())
+        });
+        _methods.add_method("sans", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.sans.clone())
+        });
+        _methods.add_method("not_before", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.not_before)
+        });
+        _methods.add_method("not_after", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.not_after)
+        });
+        _methods.add_method("fingerprint", |_, this: &LuaCertificate, ()| {
+            Ok(this.0.fingerprint.clone())
         });
     }
 }
@@ -1,17 +1,22 @@
 use super::*;
-use crate::quic::QUICPacket;
+use crate::quic::{self, Header};
 
 pub struct LuaUdpPacket(pub UdpPacket<'static>);
 
 impl LuaUdpPacket {
 
+    /// Splits the payload into QUIC packets.
+    /// Short header packets can only be recognized when coalesced behind a long header packet,
+    /// since neither a version nor the Connection ID length is visible from a single datagram.
+    pub fn quic_packets(&self) -> Vec<LuaQUIC> {
+        match quic::parse_datagram(self.0.payload(), 0) {
+            Ok(packets) if matches!(packets.first().map(|packet| &packet.header), Some(Header::Long(_))) => packets.into_iter().map(LuaQUIC).collect(),
+            _ => Vec::new()
+        }
+    }
+
     pub fn is_quic(&self) -> bool {
-        // Get the payload and check the first 2 MS bits of the first byte
-        // if both are 1, then it's a QUIC packet
-        // TODO: this only returns true for QUIC long headers, not for QUIC short headers
-        //       this should be fixed, but for now this suffices
-        let payload = self.0.payload();
-        payload.len() > 0 && (payload[0] & 0xC0 == 0xC0)
+        !self.quic_packets().is_empty()
     }
 }
 impl UserData for LuaUdpPacket {
@@ -33,7 +38,7 @@ impl UserData for LuaUdpPacket {
                 match binary {
                     Value::UserData(d) if d.is::<LuaBinary>() => {
                         let data = d.borrow::<LuaBinary>().unwrap();
-                        let mut payload = data.0.clone();
+                        let payload = data.0.clone();
 
                         let udp_header = &this.0.packet()[0..(this.0.packet().len() - this.0.payload().len())];
                         let bsize = udp_header.len() + payload.len();
@@ -42,7 +47,7 @@ impl UserData for LuaUdpPacket {
                         buf.resize(bsize, 0);
 
                         let mut udp = MutableUdpPacket::owned(buf).unwrap();
-                        udp.set_payload(&mut payload);
+                        udp.set_payload(&payload);
                         udp.set_length((8 + payload.len()) as u16);
                         udp.set_checksum(0);
 
@@ -62,24 +67,10 @@ impl UserData for LuaUdpPacket {
             Ok(this.is_quic())
         });
         _methods.add_method("quic", |_, this: &LuaUdpPacket, ()| {
-            if this.is_quic() {
-                let payload = this.0.payload();
-                // Match the QUIC packet type
-                // It is stored in the 2-4 bits of the first byte
-                // 0x00: initial
-                // 0x01: 0-RTT
-                // 0x02: Handshake
-                // 0x03: Retry
-                let packet_type = payload[0] & 0x3;
-                match packet_type {
-                    0x00 => return Ok(Some(LuaQUIC(QUICPacket::owned(payload.to_vec()).unwrap()))),
-                    0x01 => return Ok(None),
-                    0x02 => return Ok(None),
-                    0x03 => return Ok(None),
-                    _ => return Err(LuaError::external("Invalid QUIC packet type"))
-                }
-            }
-            Ok(None)
+            Ok(this.quic_packets().into_iter().next())
+        });
+        _methods.add_method("quic_packets", |_, this: &LuaUdpPacket, ()| {
+            Ok(this.quic_packets())
         });
     }
 
@@ -88,3 +79,12 @@ impl UserData for LuaUdpPacket {
     }
 }
 
+
+#[test]
+fn test_empty_payload() {
+    use hex_literal::hex;
+    // A valid UDP datagram without payload, from port 40000 to 443
+    let udp = LuaUdpPacket(UdpPacket::owned(hex!("9c40 01bb 0008 0000").to_vec()).unwrap());
+    assert!(udp.quic_packets().is_empty());
+    assert!(!udp.is_quic());
+}
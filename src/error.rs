@@ -0,0 +1,138 @@
+use std::{net::Ipv4Addr, path::PathBuf};
+
+use crate::config::ConfigError;
+
+/// Errors that end a harpy session. Every variant maps to a `sysexits.h` exit code, so wrappers
+/// can tell configuration mistakes from missing privileges or an unreachable network.
+#[derive(Debug)]
+pub enum HarpyError {
+    Config(ConfigError),
+    NoSuchInterface(String),
+    InterfaceDown(String),
+    NoIpv4Address(String),
+    NoMacAddress(String),
+    /// Raw sockets require root or CAP_NET_RAW
+    PermissionDenied(String),
+    Socket(String, std::io::Error),
+    Mtu(String, String),
+    NoGateway(String),
+    /// A session was built without targets
+    NoTargets,
+    MacResolution(Ipv4Addr),
+    NoTargetReachable,
+    ScriptRead(PathBuf, std::io::Error),
+    /// The capture to replay couldn't be read or is malformed
+    Capture(PathBuf, std::io::Error),
+    ScriptSyntax { file: PathBuf, line: Option<usize>, message: String },
+    /// The script failed while being loaded
+    Script(PathBuf, String),
+    Events(std::io::Error),
+    Metrics(std::io::Error),
+    /// Kernel forwarding or the nftables rules of `--fast-path` couldn't be set up
+    FastPath(String),
+    /// The test report couldn't be written
+    Report(PathBuf, std::io::Error),
+    TestsFailed { failed: usize, total: usize }
+}
+
+impl HarpyError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            HarpyError::TestsFailed { .. } => 1,
+            // EX_CONFIG
+            HarpyError::Config(_) => 78,
+            // EX_USAGE
+            HarpyError::NoTargets => 64,
+            // EX_UNAVAILABLE
+            HarpyError::NoSuchInterface(_) | HarpyError::InterfaceDown(_) | HarpyError::NoIpv4Address(_)
+                | HarpyError::NoMacAddress(_) | HarpyError::NoGateway(_) | HarpyError::MacResolution(_)
+                | HarpyError::NoTargetReachable => 69,
+            // EX_NOPERM
+            HarpyError::PermissionDenied(_) => 77,
+            // EX_IOERR
+            HarpyError::Socket(..) | HarpyError::Mtu(..) | HarpyError::Events(_) | HarpyError::Metrics(_) => 74,
+            // EX_OSERR
+            HarpyError::FastPath(_) => 71,
+            // EX_CANTCREAT
+            HarpyError::Report(..) => 73,
+            // EX_DATAERR
+            HarpyError::Capture(_, e) if e.kind() == std::io::ErrorKind::InvalidData => 65,
+            // EX_NOINPUT
+            HarpyError::ScriptRead(..) | HarpyError::Capture(..) => 66,
+            // EX_DATAERR
+            HarpyError::ScriptSyntax { .. } | HarpyError::Script(..) => 65
+        }
+    }
+
+    /// Converts an error raised while loading `file`, syntax errors keep their line number
+    pub fn from_lua(file: PathBuf, error: rlua::Error) -> HarpyError {
+        match error {
+            rlua::Error::SyntaxError { message, .. } => {
+                // Chunks are named `@<file>`, so Lua reports `<file>:<line>: <message>`
+                let location = message.strip_prefix(&format!("{}:", file.display()))
+                    .and_then(|rest| rest.split_once(':'))
+                    .and_then(|(line, message)| Some((line.parse().ok()?, message.trim().to_string())));
+                match location {
+                    Some((line, message)) => HarpyError::ScriptSyntax { file, line: Some(line), message },
+                    None => HarpyError::ScriptSyntax { file, line: None, message }
+                }
+            },
+            rlua::Error::CallbackError { cause, .. } => HarpyError::from_lua(file, (*cause).clone()),
+            error => HarpyError::Script(file, error.to_string())
+        }
+    }
+}
+
+impl std::fmt::Display for HarpyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HarpyError::Config(e) => write!(f, "{}", e),
+            HarpyError::NoSuchInterface(name) => write!(f, "no interface named {}, `ip link` lists the available ones", name),
+            HarpyError::InterfaceDown(name) => write!(f, "interface {} is down, bring it up with `ip link set {} up`", name, name),
+            HarpyError::NoIpv4Address(name) => write!(f, "interface {} has no IPv4 address", name),
+            HarpyError::NoMacAddress(name) => write!(f, "interface {} has no MAC address, ARP spoofing needs an Ethernet or Wi-Fi interface", name),
+            HarpyError::PermissionDenied(name) => write!(f, "not allowed to capture on {}, run harpy as root or grant it CAP_NET_RAW: \
+                `sudo setcap cap_net_raw,cap_net_admin=eip $(which harpy)`", name),
+            HarpyError::Socket(name, e) => write!(f, "couldn't open a raw socket on {}: {}", name, e),
+            HarpyError::Mtu(name, reason) => write!(f, "couldn't read the MTU of {}: {}", name, reason),
+            HarpyError::NoGateway(name) => write!(f, "no gateway could be determined for {}, pass one with --gateway", name),
+            HarpyError::MacResolution(ip) => write!(f, "{} doesn't answer ARP requests, check that it's online and on the same network", ip),
+            HarpyError::NoTargets => write!(f, "no targets to spoof"),
+            HarpyError::NoTargetReachable => write!(f, "none of the targets answered ARP requests"),
+            HarpyError::ScriptRead(file, e) => write!(f, "couldn't read {}: {}", file.display(), e),
+            HarpyError::Capture(file, e) => write!(f, "couldn't replay {}: {}", file.display(), e),
+            HarpyError::ScriptSyntax { file, line: Some(line), message } => write!(f, "syntax error in {} on line {}: {}", file.display(), line, message),
+            HarpyError::ScriptSyntax { file, line: None, message } => write!(f, "syntax error in {}: {}", file.display(), message),
+            HarpyError::Script(file, e) => write!(f, "error in {}: {}", file.display(), e),
+            HarpyError::Events(e) => write!(f, "couldn't open event output: {}", e),
+            HarpyError::Metrics(e) => write!(f, "couldn't serve metrics: {}", e),
+            HarpyError::Report(file, e) => write!(f, "couldn't write the report to {}: {}", file.display(), e),
+            HarpyError::FastPath(reason) => write!(f, "couldn't set up the kernel fast path: {}", reason),
+            HarpyError::TestsFailed { failed, total } => write!(f, "{} of {} tests failed", failed, total)
+        }
+    }
+}
+
+impl std::error::Error for HarpyError {}
+
+impl From<ConfigError> for HarpyError {
+    fn from(e: ConfigError) -> Self {
+        HarpyError::Config(e)
+    }
+}
+
+
+#[test]
+fn test_script_syntax_error() {
+    let file = PathBuf::from("examples/broken.lua");
+    let lua = rlua::Lua::new();
+    let error = lua.context(|ctx| ctx.load("local x = \n\nfunction(").set_name("@examples/broken.lua").unwrap().exec().unwrap_err());
+
+    match HarpyError::from_lua(file, error) {
+        HarpyError::ScriptSyntax { line, message, .. } => {
+            assert_eq!(line, Some(3));
+            assert!(message.contains("near <eof>"));
+        },
+        e => panic!("expected a syntax error, got {:?}", e)
+    }
+}
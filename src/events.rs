@@ -0,0 +1,93 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::Ipv4Addr,
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+use pnet::datalink::MacAddr;
+use serde_json::{json, Map, Value};
+
+use crate::session::{HostRole, SessionObserver};
+
+/// Writes structured events as newline-delimited JSON, one object per line:
+/// `{"time": 1697712000.123, "type": "mac_resolved", "ip": "192.168.0.1", ...}`
+///
+/// Cloning is cheap, all clones write to the same output. A default `Events` discards everything.
+#[derive(Clone, Default)]
+pub struct Events(Option<Arc<Mutex<Box<dyn Write + Send>>>>);
+
+impl Events {
+    /// Opens an event output, `-` for stdout, `unix:<path>` to connect to a Unix socket, any
+    /// other value is a file the events are appended to.
+    pub fn open(target: &str) -> std::io::Result<Events> {
+        let writer: Box<dyn Write + Send> = match target {
+            "-" => Box::new(std::io::stdout()),
+            target if target.starts_with("unix:") => Box::new(UnixStream::connect(&target[5..])?),
+            path => Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(Events(Some(Arc::new(Mutex::new(writer)))))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Emits an event, `fields` has to be a JSON object, anything else is stored under `value`.
+    /// `time` and `type` are set by harpy and take precedence over fields of the same name.
+    pub fn emit(&self, event_type: &str, fields: Value) {
+        let Some(writer) = &self.0 else {
+            return;
+        };
+        let mut event = match fields {
+            Value::Object(fields) => fields,
+            Value::Null => Map::new(),
+            value => Map::from_iter([("value".to_string(), value)])
+        };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        event.insert("time".to_string(), time.into());
+        event.insert("type".to_string(), event_type.into());
+
+        let mut line = Value::Object(event).to_string();
+        line.push('\n');
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            warn!("Couldn't write event: {}", e);
+        }
+    }
+}
+
+/// Emits `mac_resolved` and `spoof_established` for a session
+impl SessionObserver for Events {
+    fn on_mac_resolved(&self, role: HostRole, ip: Ipv4Addr, mac: MacAddr) {
+        self.emit("mac_resolved", json!({ "ip": ip.to_string(), "mac": mac.to_string(), "role": role.as_str() }));
+    }
+    fn on_spoofed(&self, interface: &str, gateway: Ipv4Addr, target: Ipv4Addr) {
+        self.emit("spoof_established", json!({
+            "interface": interface,
+            "target": target.to_string(),
+            "gateway": gateway.to_string()
+        }));
+    }
+}
+
+
+#[test]
+fn test_emit_json_lines() {
+    let path = std::env::temp_dir().join(format!("harpy-events-{}.jsonl", std::process::id()));
+    let events = Events::open(path.to_str().unwrap()).unwrap();
+    events.emit("mac_resolved", serde_json::json!({ "ip": "192.168.0.1", "type": "ignored" }));
+    events.emit("custom", serde_json::json!(42));
+    Events::default().emit("discarded", Value::Null);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines = contents.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()).collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["type"], "mac_resolved");
+    assert_eq!(lines[0]["ip"], "192.168.0.1");
+    assert!(lines[0]["time"].as_f64().unwrap() > 0.0);
+    assert_eq!(lines[1]["value"], 42);
+}
@@ -0,0 +1,257 @@
+//! Kernel fast path for spoof sessions. The kernel forwards the spoofed traffic at line rate,
+//! an nftables table drops the intercepted flows from its forward path instead. harpy's packet
+//! socket still receives those frames, and forwards them itself after the handlers ran.
+//!
+//! The sysctls are restored and the table is deleted when the session ends. SIGINT, SIGTERM and
+//! SIGHUP stop the session, harpy exits right away on a second signal or if the session doesn't
+//! end within a few seconds. An existing `ip harpy` table isn't touched, the session doesn't start.
+
+use std::{
+    fmt::Write as _,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex, Once, Weak},
+    time::{Duration, Instant}
+};
+use pnet::packet::{ip::IpNextHeaderProtocols, ipv4::Ipv4Packet};
+
+use crate::{error::HarpyError, fragment, impair::{Flow, Selector}, session::StopHandle};
+
+/// Name of harpy's nftables table, in the `ip` family
+pub const TABLE: &str = "harpy";
+
+/// Fast paths to restore when harpy is interrupted
+static ACTIVE: Mutex<Vec<Weak<FastPath>>> = Mutex::new(Vec::new());
+static SIGNALS: Once = Once::new();
+/// How long an interrupted session gets to end before harpy exits without it
+const STOP_GRACE: Duration = Duration::from_secs(3);
+
+/// The nftables script creating harpy's table, dropping the flows of `selectors` that arrive on
+/// `interface` from the forward path. Fragments after the first carry no ports, so every fragment
+/// of an intercepted host and protocol is dropped, first fragments included, and harpy reassembles
+/// the datagram to tell whether it's intercepted.
+pub fn ruleset(interface: &str, selectors: &[Selector]) -> String {
+    let mut script = format!("table ip {} {{\n", TABLE);
+    script.push_str("\tchain forward {\n\t\ttype filter hook forward priority filter - 1; policy accept;\n");
+    for selector in selectors {
+        let hosts = match selector.host {
+            Some(host) => vec![format!(" ip saddr {}", host), format!(" ip daddr {}", host)],
+            None => vec![String::new()]
+        };
+        let protocol = match (selector.protocol, selector.port) {
+            (Some(IpNextHeaderProtocols::Tcp), _) => " meta l4proto tcp".to_string(),
+            (Some(IpNextHeaderProtocols::Udp), _) => " meta l4proto udp".to_string(),
+            (Some(IpNextHeaderProtocols::Icmp), _) => " meta l4proto icmp".to_string(),
+            (Some(protocol), _) => format!(" meta l4proto {}", protocol.0),
+            // `th` reads any transport header, ports only exist in these
+            (None, Some(_)) => " meta l4proto { tcp, udp }".to_string(),
+            (None, None) => String::new()
+        };
+        let ports = match selector.port {
+            Some(port) => vec![format!(" th sport {}", port), format!(" th dport {}", port)],
+            None => vec![String::new()]
+        };
+        for host in hosts.iter() {
+            for port in ports.iter() {
+                let _ = writeln!(script, "\t\tiifname \"{}\"{}{}{} drop", interface, host, protocol, port);
+            }
+            if selector.port.is_some() {
+                let _ = writeln!(script, "\t\tiifname \"{}\"{}{} ip frag-off & 0x3fff != 0 drop", interface, host, protocol);
+            }
+        }
+    }
+    script.push_str("\t}\n}\n");
+    script
+}
+
+/// Whether harpy forwards `ipv4` itself rather than the kernel, the counterpart of `ruleset`.
+/// Fragments are taken by their host and protocol alone.
+pub fn intercepts(selectors: &[Selector], ipv4: &Ipv4Packet) -> bool {
+    let flow = Flow::of(ipv4);
+    match fragment::is_fragment(ipv4) {
+        true => selectors.iter().any(|selector| {
+            // Ports only exist in TCP and UDP, like `th` in the ruleset
+            let protocol = selector.protocol.is_some() || selector.port.is_none()
+                || matches!(flow.protocol, IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp);
+            protocol && Selector { port: None, ..*selector }.matches(&flow)
+        }),
+        false => selectors.iter().any(|selector| selector.matches(&flow))
+    }
+}
+
+/// Whether harpy's table exists, f.e. one left behind by a killed harpy
+fn table_exists() -> bool {
+    Command::new("nft").args(["list", "table", "ip", TABLE])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn nft(script: &str) -> Result<(), String> {
+    let mut child = Command::new("nft").args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("couldn't run nft: {}", e))?;
+    child.stdin.take().unwrap().write_all(script.as_bytes()).map_err(|e| format!("couldn't run nft: {}", e))?;
+    let output = child.wait_with_output().map_err(|e| format!("couldn't run nft: {}", e))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(format!("nft failed: {}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+/// Kernel forwarding enabled for a session, restored when dropped
+pub struct FastPath {
+    /// Sysctls harpy changed and their previous values, in the order they were changed
+    sysctls: Mutex<Vec<(PathBuf, String)>>,
+    table: Mutex<bool>,
+    /// Stops the session using the fast path when harpy is interrupted
+    stop: StopHandle
+}
+
+impl FastPath {
+    /// Enables forwarding on `interface`, without ICMP redirects, and diverts the flows of
+    /// `selectors` to harpy. Signals stop the session through `stop`.
+    pub fn enable(interface: &str, selectors: &[Selector], stop: StopHandle) -> Result<Arc<FastPath>, HarpyError> {
+        // The table may belong to someone else, it can't be restored once replaced
+        if table_exists() {
+            return Err(HarpyError::FastPath(format!("the nftables table ip {table} already exists, `nft delete table ip {table}` removes it if an earlier harpy left it behind", table = TABLE)));
+        }
+        let fast_path = Arc::new(FastPath { sysctls: Mutex::new(Vec::new()), table: Mutex::new(false), stop });
+        // Registered first, so an interrupted setup is undone as well
+        ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).push(Arc::downgrade(&fast_path));
+        SIGNALS.call_once(restore_on_signals);
+
+        // The kernel would tell the targets about the shorter route through the gateway
+        fast_path.set(format!("/proc/sys/net/ipv4/conf/{}/send_redirects", interface), "0")?;
+        fast_path.set("/proc/sys/net/ipv4/conf/all/send_redirects".to_string(), "0")?;
+        // Only frames received on the interface are forwarded, `ip_forward` would enable all of them
+        fast_path.set(format!("/proc/sys/net/ipv4/conf/{}/forwarding", interface), "1")?;
+
+        nft(&ruleset(interface, selectors)).map_err(HarpyError::FastPath)?;
+        *fast_path.table.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        info!("Kernel forwarding enabled on {}, {} flows intercepted", interface, selectors.len());
+        Ok(fast_path)
+    }
+
+    fn set(&self, path: String, value: &str) -> Result<(), HarpyError> {
+        let path = PathBuf::from(path);
+        let previous = std::fs::read_to_string(&path)
+            .map_err(|e| HarpyError::FastPath(format!("couldn't read {}: {}", path.display(), e)))?;
+        std::fs::write(&path, value)
+            .map_err(|e| HarpyError::FastPath(format!("couldn't write {}: {}", path.display(), e)))?;
+        debug!("Set {} to {}, was {}", path.display(), value, previous.trim());
+        self.sysctls.lock().unwrap_or_else(|e| e.into_inner()).push((path, previous.trim().to_string()));
+        Ok(())
+    }
+
+    /// Deletes the table and restores the sysctls, only the first call has an effect
+    pub fn restore(&self) {
+        let mut table = self.table.lock().unwrap_or_else(|e| e.into_inner());
+        if std::mem::take(&mut *table) {
+            if let Err(e) = nft(&format!("delete table ip {}\n", TABLE)) {
+                warn!("Couldn't delete the nftables table {}: {}", TABLE, e);
+            }
+        }
+        let mut sysctls = self.sysctls.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some((path, previous)) = sysctls.pop() {
+            if let Err(e) = std::fs::write(&path, &previous) {
+                warn!("Couldn't restore {} to {}: {}", path.display(), previous, e);
+            }
+        }
+    }
+}
+
+impl Drop for FastPath {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Restores every active fast path, for code paths ending harpy with `std::process::exit`
+pub fn restore_all() {
+    let active = std::mem::take(&mut *ACTIVE.lock().unwrap_or_else(|e| e.into_inner()));
+    active.iter().filter_map(Weak::upgrade).for_each(|fast_path| fast_path.restore());
+}
+
+/// Fast paths whose session is still running
+fn active() -> Vec<Arc<FastPath>> {
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).iter().filter_map(Weak::upgrade).collect()
+}
+
+fn restore_on_signals() {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    match signal_hook::iterator::Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+        Ok(mut signals) => {
+            std::thread::spawn(move || loop {
+                let Some(signal) = signals.forever().next() else {
+                    return;
+                };
+                // Sessions end like they do on their own, so the dashboard hands back the terminal.
+                // They only notice once the next frame arrives, a quiet network isn't waited for.
+                info!("Interrupted, stopping the session");
+                active().iter().for_each(|fast_path| fast_path.stop.stop());
+                let deadline = Instant::now() + STOP_GRACE;
+                while Instant::now() < deadline && signals.pending().next().is_none() {
+                    if active().is_empty() {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                if !active().is_empty() {
+                    restore_all();
+                    std::process::exit(128 + signal);
+                }
+            });
+        },
+        Err(e) => warn!("Couldn't install signal handlers, the kernel's forwarding state won't be restored when interrupted: {}", e)
+    }
+}
+
+
+#[test]
+fn test_ruleset() {
+    let selectors = [
+        Selector { host: Some("192.168.0.53/32".parse().unwrap()), protocol: Some(IpNextHeaderProtocols::Tcp), port: Some(443) },
+        Selector { port: Some(53), ..Default::default() }
+    ];
+    assert_eq!(ruleset("eth0", &selectors), "\
+table ip harpy {
+\tchain forward {
+\t\ttype filter hook forward priority filter - 1; policy accept;
+\t\tiifname \"eth0\" ip saddr 192.168.0.53/32 meta l4proto tcp th sport 443 drop
+\t\tiifname \"eth0\" ip saddr 192.168.0.53/32 meta l4proto tcp th dport 443 drop
+\t\tiifname \"eth0\" ip saddr 192.168.0.53/32 meta l4proto tcp ip frag-off & 0x3fff != 0 drop
+\t\tiifname \"eth0\" ip daddr 192.168.0.53/32 meta l4proto tcp th sport 443 drop
+\t\tiifname \"eth0\" ip daddr 192.168.0.53/32 meta l4proto tcp th dport 443 drop
+\t\tiifname \"eth0\" ip daddr 192.168.0.53/32 meta l4proto tcp ip frag-off & 0x3fff != 0 drop
+\t\tiifname \"eth0\" meta l4proto { tcp, udp } th sport 53 drop
+\t\tiifname \"eth0\" meta l4proto { tcp, udp } th dport 53 drop
+\t\tiifname \"eth0\" meta l4proto { tcp, udp } ip frag-off & 0x3fff != 0 drop
+\t}
+}
+");
+}
+
+#[test]
+fn test_intercepts() {
+    use hex_literal::hex;
+    let selectors = [Selector { port: Some(53), ..Default::default() }];
+    // UDP response from 1.1.1.1:53 to 192.168.0.2:50012 in two fragments, and one to port 123
+    let first = hex!("45000024 1234 2000 4011 0000 01010101 c0a80002 0035c35c001c0000 0102030405060708");
+    let second = hex!("45000020 1234 0002 4011 0000 01010101 c0a80002 0d0e0f1011121314 15161718");
+    let ntp = hex!("45000024 4321 0000 4011 0000 01010101 c0a80002 007bc35c00100000 0102030405060708");
+    assert!(intercepts(&selectors, &Ipv4Packet::new(&first).unwrap()));
+    // Without ports, but harpy gets its first fragment as well
+    assert!(intercepts(&selectors, &Ipv4Packet::new(&second).unwrap()));
+    assert!(!intercepts(&selectors, &Ipv4Packet::new(&ntp).unwrap()));
+
+    // ICMP has no ports, its fragments stay with the kernel
+    let mut icmp = second;
+    icmp[9] = 1;
+    assert!(!intercepts(&selectors, &Ipv4Packet::new(&icmp).unwrap()));
+    assert!(intercepts(&[Selector { protocol: Some(IpNextHeaderProtocols::Icmp), ..Default::default() }], &Ipv4Packet::new(&icmp).unwrap()));
+}
@@ -0,0 +1,349 @@
+//! Reassembly of fragmented IPv4 datagrams (RFC 791, RFC 815), so handlers see them as a whole,
+//! and fragmentation of datagrams that don't fit the link when forwarded.
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    ops::Range,
+    time::{Duration, Instant}
+};
+use pnet::packet::{
+    ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
+    Packet
+};
+use serde::Deserialize;
+
+use crate::metrics::METRICS;
+
+/// Largest payload a datagram can carry, its total length is a 16 bit field
+const MAX_PAYLOAD: usize = 65535 - 20;
+/// Datagrams reassembled at the same time, fragments of further datagrams are dropped
+const MAX_PENDING: usize = 1024;
+/// Smallest MTU every IPv4 link has to support (RFC 791)
+pub const MIN_MTU: usize = 68;
+
+/// What happens when fragments of a datagram overlap. Identical duplicates are always ignored.
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Keep the bytes that arrived first
+    First,
+    /// Overwrite them with the bytes that arrived last
+    Last,
+    /// Discard the whole datagram, as Linux does
+    Drop
+}
+
+/// Fragments of the same datagram share these (RFC 791)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    identification: u16
+}
+
+#[derive(Debug)]
+struct Pending {
+    /// Header of the fragment at offset 0, including its options
+    header: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    /// Received payload ranges, sorted and merged
+    received: Vec<Range<usize>>,
+    /// Payload length, known once the last fragment arrived
+    length: Option<usize>,
+    started: Instant
+}
+
+impl Pending {
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.length.is_some_and(|length| matches!(self.received.as_slice(), [received] if *received == (0..length)))
+    }
+
+    fn mark(&mut self, range: Range<usize>) {
+        self.received.push(range);
+        self.received.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.received.len());
+        for range in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range)
+            }
+        }
+        self.received = merged;
+    }
+}
+
+/// Result of passing a fragment to the [`Reassembler`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembly {
+    /// The reassembled datagram
+    Complete(Vec<u8>),
+    /// Fragments are still missing
+    Incomplete,
+    /// The fragment, or its whole datagram, was discarded
+    Dropped
+}
+
+/// Whether a packet is a fragment of a larger datagram
+pub fn is_fragment(ipv4: &Ipv4Packet) -> bool {
+    ipv4.get_flags() & Ipv4Flags::MoreFragments != 0 || ipv4.get_fragment_offset() != 0
+}
+
+/// Collects fragments until their datagram is complete
+#[derive(Debug)]
+pub struct Reassembler {
+    pending: HashMap<Key, Pending>,
+    timeout: Duration,
+    overlap: OverlapPolicy
+}
+
+impl Default for Reassembler {
+    /// 30 seconds, as Linux' `ipfrag_time`, and overlapping datagrams are dropped
+    fn default() -> Self {
+        Reassembler::new(Duration::from_secs(30), OverlapPolicy::Drop)
+    }
+}
+
+impl Reassembler {
+    /// Datagrams still incomplete `timeout` after their first fragment arrived are discarded
+    pub fn new(timeout: Duration, overlap: OverlapPolicy) -> Reassembler {
+        Reassembler { pending: HashMap::new(), timeout, overlap }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let before = self.pending.len();
+        self.pending.retain(|_, pending| now.saturating_duration_since(pending.started) < timeout);
+        METRICS.reassembly_timed_out.add((before - self.pending.len()) as u64);
+    }
+
+    fn discard(&mut self, key: &Key) -> Reassembly {
+        self.pending.remove(key);
+        METRICS.reassembly_invalid.inc();
+        Reassembly::Dropped
+    }
+
+    /// Adds a fragment received at `now`. Packets that aren't fragments are complete right away.
+    pub fn push(&mut self, fragment: &Ipv4Packet, now: Instant) -> Reassembly {
+        if !is_fragment(fragment) {
+            return Reassembly::Complete(fragment.packet()[..(fragment.get_total_length() as usize).min(fragment.packet().len())].to_vec());
+        }
+        self.expire(now);
+        let key = Key {
+            source: fragment.get_source(),
+            destination: fragment.get_destination(),
+            protocol: fragment.get_next_level_protocol().0,
+            identification: fragment.get_identification()
+        };
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            METRICS.reassembly_invalid.inc();
+            return Reassembly::Dropped;
+        }
+
+        let data = fragment.payload();
+        let offset = fragment.get_fragment_offset() as usize * 8;
+        let range = offset..offset + data.len();
+        let last = fragment.get_flags() & Ipv4Flags::MoreFragments == 0;
+        let overlap = self.overlap;
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            header: None,
+            payload: Vec::new(),
+            received: Vec::new(),
+            length: None,
+            started: now
+        });
+        // Only the last fragment may end off an 8 byte boundary, and none may end past the limit
+        if fragment.get_header_length() < 5 || range.end > MAX_PAYLOAD || (!last && !data.len().is_multiple_of(8)) || pending.length.is_some_and(|length| range.end > length) {
+            return self.discard(&key);
+        }
+        if last {
+            if pending.length.is_some_and(|length| length != range.end) || pending.received.last().is_some_and(|received| received.end > range.end) {
+                return self.discard(&key);
+            }
+            pending.length = Some(range.end);
+        }
+
+        let overlapping = pending.received.iter().any(|received| received.start < range.end && range.start < received.end);
+        if overlapping {
+            let duplicate = pending.received.iter().any(|received| received.start <= range.start && range.end <= received.end)
+                && pending.payload[range.clone()] == *data;
+            if duplicate {
+                return Reassembly::Incomplete;
+            }
+            METRICS.reassembly_overlapping.inc();
+            if overlap == OverlapPolicy::Drop {
+                self.pending.remove(&key);
+                return Reassembly::Dropped;
+            }
+        }
+
+        if pending.payload.len() < range.end {
+            pending.payload.resize(range.end, 0);
+        }
+        match (overlapping, overlap) {
+            (true, OverlapPolicy::First) => {
+                // Fill only the gaps between the ranges received so far
+                for (i, byte) in data.iter().enumerate() {
+                    let position = offset + i;
+                    if !pending.received.iter().any(|received| received.contains(&position)) {
+                        pending.payload[position] = *byte;
+                    }
+                }
+            },
+            _ => pending.payload[range.clone()].copy_from_slice(data)
+        }
+        if offset == 0 {
+            pending.header = Some(fragment.packet()[..(fragment.get_header_length() as usize * 4).min(fragment.packet().len())].to_vec());
+        }
+        pending.mark(range);
+
+        if !pending.is_complete() {
+            return Reassembly::Incomplete;
+        }
+        let pending = self.pending.remove(&key).unwrap();
+        let header = pending.header.unwrap();
+        let mut datagram = MutableIpv4Packet::owned([header.as_slice(), pending.payload.as_slice()].concat()).unwrap();
+        datagram.set_total_length((header.len() + pending.payload.len()) as u16);
+        datagram.set_flags(datagram.get_flags() & !Ipv4Flags::MoreFragments);
+        datagram.set_fragment_offset(0);
+        datagram.set_checksum(pnet::packet::ipv4::checksum(&datagram.to_immutable()));
+        METRICS.reassembly_complete.inc();
+        Reassembly::Complete(datagram.packet().to_vec())
+    }
+}
+
+/// Options of `header` that have to be copied into every fragment, padded to a multiple of 4 bytes
+fn copied_options(options: &[u8]) -> Vec<u8> {
+    let mut copied = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // End of options list
+            0 => break,
+            // No operation
+            1 => i += 1,
+            option => {
+                let length = options.get(i + 1).map_or(0, |length| *length as usize);
+                if length < 2 || i + length > options.len() {
+                    break;
+                }
+                if option & 0x80 != 0 {
+                    copied.extend_from_slice(&options[i..i + length]);
+                }
+                i += length;
+            }
+        }
+    }
+    copied.resize(copied.len().div_ceil(4) * 4, 0);
+    copied
+}
+
+/// Splits `datagram` into fragments of at most `mtu` bytes, regardless of its don't fragment
+/// flag. Options are only kept in later fragments if their copied flag is set. Empty if the MTU
+/// can't fit the header and 8 bytes of payload, or the header is malformed.
+pub fn fragment(datagram: &Ipv4Packet, mtu: usize) -> Vec<Vec<u8>> {
+    let total_length = (datagram.get_total_length() as usize).min(datagram.packet().len());
+    // The header can't claim more than the datagram, handlers may return anything
+    let header_length = (datagram.get_header_length() as usize * 4).min(total_length & !3);
+    if header_length < 20 {
+        return Vec::new();
+    }
+    let (first_header, payload) = (&datagram.packet()[..header_length], &datagram.packet()[header_length..total_length]);
+    let later_header = [&first_header[..20], &copied_options(&first_header[20..])].concat();
+    if mtu < first_header.len() + 8 {
+        return Vec::new();
+    }
+
+    // A fragment of a fragment keeps its offset, and the flag of the original if it's the last
+    let base_offset = datagram.get_fragment_offset() as usize * 8;
+    let more_fragments = datagram.get_flags() & Ipv4Flags::MoreFragments != 0;
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() || fragments.is_empty() {
+        let header = if offset == 0 { first_header } else { later_header.as_slice() };
+        let length = ((mtu - header.len()) & !7).min(payload.len() - offset);
+        let last = offset + length == payload.len();
+        let mut packet = MutableIpv4Packet::owned([header, &payload[offset..offset + length]].concat()).unwrap();
+        packet.set_header_length((header.len() / 4) as u8);
+        packet.set_total_length((header.len() + length) as u16);
+        packet.set_fragment_offset(((base_offset + offset) / 8) as u16);
+        let flags = packet.get_flags() & !Ipv4Flags::MoreFragments;
+        packet.set_flags(match !last || more_fragments {
+            true => flags | Ipv4Flags::MoreFragments,
+            false => flags
+        });
+        packet.set_checksum(pnet::packet::ipv4::checksum(&packet.to_immutable()));
+        fragments.push(packet.packet().to_vec());
+        offset += length;
+    }
+    fragments
+}
+
+
+#[test]
+fn test_fragmentation() {
+    use hex_literal::hex;
+    // UDP datagram from 192.168.0.1:53 to 192.168.0.2:40000 with 40 bytes of data and a
+    // record route option, which isn't copied into later fragments
+    let datagram = hex!("46000048 1234 0000 4011 0000 c0a80001 c0a80002 07040400
+        0035 9c40 0030 0000
+        00010203040506070809 10111213141516171819 20212223242526272829 30313233343536373839");
+    let mut datagram = MutableIpv4Packet::owned(datagram.to_vec()).unwrap();
+    datagram.set_checksum(pnet::packet::ipv4::checksum(&datagram.to_immutable()));
+    let datagram = datagram.consume_to_immutable();
+
+    let fragments = fragment(&datagram, 44);
+    assert_eq!(fragments.len(), 3);
+    let fragments: Vec<Ipv4Packet> = fragments.iter().map(|fragment| Ipv4Packet::new(fragment).unwrap()).collect();
+    assert_eq!(fragments.iter().map(|fragment| fragment.get_total_length()).collect::<Vec<_>>(), [40, 44, 28]);
+    assert_eq!(fragments.iter().map(|fragment| fragment.get_fragment_offset()).collect::<Vec<_>>(), [0, 2, 5]);
+    assert_eq!(fragments.iter().map(|fragment| fragment.get_flags()).collect::<Vec<_>>(), [1, 1, 0]);
+    assert!(fragments.iter().all(|fragment| fragment.get_checksum() == pnet::packet::ipv4::checksum(fragment)));
+    assert!(fragment(&datagram, 30).is_empty());
+    // Malformed headers a handler may return
+    let mut malformed = MutableIpv4Packet::owned(datagram.packet().to_vec()).unwrap();
+    malformed.set_header_length(4);
+    assert!(fragment(&malformed.to_immutable(), 44).is_empty());
+    malformed.set_header_length(15);
+    malformed.set_total_length(24);
+    assert_eq!(fragment(&malformed.to_immutable(), 44).len(), 1);
+
+    // Out of order, with a duplicate
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+    assert_eq!(reassembler.push(&fragments[2], now), Reassembly::Incomplete);
+    assert_eq!(reassembler.push(&fragments[0], now), Reassembly::Incomplete);
+    assert_eq!(reassembler.push(&fragments[0], now), Reassembly::Incomplete);
+    assert_eq!(reassembler.push(&fragments[1], now), Reassembly::Complete(datagram.packet().to_vec()));
+    assert_eq!(reassembler.pending(), 0);
+
+    // Incomplete datagrams time out
+    assert_eq!(reassembler.push(&fragments[0], now), Reassembly::Incomplete);
+    assert_eq!(reassembler.push(&fragments[1], now + Duration::from_secs(31)), Reassembly::Incomplete);
+    assert_eq!(reassembler.pending(), 1);
+
+    // The second fragment again, but overlapping the first and with different bytes
+    let mut overlapping = MutableIpv4Packet::owned([&fragments[1].packet()[..20], &[0xff; 24]].concat()).unwrap();
+    overlapping.set_total_length(44);
+    overlapping.set_fragment_offset(1);
+    let overlapping = overlapping.consume_to_immutable();
+    let reassemble = |policy: OverlapPolicy| {
+        let mut reassembler = Reassembler::new(Duration::from_secs(30), policy);
+        reassembler.push(&fragments[0], now);
+        reassembler.push(&overlapping, now);
+        reassembler.push(&fragments[1], now);
+        match reassembler.push(&fragments[2], now) {
+            Reassembly::Complete(datagram) => Some(Ipv4Packet::owned(datagram).unwrap().payload()[..32].to_vec()),
+            _ => None
+        }
+    };
+    assert_eq!(reassemble(OverlapPolicy::Drop), None);
+    assert_eq!(reassemble(OverlapPolicy::First).unwrap(), [&datagram.payload()[..16], &[0xff; 16][..]].concat());
+    assert_eq!(reassemble(OverlapPolicy::Last).unwrap(), [&datagram.payload()[..8], &[0xff; 8], &datagram.payload()[16..32]].concat());
+}
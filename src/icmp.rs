@@ -0,0 +1,130 @@
+use std::net::Ipv4Addr;
+use pnet::packet::{
+    ip::IpNextHeaderProtocols,
+    ipv4::{Ipv4Packet, MutableIpv4Packet},
+    Packet
+};
+
+// https://www.iana.org/assignments/icmp-parameters
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_DESTINATION_UNREACHABLE: u8 = 3;
+pub const TYPE_SOURCE_QUENCH: u8 = 4;
+pub const TYPE_REDIRECT: u8 = 5;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+pub const TYPE_PARAMETER_PROBLEM: u8 = 12;
+pub const TYPE_TIMESTAMP: u8 = 13;
+pub const TYPE_TIMESTAMP_REPLY: u8 = 14;
+
+pub const CODE_NET_UNREACHABLE: u8 = 0;
+pub const CODE_HOST_UNREACHABLE: u8 = 1;
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+
+pub const CODE_TTL_EXCEEDED: u8 = 0;
+pub const CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+pub const CODE_REDIRECT_NET: u8 = 0;
+pub const CODE_REDIRECT_HOST: u8 = 1;
+
+/// Error messages quote the datagram that caused them
+pub fn is_error(icmp_type: u8) -> bool {
+    matches!(icmp_type, TYPE_DESTINATION_UNREACHABLE | TYPE_SOURCE_QUENCH | TYPE_REDIRECT | TYPE_TIME_EXCEEDED | TYPE_PARAMETER_PROBLEM)
+}
+
+/// Messages identified by an identifier and a sequence number
+pub fn has_identifier(icmp_type: u8) -> bool {
+    matches!(icmp_type, TYPE_ECHO_REPLY | TYPE_ECHO_REQUEST | TYPE_TIMESTAMP | TYPE_TIMESTAMP_REPLY)
+}
+
+/// Builds an ICMP message, `rest_of_header` is the type specific second word of the header
+pub fn build(icmp_type: u8, code: u8, rest_of_header: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut icmp = Vec::with_capacity(8 + payload.len());
+    icmp.extend_from_slice(&[icmp_type, code, 0, 0]);
+    icmp.extend_from_slice(&rest_of_header);
+    icmp.extend_from_slice(payload);
+    let checksum = crate::util::checksum(&icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    icmp
+}
+
+/// Builds an echo request or reply
+pub fn echo(icmp_type: u8, identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let (identifier, sequence) = (identifier.to_be_bytes(), sequence.to_be_bytes());
+    build(icmp_type, 0, [identifier[0], identifier[1], sequence[0], sequence[1]], payload)
+}
+
+/// Wraps an ICMP message into an IPv4 packet
+pub fn ipv4(source: Ipv4Addr, destination: Ipv4Addr, ttl: u8, icmp: &[u8]) -> Vec<u8> {
+    let mut ipv4 = MutableIpv4Packet::owned(vec![0u8; 20 + icmp.len()]).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length((20 + icmp.len()) as u16);
+    ipv4.set_ttl(ttl);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ipv4.set_source(source);
+    ipv4.set_destination(destination);
+    ipv4.set_payload(icmp);
+    ipv4.set_checksum(crate::util::checksum(&ipv4.packet()[..20]));
+    ipv4.packet().to_vec()
+}
+
+/// The part of `original` an error message quotes, its header and the first 8 bytes of its
+/// payload, as required by RFC 792
+pub fn quote(original: &Ipv4Packet) -> Vec<u8> {
+    let header_len = (original.get_header_length() as usize * 4).min(original.packet().len());
+    original.packet()[..(header_len + 8).min(original.packet().len())].to_vec()
+}
+
+/// Builds an ICMP error in response to `original`, as IPv4 packet from `original`'s destination
+/// back to its source. The ICMP body carries `rest_of_header` (the unused field, or the next-hop
+/// MTU for fragmentation needed) followed by the quoted original datagram.
+pub fn build_error(original: &Ipv4Packet, icmp_type: u8, code: u8, rest_of_header: [u8; 4]) -> Vec<u8> {
+    let icmp = build(icmp_type, code, rest_of_header, &quote(original));
+    ipv4(original.get_destination(), original.get_source(), 64, &icmp)
+}
+
+/// Builds an ICMP port unreachable message for `original`
+pub fn port_unreachable(original: &Ipv4Packet) -> Vec<u8> {
+    build_error(original, TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE, [0; 4])
+}
+
+/// Builds an ICMP fragmentation needed message for `original`, announcing `mtu` as next-hop MTU
+pub fn fragmentation_needed(original: &Ipv4Packet, mtu: u16) -> Vec<u8> {
+    let mtu = mtu.to_be_bytes();
+    build_error(original, TYPE_DESTINATION_UNREACHABLE, CODE_FRAGMENTATION_NEEDED, [0, 0, mtu[0], mtu[1]])
+}
+
+
+#[test]
+fn test_port_unreachable() {
+    use hex_literal::hex;
+    // UDP datagram from 192.168.0.38:50012 to 1.1.1.1:443
+    let original = hex!("450000240000400040110000c0a8002601010101c35c01bb0010000068656c6c6f000000");
+    let original = Ipv4Packet::new(&original).unwrap();
+    let reply = port_unreachable(&original);
+    let reply = Ipv4Packet::new(&reply).unwrap();
+
+    assert_eq!(reply.get_source(), original.get_destination());
+    assert_eq!(reply.get_destination(), original.get_source());
+    assert_eq!(reply.get_next_level_protocol(), IpNextHeaderProtocols::Icmp);
+    assert_eq!(reply.get_total_length() as usize, 20 + 8 + 28);
+    assert_eq!(crate::util::checksum(&reply.packet()[..20]), 0);
+
+    let icmp = reply.payload();
+    assert_eq!(&icmp[..2], &[TYPE_DESTINATION_UNREACHABLE, CODE_PORT_UNREACHABLE]);
+    assert_eq!(crate::util::checksum(icmp), 0);
+    assert_eq!(&icmp[8..], &original.packet()[..28]);
+}
+
+#[test]
+fn test_echo() {
+    use hex_literal::hex;
+    // Reply to a ping from 192.168.0.38 to 1.1.1.1
+    let reply = ipv4(Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(192, 168, 0, 38), 64, &echo(TYPE_ECHO_REPLY, 0x1c2b, 1, b"abcdefgh"));
+    assert_eq!(reply, hex!("45000024 0000 0000 4001 b809 01010101 c0a80026
+        0000 523e 1c2b 0001 6162636465666768"));
+    assert!(is_error(TYPE_TIME_EXCEEDED) && !is_error(TYPE_ECHO_REPLY));
+    assert!(has_identifier(TYPE_ECHO_REQUEST) && !has_identifier(TYPE_REDIRECT));
+}
@@ -0,0 +1,359 @@
+//! Network impairment emulation for forwarded frames, similar to Linux' netem: delay, jitter,
+//! loss, duplication, reordering and token bucket bandwidth limits, per target or per flow.
+
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    net::Ipv4Addr,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+use pnet::{
+    ipnetwork::Ipv4Network,
+    packet::{
+        ethernet::EthernetPacket,
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::Ipv4Packet,
+        tcp::TcpPacket,
+        udp::UdpPacket,
+        Packet
+    }
+};
+
+use crate::{metrics::{Direction, METRICS}, sink::Sink};
+
+/// Frames that would wait longer than this for the rate limit are dropped instead of queued
+const MAX_BACKLOG: Duration = Duration::from_secs(1);
+/// The rate limit lets at least this many bytes through in a burst, two full-sized frames
+const MIN_BURST: f64 = 2.0 * 1514.0;
+
+/// How frames matching a rule are impaired
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Profile {
+    pub delay: Duration,
+    /// Frames are delayed by `delay` plus or minus up to `jitter`, which may reorder them
+    pub jitter: Duration,
+    /// Percentage of frames that are dropped
+    pub loss: f64,
+    /// Percentage of frames that are sent twice
+    pub duplicate: f64,
+    /// Percentage of frames that are sent right away, ahead of the delayed ones
+    pub reorder: f64,
+    /// Bandwidth limit in bits per second, frames over it are queued
+    pub rate: Option<u64>
+}
+
+/// Which frames a rule applies to, unset fields match everything
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Selector {
+    /// Matches either the source or the destination address
+    pub host: Option<Ipv4Network>,
+    pub protocol: Option<IpNextHeaderProtocol>,
+    /// Matches either the source or the destination port
+    pub port: Option<u16>
+}
+
+/// Addresses, protocol and ports of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flow {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: IpNextHeaderProtocol,
+    /// Source and destination port of TCP and UDP packets, `None` for later fragments
+    pub ports: Option<(u16, u16)>
+}
+
+impl Flow {
+    pub fn of(ipv4: &Ipv4Packet) -> Flow {
+        let protocol = ipv4.get_next_level_protocol();
+        let ports = match (protocol, ipv4.get_fragment_offset()) {
+            (IpNextHeaderProtocols::Tcp, 0) => TcpPacket::new(ipv4.payload()).map(|tcp| (tcp.get_source(), tcp.get_destination())),
+            (IpNextHeaderProtocols::Udp, 0) => UdpPacket::new(ipv4.payload()).map(|udp| (udp.get_source(), udp.get_destination())),
+            _ => None
+        };
+        Flow { source: ipv4.get_source(), destination: ipv4.get_destination(), protocol, ports }
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, flow: &Flow) -> bool {
+        self.host.is_none_or(|host| host.contains(flow.source) || host.contains(flow.destination))
+            && self.protocol.is_none_or(|protocol| protocol == flow.protocol)
+            && self.port.is_none_or(|port| flow.ports.is_some_and(|(source, destination)| port == source || port == destination))
+    }
+}
+
+/// Parses a bandwidth such as `512kbit`, `10mbit` or `1gbit` into bits per second, a plain
+/// number is taken as bits per second
+pub fn parse_rate(rate: &str) -> Option<u64> {
+    let rate = rate.trim().to_ascii_lowercase();
+    let split = rate.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rate.len());
+    let (value, unit) = rate.split_at(split);
+    let factor = match unit.trim() {
+        "" | "bit" => 1.0,
+        "kbit" => 1e3,
+        "mbit" => 1e6,
+        "gbit" => 1e9,
+        _ => return None
+    };
+    let bits = value.parse::<f64>().ok()? * factor;
+    (bits >= 1.0 && bits.is_finite()).then_some(bits as u64)
+}
+
+/// A token bucket which may go into debt, the debt is the time queued frames still wait
+#[derive(Debug, Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Option<Instant>
+}
+
+impl TokenBucket {
+    /// Takes `size` bytes, returns how long the frame has to wait for them or `None` if the
+    /// backlog is full
+    fn take(&mut self, size: usize, bytes_per_second: f64, now: Instant) -> Option<Duration> {
+        let burst = (bytes_per_second / 100.0).max(MIN_BURST);
+        self.tokens = match self.updated {
+            Some(updated) => (self.tokens + now.saturating_duration_since(updated).as_secs_f64() * bytes_per_second).min(burst),
+            None => burst
+        };
+        self.updated = Some(now);
+        let wait = Duration::from_secs_f64((size as f64 - self.tokens).max(0.0) / bytes_per_second);
+        if wait > MAX_BACKLOG {
+            return None;
+        }
+        self.tokens -= size as f64;
+        Some(wait)
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    selector: Selector,
+    profile: Profile,
+    /// One bucket per direction, so the limit applies to uploads and downloads separately
+    buckets: [TokenBucket; 2]
+}
+
+/// xorshift64*, good enough to decide which frames to impair
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn seeded() -> Rng {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64);
+        Rng(nanos | 1)
+    }
+    /// A number in `[0, 1)`
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545f4914f6cdd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn chance(&mut self, percent: f64) -> bool {
+        percent > 0.0 && self.next() * 100.0 < percent
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    rules: Vec<Rule>,
+    rng: Rng
+}
+
+/// The impairment rules of a session, the first rule matching a frame applies. Cloning is cheap,
+/// all clones share the rules, so scripts can adjust them while the session runs.
+#[derive(Debug, Clone)]
+pub struct Impairments(Arc<Mutex<State>>);
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Impairments::new(Vec::new())
+    }
+}
+
+impl Impairments {
+    pub fn new(rules: impl IntoIterator<Item = (Selector, Profile)>) -> Impairments {
+        let rules = rules.into_iter()
+            .map(|(selector, profile)| Rule { selector, profile, buckets: Default::default() })
+            .collect();
+        Impairments(Arc::new(Mutex::new(State { rules, rng: Rng::seeded() })))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replaces the profile of the rule with the same selector, or adds a rule taking precedence
+    /// over the existing ones. `None` removes the rule.
+    pub fn set(&self, selector: Selector, profile: Option<Profile>) {
+        let mut state = self.state();
+        let existing = state.rules.iter().position(|rule| rule.selector == selector);
+        match (existing, profile) {
+            (Some(i), Some(profile)) => state.rules[i].profile = profile,
+            (Some(i), None) => { state.rules.remove(i); },
+            (None, Some(profile)) => state.rules.insert(0, Rule { selector, profile, buckets: Default::default() }),
+            (None, None) => ()
+        }
+    }
+
+    pub fn rules(&self) -> Vec<(Selector, Profile)> {
+        self.state().rules.iter().map(|rule| (rule.selector, rule.profile)).collect()
+    }
+
+    /// When a frame of `size` bytes arriving at `now` is sent, once per copy. `None` if no rule
+    /// applies, an empty list if the frame is lost.
+    pub fn schedule(&self, flow: &Flow, size: usize, direction: Direction, now: Instant) -> Option<Vec<Instant>> {
+        let State { rules, rng } = &mut *self.state();
+        let rule = rules.iter_mut().find(|rule| rule.selector.matches(flow))?;
+        let profile = rule.profile;
+        if rng.chance(profile.loss) {
+            METRICS.impairment_lost.inc();
+            return Some(Vec::new());
+        }
+        let wait = match profile.rate {
+            Some(rate) => match rule.buckets[direction as usize].take(size, rate as f64 / 8.0, now) {
+                Some(wait) => wait,
+                None => {
+                    METRICS.impairment_overlimit.inc();
+                    return Some(Vec::new());
+                }
+            },
+            None => Duration::ZERO
+        };
+        let delay = if rng.chance(profile.reorder) {
+            METRICS.impairment_reordered.inc();
+            Duration::ZERO
+        } else {
+            let jitter = profile.jitter.as_secs_f64() * (rng.next() * 2.0 - 1.0);
+            Duration::from_secs_f64((profile.delay.as_secs_f64() + jitter).max(0.0))
+        };
+        let release = now + wait + delay;
+        Some(match rng.chance(profile.duplicate) {
+            true => {
+                METRICS.impairment_duplicated.inc();
+                vec![release, release]
+            },
+            false => vec![release]
+        })
+    }
+}
+
+struct Queued {
+    release: Instant,
+    sequence: u64,
+    frame: EthernetPacket<'static>
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        (self.release, self.sequence) == (other.release, other.sequence)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    /// Reversed, so the heap pops the earliest frame, frames due at the same time in queue order
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.release, other.sequence).cmp(&(self.release, self.sequence))
+    }
+}
+
+/// Holds impaired frames back until they are due
+pub struct ReleaseQueue(mpsc::Sender<(Instant, EthernetPacket<'static>)>);
+
+impl ReleaseQueue {
+    /// Starts the thread sending due frames through `sink`, it exits once the queue is dropped
+    pub fn start(sink: Arc<Sink>) -> ReleaseQueue {
+        let (tx, rx) = mpsc::channel::<(Instant, EthernetPacket<'static>)>();
+        std::thread::spawn(move || {
+            let mut queue = BinaryHeap::new();
+            let mut sequence = 0u64;
+            loop {
+                let now = Instant::now();
+                while queue.peek().is_some_and(|queued: &Queued| queued.release <= now) {
+                    sink.send(queue.pop().unwrap().frame);
+                }
+                let received = match queue.peek() {
+                    Some(next) => rx.recv_timeout(next.release - now),
+                    None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+                };
+                match received {
+                    Ok((release, frame)) => {
+                        queue.push(Queued { release, sequence, frame });
+                        sequence += 1;
+                    },
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return
+                }
+            }
+        });
+        ReleaseQueue(tx)
+    }
+
+    pub fn push(&self, release: Instant, frame: EthernetPacket<'static>) {
+        let _ = self.0.send((release, frame));
+    }
+}
+
+
+#[test]
+fn test_impairments() {
+    let flow = |port: u16| Flow {
+        source: Ipv4Addr::new(192, 168, 0, 53),
+        destination: Ipv4Addr::new(1, 1, 1, 1),
+        protocol: IpNextHeaderProtocols::Tcp,
+        ports: Some((40000, port))
+    };
+    let https = Selector { port: Some(443), ..Default::default() };
+    let target = Selector { host: Some("192.168.0.53/32".parse().unwrap()), ..Default::default() };
+    assert!(https.matches(&flow(443)) && !https.matches(&flow(80)));
+    assert!(target.matches(&flow(80)));
+
+    let now = Instant::now();
+    let impairments = Impairments::new([(target, Profile { delay: Duration::from_millis(100), ..Default::default() })]);
+    assert_eq!(impairments.schedule(&flow(80), 100, Direction::TargetToGateway, now), Some(vec![now + Duration::from_millis(100)]));
+    // Newer rules take precedence, removing them restores the previous behaviour
+    impairments.set(https, Some(Profile { loss: 100.0, ..Default::default() }));
+    assert_eq!(impairments.schedule(&flow(443), 100, Direction::TargetToGateway, now), Some(vec![]));
+    impairments.set(https, None);
+    assert_eq!(impairments.rules().len(), 1);
+
+    impairments.set(target, Some(Profile { duplicate: 100.0, reorder: 100.0, delay: Duration::from_millis(100), ..Default::default() }));
+    assert_eq!(impairments.schedule(&flow(80), 100, Direction::TargetToGateway, now), Some(vec![now, now]));
+    impairments.set(target, None);
+    assert_eq!(impairments.schedule(&flow(80), 100, Direction::TargetToGateway, now), None);
+
+    // 8 mbit/s are 1000 bytes per millisecond, after a burst of 10ms every 1000 bytes wait another millisecond
+    let impairments = Impairments::new([(Selector::default(), Profile { rate: parse_rate("8mbit"), ..Default::default() })]);
+    let waits: Vec<u128> = (0..12)
+        .map(|_| impairments.schedule(&flow(80), 1000, Direction::GatewayToTarget, now).unwrap()[0])
+        .map(|release| (release - now).as_secs_f64().mul_add(1000.0, 0.5) as u128)
+        .collect();
+    assert_eq!(waits, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+    // Each direction has its own bucket, frames over the backlog are dropped
+    assert_eq!(impairments.schedule(&flow(80), 1000, Direction::TargetToGateway, now), Some(vec![now]));
+    assert_eq!(impairments.schedule(&flow(80), 2_000_000, Direction::TargetToGateway, now), Some(vec![]));
+
+    // Scripts share the rules with the session
+    let harpy = crate::engine::HarpyEngine::new();
+    let impairments = Impairments::default();
+    harpy.set_impairments(impairments.clone());
+    harpy.context(|ctx| ctx.load(r#"harpy.impair({ target = "192.168.0.53", delay = 100, loss = 0.5 })"#).exec()).unwrap();
+    assert_eq!(impairments.rules(), [(target, Profile { delay: Duration::from_millis(100), loss: 0.5, ..Default::default() })]);
+    harpy.context(|ctx| ctx.load(r#"harpy.impair({ target = "192.168.0.53" })"#).exec()).unwrap();
+    assert!(impairments.rules().is_empty());
+    assert!(harpy.context(|ctx| ctx.load(r#"harpy.impair({ rate = "fast" })"#).exec()).is_err());
+
+    assert_eq!(parse_rate("1.5mbit"), Some(1_500_000));
+    assert_eq!(parse_rate("64000"), Some(64000));
+    assert_eq!(parse_rate("fast"), None);
+    assert_eq!(parse_rate("0kbit"), None);
+}
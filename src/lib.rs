@@ -0,0 +1,42 @@
+//! Harpy places itself between a gateway and its targets by ARP spoofing, and lets Rust code or
+//! Lua scripts inspect, drop and modify the traffic passing through.
+//!
+//! [`MitmSession`] runs a spoofing session, frames are passed to [`PacketHandler`]s and progress
+//! is reported to [`SessionObserver`]s. [`engine::HarpyEngine`] runs the Lua scripts the `harpy`
+//! command line tool uses, [`tls`] and [`quic`] parse the handshakes they inspect.
+//!
+//! The modules only the command line tool needs, the dashboard, `harpy test` and `harpy replay`,
+//! are behind the default `cli` feature. `default-features = false` leaves out their dependencies.
+
+#[macro_use]
+extern crate log;
+
+pub mod util;
+pub mod arp;
+pub mod tls;
+pub mod pack;
+pub mod quic;
+pub mod icmp;
+pub mod tcp;
+pub mod downgrade;
+pub mod events;
+pub mod metrics;
+#[cfg(feature = "cli")]
+pub mod tui;
+pub mod config;
+pub mod error;
+pub mod sink;
+pub mod session;
+pub mod impair;
+pub(crate) mod fastpath;
+pub mod fragment;
+pub mod encap;
+pub mod engine;
+pub mod pcap;
+#[cfg(feature = "cli")]
+pub mod replay;
+#[cfg(feature = "cli")]
+pub mod testing;
+
+pub use error::HarpyError;
+pub use session::{Frame, HostRole, MitmSession, MitmSessionBuilder, PacketHandler, SessionObserver, StopHandle, Verdict};
@@ -1,12 +1,6 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
-
-pub mod util;
-pub mod arp;
-pub mod tls;
-pub mod quic;
-pub mod sink;
-pub mod engine;
+use harpy::{config::{ImpairmentConfig, SandboxConfig}, downgrade, fragment, engine::sandbox::OverBudgetPolicy, testing::ReportFormat};
 
 mod commands;
 
@@ -20,17 +14,21 @@ pub(crate) struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Spoof {
+        /// Read the session from a TOML file, flags override its values
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+
         /// The target IP address to spoof
         #[clap(short, long)]
-        target: String,
+        target: Option<String>,
 
-        /// The interface to use, defaults to the first one found
+        /// The gateway to use, defaults to the interface's default route
         #[clap(short, long)]
-        gateway: Option<String>,
+        gateway: Option<std::net::Ipv4Addr>,
 
         /// The interface to use
         #[clap(short, long)]
-        interface: String,
+        interface: Option<String>,
 
         /// The lua file to interpret
         #[clap(short, long)]
@@ -38,14 +36,198 @@ enum Commands {
 
         /// Capture all traffic instead of just traffic between the gateway and the target
         #[clap(short, long)]
-        all: bool
+        all: bool,
+
+        /// Block QUIC to force clients back to TCP/TLS, by dropping it or rejecting it with ICMP
+        /// port unreachable. Only new connections are blocked, established ones keep working
+        #[clap(long, arg_enum)]
+        block_quic: Option<downgrade::QuicBlockMode>,
+
+        /// Write events as JSON lines to a file, a Unix socket (unix:<path>) or stdout (-)
+        #[clap(short, long)]
+        events: Option<String>,
+
+        /// Serve Prometheus metrics on http://<METRICS>/metrics, f.e. 127.0.0.1:9100
+        #[clap(short, long)]
+        metrics: Option<std::net::SocketAddr>,
+
+        /// Show an interactive dashboard instead of log output
+        #[clap(long)]
+        tui: bool,
+
+        /// Re-send the spoofed ARP replies every <SECONDS>
+        #[clap(long, value_name = "SECONDS")]
+        repoison_interval: Option<u64>,
+
+        /// Let the kernel forward all traffic, except the flows the scripts intercept
+        #[clap(long)]
+        fast_path: bool,
+
+        /// Wait <SECONDS> for the missing fragments of a datagram [default: 30]
+        #[clap(long, value_name = "SECONDS")]
+        fragment_timeout: Option<u64>,
+
+        /// What to do with datagrams whose fragments overlap [default: drop]
+        #[clap(long, arg_enum, value_name = "POLICY")]
+        fragment_overlap: Option<fragment::OverlapPolicy>,
+
+        /// Clamp the MSS of TCP handshakes to leave room for <BYTES> added by the scripts [default: 0]
+        #[clap(long, value_name = "BYTES")]
+        mss_headroom: Option<u16>,
+
+        #[clap(flatten)]
+        sandbox: SandboxArgs,
+
+        #[clap(flatten)]
+        impairment: ImpairmentArgs
     },
     Inspect {
         #[clap(short, long)]
         file: PathBuf,
 
         #[clap(short, long)]
-        interface: String
+        interface: String,
+
+        /// Write events as JSON lines to a file, a Unix socket (unix:<path>) or stdout (-)
+        #[clap(short, long)]
+        events: Option<String>,
+
+        /// Serve Prometheus metrics on http://<METRICS>/metrics, f.e. 127.0.0.1:9100
+        #[clap(short, long)]
+        metrics: Option<std::net::SocketAddr>,
+
+        #[clap(flatten)]
+        sandbox: SandboxArgs
+    },
+    /// Send the frames of a capture, optionally rewritten and passed through a Lua script
+    Replay {
+        /// The capture to replay, in the pcap format
+        capture: PathBuf,
+
+        /// The interface to use
+        #[clap(short, long)]
+        interface: String,
+
+        /// Speed up the recorded timing by <SPEED>, 2 replays twice as fast, 0.5 half as fast
+        #[clap(long, default_value = "1")]
+        speed: f64,
+
+        /// Send frames as fast as possible, ignoring their recorded timing
+        #[clap(long, conflicts_with = "speed")]
+        topspeed: bool,
+
+        /// Replay the capture <LOOPS> times
+        #[clap(long, default_value = "1")]
+        loops: u32,
+
+        /// Rewrite a MAC address, may be repeated
+        #[clap(long, value_name = "OLD=NEW", multiple_occurrences = true)]
+        rewrite_mac: Vec<String>,
+
+        /// Rewrite an IPv4 address or network, f.e. 10.0.0.0/24=192.168.1.0/24, may be repeated
+        #[clap(long, value_name = "OLD=NEW", multiple_occurrences = true)]
+        rewrite_ip: Vec<String>,
+
+        /// The lua file to pass every frame through before it is sent
+        #[clap(short, long)]
+        file: Option<PathBuf>,
+
+        /// Write events as JSON lines to a file, a Unix socket (unix:<path>) or stdout (-)
+        #[clap(short, long)]
+        events: Option<String>,
+
+        #[clap(flatten)]
+        sandbox: SandboxArgs
+    },
+    /// Run the tests of a Lua script against frames built in Lua or read from a pcap
+    Test {
+        /// The lua file to test
+        script: PathBuf,
+
+        /// The lua file declaring the tests, defaults to <SCRIPT>_test.lua next to the script
+        #[clap(short, long)]
+        spec: Option<PathBuf>,
+
+        /// Report format
+        #[clap(long, arg_enum, default_value = "tap")]
+        format: ReportFormat,
+
+        /// Write the report to a file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        #[clap(flatten)]
+        sandbox: SandboxArgs
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct SandboxArgs {
+    /// Run scripts with a restricted standard library and limited time and memory per callback
+    #[clap(long)]
+    sandbox: bool,
+
+    /// Time a callback of a sandboxed script may take [default: 50]
+    #[clap(long, value_name = "MS")]
+    time_budget: Option<u64>,
+
+    /// Memory a sandboxed script may allocate [default: 64]
+    #[clap(long, value_name = "MIB")]
+    memory_limit: Option<u64>,
+
+    /// What to do with a frame when a callback exceeds its budget [default: forward]
+    #[clap(long, arg_enum, value_name = "POLICY")]
+    over_budget: Option<OverBudgetPolicy>
+}
+
+/// Impairment of all forwarded frames, per target and per flow rules are set in the session file
+#[derive(clap::Args, Debug)]
+struct ImpairmentArgs {
+    /// Delay forwarded frames
+    #[clap(long, value_name = "MS")]
+    delay: Option<u64>,
+
+    /// Vary the delay by up to <MS> in either direction
+    #[clap(long, value_name = "MS")]
+    jitter: Option<u64>,
+
+    /// Drop a percentage of forwarded frames
+    #[clap(long, value_name = "PERCENT")]
+    loss: Option<f64>,
+
+    /// Send a percentage of forwarded frames twice
+    #[clap(long, value_name = "PERCENT")]
+    duplicate: Option<f64>,
+
+    /// Send a percentage of forwarded frames without the delay, ahead of the others
+    #[clap(long, value_name = "PERCENT")]
+    reorder: Option<f64>,
+
+    /// Limit the bandwidth of each direction, f.e. 512kbit or 10mbit
+    #[clap(long)]
+    rate: Option<String>
+}
+
+impl ImpairmentArgs {
+    fn into_config(self) -> Option<ImpairmentConfig> {
+        let config = ImpairmentConfig {
+            delay: self.delay,
+            jitter: self.jitter,
+            loss: self.loss,
+            duplicate: self.duplicate,
+            reorder: self.reorder,
+            rate: self.rate,
+            ..Default::default()
+        };
+        (config != ImpairmentConfig::default()).then_some(config)
+    }
+}
+
+impl SandboxArgs {
+    /// Setting any of the limits implies --sandbox
+    fn into_config(self) -> Option<SandboxConfig> {
+        let config = SandboxConfig { time_budget: self.time_budget, memory_limit: self.memory_limit, over_budget: self.over_budget };
+        (self.sandbox || config != SandboxConfig::default()).then_some(config)
     }
 }
 
@@ -55,19 +237,29 @@ enum Commands {
 extern crate log;
 
 fn main() {
-    pretty_env_logger::init();
-
     let args = Args::parse();
 
-    match args.command {
-        Commands::Spoof { .. } => {
-            commands::spoof::run(args);
-        },
+    let result = match args.command {
+        // Spoof sets up logging itself, the dashboard installs its own logger
+        Commands::Spoof { .. } => commands::spoof::run(args),
         Commands::Inspect { .. } => {
-            commands::inspect::run(args);
+            pretty_env_logger::init();
+            commands::inspect::run(args)
+        },
+        Commands::Replay { .. } => {
+            pretty_env_logger::init();
+            commands::replay::run(args)
+        },
+        Commands::Test { .. } => {
+            pretty_env_logger::init();
+            commands::test::run(args)
         }
+    };
+    // Printed directly, the logger may not be set up yet
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
     }
-
 }
 
 
@@ -0,0 +1,317 @@
+//! Process-wide counters, exposed in the Prometheus text format on `/metrics`.
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    time::Duration
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the latency histogram buckets in seconds
+pub const LATENCY_BUCKETS: [f64; 11] = [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1];
+
+pub struct Histogram {
+    buckets: [Counter; LATENCY_BUCKETS.len()],
+    sum_micros: Counter,
+    count: Counter
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { Counter::new() }; LATENCY_BUCKETS.len()],
+            sum_micros: Counter::new(),
+            count: Counter::new()
+        }
+    }
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        // Buckets are stored non-cumulative and summed up when rendered
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].inc();
+        }
+        self.sum_micros.add(duration.as_micros() as u64);
+        self.count.inc();
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Direction of a frame forwarded by the spoof loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    TargetToGateway,
+    GatewayToTarget
+}
+
+impl Direction {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Direction::TargetToGateway => "target_to_gateway",
+            Direction::GatewayToTarget => "gateway_to_target"
+        }
+    }
+}
+
+/// A counter registered by a Lua script through `harpy.counter`
+pub struct ScriptCounter {
+    pub help: String,
+    pub value: Counter
+}
+
+pub struct Metrics {
+    frames_received: [Counter; 2],
+    frames_forwarded: [Counter; 2],
+    pub packets_dropped: Counter,
+    pub packets_tampered: Counter,
+    pub lua_latency: Histogram,
+    /// Frames put on the bus by the sink and taken off by its receivers, their difference is the backlog
+    pub bus_sent: Counter,
+    pub bus_received: Counter,
+    pub sink_send_errors: Counter,
+    pub arp_replies_sent: Counter,
+    /// Callbacks of sandboxed scripts aborted for exceeding their time or memory budget
+    pub scripts_over_time_budget: Counter,
+    pub scripts_over_memory_budget: Counter,
+    /// Frames affected by network impairment, `overlimit` ones didn't fit the rate limit's backlog
+    pub impairment_lost: Counter,
+    pub impairment_overlimit: Counter,
+    pub impairment_duplicated: Counter,
+    pub impairment_reordered: Counter,
+    /// Fragmented datagrams reassembled, or discarded for timing out, overlapping or being malformed
+    pub reassembly_complete: Counter,
+    pub reassembly_timed_out: Counter,
+    pub reassembly_overlapping: Counter,
+    pub reassembly_invalid: Counter,
+    /// Fragments sent for datagrams exceeding the MTU
+    pub fragments_sent: Counter,
+    pub icmp_frag_needed_sent: Counter,
+    /// SYN segments whose MSS was lowered
+    pub mss_clamped: Counter,
+    /// QUIC datagrams blocked by `--block-quic`, and clients that fell back to TCP afterwards
+    pub quic_blocked: Counter,
+    pub quic_fallbacks: Counter,
+    script_counters: Mutex<BTreeMap<String, Arc<ScriptCounter>>>
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            frames_received: [Counter::new(), Counter::new()],
+            frames_forwarded: [Counter::new(), Counter::new()],
+            packets_dropped: Counter::new(),
+            packets_tampered: Counter::new(),
+            lua_latency: Histogram::new(),
+            bus_sent: Counter::new(),
+            bus_received: Counter::new(),
+            sink_send_errors: Counter::new(),
+            arp_replies_sent: Counter::new(),
+            scripts_over_time_budget: Counter::new(),
+            scripts_over_memory_budget: Counter::new(),
+            impairment_lost: Counter::new(),
+            impairment_overlimit: Counter::new(),
+            impairment_duplicated: Counter::new(),
+            impairment_reordered: Counter::new(),
+            reassembly_complete: Counter::new(),
+            reassembly_timed_out: Counter::new(),
+            reassembly_overlapping: Counter::new(),
+            reassembly_invalid: Counter::new(),
+            fragments_sent: Counter::new(),
+            icmp_frag_needed_sent: Counter::new(),
+            mss_clamped: Counter::new(),
+            quic_blocked: Counter::new(),
+            quic_fallbacks: Counter::new(),
+            script_counters: Mutex::new(BTreeMap::new())
+        }
+    }
+
+    pub fn frames_received(&self, direction: Direction) -> &Counter {
+        &self.frames_received[direction as usize]
+    }
+    pub fn frames_forwarded(&self, direction: Direction) -> &Counter {
+        &self.frames_forwarded[direction as usize]
+    }
+
+    /// Returns the script counter `name`, registering it on first use.
+    /// Names are restricted to `[a-zA-Z_][a-zA-Z0-9_]*` so they form valid metric names.
+    pub fn script_counter(&self, name: &str, help: Option<&str>) -> Option<Arc<ScriptCounter>> {
+        let valid = name.chars().enumerate().all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if name.is_empty() || !valid {
+            return None;
+        }
+        let mut counters = self.script_counters.lock().unwrap_or_else(|e| e.into_inner());
+        Some(counters.entry(name.to_string()).or_insert_with(|| Arc::new(ScriptCounter {
+            help: help.unwrap_or("Counter registered by the Lua script").to_string(),
+            value: Counter::new()
+        })).clone())
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let directions = [Direction::TargetToGateway, Direction::GatewayToTarget];
+        let by_direction = |counters: &[Counter; 2]| directions.map(|d| (format!("{{direction=\"{}\"}}", d.name()), counters[d as usize].get()));
+        let received = by_direction(&self.frames_received);
+        let forwarded = by_direction(&self.frames_forwarded);
+
+        counter(&mut out, "harpy_frames_received_total", "IPv4 frames of the spoofed hosts received by the spoof loop",
+            &received.iter().map(|(l, v)| (l.as_str(), *v)).collect::<Vec<_>>());
+        counter(&mut out, "harpy_frames_forwarded_total", "IPv4 frames forwarded by the spoof loop",
+            &forwarded.iter().map(|(l, v)| (l.as_str(), *v)).collect::<Vec<_>>());
+        counter(&mut out, "harpy_engine_results_total", "Frames dropped or tampered with by the Lua script",
+            &[("{result=\"drop\"}", self.packets_dropped.get()), ("{result=\"tamper\"}", self.packets_tampered.get())]);
+        counter(&mut out, "harpy_sink_send_errors_total", "Frames the sink failed to send", &[("", self.sink_send_errors.get())]);
+        counter(&mut out, "harpy_arp_replies_sent_total", "Spoofed ARP replies sent", &[("", self.arp_replies_sent.get())]);
+        counter(&mut out, "harpy_script_budget_exceeded_total", "Lua callbacks aborted by the sandbox",
+            &[("{budget=\"time\"}", self.scripts_over_time_budget.get()), ("{budget=\"memory\"}", self.scripts_over_memory_budget.get())]);
+        counter(&mut out, "harpy_impaired_frames_total", "Forwarded frames lost, duplicated or reordered by network impairment",
+            &[("{effect=\"loss\"}", self.impairment_lost.get()), ("{effect=\"overlimit\"}", self.impairment_overlimit.get()),
+                ("{effect=\"duplicate\"}", self.impairment_duplicated.get()), ("{effect=\"reorder\"}", self.impairment_reordered.get())]);
+        counter(&mut out, "harpy_reassembly_total", "Fragmented datagrams reassembled or discarded",
+            &[("{result=\"complete\"}", self.reassembly_complete.get()), ("{result=\"timeout\"}", self.reassembly_timed_out.get()),
+                ("{result=\"overlap\"}", self.reassembly_overlapping.get()), ("{result=\"invalid\"}", self.reassembly_invalid.get())]);
+        counter(&mut out, "harpy_fragments_sent_total", "Fragments of forwarded datagrams exceeding the MTU", &[("", self.fragments_sent.get())]);
+        counter(&mut out, "harpy_icmp_frag_needed_sent_total", "ICMP fragmentation needed sent for datagrams exceeding the MTU with don't fragment set",
+            &[("", self.icmp_frag_needed_sent.get())]);
+        counter(&mut out, "harpy_mss_clamped_total", "SYN and SYN-ACK segments whose announced MSS was lowered", &[("", self.mss_clamped.get())]);
+        counter(&mut out, "harpy_quic_blocked_total", "QUIC handshake datagrams blocked", &[("", self.quic_blocked.get())]);
+        counter(&mut out, "harpy_quic_fallbacks_total", "Servers clients connected to over TCP after their QUIC traffic was blocked",
+            &[("", self.quic_fallbacks.get())]);
+
+        let _ = writeln!(out, "# HELP harpy_bus_backlog Frames received from the network not yet processed\n# TYPE harpy_bus_backlog gauge");
+        let _ = writeln!(out, "harpy_bus_backlog {}", self.bus_sent.get().saturating_sub(self.bus_received.get()));
+
+        let name = "harpy_lua_on_packet_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent in the on_packet Lua function\n# TYPE {} histogram", name, name);
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.lua_latency.buckets.iter()) {
+            cumulative += bucket.get();
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let count = self.lua_latency.count.get();
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.lua_latency.sum_micros.get() as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        for (name, script_counter) in self.script_counters.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            let help = script_counter.help.replace('\\', "\\\\").replace('\n', "\\n");
+            counter(&mut out, &format!("harpy_script_{}", name), &help, &[("", script_counter.value.get())]);
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves `METRICS` on `http://<addr>/metrics` from a background thread, each connection is
+/// answered once from its own thread and closed, so a slow client doesn't hold up the others
+pub fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            std::thread::spawn(move || respond(stream));
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+    let mut request_line = String::new();
+    // Longer request lines aren't ours, reading stops there and the path won't match
+    if BufReader::new((&stream).take(1024)).read_line(&mut request_line).is_err() {
+        return;
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let response = match path {
+        "/metrics" => {
+            let body = METRICS.render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+
+#[test]
+fn test_render() {
+    let metrics = Metrics::new();
+    metrics.frames_received(Direction::GatewayToTarget).add(3);
+    metrics.lua_latency.observe(Duration::from_micros(80));
+    metrics.lua_latency.observe(Duration::from_millis(2));
+    metrics.lua_latency.observe(Duration::from_secs(1));
+    metrics.bus_sent.add(5);
+    metrics.bus_received.add(2);
+    metrics.script_counter("sni_seen", Some("SNIs seen")).unwrap().value.add(7);
+    assert!(metrics.script_counter("9lives", None).is_none());
+    assert!(metrics.script_counter("bad-name", None).is_none());
+
+    let rendered = metrics.render();
+    assert!(rendered.contains("harpy_frames_received_total{direction=\"gateway_to_target\"} 3\n"));
+    assert!(rendered.contains("harpy_frames_received_total{direction=\"target_to_gateway\"} 0\n"));
+    assert!(rendered.contains("harpy_bus_backlog 3\n"));
+    assert!(rendered.contains("harpy_lua_on_packet_duration_seconds_bucket{le=\"0.00005\"} 0\n"));
+    assert!(rendered.contains("harpy_lua_on_packet_duration_seconds_bucket{le=\"0.0001\"} 1\n"));
+    assert!(rendered.contains("harpy_lua_on_packet_duration_seconds_bucket{le=\"0.1\"} 2\n"));
+    assert!(rendered.contains("harpy_lua_on_packet_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(rendered.contains("harpy_lua_on_packet_duration_seconds_count 3\n"));
+    assert!(rendered.contains("# HELP harpy_script_sni_seen SNIs seen\n"));
+    assert!(rendered.contains("harpy_script_sni_seen 7\n"));
+}
+
+#[test]
+fn test_serve_concurrent() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    serve(addr).unwrap();
+    // A connection that never sends its request doesn't hold up the next one
+    let _idle = TcpStream::connect(addr).unwrap();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("harpy_bus_backlog "));
+}
@@ -0,0 +1,382 @@
+//! A small struct packing language for binary protocols, in the spirit of Lua's `string.pack`.
+//!
+//! | Format | Meaning |
+//! |--------|---------|
+//! | `>` `<` | switch to big-endian (default) or little-endian |
+//! | `B` `H` `I` `Q` | unsigned 1, 2, 4 and 8 byte integers |
+//! | `b` `h` `i` `q` | signed 1, 2, 4 and 8 byte integers |
+//! | `In` `in` | unsigned or signed integer of `n` bytes (1-8) |
+//! | `v` | QUIC variable-length integer |
+//! | `cn` | fixed-length string of `n` bytes |
+//! | `s1` `s2` `s4` `sv` | string prefixed by its length as 1, 2, 4 byte integer or varint |
+//! | `z` | zero-terminated string |
+//! | `x` `xn` | one or `n` padding bytes |
+//! | `[4 4]` | bitfields, most significant bit first, spanning a whole number of bytes |
+//!
+//! Whitespace between items is ignored. Counts of `c` and `x` are limited to `MAX_COUNT`.
+
+use crate::quic::QUICInteger;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PackError {
+    InvalidFormat(String),
+    /// The input ended before all items were read
+    Truncated,
+    /// A value doesn't fit into its item
+    Overflow(String),
+    /// Fewer values than items were passed to `pack`
+    MissingValue,
+    /// An integer was passed for a string item or vice versa
+    TypeMismatch
+}
+
+impl std::fmt::Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackError::InvalidFormat(reason) => write!(f, "invalid format: {}", reason),
+            PackError::Truncated => write!(f, "data too short for format"),
+            PackError::Overflow(reason) => write!(f, "overflow: {}", reason),
+            PackError::MissingValue => write!(f, "not enough values for format"),
+            PackError::TypeMismatch => write!(f, "value type doesn't match format")
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Int { width: usize, little_endian: bool },
+    Varint
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Int { width: usize, signed: bool, little_endian: bool },
+    Varint,
+    Fixed(usize),
+    Prefixed(Length),
+    ZeroTerminated,
+    Padding(usize),
+    Bits(Vec<u32>)
+}
+
+/// A packed or unpacked value, integers are 64-bit like Lua's
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packed {
+    Int(i64),
+    Bytes(Vec<u8>)
+}
+
+/// Largest count of `c` and `x` items, packets don't get any larger
+pub const MAX_COUNT: usize = 65535;
+/// Largest result of `pack`, padding items add up
+pub const MAX_PACKED: usize = 1 << 20;
+
+/// Reads the digits following an item, numbers too large for `usize` read as `usize::MAX`
+fn number(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<usize> {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        digits.push(*c);
+        chars.next();
+    }
+    (!digits.is_empty()).then(|| digits.parse().unwrap_or(usize::MAX))
+}
+
+fn count(count: usize, item: char) -> Result<usize, PackError> {
+    match count <= MAX_COUNT {
+        true => Ok(count),
+        false => Err(PackError::InvalidFormat(format!("count {} for '{}' exceeds {}", count, item, MAX_COUNT)))
+    }
+}
+
+pub fn parse_format(format: &str) -> Result<Vec<Item>, PackError> {
+    let mut items = Vec::new();
+    let mut little_endian = false;
+    let mut chars = format.chars().peekable();
+    let invalid = |reason: String| PackError::InvalidFormat(reason);
+    while let Some(c) = chars.next() {
+        let item = match c {
+            c if c.is_whitespace() => continue,
+            '>' | '!' => { little_endian = false; continue },
+            '<' => { little_endian = true; continue },
+            'B' | 'b' => Item::Int { width: 1, signed: c == 'b', little_endian },
+            'H' | 'h' => Item::Int { width: 2, signed: c == 'h', little_endian },
+            'Q' | 'q' => Item::Int { width: 8, signed: c == 'q', little_endian },
+            'I' | 'i' => {
+                let width = number(&mut chars).unwrap_or(4);
+                if !(1..=8).contains(&width) {
+                    return Err(invalid(format!("integer width {} out of range 1-8", width)));
+                }
+                Item::Int { width, signed: c == 'i', little_endian }
+            },
+            'v' => Item::Varint,
+            'c' => Item::Fixed(count(number(&mut chars).ok_or_else(|| invalid("missing length for 'c'".to_string()))?, 'c')?),
+            's' => match chars.peek() {
+                Some('v') => { chars.next(); Item::Prefixed(Length::Varint) },
+                _ => match number(&mut chars) {
+                    Some(width @ (1 | 2 | 4 | 8)) => Item::Prefixed(Length::Int { width, little_endian }),
+                    Some(width) => return Err(invalid(format!("length prefix width {} not one of 1, 2, 4, 8", width))),
+                    None => Item::Prefixed(Length::Int { width: 4, little_endian })
+                }
+            },
+            'z' => Item::ZeroTerminated,
+            'x' => Item::Padding(count(number(&mut chars).unwrap_or(1), 'x')?),
+            '[' => {
+                let mut widths = Vec::new();
+                loop {
+                    match chars.peek() {
+                        Some(']') => { chars.next(); break },
+                        Some(c) if c.is_whitespace() || *c == ',' => { chars.next(); },
+                        Some(c) if c.is_ascii_digit() => {
+                            let width = number(&mut chars).unwrap_or(0);
+                            if !(1..=64).contains(&width) {
+                                return Err(invalid(format!("bitfield width {} out of range 1-64", width)));
+                            }
+                            widths.push(width as u32);
+                        },
+                        _ => return Err(invalid("unterminated bitfield".to_string()))
+                    }
+                }
+                let total: u32 = widths.iter().sum();
+                if total == 0 || !total.is_multiple_of(8) || total > 64 {
+                    return Err(invalid(format!("bitfields span {} bits, expected whole bytes up to 64", total)));
+                }
+                Item::Bits(widths)
+            },
+            c => return Err(invalid(format!("unknown format character '{}'", c)))
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+fn write_uint(buffer: &mut Vec<u8>, value: u64, width: usize, little_endian: bool) {
+    let bytes = value.to_be_bytes();
+    let start = buffer.len();
+    buffer.extend_from_slice(&bytes[8 - width..]);
+    if little_endian {
+        buffer[start..].reverse();
+    }
+}
+
+fn read_uint(bytes: &[u8], offset: &mut usize, width: usize, little_endian: bool) -> Result<u64, PackError> {
+    let field = take(bytes, offset, width)?;
+    let fold = |value: u64, byte: &u8| value << 8 | *byte as u64;
+    Ok(if little_endian { field.iter().rev().fold(0, fold) } else { field.iter().fold(0, fold) })
+}
+
+/// Length of `buffer` after appending `len` bytes, bounded by `MAX_PACKED`
+fn grown(buffer: &[u8], len: usize) -> Result<usize, PackError> {
+    buffer.len().checked_add(len).filter(|&end| end <= MAX_PACKED)
+        .ok_or_else(|| PackError::Overflow(format!("packing more than {} bytes", MAX_PACKED)))
+}
+
+fn take<'a>(bytes: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], PackError> {
+    let end = offset.checked_add(len).ok_or(PackError::Truncated)?;
+    let field = bytes.get(*offset..end).ok_or(PackError::Truncated)?;
+    *offset = end;
+    Ok(field)
+}
+
+fn write_length(buffer: &mut Vec<u8>, len: usize, length: Length) -> Result<(), PackError> {
+    match length {
+        Length::Int { width, little_endian } => {
+            if width < 8 && (len as u64) >> (width * 8) != 0 {
+                return Err(PackError::Overflow(format!("length {} doesn't fit into {} bytes", len, width)));
+            }
+            write_uint(buffer, len as u64, width, little_endian);
+        },
+        Length::Varint => buffer.extend(QUICInteger::new(len as u64)
+            .map_err(|_| PackError::Overflow(format!("length {} exceeds a varint", len)))?
+            .to_binary())
+    }
+    Ok(())
+}
+
+fn next_int<'a>(values: &mut impl Iterator<Item = &'a Packed>) -> Result<i64, PackError> {
+    match values.next() {
+        Some(Packed::Int(value)) => Ok(*value),
+        Some(Packed::Bytes(_)) => Err(PackError::TypeMismatch),
+        None => Err(PackError::MissingValue)
+    }
+}
+
+fn next_bytes<'a>(values: &mut impl Iterator<Item = &'a Packed>) -> Result<&'a [u8], PackError> {
+    match values.next() {
+        Some(Packed::Bytes(bytes)) => Ok(bytes),
+        Some(Packed::Int(_)) => Err(PackError::TypeMismatch),
+        None => Err(PackError::MissingValue)
+    }
+}
+
+/// Packs `values` according to `format`, surplus values are ignored
+pub fn pack(format: &str, values: &[Packed]) -> Result<Vec<u8>, PackError> {
+    let mut buffer = Vec::new();
+    let mut values = values.iter();
+    for item in parse_format(format)? {
+        match item {
+            Item::Int { width, signed, little_endian } => {
+                let value = next_int(&mut values)?;
+                let bits = width as u32 * 8;
+                let fits = width == 8 || if signed {
+                    value >= -(1i64 << (bits - 1)) && value < (1i64 << (bits - 1))
+                } else {
+                    value >= 0 && value < (1i64 << bits)
+                };
+                if !fits {
+                    return Err(PackError::Overflow(format!("{} doesn't fit into {} {} bytes", value, width, if signed { "signed" } else { "unsigned" })));
+                }
+                write_uint(&mut buffer, value as u64, width, little_endian);
+            },
+            Item::Varint => {
+                let value = next_int(&mut values)?;
+                let varint = u64::try_from(value).ok().and_then(|value| QUICInteger::new(value).ok())
+                    .ok_or_else(|| PackError::Overflow(format!("{} is not a valid varint", value)))?;
+                buffer.extend(varint.to_binary());
+            },
+            Item::Fixed(len) => {
+                let bytes = next_bytes(&mut values)?;
+                if bytes.len() > len {
+                    return Err(PackError::Overflow(format!("string of {} bytes longer than 'c{}'", bytes.len(), len)));
+                }
+                // Shorter strings are padded with zeroes
+                let end = grown(&buffer, len)?;
+                buffer.extend_from_slice(bytes);
+                buffer.resize(end, 0);
+            },
+            Item::Prefixed(length) => {
+                let bytes = next_bytes(&mut values)?;
+                write_length(&mut buffer, bytes.len(), length)?;
+                buffer.extend_from_slice(bytes);
+            },
+            Item::ZeroTerminated => {
+                let bytes = next_bytes(&mut values)?;
+                if bytes.contains(&0) {
+                    return Err(PackError::Overflow("string for 'z' contains zeroes".to_string()));
+                }
+                buffer.extend_from_slice(bytes);
+                buffer.push(0);
+            },
+            Item::Padding(len) => buffer.resize(grown(&buffer, len)?, 0),
+            Item::Bits(widths) => {
+                let total: u32 = widths.iter().sum();
+                let mut field: u64 = 0;
+                for width in widths {
+                    let value = next_int(&mut values)? as u64;
+                    if width < 64 && value >> width != 0 {
+                        return Err(PackError::Overflow(format!("{} doesn't fit into {} bits", value as i64, width)));
+                    }
+                    field = if width == 64 { value } else { field << width | value };
+                }
+                write_uint(&mut buffer, field, total as usize / 8, false);
+            }
+        }
+    }
+    Ok(buffer)
+}
+
+/// Unpacks the values described by `format` from `bytes` starting at `offset`.
+/// Returns the values and the offset of the first unread byte.
+pub fn unpack(format: &str, bytes: &[u8], offset: usize) -> Result<(Vec<Packed>, usize), PackError> {
+    let mut values = Vec::new();
+    let mut offset = offset;
+    for item in parse_format(format)? {
+        match item {
+            Item::Int { width, signed, little_endian } => {
+                let value = read_uint(bytes, &mut offset, width, little_endian)?;
+                let bits = width as u32 * 8;
+                // Sign-extend by shifting the sign bit into the MSB and back
+                let value = if signed && bits < 64 { ((value << (64 - bits)) as i64) >> (64 - bits) } else { value as i64 };
+                values.push(Packed::Int(value));
+            },
+            Item::Varint => values.push(Packed::Int(read_varint(bytes, &mut offset)? as i64)),
+            Item::Fixed(len) => values.push(Packed::Bytes(take(bytes, &mut offset, len)?.to_vec())),
+            Item::Prefixed(length) => {
+                let len = match length {
+                    Length::Int { width, little_endian } => read_uint(bytes, &mut offset, width, little_endian)?,
+                    Length::Varint => read_varint(bytes, &mut offset)?
+                };
+                let len = usize::try_from(len).map_err(|_| PackError::Truncated)?;
+                values.push(Packed::Bytes(take(bytes, &mut offset, len)?.to_vec()));
+            },
+            Item::ZeroTerminated => {
+                let len = bytes.get(offset..).and_then(|rest| rest.iter().position(|b| *b == 0)).ok_or(PackError::Truncated)?;
+                values.push(Packed::Bytes(take(bytes, &mut offset, len)?.to_vec()));
+                offset += 1;
+            },
+            Item::Padding(len) => { take(bytes, &mut offset, len)?; },
+            Item::Bits(widths) => {
+                let total: u32 = widths.iter().sum();
+                let field = read_uint(bytes, &mut offset, total as usize / 8, false)?;
+                let mut remaining = total;
+                for width in widths {
+                    remaining -= width;
+                    let mask = if width == 64 { u64::MAX } else { (1 << width) - 1 };
+                    values.push(Packed::Int(((field >> remaining) & mask) as i64));
+                }
+            }
+        }
+    }
+    Ok((values, offset))
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> Result<u64, PackError> {
+    let varint = QUICInteger::try_from(bytes.get(*offset..).ok_or(PackError::Truncated)?).map_err(|_| PackError::Truncated)?;
+    *offset += varint.length as usize;
+    Ok(varint.value)
+}
+
+
+#[test]
+fn test_pack_roundtrip() {
+    use hex_literal::hex;
+    let values = vec![
+        Packed::Int(0x1234),
+        Packed::Int(-2),
+        Packed::Int(0x0102),
+        Packed::Int(1241),
+        Packed::Bytes(b"ab".to_vec()),
+        Packed::Bytes(b"host".to_vec()),
+        Packed::Int(4),
+        Packed::Int(5),
+        Packed::Bytes(b"z".to_vec())
+    ];
+    let packed = pack(">H b <H >v c3 s1 x [4 4] z", &values).unwrap();
+    assert_eq!(packed, hex!("1234 fe 0201 44d9 616200 04686f7374 00 45 7a00"));
+
+    let (unpacked, offset) = unpack(">H b <H >v c3 s1 x [4 4] z", &packed, 0).unwrap();
+    assert_eq!(offset, packed.len());
+    assert_eq!(unpacked[..4], values[..4]);
+    assert_eq!(unpacked[4], Packed::Bytes(b"ab\0".to_vec()));
+    assert_eq!(unpacked[5..], values[5..]);
+}
+
+#[test]
+fn test_pack_errors() {
+    use hex_literal::hex;
+    // DNS header: ID, QR/Opcode/AA/TC/RD, RA/Z/RCODE, counts
+    let (header, offset) = unpack(">H [1 4 1 1 1] [1 3 4] HHHH", &hex!("fc9d 81 80 0001 0001 0000 0001"), 0).unwrap();
+    assert_eq!(offset, 12);
+    assert_eq!(header[..9], [0xfc9d, 1, 0, 0, 0, 1, 1, 0, 0].map(Packed::Int));
+
+    assert_eq!(unpack("I", &hex!("000000"), 0), Err(PackError::Truncated));
+    assert_eq!(unpack("s2", &hex!("ffff00"), 0), Err(PackError::Truncated));
+    assert_eq!(unpack("B", &[], usize::MAX), Err(PackError::Truncated));
+    assert!(matches!(pack("B", &[Packed::Int(256)]), Err(PackError::Overflow(_))));
+    assert!(matches!(pack("b", &[Packed::Int(-129)]), Err(PackError::Overflow(_))));
+    assert!(matches!(pack("[3 5]", &[Packed::Int(8), Packed::Int(0)]), Err(PackError::Overflow(_))));
+    assert_eq!(pack("H", &[]), Err(PackError::MissingValue));
+    assert_eq!(pack("s1", &[Packed::Int(1)]), Err(PackError::TypeMismatch));
+    assert!(matches!(parse_format("[3 4]"), Err(PackError::InvalidFormat(_))));
+    assert!(matches!(parse_format("I9"), Err(PackError::InvalidFormat(_))));
+    assert!(matches!(parse_format("y"), Err(PackError::InvalidFormat(_))));
+    // Counts are bounded, they would allocate before any value is checked
+    assert!(matches!(parse_format("x99999999999999"), Err(PackError::InvalidFormat(_))));
+    assert!(matches!(parse_format("c65536"), Err(PackError::InvalidFormat(_))));
+    assert!(matches!(parse_format("x999999999999999999999999"), Err(PackError::InvalidFormat(_))));
+    assert_eq!(parse_format("c65535 x65535"), Ok(vec![Item::Fixed(65535), Item::Padding(65535)]));
+    assert!(matches!(pack(&"x65535".repeat(17), &[]), Err(PackError::Overflow(_))));
+    assert!(matches!(pack(&format!("{}c65535", "x65535".repeat(16)), &[Packed::Bytes(Vec::new())]), Err(PackError::Overflow(_))));
+    assert!(matches!(parse_format("I99999999999999999999"), Err(PackError::InvalidFormat(_))));
+}
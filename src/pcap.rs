@@ -0,0 +1,139 @@
+//! Reads captures in the classic libpcap format, as written by `tcpdump -w` and Wireshark's
+//! "Wireshark/tcpdump - pcap". pcapng isn't supported, `editcap -F pcap` converts it.
+//! https://wiki.wireshark.org/Development/LibpcapFileFormat
+
+use std::{
+    io::{self, Read},
+    path::Path,
+    time::Duration
+};
+
+/// Link type of Ethernet captures
+pub const LINKTYPE_ETHERNET: u32 = 1;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const MAGIC_PCAPNG: u32 = 0x0a0d0d0a;
+/// Records larger than this are rejected as corrupt, tcpdump's own upper bound for snaplen
+const MAX_RECORD: usize = 256 * 1024;
+
+/// A captured frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Capture time since the Unix epoch
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+    /// Length of the frame on the wire, more than `data.len()` if it was truncated by the snaplen
+    pub original_len: usize
+}
+
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: u32
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+impl PcapReader<io::BufReader<std::fs::File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        PcapReader::new(io::BufReader::new(std::fs::File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the global header
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header).map_err(|_| invalid("not a pcap file, the header is truncated"))?;
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (big_endian, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            MAGIC_PCAPNG => return Err(invalid("pcapng isn't supported, convert the capture with `editcap -F pcap`")),
+            _ => return Err(invalid("not a pcap file"))
+        };
+        let mut pcap = PcapReader { reader, big_endian, nanos, link_type: 0 };
+        pcap.link_type = pcap.u32(&header[20..24]);
+        Ok(pcap)
+    }
+
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// Reads the next record, `None` at the end of the capture
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 16];
+        // A capture cut off in the middle of a record header ends like a complete one
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e)
+        }
+        let seconds = self.u32(&header[0..4]) as u64;
+        let fraction = self.u32(&header[4..8]);
+        let included = self.u32(&header[8..12]) as usize;
+        let original_len = self.u32(&header[12..16]) as usize;
+        if included > MAX_RECORD {
+            return Err(invalid(format!("record of {} bytes, the capture is corrupt", included)));
+        }
+        let mut data = vec![0u8; included];
+        self.reader.read_exact(&mut data).map_err(|_| invalid("the last record is truncated"))?;
+        let timestamp = Duration::from_secs(seconds) + match self.nanos {
+            true => Duration::from_nanos(fraction as u64),
+            false => Duration::from_micros(fraction as u64)
+        };
+        Ok(Some(Record { timestamp, data, original_len }))
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Reads all records of an Ethernet capture
+pub fn read_ethernet(path: &Path) -> io::Result<Vec<Record>> {
+    let reader = PcapReader::open(path)?;
+    if reader.link_type() != LINKTYPE_ETHERNET {
+        return Err(invalid(format!("link type {} isn't supported, only Ethernet captures are", reader.link_type())));
+    }
+    reader.collect()
+}
+
+
+#[test]
+fn test_pcap_reader() {
+    use hex_literal::hex;
+    // Big-endian, microsecond capture with one 14 byte frame, followed by a truncated record header
+    let capture = hex!("a1b2c3d4 0002 0004 00000000 00000000 0000ffff 00000001
+        5f5e1000 000186a0 0000000e 0000003c 020000000002 020000000001 0800
+        5f5e10");
+    let mut reader = PcapReader::new(&capture[..]).unwrap();
+    assert_eq!(reader.link_type(), LINKTYPE_ETHERNET);
+    assert_eq!(reader.next_record().unwrap(), Some(Record {
+        timestamp: Duration::from_secs(1600000000) + Duration::from_millis(100),
+        data: hex!("020000000002 020000000001 0800").to_vec(),
+        original_len: 60
+    }));
+    assert_eq!(reader.next_record().unwrap(), None);
+
+    assert!(PcapReader::new(&hex!("0a0d0d0a 00000000 00000000 00000000 00000000 00000000")[..]).is_err());
+}
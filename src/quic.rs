@@ -1,209 +1,773 @@
-use pnet_macros::packet;
 use pnet_macros_support::types::*;
+use aes_gcm::{Aes128Gcm, Nonce, KeyInit, aead::{Aead, Payload}, aes::cipher::BlockEncrypt};
+use hkdf::Hkdf;
+use sha2::Sha256;
 
-pub trait FromBinary
-    where Self: Sized {
-    type Error;
-    fn from_binary(bytes: &[u8]) -> Result<Self, Self::Error>;
-}
-// Waiting for generic_const_exprs to be stable, once it is this code can be improved by allocating
-// an array instead of a vector and resizing it
-impl<T: Copy> FromBinary for T {
-    type Error = ();
-    fn from_binary(bytes: &[u8]) -> Result<T, ()> {
-        if bytes.len() != std::mem::size_of::<T>() {
-            return Err(());
+use crate::tls::Reader;
+
+/// Errors raised while parsing or decrypting QUIC packets.
+/// Everything arriving on the wire is untrusted, malformed input never panics.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QUICError {
+    /// The input ended before the structure was complete
+    Truncated,
+    /// The fixed bit, which has to be set on every packet but Version Negotiation, is unset
+    InvalidFixedBit,
+    /// Connection IDs are limited to 20 bytes in QUIC v1 and v2
+    InvalidConnectionId,
+    /// A value doesn't fit into a variable-length integer (2^62 - 1 at most)
+    IntegerOverflow,
+    UnknownFrame(u64),
+    /// No Initial salt is known for the version, its packets can't be decrypted
+    UnsupportedVersion(u32),
+    /// The packet can't be decrypted with the given keys
+    Decryption
+}
+
+impl std::fmt::Display for QUICError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QUICError::Truncated => write!(f, "truncated QUIC packet"),
+            QUICError::InvalidFixedBit => write!(f, "QUIC fixed bit is not set"),
+            QUICError::InvalidConnectionId => write!(f, "QUIC connection ID longer than 20 bytes"),
+            QUICError::IntegerOverflow => write!(f, "value exceeds the range of a QUIC variable-length integer"),
+            QUICError::UnknownFrame(frame_type) => write!(f, "unknown QUIC frame type 0x{:x}", frame_type),
+            QUICError::UnsupportedVersion(version) => write!(f, "unsupported QUIC version 0x{:08x}", version),
+            QUICError::Decryption => write!(f, "QUIC packet could not be decrypted")
         }
-        let mut buf = Vec::with_capacity(std::mem::size_of::<T>());
-        buf.extend_from_slice(bytes);
-        Ok(unsafe { *(buf.as_ptr() as *const T) })
     }
 }
 
+impl std::error::Error for QUICError {}
 
-#[packet]
-pub struct QUIC {
-    pub header_form: u1,
-    pub fixed_bit: u1,
-    pub packet_type: u2,
-    pub reserved: u2,
-    pub packet_num_len: u2,
+fn take<'a>(reader: &mut Reader<'a>, len: usize) -> Result<&'a [u8], QUICError> {
+    reader.bytes(len).ok_or(QUICError::Truncated)
+}
 
-    pub version: u32be,
-    pub dst_connection_id_len: u8,
-    #[length = "dst_connection_id_len"]
-    pub dst_connection_id: Vec<u8>,
+/// Consumes everything up to the end of the input
+fn take_rest<'a>(reader: &mut Reader<'a>) -> &'a [u8] {
+    let rest = reader.rest();
+    reader.bytes(rest.len());
+    rest
+}
 
-    pub src_connection_id_len: u8,
-    #[length = "src_connection_id_len"]
-    pub src_connection_id: Vec<u8>,
-    #[payload]
-    pub payload: Vec<u8>
+/// Reads a variable-length integer
+fn varint(reader: &mut Reader) -> Result<u64, QUICError> {
+    let int = QUICInteger::try_from(reader.rest())?;
+    take(reader, int.length as usize)?;
+    Ok(int.value)
+}
+
+/// Reads data prefixed by a variable-length integer length
+fn varint_bytes<'a>(reader: &mut Reader<'a>) -> Result<&'a [u8], QUICError> {
+    let len = varint(reader)?;
+    take(reader, usize::try_from(len).map_err(|_| QUICError::Truncated)?)
+}
+
+fn connection_id(reader: &mut Reader) -> Result<Vec<u8>, QUICError> {
+    let len = take(reader, 1)?[0] as usize;
+    if len > 20 {
+        return Err(QUICError::InvalidConnectionId);
+    }
+    Ok(take(reader, len)?.to_vec())
+}
+
+pub const QUIC_V1: u32 = 0x0000_0001;
+pub const QUIC_V2: u32 = 0x6b33_43cf;
+
+pub const fn get_quic_salt(version: u32be) -> Option<[u8; 20]> {
+    match version {
+        // VERSION 1
+        QUIC_V1 => Some([ 0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a ]),
+        // VERSION 2 (RFC 9369)
+        QUIC_V2 => Some([ 0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9 ]),
+        // DRAFT 29
+        0xff00001d => Some([ 0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61, 0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99 ]),
+        // DRAFT 27 & 28
+        0xff00001b
+        | 0xff00_001c => Some([ 0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4, 0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02 ]),
+        _ => None
+    }
+}
+
+/// Whether the long header packet type bits denote an Initial packet.
+/// QUIC v2 shuffled the packet type numbers around (RFC 9369 section 3.2).
+pub const fn is_initial(first_byte: u8, version: u32) -> bool {
+    matches!(PacketType::from_long_header(first_byte, version), PacketType::Initial)
+}
+
+// https://www.rfc-editor.org/rfc/rfc9000#section-17
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+    VersionNegotiation,
+    /// Packets with a short header, carrying application data
+    OneRtt
 }
 
+impl PacketType {
+    pub const fn from_long_header(first_byte: u8, version: u32) -> Self {
+        if version == 0 {
+            return PacketType::VersionNegotiation;
+        }
+        match (version, (first_byte >> 4) & 0x03) {
+            (QUIC_V2, 0b00) => PacketType::Retry,
+            (QUIC_V2, 0b01) => PacketType::Initial,
+            (QUIC_V2, 0b10) => PacketType::ZeroRtt,
+            (QUIC_V2, _) => PacketType::Handshake,
+            (_, 0b00) => PacketType::Initial,
+            (_, 0b01) => PacketType::ZeroRtt,
+            (_, 0b10) => PacketType::Handshake,
+            (_, _) => PacketType::Retry
+        }
+    }
+    pub const fn name(&self) -> &'static str {
+        match self {
+            PacketType::Initial => "initial",
+            PacketType::ZeroRtt => "0rtt",
+            PacketType::Handshake => "handshake",
+            PacketType::Retry => "retry",
+            PacketType::VersionNegotiation => "version_negotiation",
+            PacketType::OneRtt => "1rtt"
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct Initial {
-    pub token_len: QUICInteger,
+pub struct LongHeader {
+    pub first_byte: u8,
+    pub packet_type: PacketType,
+    pub version: u32,
+    pub dst_connection_id: Vec<u8>,
+    pub src_connection_id: Vec<u8>,
+    /// Address validation token of Initial packets, or the token of a Retry packet
     pub token: Vec<u8>,
-    pub length: QUICInteger,
-    pub packet_num: QUICInteger,
-    pub payload: Vec<u8>
-}
-impl FromBinary for Initial {
-    type Error = ();
-    fn from_binary(bytes: &[u8]) -> Result<Self, Self::Error> {
-        if bytes.len() < 2 {
-            return Err(());
+    /// Length of the packet number and the payload, absent for Retry and Version Negotiation
+    pub length: Option<u64>,
+    /// Versions offered by a Version Negotiation packet
+    pub supported_versions: Vec<u32>,
+    pub retry_integrity_tag: Option<[u8; 16]>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShortHeader {
+    pub first_byte: u8,
+    pub spin_bit: bool,
+    /// The length isn't encoded in short headers, it has to be known from the connection
+    pub dst_connection_id: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Header {
+    Long(LongHeader),
+    Short(ShortHeader)
+}
+
+/// A single QUIC packet, datagrams may contain multiple coalesced packets
+#[derive(Debug, Clone, PartialEq)]
+pub struct QUICPacket {
+    pub header: Header,
+    /// The raw bytes of the packet, header included
+    pub bytes: Vec<u8>,
+    /// Offset of the (protected) packet number, for Retry and Version Negotiation the offset of
+    /// the end of the header
+    pub pn_offset: usize
+}
+
+impl QUICPacket {
+    /// Parses the first packet of `bytes` and returns it along with the number of bytes it spans.
+    /// `short_dcid_len` is the connection ID length used for short header packets.
+    pub fn parse(bytes: &[u8], short_dcid_len: usize) -> Result<(QUICPacket, usize), QUICError> {
+        let mut reader = Reader::new(bytes);
+        let first_byte = take(&mut reader, 1)?[0];
+
+        if first_byte & 0x80 == 0 {
+            // Short header, the packet extends to the end of the datagram
+            if first_byte & 0x40 == 0 {
+                return Err(QUICError::InvalidFixedBit);
+            }
+            let dst_connection_id = take(&mut reader, short_dcid_len)?.to_vec();
+            let packet = QUICPacket {
+                header: Header::Short(ShortHeader {
+                    first_byte,
+                    spin_bit: first_byte & 0x20 != 0,
+                    dst_connection_id
+                }),
+                bytes: bytes.to_vec(),
+                pn_offset: reader.position()
+            };
+            return Ok((packet, bytes.len()));
         }
-        let mut addr = 0;
-        let token_len = QUICInteger::try_from(&bytes[0..std::cmp::min(bytes.len(), 4)])?;
-        addr += token_len.length as usize;
 
-        let token = &bytes[ token_len.length as usize .. std::cmp::max(token_len.value as usize, token_len.length as usize )];
-        addr += token.len();
+        let version = u32::from_be_bytes(take(&mut reader, 4)?.try_into().map_err(|_| QUICError::Truncated)?);
+        let packet_type = PacketType::from_long_header(first_byte, version);
+        if packet_type != PacketType::VersionNegotiation && first_byte & 0x40 == 0 {
+            return Err(QUICError::InvalidFixedBit);
+        }
+        let dst_connection_id = connection_id(&mut reader)?;
+        let src_connection_id = connection_id(&mut reader)?;
+        let mut header = LongHeader {
+            first_byte,
+            packet_type,
+            version,
+            dst_connection_id,
+            src_connection_id,
+            token: Vec::new(),
+            length: None,
+            supported_versions: Vec::new(),
+            retry_integrity_tag: None
+        };
 
-        let length = QUICInteger::try_from(&bytes[addr..std::cmp::min(bytes.len(), addr + 4)])?;
-        addr += length.length as usize;
+        let end = match packet_type {
+            PacketType::VersionNegotiation => {
+                while !reader.is_empty() {
+                    let version = take(&mut reader, 4)?;
+                    header.supported_versions.push(u32::from_be_bytes([version[0], version[1], version[2], version[3]]));
+                }
+                bytes.len()
+            },
+            PacketType::Retry => {
+                let token_len = reader.rest().len().checked_sub(16).ok_or(QUICError::Truncated)?;
+                header.token = take(&mut reader, token_len)?.to_vec();
+                let mut tag = [0u8; 16];
+                tag.copy_from_slice(take(&mut reader, 16)?);
+                header.retry_integrity_tag = Some(tag);
+                bytes.len()
+            },
+            _ => {
+                if packet_type == PacketType::Initial {
+                    header.token = varint_bytes(&mut reader)?.to_vec();
+                }
+                let length = varint(&mut reader)?;
+                header.length = Some(length);
+                let length = usize::try_from(length).map_err(|_| QUICError::Truncated)?;
+                let end = reader.position().checked_add(length).ok_or(QUICError::Truncated)?;
+                if end > bytes.len() {
+                    return Err(QUICError::Truncated);
+                }
+                end
+            }
+        };
+        let packet = QUICPacket {
+            header: Header::Long(header),
+            bytes: bytes[..end].to_vec(),
+            pn_offset: reader.position()
+        };
+        Ok((packet, end))
+    }
 
-        let packet_num = QUICInteger::try_from(&bytes[addr..std::cmp::min(bytes.len(), addr + 4)])?;
-        addr += packet_num.length as usize;
-        let payload = &bytes[addr..];
+    pub fn packet_type(&self) -> PacketType {
+        match &self.header {
+            Header::Long(header) => header.packet_type,
+            Header::Short(_) => PacketType::OneRtt
+        }
+    }
+    /// The version of long header packets, short headers don't carry one
+    pub fn version(&self) -> Option<u32> {
+        match &self.header {
+            Header::Long(header) => Some(header.version),
+            Header::Short(_) => None
+        }
+    }
+    pub fn dst_connection_id(&self) -> &[u8] {
+        match &self.header {
+            Header::Long(header) => &header.dst_connection_id,
+            Header::Short(header) => &header.dst_connection_id
+        }
+    }
+    pub fn src_connection_id(&self) -> Option<&[u8]> {
+        match &self.header {
+            Header::Long(header) => Some(&header.src_connection_id),
+            Header::Short(_) => None
+        }
+    }
 
-        Ok(Self {
-            token_len,
-            token: token.to_vec(),
-            length,
-            packet_num,
-            payload: payload.to_vec()
-        })
+    /// Decrypts an Initial packet with the keys derived from `initial_dcid`, the Destination
+    /// Connection ID of the first packet the client sent. For client packets that is the
+    /// packet's own Destination Connection ID.
+    pub fn decrypt_initial(&self, initial_dcid: &[u8], client: bool) -> Result<(u64, Vec<u8>), QUICError> {
+        match &self.header {
+            Header::Long(header) if header.packet_type == PacketType::Initial => {
+                let keys = InitialKeys::derive(header.version, initial_dcid, client)?;
+                keys.decrypt(&self.bytes, self.pn_offset, self.bytes.len())
+            },
+            _ => Err(QUICError::Decryption)
+        }
     }
 }
 
-pub const fn get_quic_salt(version: u32be) -> [u8; 20]{
-    match version {
-        // VERSION 1
-        0x1 => [ 0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a ],
-        // DRAFT 29
-        0xff00001d => [ 0xaf, 0xbf, 0xec, 0x28, 0x99, 0x93, 0xd2, 0x4c, 0x9e, 0x97, 0x86, 0xf1, 0x9c, 0x61, 0x11, 0xe0, 0x43, 0x90, 0xa8, 0x99 ],
-        // DRAFT 27 & 28
-        0xff00001b
-        | 0xff00_001c => [ 0xc3, 0xee, 0xf7, 0x12, 0xc7, 0x2e, 0xbb, 0x5a, 0x11, 0xa7, 0xd2, 0x43, 0x2b, 0xb4, 0x63, 0x65, 0xbe, 0xf9, 0xf5, 0x02 ],
-        _ => unimplemented!()
+/// Splits a UDP datagram into its coalesced QUIC packets.
+/// Short header packets use the Destination Connection ID length of a preceding long header
+/// packet, or `short_dcid_len` if the datagram starts with a short header.
+/// Only a malformed first packet is an error, trailing garbage after valid packets is ignored.
+pub fn parse_datagram(datagram: &[u8], short_dcid_len: usize) -> Result<Vec<QUICPacket>, QUICError> {
+    let mut packets: Vec<QUICPacket> = Vec::new();
+    let mut offset = 0;
+    let mut dcid_len = short_dcid_len;
+    while offset < datagram.len() {
+        match QUICPacket::parse(&datagram[offset..], dcid_len) {
+            Ok((packet, consumed)) => {
+                if let Header::Long(header) = &packet.header {
+                    dcid_len = header.dst_connection_id.len();
+                }
+                packets.push(packet);
+                offset += consumed;
+            },
+            Err(e) if packets.is_empty() => return Err(e),
+            Err(_) => break
+        }
+    }
+    Ok(packets)
+}
+
+/// Removes the protection of the first client Initial packet in a datagram.
+/// Returns the Destination Connection ID the keys were derived from and the decrypted payload.
+pub fn decrypt_client_initial(datagram: &[u8]) -> Result<(Vec<u8>, Vec<u8>), QUICError> {
+    let packets = parse_datagram(datagram, 0)?;
+    let initial = packets.iter()
+        .find(|p| p.packet_type() == PacketType::Initial)
+        .ok_or(QUICError::Decryption)?;
+    let dcid = initial.dst_connection_id().to_vec();
+    let (_, payload) = initial.decrypt_initial(&dcid, true)?;
+    Ok((dcid, payload))
+}
+
+// https://www.rfc-editor.org/rfc/rfc9000#section-19
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    /// One or more consecutive PADDING frames
+    Padding(usize),
+    Ping,
+    Ack {
+        largest: u64,
+        delay: u64,
+        first_range: u64,
+        /// `(gap, length)` of every further ACK range
+        ranges: Vec<(u64, u64)>,
+        /// ECT(0), ECT(1) and ECN-CE counts
+        ecn: Option<(u64, u64, u64)>
+    },
+    ResetStream { stream_id: u64, error_code: u64, final_size: u64 },
+    StopSending { stream_id: u64, error_code: u64 },
+    Crypto { offset: u64, data: Vec<u8> },
+    NewToken { token: Vec<u8> },
+    Stream { stream_id: u64, offset: u64, fin: bool, data: Vec<u8> },
+    MaxData(u64),
+    MaxStreamData { stream_id: u64, maximum: u64 },
+    MaxStreams { bidirectional: bool, maximum: u64 },
+    DataBlocked(u64),
+    StreamDataBlocked { stream_id: u64, limit: u64 },
+    StreamsBlocked { bidirectional: bool, limit: u64 },
+    NewConnectionId { sequence: u64, retire_prior_to: u64, connection_id: Vec<u8>, reset_token: [u8; 16] },
+    RetireConnectionId(u64),
+    PathChallenge([u8; 8]),
+    PathResponse([u8; 8]),
+    /// `frame_type` is only set for transport errors (type 0x1c), application closes don't carry it
+    ConnectionClose { error_code: u64, frame_type: Option<u64>, reason: Vec<u8> },
+    HandshakeDone,
+    /// Unreliable datagram extension (RFC 9221)
+    Datagram { data: Vec<u8> }
+}
+
+impl Frame {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Frame::Padding(_) => "padding",
+            Frame::Ping => "ping",
+            Frame::Ack { .. } => "ack",
+            Frame::ResetStream { .. } => "reset_stream",
+            Frame::StopSending { .. } => "stop_sending",
+            Frame::Crypto { .. } => "crypto",
+            Frame::NewToken { .. } => "new_token",
+            Frame::Stream { .. } => "stream",
+            Frame::MaxData(_) => "max_data",
+            Frame::MaxStreamData { .. } => "max_stream_data",
+            Frame::MaxStreams { .. } => "max_streams",
+            Frame::DataBlocked(_) => "data_blocked",
+            Frame::StreamDataBlocked { .. } => "stream_data_blocked",
+            Frame::StreamsBlocked { .. } => "streams_blocked",
+            Frame::NewConnectionId { .. } => "new_connection_id",
+            Frame::RetireConnectionId(_) => "retire_connection_id",
+            Frame::PathChallenge(_) => "path_challenge",
+            Frame::PathResponse(_) => "path_response",
+            Frame::ConnectionClose { .. } => "connection_close",
+            Frame::HandshakeDone => "handshake_done",
+            Frame::Datagram { .. } => "datagram"
+        }
+    }
+}
+
+/// Parses all frames of a decrypted packet payload
+pub fn parse_frames(payload: &[u8]) -> Result<Vec<Frame>, QUICError> {
+    let mut reader = Reader::new(payload);
+    let mut frames = Vec::new();
+    while !reader.is_empty() {
+        let frame_type = varint(&mut reader)?;
+        let frame = match frame_type {
+            0x00 => {
+                let mut count = 1;
+                while reader.rest().first() == Some(&0) {
+                    take(&mut reader, 1)?;
+                    count += 1;
+                }
+                Frame::Padding(count)
+            },
+            0x01 => Frame::Ping,
+            0x02 | 0x03 => {
+                let largest = varint(&mut reader)?;
+                let delay = varint(&mut reader)?;
+                let range_count = varint(&mut reader)?;
+                let first_range = varint(&mut reader)?;
+                let mut ranges = Vec::new();
+                for _ in 0..range_count {
+                    ranges.push((varint(&mut reader)?, varint(&mut reader)?));
+                }
+                let ecn = if frame_type == 0x03 {
+                    Some((varint(&mut reader)?, varint(&mut reader)?, varint(&mut reader)?))
+                } else {
+                    None
+                };
+                Frame::Ack { largest, delay, first_range, ranges, ecn }
+            },
+            0x04 => Frame::ResetStream {
+                stream_id: varint(&mut reader)?,
+                error_code: varint(&mut reader)?,
+                final_size: varint(&mut reader)?
+            },
+            0x05 => Frame::StopSending {
+                stream_id: varint(&mut reader)?,
+                error_code: varint(&mut reader)?
+            },
+            0x06 => Frame::Crypto {
+                offset: varint(&mut reader)?,
+                data: varint_bytes(&mut reader)?.to_vec()
+            },
+            0x07 => Frame::NewToken { token: varint_bytes(&mut reader)?.to_vec() },
+            // The lower three bits are the OFF, LEN and FIN flags
+            0x08..=0x0f => {
+                let stream_id = varint(&mut reader)?;
+                let offset = if frame_type & 0x04 != 0 { varint(&mut reader)? } else { 0 };
+                let data = if frame_type & 0x02 != 0 {
+                    varint_bytes(&mut reader)?
+                } else {
+                    take_rest(&mut reader)
+                };
+                Frame::Stream { stream_id, offset, fin: frame_type & 0x01 != 0, data: data.to_vec() }
+            },
+            0x10 => Frame::MaxData(varint(&mut reader)?),
+            0x11 => Frame::MaxStreamData {
+                stream_id: varint(&mut reader)?,
+                maximum: varint(&mut reader)?
+            },
+            0x12 | 0x13 => Frame::MaxStreams { bidirectional: frame_type == 0x12, maximum: varint(&mut reader)? },
+            0x14 => Frame::DataBlocked(varint(&mut reader)?),
+            0x15 => Frame::StreamDataBlocked {
+                stream_id: varint(&mut reader)?,
+                limit: varint(&mut reader)?
+            },
+            0x16 | 0x17 => Frame::StreamsBlocked { bidirectional: frame_type == 0x16, limit: varint(&mut reader)? },
+            0x18 => {
+                let sequence = varint(&mut reader)?;
+                let retire_prior_to = varint(&mut reader)?;
+                let connection_id = connection_id(&mut reader)?;
+                let mut reset_token = [0u8; 16];
+                reset_token.copy_from_slice(take(&mut reader, 16)?);
+                Frame::NewConnectionId { sequence, retire_prior_to, connection_id, reset_token }
+            },
+            0x19 => Frame::RetireConnectionId(varint(&mut reader)?),
+            0x1a | 0x1b => {
+                let mut data = [0u8; 8];
+                data.copy_from_slice(take(&mut reader, 8)?);
+                if frame_type == 0x1a { Frame::PathChallenge(data) } else { Frame::PathResponse(data) }
+            },
+            0x1c | 0x1d => {
+                let error_code = varint(&mut reader)?;
+                let frame_type = if frame_type == 0x1c { Some(varint(&mut reader)?) } else { None };
+                let reason = varint_bytes(&mut reader)?.to_vec();
+                Frame::ConnectionClose { error_code, frame_type, reason }
+            },
+            0x1e => Frame::HandshakeDone,
+            0x30 => Frame::Datagram { data: take_rest(&mut reader).to_vec() },
+            0x31 => Frame::Datagram { data: varint_bytes(&mut reader)?.to_vec() },
+            _ => return Err(QUICError::UnknownFrame(frame_type))
+        };
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// Extracts `(offset, data)` of all CRYPTO frames in a decrypted payload
+pub fn crypto_frames(payload: &[u8]) -> Result<Vec<(u64, Vec<u8>)>, QUICError> {
+    Ok(parse_frames(payload)?.into_iter().filter_map(|frame| match frame {
+        Frame::Crypto { offset, data } => Some((offset, data)),
+        _ => None
+    }).collect())
+}
+
+/// Packet protection keys for one direction of the Initial packet number space
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitialKeys {
+    pub key: [u8; 16],
+    pub iv: [u8; 12],
+    pub hp: [u8; 16]
+}
+
+/// HKDF-Expand-Label as defined in RFC 8446 section 7.1, with an empty context
+fn hkdf_expand_label(secret: &[u8], label: &str, out: &mut [u8]) -> Result<(), QUICError> {
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).map_err(|_| QUICError::Decryption)?;
+    let label = format!("tls13 {}", label);
+    let mut info = Vec::with_capacity(4 + label.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+    hkdf.expand(&info, out).map_err(|_| QUICError::Decryption)
+}
+
+impl InitialKeys {
+    /// Derives the Initial keys from the Destination Connection ID of the client's first packet.
+    /// https://www.rfc-editor.org/rfc/rfc9001#section-5.2
+    pub fn derive(version: u32, dst_connection_id: &[u8], client: bool) -> Result<Self, QUICError> {
+        let salt = get_quic_salt(version).ok_or(QUICError::UnsupportedVersion(version))?;
+        let (initial_secret, _) = Hkdf::<Sha256>::extract(Some(&salt), dst_connection_id);
+
+        let mut secret = [0u8; 32];
+        hkdf_expand_label(&initial_secret, if client { "client in" } else { "server in" }, &mut secret)?;
+
+        let (key_label, iv_label, hp_label) = match version {
+            QUIC_V2 => ("quicv2 key", "quicv2 iv", "quicv2 hp"),
+            _ => ("quic key", "quic iv", "quic hp")
+        };
+        let mut keys = InitialKeys { key: [0; 16], iv: [0; 12], hp: [0; 16] };
+        hkdf_expand_label(&secret, key_label, &mut keys.key)?;
+        hkdf_expand_label(&secret, iv_label, &mut keys.iv)?;
+        hkdf_expand_label(&secret, hp_label, &mut keys.hp)?;
+        Ok(keys)
+    }
+
+    /// Removes header protection and decrypts a long header packet.
+    /// `pn_offset` is the offset of the packet number, `end` the end of the packet as announced by
+    /// its Length field. Returns the packet number and the plaintext payload.
+    /// https://www.rfc-editor.org/rfc/rfc9001#section-5.4
+    pub fn decrypt(&self, packet: &[u8], pn_offset: usize, end: usize) -> Result<(u64, Vec<u8>), QUICError> {
+        if end > packet.len() || pn_offset + 4 + 16 > end {
+            return Err(QUICError::Truncated);
+        }
+        let mut header = packet[..pn_offset + 4].to_vec();
+
+        let mut mask = aes_gcm::aes::Block::clone_from_slice(&packet[pn_offset + 4..pn_offset + 4 + 16]);
+        aes_gcm::aes::Aes128::new(&self.hp.into()).encrypt_block(&mut mask);
+
+        // Long headers protect the lower four bits of the first byte
+        header[0] ^= mask[0] & 0x0f;
+        let pn_len = (header[0] & 0x03) as usize + 1;
+        let mut packet_number: u64 = 0;
+        for i in 0..pn_len {
+            header[pn_offset + i] ^= mask[1 + i];
+            packet_number = (packet_number << 8) | header[pn_offset + i] as u64;
+        }
+        header.truncate(pn_offset + pn_len);
+
+        let mut nonce = self.iv;
+        for (i, byte) in packet_number.to_be_bytes().iter().enumerate() {
+            nonce[4 + i] ^= byte;
+        }
+        let cipher = Aes128Gcm::new(&self.key.into());
+        let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), Payload {
+            msg: &packet[pn_offset + pn_len..end],
+            aad: &header
+        }).map_err(|_| QUICError::Decryption)?;
+        Ok((packet_number, plaintext))
     }
 }
 
+struct CryptoStream {
+    fragments: std::collections::BTreeMap<u64, Vec<u8>>,
+    /// The handshake message once complete, kept around so retransmissions and repeated
+    /// lookups of the same packet yield it again
+    complete: Option<Vec<u8>>,
+    updated: std::time::Instant
+}
+
+/// Reassembles the CRYPTO stream of client Initial packets, the ClientHello of a single connection
+/// may be split across multiple packets (f.e. due to large post-quantum key shares).
+/// Connections are identified by the Destination Connection ID chosen by the client.
+pub struct CryptoReassembler {
+    streams: std::collections::HashMap<Vec<u8>, CryptoStream>,
+    timeout: std::time::Duration,
+    max_streams: usize,
+    /// CRYPTO data beyond this offset is dropped, a ClientHello fits with room to spare
+    max_length: u64,
+    max_fragments: usize
+}
+
+impl Default for CryptoReassembler {
+    fn default() -> Self {
+        CryptoReassembler {
+            streams: Default::default(),
+            timeout: std::time::Duration::from_secs(10),
+            max_streams: 1024,
+            max_length: 64 * 1024,
+            max_fragments: 64
+        }
+    }
+}
+
+impl CryptoReassembler {
+    /// Adds CRYPTO frames of a connection and returns the first handshake message of the stream
+    /// (type and length header included) once it is complete.
+    pub fn feed(&mut self, dst_connection_id: &[u8], frames: Vec<(u64, Vec<u8>)>) -> Option<Vec<u8>> {
+        let now = std::time::Instant::now();
+        let timeout = self.timeout;
+        self.streams.retain(|_, stream| now.duration_since(stream.updated) < timeout);
+        if !self.streams.contains_key(dst_connection_id) && self.streams.len() >= self.max_streams {
+            return None;
+        }
+
+        let stream = self.streams.entry(dst_connection_id.to_vec()).or_insert_with(|| CryptoStream {
+            fragments: Default::default(),
+            complete: None,
+            updated: now
+        });
+        stream.updated = now;
+        if stream.complete.is_some() {
+            return stream.complete.clone();
+        }
+        for (offset, data) in frames {
+            if offset.saturating_add(data.len() as u64) > self.max_length {
+                continue;
+            }
+            if stream.fragments.len() >= self.max_fragments && !stream.fragments.contains_key(&offset) {
+                break;
+            }
+            stream.fragments.insert(offset, data);
+        }
 
-// https://tools.ietf.org/html/draft-ietf-quic-transport-16#section-4.1
+        // Stitch together everything that is contiguous from offset 0
+        let mut contiguous: Vec<u8> = Vec::new();
+        for (offset, data) in stream.fragments.iter() {
+            let offset = *offset as usize;
+            if offset > contiguous.len() {
+                break;
+            }
+            if offset + data.len() > contiguous.len() {
+                contiguous.extend_from_slice(&data[contiguous.len() - offset..]);
+            }
+        }
+        if contiguous.len() < 4 {
+            return None;
+        }
+        let length = u32::from_be_bytes([0, contiguous[1], contiguous[2], contiguous[3]]) as usize;
+        if contiguous.len() < 4 + length {
+            return None;
+        }
+        contiguous.truncate(4 + length);
+        stream.fragments.clear();
+        stream.complete = Some(contiguous);
+        stream.complete.clone()
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc9000#section-16
 #[derive(Debug, PartialEq, Clone)]
 pub struct QUICInteger {
     pub length: u8,
     pub value: u64be
 }
 impl QUICInteger {
+    pub const MAX: u64 = (1 << 62) - 1;
+
     pub const fn max_length() -> u8 {
         8
     }
+    /// Encodes `value` with the shortest possible length
+    pub const fn new(value: u64) -> Result<Self, QUICError> {
+        let length = match value {
+            0..=0x3f => 1,
+            0x40..=0x3fff => 2,
+            0x4000..=0x3fff_ffff => 4,
+            0x4000_0000..=Self::MAX => 8,
+            _ => return Err(QUICError::IntegerOverflow)
+        };
+        Ok(Self { length, value })
+    }
+    /// Encodes `value` with a fixed length of 1, 2, 4 or 8 bytes
+    pub const fn with_length(value: u64, length: u8) -> Result<Self, QUICError> {
+        let max = match length {
+            1 => 0x3f,
+            2 => 0x3fff,
+            4 => 0x3fff_ffff,
+            8 => Self::MAX,
+            _ => return Err(QUICError::IntegerOverflow)
+        };
+        if value > max {
+            return Err(QUICError::IntegerOverflow);
+        }
+        Ok(Self { length, value })
+    }
 }
 // Implement conversion traits and function to easily convert between QUICInteger and u8-u64
 impl From<u8> for QUICInteger {
     fn from(value: u8) -> Self {
-        Self {
-            length: 1,
-            value: value.into()
-        }
+        Self::new(value as u64).expect("u8 fits into a QUIC integer")
     }
 }
 impl From<u16> for QUICInteger {
     fn from(value: u16) -> Self {
-        Self {
-            length: 2,
-            value: value.into()
-        }
+        Self::new(value as u64).expect("u16 fits into a QUIC integer")
     }
 }
 impl From<u32> for QUICInteger {
     fn from(value: u32) -> Self {
-        Self {
-            length: 4,
-            value: value.into()
-        }
+        Self::new(value as u64).expect("u32 fits into a QUIC integer")
     }
 }
-impl From<u64> for QUICInteger {
-    fn from(value: u64) -> Self {
-        Self {
-            length: 8,
-            value
-        }
+impl TryFrom<u64> for QUICInteger {
+    type Error = QUICError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        Self::new(value)
     }
 }
 impl TryFrom<QUICInteger> for u8 {
-    type Error = ();
+    type Error = QUICError;
 
     fn try_from(value: QUICInteger) -> Result<Self, Self::Error> {
-        if value.length != 1 {
-            Err(())
-        } else {
-            Ok(value.value.try_into().unwrap())
-        }
+        value.value.try_into().map_err(|_| QUICError::IntegerOverflow)
     }
 }
 impl TryFrom<QUICInteger> for u16 {
-    type Error = ();
+    type Error = QUICError;
 
     fn try_from(value: QUICInteger) -> Result<Self, Self::Error> {
-        if value.length > 2 {
-            Err(())
-        } else {
-            Ok(value.value.try_into().unwrap())
-        }
+        value.value.try_into().map_err(|_| QUICError::IntegerOverflow)
     }
 }
 impl TryFrom<QUICInteger> for u32 {
-    type Error = ();
+    type Error = QUICError;
 
     fn try_from(value: QUICInteger) -> Result<Self, Self::Error> {
-        if value.length > 4 {
-            Err(())
-        } else {
-            Ok(value.value.try_into().unwrap())
-        }
+        value.value.try_into().map_err(|_| QUICError::IntegerOverflow)
     }
 }
-impl TryFrom<QUICInteger> for u64 {
-    type Error = ();
-
-    fn try_from(value: QUICInteger) -> Result<Self, Self::Error> {
-        if value.length > 8 {
-            Err(())
-        } else {
-            Ok(value.value)
-        }
+impl From<QUICInteger> for u64 {
+    fn from(value: QUICInteger) -> Self {
+        value.value
     }
 }
 
 impl TryFrom<&[u8]> for QUICInteger {
-    type Error = ();
+    type Error = QUICError;
     fn try_from(octets: &[u8]) -> Result<Self, Self::Error> {
-        if octets.len() == 0 {
-            return Err(());
-        }
-        // Check the two MSB bits of the first byte to determine the length
-        let length: u8 = match octets[0] >> 6 {
-            0b00 => 1,
-            0b01 => 2,
-            0b10 => 4,
-            0b11 => 8,
-            _ => unimplemented!()
-        };
-        let mut value: u64 = 0;
-        // Remove the two MSB bits of the first byte and add the remaining bits to the value
-        value |= (octets[0] & 0x3F) as u64;
-        for i in 1..length {
-            value <<= 8;
-            value |= octets[i as usize] as u64;
-        }
+        let first = *octets.first().ok_or(QUICError::Truncated)?;
+        // The two MSB bits of the first byte encode the length
+        let length: u8 = 1 << (first >> 6);
+        let bytes = octets.get(1..length as usize).ok_or(QUICError::Truncated)?;
+        // Remove the two MSB bits of the first byte and add the remaining bytes to the value
+        let value = bytes.iter().fold((first & 0x3f) as u64, |value, byte| value << 8 | *byte as u64);
         Ok(Self {
             length,
             value
@@ -211,110 +775,27 @@ impl TryFrom<&[u8]> for QUICInteger {
     }
 }
 impl<const N: usize> TryFrom<&[u8; N]> for QUICInteger {
-    type Error = ();
+    type Error = QUICError;
 
     fn try_from(octets: &[u8; N]) -> Result<Self, Self::Error> {
-        if octets.len() == 0 {
-            return Err(());
-        }
-        // Check the two MSB bits of the first byte to determine the length
-        let length: u8 = match octets[0] >> 6 {
-            0b00 => 1,
-            0b01 => 2,
-            0b10 => 4,
-            0b11 => 8,
-            _ => unimplemented!()
-        };
-        let mut value: u64 = 0;
-        // Remove the two MSB bits of the first byte and add the remaining bits to the value
-        value |= (octets[0] & 0x3F) as u64;
-        for i in 1..length {
-            value <<= 8;
-            value |= octets[i as usize] as u64;
-        }
-        Ok(Self {
-            length,
-            value
-        })
+        Self::try_from(&octets[..])
     }
 }
 
 impl QUICInteger {
     pub fn to_binary(&self) -> Vec<u8> {
-        let mut result = Vec::new();
-        let mut value = self.value;
-
-        let index = (match self.length {
-            1 => 0b00,
-            2 => 0b01,
-            4 => 0b10,
-            8 => 0b11,
-            _ => unimplemented!()
-        } << 6 | (value >> (self.length - 1) * 8)) as u8;
-        value &= !(0xFF << 8 * (self.length - 1));
-        result.push(index);
-
-        for _ in 1..self.length {
-            result.push((value & 0xFF) as u8);
-            value >>= 8;
-        }
+        let (prefix, length) = match self.length {
+            1 => (0b00, 1),
+            2 => (0b01, 2),
+            4 => (0b10, 4),
+            _ => (0b11, 8)
+        };
+        let mut result = self.value.to_be_bytes()[8 - length..].to_vec();
+        result[0] = (result[0] & 0x3f) | prefix << 6;
         result
     }
 }
 
-
-
-#[derive(PartialEq, Clone, Debug)]
-pub struct Crypto {
-    pub frame_type: QUICInteger,
-    pub offset: QUICInteger,
-    pub length: QUICInteger,
-    pub payload: Vec<u8>
-}
-
-impl<const N: usize> TryFrom<&[u8; N]> for Crypto {
-    type Error = ();
-
-    fn try_from(octets: &[u8; N]) -> Result<Self, Self::Error> {
-        if octets.len() == 0 {
-            return Err(());
-        }
-        let frame_type = TryInto::<QUICInteger>::try_into(&octets[0..std::cmp::min(N, QUICInteger::max_length() as usize)]).unwrap();
-        let offset = TryInto::<QUICInteger>::try_into(&octets[(frame_type.length as usize)..std::cmp::min(N, QUICInteger::max_length() as usize)]).unwrap();
-        let length = TryInto::<QUICInteger>::try_into(&octets[(frame_type.length + offset.length) as usize..std::cmp::min(N, QUICInteger::max_length() as usize)]).unwrap();
-        let payload = octets[(frame_type.length + offset.length + length.length) as usize..].to_vec();
-        Ok(Self {
-            frame_type,
-            offset,
-            length,
-            payload
-        })
-    }
-}
-impl TryFrom<&[u8]> for Crypto {
-    type Error = ();
-
-    fn try_from(octets: &[u8]) -> Result<Self, Self::Error> {
-        if octets.len() == 0 {
-            return Err(());
-        }
-        let frame_type = TryInto::<QUICInteger>::try_into(&octets[0..std::cmp::min(octets.len(), QUICInteger::max_length() as usize)]).unwrap();
-        let offset = TryInto::<QUICInteger>::try_into(&octets[(frame_type.length as usize)..std::cmp::min(octets.len(), QUICInteger::max_length() as usize)]).unwrap();
-        let length = TryInto::<QUICInteger>::try_into(&octets[(frame_type.length + offset.length) as usize..std::cmp::min(octets.len(), QUICInteger::max_length() as usize)]).unwrap();
-        let payload = octets[(frame_type.length + offset.length + length.length) as usize..].to_vec();
-        Ok(Self {
-            frame_type,
-            offset,
-            length,
-            payload
-        })
-    }
-}
-
-
-
-
-
 #[test]
 fn test_variable_integers() {
     let val_quic = TryInto::<QUICInteger>::try_into(&[0x44, 0xd9]).expect("QUIC int");
@@ -334,75 +815,149 @@ fn test_variable_integers() {
 
 }
 
+#[test]
+fn test_initial_key_derivation() {
+    use hex_literal::hex;
+    // https://www.rfc-editor.org/rfc/rfc9001#appendix-A.1
+    let keys = InitialKeys::derive(QUIC_V1, &hex!("8394c8f03e515708"), true).unwrap();
+    assert_eq!(keys.key, hex!("1f369613dd76d5467730efcbe3b1a22d"));
+    assert_eq!(keys.iv, hex!("fa044b2f42a3fd3b46fb255c"));
+    assert_eq!(keys.hp, hex!("9f50449e04a0e810283a1e9933adedd2"));
+
+    let keys = InitialKeys::derive(QUIC_V1, &hex!("8394c8f03e515708"), false).unwrap();
+    assert_eq!(keys.key, hex!("cf3a5331653c364c88f0f379b6067e37"));
+    assert_eq!(keys.iv, hex!("0ac1493ca1905853b0bba03e"));
+    assert_eq!(keys.hp, hex!("c206b8d9b9f0f37644430b490eeaa314"));
+
+    // https://www.rfc-editor.org/rfc/rfc9369#appendix-A.1
+    let keys = InitialKeys::derive(QUIC_V2, &hex!("8394c8f03e515708"), true).unwrap();
+    assert_eq!(keys.key, hex!("8b1a0bc121284290a29e0971b5cd045d"));
+    assert_eq!(keys.iv, hex!("91f73e2351d8fa91660e909f"));
+    assert_eq!(keys.hp, hex!("45b95e15235d6f45a6b19cbcb0294ba9"));
+
+    assert!(InitialKeys::derive(0x0a0a0a0a, &hex!("8394c8f03e515708"), true).is_err());
+}
+
 #[test]
 fn can_decrypt_quic_crypto() {
     use hex_literal::*;
-    use pnet::packet::Packet;
-    /*use aes_gcm::{Aes256Gcm, Aes128Gcm, Key, Nonce}; // Or `Aes128Gcm`
-    use sha2::Sha256;
-    use aes_gcm::aead::{Aead, NewAead};*/
-
-    let mut quic = hex!("c500000001088c4f1de81c072e17032f8c6f0042152b7fab163077f653ee7f52c82eb920cc69afc1c26282d27b76fcb341b8238ef14968d2d158ac58798d58a2634b93095c11492123b75be0b2f1bfa1209175a81e58b0466e3404272e4b1e1baad3e1ecaf09ef90c0ffbc206db66fb617a95a68f721594f46b720424f56fef6b6108b390f88cf2dd04043cc60bb22ef3278ec8160047c4af43c264f1403fe78f175183bf61c435b687a29d684b5906afeed56307bbc41d802721afce264d60d51abd897947459e5c930df35a4944d17e6d348d51efbc8dc2a33b1ea3122dbd774218ddcd8276b9e2ca0d9875f7717462ddee6480c1e19663653bef94d07b6de4956bbe15a85c341023311f738cdd5bda50e4e9194c435dbcec39b553812bdf8b53e607a0068f1c72d99887723e544be54663c9f79ab7e05021f2aef106ce02c6960367fbd186c4f32b2960262127658b53a719b6ad947a067f2be62cab057cbe77afbdd8ed385768aaff876d336ed7dda23dbb946d04b9a0dda311001bde540f0ffe11cf610a79b7e951f00fda76d0c2292da09cda98807e6eeb2c510e917064d1a94fd6c48f7b22ddbf374d98cadb30eb23a2822083cbe72fbd5ce72224a9790cb39cf08a10df6d3e9ca02ff7d557d6a32da271a7904b662e7b56f8f38f9f68787ab17f0b989b92dc21456f4672731344608e94557dd6d8aa01e30e01ca7c849fabc20e5018acb41b4908300042743d4ff135dde2069926a270cc4cf83b5660ca86599c9a3e8bed2ee");
-
-    let quic = QUICPacket::new(&mut quic).unwrap();
-
-    let initial = Initial::from_binary(quic.payload()).unwrap();
-    assert_eq!(initial.length.value, 533);
-
-    let _salt = get_quic_salt(quic.get_version());
-    let _initial_secret = quic.get_dst_connection_id();
 
-    let _encrypted = &initial.payload;
+    let quic = hex!("c500000001088c4f1de81c072e17032f8c6f0042152b7fab163077f653ee7f52c82eb920cc69afc1c26282d27b76fcb341b8238ef14968d2d158ac58798d58a2634b93095c11492123b75be0b2f1bfa1209175a81e58b0466e3404272e4b1e1baad3e1ecaf09ef90c0ffbc206db66fb617a95a68f721594f46b720424f56fef6b6108b390f88cf2dd04043cc60bb22ef3278ec8160047c4af43c264f1403fe78f175183bf61c435b687a29d684b5906afeed56307bbc41d802721afce264d60d51abd897947459e5c930df35a4944d17e6d348d51efbc8dc2a33b1ea3122dbd774218ddcd8276b9e2ca0d9875f7717462ddee6480c1e19663653bef94d07b6de4956bbe15a85c341023311f738cdd5bda50e4e9194c435dbcec39b553812bdf8b53e607a0068f1c72d99887723e544be54663c9f79ab7e05021f2aef106ce02c6960367fbd186c4f32b2960262127658b53a719b6ad947a067f2be62cab057cbe77afbdd8ed385768aaff876d336ed7dda23dbb946d04b9a0dda311001bde540f0ffe11cf610a79b7e951f00fda76d0c2292da09cda98807e6eeb2c510e917064d1a94fd6c48f7b22ddbf374d98cadb30eb23a2822083cbe72fbd5ce72224a9790cb39cf08a10df6d3e9ca02ff7d557d6a32da271a7904b662e7b56f8f38f9f68787ab17f0b989b92dc21456f4672731344608e94557dd6d8aa01e30e01ca7c849fabc20e5018acb41b4908300042743d4ff135dde2069926a270cc4cf83b5660ca86599c9a3e8bed2ee");
 
-    // https://github.com/cloudflare/quiche/blob/88955b9366698b3a69b75380f081e1124d913a58/quiche/src/crypto.rs
-    //
-    //let hk = (Some(&salt), &initial_secret);
-
-    /*let mut client_key = [0; 32];
-    hk.expand(b"client in", &mut client_key).unwrap();
-
-    let mut server_key = [0; 32];
-    hk.expand(b"server in", &mut server_key).unwrap();
-
-    let (client_gcm_key, client_gcm_iv )= {
-        let hkdf = Hkdf::<Sha256>::new(None, &client_key);
-        let mut key_buf = [0; 16];
-        let mut iv_buf = [0; 12];
-        hkdf.expand(b"tls13 quic key", &mut key_buf).unwrap();
-        hkdf.expand(b"tls13 quic iv", &mut iv_buf).unwrap();
-        (key_buf, iv_buf)
-    };
-
-    let (server_gcm_key, server_gcm_iv )= {
-        let hkdf = Hkdf::<Sha256>::new(None, &server_key);
-        let mut key_buf = [0; 16];
-        let mut iv_buf = [0; 12];
-        hkdf.expand(b"tls13 quic key", &mut key_buf).unwrap();
-        hkdf.expand(b"tls13 quic iv", &mut iv_buf).unwrap();
-        (key_buf, iv_buf)
-    };
-
-    // Print everything out
-    println!("Client key: {:?}", client_key);
-    println!("Server key: {:?}", server_key);
-    println!("Client gcm key: {:?}", client_gcm_key);
-    println!("Server gcm key: {:?}", server_gcm_key);
-    println!("Client gcm iv: {:?}", client_gcm_iv);
-    println!("Server gcm iv: {:?}", server_gcm_iv);
+    let packets = parse_datagram(&quic, 0).unwrap();
+    assert_eq!(packets.len(), 1);
+    let initial = &packets[0];
+    assert_eq!(initial.packet_type(), PacketType::Initial);
+    assert_eq!(initial.version(), Some(QUIC_V1));
+    match &initial.header {
+        Header::Long(header) => assert_eq!(header.length, Some(533)),
+        Header::Short(_) => panic!("Initial packets have a long header")
+    }
 
+    let (dcid, payload) = decrypt_client_initial(&quic).unwrap();
+    assert_eq!(dcid, initial.dst_connection_id());
+    assert!(parse_frames(&payload).unwrap().iter().any(|frame| frame.name() == "crypto"));
 
-    let key = Key::from_slice(&server_key);
-    let nonce = Nonce::from_slice(&server_gcm_iv);
-    let aead = Aes128Gcm::new(key);
-    let plain = aead.decrypt(nonce, quic.payload()).unwrap_err();
-    println!("Plaintext: {:#?}", plain);*/
+    let frames = crypto_frames(&payload).unwrap();
+    assert!(!frames.is_empty());
 
-    /*
-    let crypto = Crypto::try_from(initial.payload.as_slice()).unwrap();
-    println!("{:?}", crypto);*/
+    let mut reassembler = CryptoReassembler::default();
+    let handshake = reassembler.feed(&dcid, frames).unwrap();
+    assert_eq!(handshake[0], crate::tls::HANDSHAKE_CLIENT_HELLO);
+    let hello = crate::tls::ClientHello::parse(&handshake[4..]).unwrap();
+    assert_eq!(hello.sni(), Some("img.shields.io".to_string()));
+    assert_eq!(hello.alpn(), vec!["h3".to_string()]);
+}
 
+#[test]
+fn test_crypto_reassembly_limits() {
+    let dcid = [1, 2, 3, 4];
+    let mut reassembler = CryptoReassembler::default();
+    // Data past 64 KiB is dropped, the message can never complete
+    assert_eq!(reassembler.feed(&dcid, vec![(0, vec![1, 0x01, 0x00, 0x00]), (65532, vec![0; 8])]), None);
+    assert_eq!(reassembler.streams[&dcid[..]].fragments.len(), 1);
+    assert_eq!(reassembler.feed(&dcid, vec![(u64::MAX, vec![0])]), None);
+
+    // Fragments beyond the cap are dropped, retransmissions still replace buffered ones
+    let frames = (1..100).map(|i| (i * 8, vec![0; 4])).collect();
+    assert_eq!(reassembler.feed(&dcid, frames), None);
+    assert_eq!(reassembler.streams[&dcid[..]].fragments.len(), 64);
+    assert_eq!(reassembler.feed(&dcid, vec![(8, vec![0; 4])]), None);
+    assert_eq!(reassembler.streams[&dcid[..]].fragments.len(), 64);
+
+    let hello = reassembler.feed(&[5, 6], vec![(4, vec![0xaa, 0xbb]), (0, vec![1, 0, 0, 2])]);
+    assert_eq!(hello, Some(vec![1, 0, 0, 2, 0xaa, 0xbb]));
+}
 
+#[test]
+fn test_variable_integer_encoding() {
+    use hex_literal::hex;
+    // https://www.rfc-editor.org/rfc/rfc9000#appendix-A.1
+    for (bytes, value) in [(&hex!("c2197c5eff14e88c")[..], 151_288_809_941_952_652u64), (&hex!("9d7f3e7d"), 494_878_333), (&hex!("7bbd"), 15_293), (&hex!("25"), 37)] {
+        let int = QUICInteger::try_from(bytes).unwrap();
+        assert_eq!(int.value, value);
+        assert_eq!(int.to_binary(), bytes);
+        assert_eq!(QUICInteger::new(value).unwrap().to_binary(), bytes);
+    }
+    assert_eq!(QUICInteger::try_from(&hex!("c2197c")), Err(QUICError::Truncated));
+    assert_eq!(QUICInteger::new(1 << 62), Err(QUICError::IntegerOverflow));
+    assert_eq!(QUICInteger::with_length(37, 4).unwrap().to_binary(), hex!("80000025"));
+}
 
+#[test]
+fn test_coalesced_packets() {
+    use hex_literal::hex;
+    // Handshake packet (length 3) followed by a 1-RTT packet using the same connection ID
+    let datagram = hex!("e000000001 04 01020304 00 03 aabbcc 41 01020304 ddeeff");
+    let packets = parse_datagram(&datagram, 0).unwrap();
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].packet_type(), PacketType::Handshake);
+    assert_eq!(packets[0].bytes.len(), 15);
+    assert_eq!(packets[1].packet_type(), PacketType::OneRtt);
+    assert_eq!(packets[1].dst_connection_id(), hex!("01020304"));
+    assert_eq!(packets[1].version(), None);
+
+    let negotiation = parse_datagram(&hex!("80 00000000 00 04 01020304 00000001 6b3343cf"), 0).unwrap();
+    assert_eq!(negotiation[0].packet_type(), PacketType::VersionNegotiation);
+    match &negotiation[0].header {
+        Header::Long(header) => assert_eq!(header.supported_versions, vec![QUIC_V1, QUIC_V2]),
+        Header::Short(_) => panic!("Version Negotiation packets have a long header")
+    }
 
+    let frames = parse_frames(&hex!("0000 01 06 00 02 abcd 1d 00 02 6f6b")).unwrap();
+    assert_eq!(frames, vec![
+        Frame::Padding(2),
+        Frame::Ping,
+        Frame::Crypto { offset: 0, data: vec![0xab, 0xcd] },
+        Frame::ConnectionClose { error_code: 0, frame_type: None, reason: b"ok".to_vec() }
+    ]);
+    assert_eq!(parse_frames(&hex!("1f")), Err(QUICError::UnknownFrame(0x1f)));
 }
 
+#[test]
+fn test_malformed_packets() {
+    use hex_literal::hex;
+    assert_eq!(parse_datagram(&hex!("c0 000000"), 0), Err(QUICError::Truncated));
+    assert_eq!(parse_datagram(&hex!("c0 00000001 15"), 0), Err(QUICError::InvalidConnectionId));
+    assert_eq!(parse_datagram(&hex!("80 00000001 00 00 00 00"), 0), Err(QUICError::InvalidFixedBit));
+    // Length pointing past the end of the datagram
+    assert_eq!(parse_datagram(&hex!("c0 00000001 00 00 00 4fff 00"), 0), Err(QUICError::Truncated));
+    // Trailing garbage after a valid packet is ignored
+    assert_eq!(parse_datagram(&hex!("e0 00000001 00 00 01 aa 00"), 0).unwrap().len(), 1);
+    assert!(parse_frames(&hex!("02 00 00 ff")).is_err());
+    assert!(parse_frames(&hex!("18 00 00 15")).is_err());
+
+    // Every prefix and bit flip of a real packet must be handled gracefully
+    let packet = hex!("c000000001 08 8394c8f03e515708 00 00 41 00 0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+    for len in 0..packet.len() {
+        let _ = parse_datagram(&packet[..len], 8);
+        let _ = decrypt_client_initial(&packet[..len]);
+        let _ = parse_frames(&packet[..len]);
+    }
+    for i in 0..packet.len() {
+        let mut flipped = packet;
+        flipped[i] ^= 0xff;
+        let _ = parse_datagram(&flipped, 8);
+        let _ = decrypt_client_initial(&flipped);
+    }
+}
@@ -0,0 +1,172 @@
+//! Rewriting and pacing of recorded frames for `harpy replay`
+
+use std::{net::Ipv4Addr, str::FromStr, time::Duration};
+use pnet::{
+    datalink::MacAddr,
+    ipnetwork::Ipv4Network,
+    packet::{
+        arp::MutableArpPacket,
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        ipv4::Ipv4Packet,
+        MutablePacket, Packet
+    }
+};
+
+use crate::{encap::Encapsulation, engine::types::LuaIpv4Packet};
+
+/// Maps one network onto another of the same size, keeping the host part of addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkMapping {
+    pub from: Ipv4Network,
+    pub to: Ipv4Network
+}
+
+impl NetworkMapping {
+    pub fn apply(&self, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        if !self.from.contains(ip) {
+            return None;
+        }
+        let host = u32::from(ip) & !u32::from(self.from.mask());
+        Some(Ipv4Addr::from(u32::from(self.to.network()) | host))
+    }
+}
+
+/// Address rewriting rules, the first matching rule of each kind applies
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RewriteRules {
+    pub macs: Vec<(MacAddr, MacAddr)>,
+    pub networks: Vec<NetworkMapping>
+}
+
+fn split_rule<'a>(rule: &'a str, kind: &str) -> Result<(&'a str, &'a str), String> {
+    rule.split_once('=').map(|(from, to)| (from.trim(), to.trim()))
+        .ok_or_else(|| format!("`{}` isn't a rule for rewriting {} addresses, expected <OLD>=<NEW>", rule, kind))
+}
+
+impl RewriteRules {
+    /// Adds a rule of the form `<OLD>=<NEW>`, f.e. `00:11:22:33:44:55=02:00:00:00:00:01`
+    pub fn add_mac(&mut self, rule: &str) -> Result<(), String> {
+        let (from, to) = split_rule(rule, "MAC")?;
+        let parse = |mac: &str| MacAddr::from_str(mac).map_err(|_| format!("`{}` in `{}` isn't a MAC address", mac, rule));
+        self.macs.push((parse(from)?, parse(to)?));
+        Ok(())
+    }
+
+    /// Adds a rule of the form `<OLD>=<NEW>` for addresses or equally sized networks, f.e.
+    /// `10.0.0.0/24=192.168.1.0/24`
+    pub fn add_ip(&mut self, rule: &str) -> Result<(), String> {
+        let (from, to) = split_rule(rule, "IP")?;
+        let parse = |network: &str| network.parse::<Ipv4Network>().map_err(|_| format!("`{}` in `{}` is neither an IPv4 address nor a CIDR range", network, rule));
+        let (from, to) = (parse(from)?, parse(to)?);
+        if from.prefix() != to.prefix() {
+            return Err(format!("`{}` maps a /{} onto a /{}, both sides need the same prefix length", rule, from.prefix(), to.prefix()));
+        }
+        self.networks.push(NetworkMapping { from, to });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macs.is_empty() && self.networks.is_empty()
+    }
+
+    fn mac(&self, mac: MacAddr) -> MacAddr {
+        self.macs.iter().find(|(from, _)| *from == mac).map_or(mac, |(_, to)| *to)
+    }
+
+    fn ip(&self, ip: Ipv4Addr) -> Ipv4Addr {
+        self.networks.iter().find_map(|mapping| mapping.apply(ip)).unwrap_or(ip)
+    }
+
+    /// Rewrites the Ethernet addresses and the addresses of IPv4 and ARP packets, also behind VLAN
+    /// tags, PPPoE and MPLS. Checksums of IPv4 packets are recomputed if an address changed, other
+    /// frames are passed on untouched.
+    pub fn rewrite(&self, frame: EthernetPacket<'static>) -> EthernetPacket<'static> {
+        if self.is_empty() {
+            return frame;
+        }
+        let mut ethernet = MutableEthernetPacket::owned(frame.packet().to_vec()).unwrap();
+        ethernet.set_source(self.mac(frame.get_source()));
+        ethernet.set_destination(self.mac(frame.get_destination()));
+
+        let encapsulation = Encapsulation::parse(&frame);
+        match encapsulation.ethertype {
+            EtherTypes::Ipv4 => if let Some(ipv4) = Ipv4Packet::owned(encapsulation.payload(&frame).to_vec()) {
+                let (source, destination) = (self.ip(ipv4.get_source()), self.ip(ipv4.get_destination()));
+                if (source, destination) != (ipv4.get_source(), ipv4.get_destination()) {
+                    let mut header = pnet::packet::ipv4::MutableIpv4Packet::owned(ipv4.packet().to_vec()).unwrap();
+                    header.set_source(source);
+                    header.set_destination(destination);
+                    let mut ipv4 = LuaIpv4Packet(header.consume_to_immutable());
+                    ipv4.update_checksums();
+                    // Only the packet is replaced, trailing Ethernet padding stays
+                    let offset = encapsulation.offset;
+                    ethernet.packet_mut()[offset..offset + ipv4.0.packet().len()].copy_from_slice(ipv4.0.packet());
+                }
+            },
+            EtherTypes::Arp => if let Some(mut arp) = MutableArpPacket::new(&mut ethernet.packet_mut()[encapsulation.offset..]) {
+                arp.set_sender_hw_addr(self.mac(arp.get_sender_hw_addr()));
+                arp.set_target_hw_addr(self.mac(arp.get_target_hw_addr()));
+                arp.set_sender_proto_addr(self.ip(arp.get_sender_proto_addr()));
+                arp.set_target_proto_addr(self.ip(arp.get_target_proto_addr()));
+            },
+            _ => ()
+        }
+        ethernet.consume_to_immutable()
+    }
+}
+
+/// When recorded frames are sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Keep the recorded gaps between frames, divided by the speed factor
+    Original { speed: f64 },
+    /// Send every frame right after the previous one
+    Topspeed
+}
+
+impl Pacing {
+    /// Offset from the start of the replay a frame recorded `elapsed` after the first one is sent at
+    pub fn offset(&self, elapsed: Duration) -> Duration {
+        match *self {
+            Pacing::Original { speed } => elapsed.div_f64(speed),
+            Pacing::Topspeed => Duration::ZERO
+        }
+    }
+}
+
+
+#[test]
+fn test_rewrite() {
+    use hex_literal::hex;
+    // UDP datagram from 10.0.0.5:50012 to 10.0.0.1:53, padded to 60 bytes
+    let frame = hex!("020000000001 001122334455 0800
+        4500001c 0001 0000 4011 66d0 0a000005 0a000001
+        c35c 0035 0008 0000
+        000000000000000000000000000000000000");
+    let mut rules = RewriteRules::default();
+    rules.add_mac("00:11:22:33:44:55=02:00:00:00:00:99").unwrap();
+    rules.add_ip("10.0.0.0/24=192.168.1.0/24").unwrap();
+    assert!(rules.add_ip("10.0.0.0/24=192.168.0.0/16").is_err());
+    assert!(rules.add_mac("00:11:22:33:44:55").is_err());
+
+    let rewritten = rules.rewrite(EthernetPacket::owned(frame.to_vec()).unwrap());
+    assert_eq!(rewritten.packet().len(), frame.len());
+    assert_eq!(rewritten.get_source(), MacAddr::new(2, 0, 0, 0, 0, 0x99));
+    assert_eq!(rewritten.get_destination(), MacAddr::new(2, 0, 0, 0, 0, 1));
+    let ipv4 = Ipv4Packet::new(rewritten.payload()).unwrap();
+    assert_eq!(ipv4.get_source(), Ipv4Addr::new(192, 168, 1, 5));
+    assert_eq!(ipv4.get_destination(), Ipv4Addr::new(192, 168, 1, 1));
+    assert_eq!(ipv4.get_checksum(), pnet::packet::ipv4::checksum(&ipv4));
+    let udp = pnet::packet::udp::UdpPacket::new(ipv4.payload()).unwrap();
+    assert_eq!(udp.get_checksum(), pnet::packet::udp::ipv4_checksum(&udp, &ipv4.get_source(), &ipv4.get_destination()));
+
+    // Behind a VLAN tag
+    let tagged = crate::encap::retag(&EthernetPacket::new(&frame).unwrap(), &[crate::encap::Tag::Vlan { tpid: 0x8100, pcp: 0, dei: false, vid: 10 }]).unwrap();
+    let rewritten = rules.rewrite(tagged);
+    let encapsulation = Encapsulation::parse(&rewritten);
+    assert_eq!(encapsulation.tags.len(), 1);
+    assert_eq!(encapsulation.payload(&rewritten), ipv4.packet());
+
+    assert_eq!(Pacing::Original { speed: 2.0 }.offset(Duration::from_secs(3)), Duration::from_millis(1500));
+    assert_eq!(Pacing::Topspeed.offset(Duration::from_secs(3)), Duration::ZERO);
+}
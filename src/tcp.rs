@@ -0,0 +1,246 @@
+//! TCP options (RFC 9293, RFC 7323, RFC 2018) and MSS clamping of forwarded SYN segments
+
+use pnet::packet::{
+    ipv4::Ipv4Packet,
+    tcp::{MutableTcpPacket, TcpFlags, TcpPacket},
+    Packet
+};
+
+use crate::engine::types::LuaIpv4Packet;
+
+// https://www.iana.org/assignments/tcp-parameters
+pub const OPTION_END: u8 = 0;
+pub const OPTION_NOP: u8 = 1;
+pub const OPTION_MSS: u8 = 2;
+pub const OPTION_WINDOW_SCALE: u8 = 3;
+pub const OPTION_SACK_PERMITTED: u8 = 4;
+pub const OPTION_SACK: u8 = 5;
+pub const OPTION_TIMESTAMPS: u8 = 8;
+
+/// Most option bytes a header can carry, the data offset counts at most 15 words
+pub const MAX_OPTIONS_LEN: usize = 40;
+/// Smallest MSS harpy clamps to, Linux doesn't go below it either
+pub const MIN_MSS: u16 = 88;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    End,
+    Nop,
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    /// Left and right edges of the received blocks
+    Sack(Vec<(u32, u32)>),
+    Timestamps { value: u32, echo: u32 },
+    Unknown { kind: u8, data: Vec<u8> }
+}
+
+impl TcpOption {
+    pub fn kind(&self) -> u8 {
+        match self {
+            TcpOption::End => OPTION_END,
+            TcpOption::Nop => OPTION_NOP,
+            TcpOption::Mss(_) => OPTION_MSS,
+            TcpOption::WindowScale(_) => OPTION_WINDOW_SCALE,
+            TcpOption::SackPermitted => OPTION_SACK_PERMITTED,
+            TcpOption::Sack(_) => OPTION_SACK,
+            TcpOption::Timestamps { .. } => OPTION_TIMESTAMPS,
+            TcpOption::Unknown { kind, .. } => *kind
+        }
+    }
+
+    /// Returns the name scripts know the option by, nil for unknown options
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            TcpOption::End => Some("end"),
+            TcpOption::Nop => Some("nop"),
+            TcpOption::Mss(_) => Some("mss"),
+            TcpOption::WindowScale(_) => Some("window_scale"),
+            TcpOption::SackPermitted => Some("sack_permitted"),
+            TcpOption::Sack(_) => Some("sack"),
+            TcpOption::Timestamps { .. } => Some("timestamps"),
+            TcpOption::Unknown { .. } => None
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let data = match self {
+            TcpOption::End | TcpOption::Nop => return out.push(self.kind()),
+            TcpOption::Mss(mss) => mss.to_be_bytes().to_vec(),
+            TcpOption::WindowScale(shift) => vec![*shift],
+            TcpOption::SackPermitted => Vec::new(),
+            TcpOption::Sack(blocks) => blocks.iter().flat_map(|(left, right)| [left.to_be_bytes(), right.to_be_bytes()].concat()).collect(),
+            TcpOption::Timestamps { value, echo } => [value.to_be_bytes(), echo.to_be_bytes()].concat(),
+            TcpOption::Unknown { data, .. } => data.clone()
+        };
+        out.push(self.kind());
+        out.push((2 + data.len()) as u8);
+        out.extend(data);
+    }
+}
+
+/// Parses the options of a TCP header. Parsing stops at the end of option list, the padding
+/// after it is dropped.
+pub fn parse_options(mut data: &[u8]) -> Result<Vec<TcpOption>, String> {
+    let mut options = Vec::new();
+    while let [kind, rest @ ..] = data {
+        match *kind {
+            OPTION_END => {
+                options.push(TcpOption::End);
+                break;
+            },
+            OPTION_NOP => {
+                options.push(TcpOption::Nop);
+                data = rest;
+                continue;
+            },
+            _ => ()
+        }
+        let length = *rest.first().ok_or_else(|| format!("option {} is missing its length", kind))? as usize;
+        if length < 2 || length > data.len() {
+            return Err(format!("option {} has a length of {}, {} bytes are left", kind, length, data.len()));
+        }
+        let value = &data[2..length];
+        let malformed = || format!("option {} can't be {} bytes long", kind, length);
+        options.push(match *kind {
+            OPTION_MSS => TcpOption::Mss(u16::from_be_bytes(value.try_into().map_err(|_| malformed())?)),
+            OPTION_WINDOW_SCALE => match value {
+                [shift] => TcpOption::WindowScale(*shift),
+                _ => return Err(malformed())
+            },
+            OPTION_SACK_PERMITTED if value.is_empty() => TcpOption::SackPermitted,
+            OPTION_SACK_PERMITTED => return Err(malformed()),
+            OPTION_SACK if !value.is_empty() && value.len().is_multiple_of(8) => TcpOption::Sack(value.chunks(8)
+                .map(|block| (u32::from_be_bytes(block[..4].try_into().unwrap()), u32::from_be_bytes(block[4..].try_into().unwrap())))
+                .collect()),
+            OPTION_SACK => return Err(malformed()),
+            OPTION_TIMESTAMPS if value.len() == 8 => TcpOption::Timestamps {
+                value: u32::from_be_bytes(value[..4].try_into().unwrap()),
+                echo: u32::from_be_bytes(value[4..].try_into().unwrap())
+            },
+            OPTION_TIMESTAMPS => return Err(malformed()),
+            kind => TcpOption::Unknown { kind, data: value.to_vec() }
+        });
+        data = &data[length..];
+    }
+    Ok(options)
+}
+
+/// Serializes options, padded with zeros to a multiple of four bytes
+pub fn write_options(options: &[TcpOption]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    for option in options {
+        if let TcpOption::Unknown { kind, data } = option {
+            if data.len() > 253 {
+                return Err(format!("option {} can't carry {} bytes", kind, data.len()));
+            }
+        }
+        option.write(&mut out);
+    }
+    out.resize(out.len().next_multiple_of(4), OPTION_END);
+    match out.len() {
+        len if len > MAX_OPTIONS_LEN => Err(format!("options take {} bytes, a TCP header fits {}", len, MAX_OPTIONS_LEN)),
+        _ => Ok(out)
+    }
+}
+
+/// Returns `segment` with its options replaced, the payload is kept
+pub fn with_options(segment: &TcpPacket, options: &[TcpOption]) -> Result<TcpPacket<'static>, String> {
+    let options = write_options(options)?;
+    let data_offset = ((20 + options.len()) / 4) as u8;
+    // TcpPacket guarantees the fixed 20 bytes of the header
+    let header_len = (segment.get_data_offset() as usize * 4).clamp(20, segment.packet().len());
+    let mut buf = Vec::with_capacity(20 + options.len() + segment.payload().len());
+    buf.extend(&segment.packet()[..20]);
+    buf.extend(options);
+    buf.extend(&segment.packet()[header_len..]);
+
+    let mut tcp = MutableTcpPacket::owned(buf).unwrap();
+    tcp.set_data_offset(data_offset);
+    Ok(tcp.consume_to_immutable())
+}
+
+/// Lowers the MSS a SYN or SYN-ACK segment in `datagram` announces to `mss`. Segments without
+/// the option get one if the default of 536 is too large. Returns the rewritten datagram, or
+/// None if there was nothing to clamp.
+pub fn clamp_mss(datagram: &Ipv4Packet, mss: u16) -> Option<Vec<u8>> {
+    if datagram.get_next_level_protocol() != pnet::packet::ip::IpNextHeaderProtocols::Tcp || crate::fragment::is_fragment(datagram) {
+        return None;
+    }
+    let segment = TcpPacket::new(datagram.payload())?;
+    if segment.get_flags() & TcpFlags::SYN == 0 {
+        return None;
+    }
+    let mut options = parse_options(segment.get_options_raw()).ok()?;
+    match options.iter_mut().find_map(|option| match option {
+        TcpOption::Mss(announced) => Some(announced),
+        _ => None
+    }) {
+        Some(announced) if *announced > mss => *announced = mss,
+        Some(_) => return None,
+        None if mss < 536 => options.insert(0, TcpOption::Mss(mss)),
+        None => return None
+    }
+    let segment = with_options(&segment, &options).ok()?;
+    let mut ipv4 = LuaIpv4Packet(Ipv4Packet::owned(datagram.packet().to_vec())?);
+    ipv4.set_payload(segment.packet());
+    Some(ipv4.0.packet().to_vec())
+}
+
+
+#[test]
+fn test_options() {
+    use hex_literal::hex;
+    // Options of a Linux SYN
+    let raw = hex!("020405b4 0402 080a 0016ea2e 00000000 01 030307");
+    let options = parse_options(&raw).unwrap();
+    assert_eq!(options, vec![
+        TcpOption::Mss(1460),
+        TcpOption::SackPermitted,
+        TcpOption::Timestamps { value: 0x0016ea2e, echo: 0 },
+        TcpOption::Nop,
+        TcpOption::WindowScale(7)
+    ]);
+    assert_eq!(write_options(&options).unwrap(), raw);
+
+    let sack = hex!("0101 050a 00000001 00000005 0000");
+    assert_eq!(parse_options(&sack).unwrap(), vec![TcpOption::Nop, TcpOption::Nop, TcpOption::Sack(vec![(1, 5)]), TcpOption::End]);
+    assert_eq!(write_options(&[TcpOption::Mss(1400), TcpOption::WindowScale(2)]).unwrap(), hex!("02040578 030302 00"));
+    assert_eq!(parse_options(&hex!("1e03ff00")).unwrap(), vec![TcpOption::Unknown { kind: 30, data: vec![0xff] }, TcpOption::End]);
+
+    assert!(parse_options(&hex!("0204")).is_err());
+    assert!(parse_options(&hex!("020305")).is_err());
+    assert!(parse_options(&hex!("0801")).is_err());
+    assert!(write_options(&[TcpOption::Sack(vec![(0, 0); 4]), TcpOption::Timestamps { value: 0, echo: 0 }]).is_err());
+}
+
+#[test]
+fn test_clamp_mss() {
+    use hex_literal::hex;
+    // SYN from 192.168.0.53:50012 to 1.1.1.1:443, announcing an MSS of 1460
+    let syn = hex!("4500002c 0001 4000 4006 0000 c0a80035 01010101
+        c35c 01bb 00000001 00000000 6002 ffff 0000 0000
+        020405b4");
+    let mut syn = LuaIpv4Packet(Ipv4Packet::owned(syn.to_vec()).unwrap());
+    syn.update_checksums();
+
+    let clamped = clamp_mss(&syn.0, 1360).unwrap();
+    let clamped = Ipv4Packet::new(&clamped).unwrap();
+    assert_eq!(clamped.get_checksum(), pnet::packet::ipv4::checksum(&clamped));
+    let segment = TcpPacket::new(clamped.payload()).unwrap();
+    assert_eq!(parse_options(segment.get_options_raw()).unwrap(), vec![TcpOption::Mss(1360)]);
+    assert_eq!(segment.get_checksum(), pnet::packet::tcp::ipv4_checksum(&segment, &clamped.get_source(), &clamped.get_destination()));
+    assert!(clamp_mss(&syn.0, 1460).is_none());
+
+    // Without an option, one is only added below the default of 536
+    let bare = hex!("45000028 0001 4000 4006 0000 c0a80035 01010101
+        c35c 01bb 00000001 00000000 5002 ffff 0000 0000");
+    let bare = Ipv4Packet::new(&bare).unwrap();
+    assert!(clamp_mss(&bare, 1360).is_none());
+    let clamped = clamp_mss(&bare, 500).unwrap();
+    let clamped = Ipv4Packet::new(&clamped).unwrap();
+    assert_eq!(clamped.get_total_length(), 44);
+    let segment = TcpPacket::new(clamped.payload()).unwrap();
+    assert_eq!(segment.get_data_offset(), 6);
+    assert_eq!(parse_options(segment.get_options_raw()).unwrap(), vec![TcpOption::Mss(500)]);
+}
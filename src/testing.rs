@@ -0,0 +1,588 @@
+//! Runs the tests of a Lua script. A spec file declares them with `test(name, function)`, loads
+//! the script under test with `script([settings])` and builds frames with the `fixture` helpers.
+
+use std::{
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant}
+};
+use pnet::{
+    datalink::MacAddr,
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperation, ArpPacket, MutableArpPacket},
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+        tcp::{self, MutableTcpPacket},
+        udp::{self, MutableUdpPacket},
+        Packet
+    }
+};
+use rlua::{AnyUserData, Error as LuaError, Function, Table, UserData, UserDataMethods, Value};
+
+use crate::{
+    encap,
+    engine::{sandbox::Sandbox, types::{ethernet::tag_from_table, LuaBinary, LuaEthernetPacket, LuaIpv4Packet}, EngineResult, HarpyEngine},
+    error::HarpyError
+};
+
+/// Name of the registry value holding the declared tests
+const TESTS: &str = "harpy.tests";
+
+/// Assertion helpers, written in Lua so failures point to the line of the test
+const PRELUDE: &str = r#"
+local function hex(bytes)
+    return (bytes:gsub(".", function(c) return ("%02x"):format(c:byte()) end))
+end
+local function show(value)
+    return type(value) == "string" and ("%q"):format(value) or tostring(value)
+end
+local function fail(message, reason)
+    error(message and (message .. ": " .. reason) or reason, 3)
+end
+
+function assert_eq(actual, expected, message)
+    if type(actual) == "userdata" or type(expected) == "userdata" then
+        local a, e = fixture.bytes(actual), fixture.bytes(expected)
+        if a == nil or e == nil then
+            fail(message, ("can't compare %s with %s"):format(type(actual), type(expected)))
+        elseif a ~= e then
+            local offset = 1
+            while a:byte(offset) == e:byte(offset) do offset = offset + 1 end
+            fail(message, ("bytes differ at offset %d\n  expected %s\n  got      %s"):format(offset - 1, hex(e), hex(a)))
+        end
+    elseif actual ~= expected then
+        fail(message, ("expected %s, got %s"):format(show(expected), show(actual)))
+    end
+end
+
+function assert_ne(actual, unexpected, message)
+    local a, u = fixture.bytes(actual), fixture.bytes(unexpected)
+    if actual == unexpected or (a ~= nil and a == u) then
+        fail(message, ("expected anything but %s"):format(show(unexpected)))
+    end
+end
+
+function assert_contains(haystack, needle, message)
+    local h, n = fixture.bytes(haystack), fixture.bytes(needle)
+    if h == nil or n == nil or not h:find(n, 1, true) then
+        fail(message, ("%s doesn't contain %s"):format(show(haystack), show(needle)))
+    end
+end
+"#;
+
+/// Format of the test report
+#[cfg_attr(feature = "cli", derive(clap::ArgEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Test Anything Protocol, version 13
+    Tap,
+    /// JUnit XML, as understood by most CI systems
+    Junit
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestOutcome {
+    pub name: String,
+    pub duration: Duration,
+    /// Why the test failed, `None` if it passed
+    pub failure: Option<String>
+}
+
+impl TestOutcome {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// A freshly loaded instance of the script under test, returned by `script()`
+struct LuaScript {
+    engine: HarpyEngine,
+    file: PathBuf
+}
+
+impl UserData for LuaScript {
+    fn add_methods<'lua, T: UserDataMethods<'lua, Self>>(methods: &mut T) {
+        // Returns the verdict, the frame as it leaves the script and the datagrams it replied with
+        methods.add_method("on_packet", |_, this: &LuaScript, (frame,): (AnyUserData,)| {
+            let frame = frame.borrow::<LuaEthernetPacket>()?;
+            let result = this.engine.on_packet(&frame.0);
+            let replies = this.engine.take_replies().into_iter()
+                .filter_map(|datagram| Ipv4Packet::owned(datagram).map(LuaIpv4Packet))
+                .collect::<Vec<_>>();
+            match result {
+                Ok(EngineResult::Continue) => Ok(("continue", LuaEthernetPacket::new(frame.clone().0), replies)),
+                Ok(EngineResult::Drop) => Ok(("drop", LuaEthernetPacket::new(frame.clone().0), replies)),
+                Ok(EngineResult::Tamper(packet)) => Ok(("tamper", LuaEthernetPacket::new(packet), replies)),
+                Err(e) => Err(LuaError::RuntimeError(format!("{}: on_packet: {}", this.file.display(), e)))
+            }
+        });
+        // Returns the verdict and the reply as it leaves the script
+        methods.add_method("on_arp_reply", |_, this: &LuaScript, (request, reply): (AnyUserData, AnyUserData)| {
+            let (request, reply) = (request.borrow::<LuaEthernetPacket>()?, reply.borrow::<LuaEthernetPacket>()?);
+            let result = this.engine.on_arp_reply(&request.0, &reply.0);
+            this.engine.take_replies();
+            match result {
+                Ok(EngineResult::Continue) => Ok(("continue", LuaEthernetPacket::new(reply.clone().0))),
+                Ok(EngineResult::Drop) => Ok(("drop", LuaEthernetPacket::new(reply.clone().0))),
+                Ok(EngineResult::Tamper(packet)) => Ok(("tamper", LuaEthernetPacket::new(packet))),
+                Err(e) => Err(LuaError::RuntimeError(format!("{}: on_arp_reply: {}", this.file.display(), e)))
+            }
+        });
+    }
+}
+
+/// Converts the settings passed to `script()` into the TOML the engine expects
+fn lua_to_toml(value: Value, depth: usize) -> rlua::Result<toml::Value> {
+    if depth > 16 {
+        return Err(LuaError::RuntimeError("script(): settings nested too deeply".to_string()));
+    }
+    Ok(match value {
+        Value::Boolean(b) => toml::Value::Boolean(b),
+        Value::Integer(n) => toml::Value::Integer(n),
+        Value::Number(n) => toml::Value::Float(n),
+        Value::String(s) => toml::Value::String(s.to_str()?.to_string()),
+        Value::Table(table) if table.raw_len() > 0 => toml::Value::Array(table.sequence_values::<Value>()
+            .map(|value| lua_to_toml(value?, depth + 1))
+            .collect::<rlua::Result<_>>()?),
+        Value::Table(table) => toml::Value::Table(table.pairs::<String, Value>()
+            .map(|pair| pair.and_then(|(key, value)| Ok((key, lua_to_toml(value, depth + 1)?))))
+            .collect::<rlua::Result<_>>()?),
+        value => return Err(LuaError::RuntimeError(format!("script(): {} settings are not supported", value.type_name())))
+    })
+}
+
+fn load_script(file: &Path, sandbox: Option<Sandbox>, settings: toml::value::Table) -> rlua::Result<LuaScript> {
+    let engine = sandbox.map_or_else(HarpyEngine::new, HarpyEngine::sandboxed);
+    engine.context(|ctx| {
+        ctx.globals().set("MTU", 1500)?;
+        ctx.globals().set("harpy_mode", "test")
+    })?;
+    engine.set_settings(&settings)?;
+    engine.run_file(file.to_owned()).map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+    Ok(LuaScript { engine, file: file.to_owned() })
+}
+
+fn field<'lua, T: rlua::FromLua<'lua>>(options: &Option<Table<'lua>>, name: &str) -> rlua::Result<Option<T>> {
+    match options {
+        Some(options) => options.get::<_, Option<T>>(name),
+        None => Ok(None)
+    }
+}
+
+fn parsed_field<T: std::str::FromStr>(options: &Option<Table>, name: &str, default: &str) -> rlua::Result<T> {
+    let value = field::<String>(options, name)?.unwrap_or_else(|| default.to_string());
+    value.parse().map_err(|_| LuaError::RuntimeError(format!("fixture: `{}` is not a valid {}", value, name)))
+}
+
+fn payload_field(options: &Option<Table>) -> rlua::Result<Vec<u8>> {
+    match field::<Value>(options, "payload")? {
+        None | Some(Value::Nil) => Ok(Vec::new()),
+        Some(value) => LuaBinary::bytes_of(&value)
+            .ok_or_else(|| LuaError::RuntimeError(format!("fixture: can't convert a {} payload to bytes", value.type_name())))
+    }
+}
+
+fn ethernet_frame(options: &Option<Table>, ethertype: u16, payload: &[u8]) -> rlua::Result<LuaEthernetPacket> {
+    let mut ethernet = MutableEthernetPacket::owned(vec![0u8; EthernetPacket::minimum_packet_size() + payload.len()]).unwrap();
+    ethernet.set_source(parsed_field::<MacAddr>(options, "src_mac", "02:00:00:00:00:01")?);
+    ethernet.set_destination(parsed_field::<MacAddr>(options, "dst_mac", "02:00:00:00:00:02")?);
+    ethernet.set_ethertype(pnet::packet::ethernet::EtherType(ethertype));
+    ethernet.set_payload(payload);
+    let frame = ethernet.consume_to_immutable();
+    let Some(tags) = field::<Vec<Table>>(options, "tags")? else {
+        return Ok(LuaEthernetPacket::new(frame));
+    };
+    let tags = tags.into_iter().map(|tag| tag_from_table(tag, "fixture")).collect::<rlua::Result<Vec<_>>>()?;
+    encap::retag(&frame, &tags).map(LuaEthernetPacket::new).map_err(|e| LuaError::RuntimeError(format!("fixture: {}", e)))
+}
+
+fn ipv4_frame(options: &Option<Table>, protocol: IpNextHeaderProtocol, build_payload: impl FnOnce(Ipv4Addr, Ipv4Addr) -> rlua::Result<Vec<u8>>) -> rlua::Result<LuaEthernetPacket> {
+    let source: Ipv4Addr = parsed_field(options, "src", "192.168.0.2")?;
+    let destination: Ipv4Addr = parsed_field(options, "dst", "192.168.0.1")?;
+    let payload = build_payload(source, destination)?;
+    let mut ipv4 = MutableIpv4Packet::owned(vec![0u8; 20 + payload.len()]).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length((20 + payload.len()) as u16);
+    ipv4.set_identification(field(options, "id")?.unwrap_or(1));
+    ipv4.set_ttl(field(options, "ttl")?.unwrap_or(64));
+    ipv4.set_next_level_protocol(protocol);
+    ipv4.set_source(source);
+    ipv4.set_destination(destination);
+    ipv4.set_payload(&payload);
+    ipv4.set_checksum(ipv4::checksum(&ipv4.to_immutable()));
+    ethernet_frame(options, 0x0800, ipv4.packet())
+}
+
+/// Registers `test`, `script`, `fixture` and the assertion helpers
+fn register(engine: &HarpyEngine, script: &Path, spec: &Path, sandbox: Option<Sandbox>) -> rlua::Result<()> {
+    let script = script.to_owned();
+    let base = spec.parent().unwrap_or_else(|| Path::new("")).to_owned();
+    engine.context(|ctx| {
+        let g = ctx.globals();
+        g.set("harpy_mode", "test")?;
+        ctx.set_named_registry_value(TESTS, ctx.create_table()?)?;
+        g.set("test", ctx.create_function(|ctx, (name, function): (String, Function)| {
+            let tests: Table = ctx.named_registry_value(TESTS)?;
+            let test = ctx.create_table()?;
+            test.set("name", name)?;
+            test.set("run", function)?;
+            tests.set(tests.raw_len() + 1, test)
+        })?)?;
+        g.set("script", ctx.create_function(move |_, (settings,): (Option<Table>,)| {
+            let settings = match settings {
+                Some(settings) => match lua_to_toml(Value::Table(settings), 0)? {
+                    toml::Value::Table(settings) => settings,
+                    _ => return Err(LuaError::RuntimeError("script(): settings have to be a table with keys".to_string()))
+                },
+                None => Default::default()
+            };
+            load_script(&script, sandbox, settings)
+        })?)?;
+
+        let fixture = ctx.create_table()?;
+        fixture.set("frame", ctx.create_function(|_, (bytes,): (Value,)| {
+            let bytes = LuaBinary::bytes_of(&bytes)
+                .ok_or_else(|| LuaError::RuntimeError(format!("fixture.frame(): can't convert {} to bytes", bytes.type_name())))?;
+            EthernetPacket::owned(bytes).map(LuaEthernetPacket::new)
+                .ok_or_else(|| LuaError::RuntimeError("fixture.frame(): frames are at least 14 bytes long".to_string()))
+        })?)?;
+        fixture.set("ethernet", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            let ethertype = field(&options, "type")?.unwrap_or(0x0800);
+            ethernet_frame(&options, ethertype, &payload_field(&options)?)
+        })?)?;
+        fixture.set("ipv4", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            let protocol = IpNextHeaderProtocol(field(&options, "protocol")?.unwrap_or(IpNextHeaderProtocols::Udp.0));
+            ipv4_frame(&options, protocol, |_, _| payload_field(&options))
+        })?)?;
+        fixture.set("udp", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            ipv4_frame(&options, IpNextHeaderProtocols::Udp, |source, destination| {
+                let payload = payload_field(&options)?;
+                let mut udp = MutableUdpPacket::owned(vec![0u8; 8 + payload.len()]).unwrap();
+                udp.set_source(field(&options, "src_port")?.unwrap_or(40000));
+                udp.set_destination(field(&options, "dst_port")?.unwrap_or(53));
+                udp.set_length((8 + payload.len()) as u16);
+                udp.set_payload(&payload);
+                udp.set_checksum(udp::ipv4_checksum(&udp.to_immutable(), &source, &destination));
+                Ok(udp.packet().to_vec())
+            })
+        })?)?;
+        fixture.set("tcp", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            ipv4_frame(&options, IpNextHeaderProtocols::Tcp, |source, destination| {
+                let payload = payload_field(&options)?;
+                let mut tcp = MutableTcpPacket::owned(vec![0u8; 20 + payload.len()]).unwrap();
+                tcp.set_source(field(&options, "src_port")?.unwrap_or(40000));
+                tcp.set_destination(field(&options, "dst_port")?.unwrap_or(443));
+                tcp.set_sequence(field(&options, "seq")?.unwrap_or(1));
+                tcp.set_acknowledgement(field(&options, "ack")?.unwrap_or(1));
+                tcp.set_data_offset(5);
+                // PSH, ACK
+                tcp.set_flags(field(&options, "flags")?.unwrap_or(0x18));
+                tcp.set_window(field(&options, "window")?.unwrap_or(65535));
+                tcp.set_payload(&payload);
+                tcp.set_checksum(tcp::ipv4_checksum(&tcp.to_immutable(), &source, &destination));
+                Ok(tcp.packet().to_vec())
+            })
+        })?)?;
+        fixture.set("icmp", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            ipv4_frame(&options, IpNextHeaderProtocols::Icmp, |_, _| {
+                let (identifier, sequence) = (field::<u16>(&options, "icmp_id")?.unwrap_or(1).to_be_bytes(), field::<u16>(&options, "seq")?.unwrap_or(1).to_be_bytes());
+                // Echo request
+                Ok(crate::icmp::build(field(&options, "icmp_type")?.unwrap_or(8), field(&options, "code")?.unwrap_or(0),
+                    [identifier[0], identifier[1], sequence[0], sequence[1]], &payload_field(&options)?))
+            })
+        })?)?;
+        fixture.set("arp", ctx.create_function(|_, (options,): (Option<Table>,)| {
+            let mut arp = MutableArpPacket::owned(vec![0u8; ArpPacket::minimum_packet_size()]).unwrap();
+            arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+            arp.set_protocol_type(EtherTypes::Ipv4);
+            arp.set_hw_addr_len(6);
+            arp.set_proto_addr_len(4);
+            arp.set_operation(ArpOperation(field(&options, "operation")?.unwrap_or(1)));
+            arp.set_sender_hw_addr(parsed_field(&options, "sender_mac", "02:00:00:00:00:01")?);
+            arp.set_sender_proto_addr(parsed_field(&options, "sender_ip", "192.168.0.2")?);
+            arp.set_target_hw_addr(parsed_field(&options, "target_mac", "00:00:00:00:00:00")?);
+            arp.set_target_proto_addr(parsed_field(&options, "target_ip", "192.168.0.1")?);
+            ethernet_frame(&options, 0x0806, arp.packet())
+        })?)?;
+        fixture.set("pcap", ctx.create_function(move |ctx, (file,): (String,)| {
+            let path = base.join(&file);
+            let records = crate::pcap::read_ethernet(&path)
+                .map_err(|e| LuaError::RuntimeError(format!("fixture.pcap(): {}: {}", path.display(), e)))?;
+            ctx.create_sequence_from(records.into_iter()
+                .filter_map(|record| EthernetPacket::owned(record.data).map(LuaEthernetPacket::new)))
+        })?)?;
+        // Raw bytes of frames, binaries and strings as Lua string, used by the assertion helpers
+        fixture.set("bytes", ctx.create_function(|ctx, (value,): (Value,)| {
+            let bytes = match &value {
+                Value::UserData(data) if data.is::<LuaEthernetPacket>() => Some(data.borrow::<LuaEthernetPacket>()?.0.packet().to_vec()),
+                Value::UserData(data) if data.is::<LuaIpv4Packet>() => Some(data.borrow::<LuaIpv4Packet>()?.0.packet().to_vec()),
+                Value::UserData(_) | Value::String(_) => LuaBinary::bytes_of(&value),
+                _ => None
+            };
+            bytes.map(|bytes| ctx.create_string(&bytes)).transpose()
+        })?)?;
+        g.set("fixture", fixture)?;
+
+        ctx.load(PRELUDE).set_name("=harpy test")?.exec()
+    })
+}
+
+/// The message of an error raised by a test, without rlua's wrapping
+fn failure_message(error: &LuaError) -> String {
+    match error {
+        LuaError::CallbackError { cause, .. } => failure_message(cause),
+        LuaError::RuntimeError(message) => message.split("\nstack traceback:").next().unwrap_or(message).to_string(),
+        error => error.to_string()
+    }
+}
+
+/// Loads `spec` and runs the tests it declares against `script`, every test runs even if others fail
+pub fn run(script: &Path, spec: &Path, sandbox: Option<Sandbox>) -> Result<Vec<TestOutcome>, HarpyError> {
+    let engine = HarpyEngine::new();
+    register(&engine, script, spec, sandbox).map_err(|e| HarpyError::from_lua(spec.to_owned(), e))?;
+    engine.run_file(spec.to_owned())?;
+
+    engine.context(|ctx| {
+        let tests: Table = ctx.named_registry_value(TESTS)?;
+        tests.sequence_values::<Table>().map(|test| {
+            let test = test?;
+            let name: String = test.get("name")?;
+            let start = Instant::now();
+            let failure = test.get::<_, Function>("run")?.call::<_, ()>(()).err().map(|e| failure_message(&e));
+            Ok(TestOutcome { name, duration: start.elapsed(), failure })
+        }).collect::<rlua::Result<Vec<_>>>()
+    }).map_err(|e| HarpyError::from_lua(spec.to_owned(), e))
+}
+
+/// Renders outcomes in the Test Anything Protocol
+pub fn tap(outcomes: &[TestOutcome]) -> String {
+    let mut out = format!("TAP version 13\n1..{}\n", outcomes.len());
+    for (i, outcome) in outcomes.iter().enumerate() {
+        match &outcome.failure {
+            None => out.push_str(&format!("ok {} - {}\n", i + 1, outcome.name)),
+            Some(failure) => {
+                out.push_str(&format!("not ok {} - {}\n", i + 1, outcome.name));
+                // JSON strings are valid YAML
+                out.push_str(&format!("  ---\n  message: {}\n  ...\n", serde_json::Value::from(failure.as_str())));
+            }
+        }
+    }
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+/// Renders outcomes as a JUnit XML report with a single test suite named `suite`
+pub fn junit(suite: &str, outcomes: &[TestOutcome]) -> String {
+    let failures = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+    let time = outcomes.iter().map(|outcome| outcome.duration).sum::<Duration>().as_secs_f64();
+    let suite = xml_escape(suite);
+    let mut out = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+    out.push_str(&format!("<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.6}\">\n", outcomes.len(), failures, time));
+    out.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.6}\">\n", suite, outcomes.len(), failures, time));
+    for outcome in outcomes {
+        let testcase = format!("<testcase name=\"{}\" classname=\"{}\" time=\"{:.6}\"", xml_escape(&outcome.name), suite, outcome.duration.as_secs_f64());
+        match &outcome.failure {
+            None => out.push_str(&format!("    {}/>\n", testcase)),
+            Some(failure) => {
+                let message = xml_escape(failure.lines().next().unwrap_or(""));
+                out.push_str(&format!("    {}>\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n", testcase, message, xml_escape(failure)));
+            }
+        }
+    }
+    out.push_str("  </testsuite>\n</testsuites>\n");
+    out
+}
+
+
+/// Temporary directory holding a script and its spec, removed even if the test fails
+#[cfg(test)]
+struct SpecDir(PathBuf);
+
+#[cfg(test)]
+impl Drop for SpecDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes `script` and `spec` to `<name>.lua` and `<name>_test.lua` in a temporary directory and
+/// runs them. Returns the path the spec had, it is gone by the time the outcomes are checked.
+#[cfg(test)]
+fn run_spec(name: &str, script: &str, spec: &str) -> (PathBuf, Vec<TestOutcome>) {
+    let dir = SpecDir(std::env::temp_dir().join(format!("harpy-test-{}-{}", name, std::process::id())));
+    std::fs::create_dir_all(&dir.0).unwrap();
+    let (script_path, spec_path) = (dir.0.join(format!("{}.lua", name)), dir.0.join(format!("{}_test.lua", name)));
+    std::fs::write(&script_path, script).unwrap();
+    std::fs::write(&spec_path, spec).unwrap();
+    let outcomes = run(&script_path, &spec_path, None).unwrap();
+    (spec_path, outcomes)
+}
+
+#[cfg(test)]
+fn assert_passed(outcomes: &[TestOutcome]) {
+    for outcome in outcomes {
+        assert!(outcome.passed(), "{}: {:?}", outcome.name, outcome.failure);
+    }
+}
+
+#[test]
+fn test_spec() {
+    let (spec, outcomes) = run_spec("block", r#"
+        function on_packet(frame)
+            local udp = frame:ipv4() and frame:ipv4():udp()
+            if udp and udp:dst_port() == settings.port then
+                frame:drop()
+            end
+            return frame
+        end
+    "#, r#"
+        test("drops the blocked port", function()
+            local verdict = script{ port = 53 }:on_packet(fixture.udp{ dst_port = 53, payload = "query" })
+            assert_eq(verdict, "drop")
+        end)
+        test("forwards other frames unchanged", function()
+            local frame = fixture.udp{ dst_port = 443 }
+            local verdict, result = script{ port = 53 }:on_packet(frame)
+            assert_eq(verdict, "continue")
+            assert_eq(result, frame)
+        end)
+        test("reports differing bytes", function()
+            assert_eq(fixture.frame(binary.from_hex("0200000000020200000000010800")), fixture.ethernet{ type = 0x0806 }, "frame")
+        end)
+    "#);
+    assert_eq!(outcomes.iter().map(|outcome| outcome.passed()).collect::<Vec<_>>(), [true, true, false]);
+    let failure = outcomes[2].failure.as_deref().unwrap();
+    assert!(failure.starts_with(&format!("{}:13: frame: bytes differ at offset 13", spec.display())), "{}", failure);
+
+    let report = tap(&outcomes);
+    assert!(report.starts_with("TAP version 13\n1..3\nok 1 - drops the blocked port\nok 2"));
+    assert!(report.contains("not ok 3 - reports differing bytes\n  ---\n  message: \""));
+    let report = junit("block.lua", &outcomes);
+    assert!(report.contains("<testsuite name=\"block.lua\" tests=\"3\" failures=\"1\""));
+    assert!(report.contains("<failure message=\""));
+}
+
+#[test]
+fn test_icmp_replies() {
+    let (_, outcomes) = run_spec("pong", r#"
+        function on_packet(frame)
+            local ipv4 = frame:ipv4()
+            local ping = ipv4 and ipv4:icmp()
+            if ping and ping:type() == 8 then
+                harpy.reply(icmp.ipv4(icmp.echo_reply{ id = ping:id(), seq = ping:seq(), payload = ping:payload() }, ipv4:dst(), ipv4:src()))
+                frame:drop()
+            elseif ipv4 and ipv4:udp() then
+                harpy.reply(icmp.ipv4(icmp.unreachable(ipv4), ipv4:dst(), ipv4:src()))
+            end
+            return frame
+        end
+    "#, r#"
+        test("answers pings", function()
+            local verdict, _, replies = script():on_packet(fixture.icmp{ icmp_id = 7, seq = 3, payload = "ping" })
+            assert_eq(verdict, "drop")
+            assert_eq(#replies, 1)
+            assert_eq(replies[1], fixture.icmp{ src = "192.168.0.1", dst = "192.168.0.2", id = 0, icmp_type = 0, icmp_id = 7, seq = 3, payload = "ping" }:ipv4())
+        end)
+        test("quotes the original datagram", function()
+            local _, _, replies = script():on_packet(fixture.udp{ dst_port = 5353, payload = "hello" })
+            local unreachable = replies[1]:icmp()
+            assert_eq(unreachable:type(), 3)
+            assert_eq(unreachable:code(), 3)
+            assert_eq(unreachable:id(), nil)
+            assert_eq(unreachable:original():udp():dst_port(), 5353)
+            assert_eq(unreachable:original():src(), "192.168.0.2")
+        end)
+    "#);
+    assert_passed(&outcomes);
+}
+
+#[test]
+fn test_arp_replies() {
+    let (_, outcomes) = run_spec("arp", r#"
+        function on_arp_reply(request, reply)
+            local arp = request:arp()
+            if arp:sender_ip() == settings.ignore then
+                reply:drop()
+            elseif arp:sender_ip() == settings.decoy then
+                local answer = reply:arp()
+                answer:sender_mac("02:00:00:00:00:99")
+                reply:payload(answer)
+            end
+            return reply
+        end
+    "#, r#"
+        local request = fixture.arp{ dst_mac = "ff:ff:ff:ff:ff:ff", sender_ip = "192.168.0.7" }
+        local reply = fixture.arp{ src_mac = "02:00:00:00:00:02", dst_mac = "02:00:00:00:00:01", operation = 2,
+            sender_mac = "02:00:00:00:00:02", sender_ip = "192.168.0.1", target_mac = "02:00:00:00:00:01", target_ip = "192.168.0.7" }
+        test("vetoes replies", function()
+            assert_eq(script{ ignore = "192.168.0.7" }:on_arp_reply(request, reply), "drop")
+            assert_eq(script{ ignore = "192.168.0.8" }:on_arp_reply(request, reply), "continue")
+        end)
+        test("modifies replies", function()
+            local verdict, modified = script{ decoy = "192.168.0.7" }:on_arp_reply(request, reply)
+            assert_eq(verdict, "tamper")
+            local arp = modified:arp()
+            assert_eq(arp:is_reply(), true)
+            assert_eq(arp:operation(), 2)
+            assert_eq(arp:sender_mac(), "02:00:00:00:00:99")
+            assert_eq(arp:target_ip(), "192.168.0.7")
+            assert_eq(fixture.udp():arp(), nil)
+        end)
+    "#);
+    assert_passed(&outcomes);
+}
+
+#[test]
+fn test_tags() {
+    let (_, outcomes) = run_spec("tags", r#"
+        function on_packet(frame)
+            local ipv4 = frame:ipv4()
+            if not ipv4 then
+                return
+            end
+            local tags = frame:tags()
+            if tags[1] and tags[1].kind == "vlan" and tags[1].vid == settings.quarantine then
+                tags[1].vid = settings.vlan
+                frame:tags(tags)
+            end
+            ipv4:payload(ipv4:udp() or ipv4:tcp())
+            frame:payload(ipv4)
+            return frame
+        end
+    "#, r#"
+        test("finds IPv4 behind tags", function()
+            local frame = fixture.udp{ tags = { { kind = "vlan", tpid = 0x88a8, vid = 100 }, { kind = "vlan", pcp = 5, vid = 20 } } }
+            assert_eq(frame:type(), "Ipv4")
+            assert_eq(frame:size(), fixture.udp():size() + 8)
+            assert_eq(frame:ipv4():dst(), "192.168.0.1")
+            local tags = frame:tags()
+            assert_eq(#tags, 2)
+            assert_eq(tags[1].tpid, 0x88a8)
+            assert_eq(tags[2].pcp, 5)
+            assert_eq(tags[2].vid, 20)
+            assert_eq(#fixture.udp():tags(), 0)
+        end)
+        test("keeps tags when tampering", function()
+            for _, tags in ipairs{ { { kind = "vlan", vid = 10 } }, { { kind = "pppoe", session = 7 } }, { { kind = "mpls", label = 16 }, { kind = "mpls", label = 17 } } } do
+                local verdict, frame = script{ quarantine = 666, vlan = 10 }:on_packet(fixture.udp{ tags = tags, payload = "hello" })
+                assert_eq(verdict, "tamper")
+                assert_eq(#frame:tags(), #tags)
+                assert_eq(frame:tags()[1].kind, tags[1].kind)
+                assert_eq(frame:ipv4():src(), "192.168.0.2")
+                assert_eq(frame:ipv4():udp():payload():string(), "hello")
+            end
+        end)
+        test("moves frames between VLANs", function()
+            local _, frame = script{ quarantine = 666, vlan = 10 }:on_packet(fixture.tcp{ tags = { { kind = "vlan", vid = 666 } } })
+            assert_eq(frame:tags()[1].vid, 10)
+            assert_eq(frame:ipv4():tcp():dst_port(), 443)
+        end)
+    "#);
+    assert_passed(&outcomes);
+    assert_eq!(outcomes.len(), 3);
+}
@@ -1,5 +1,8 @@
 use pnet_macros::packet;
 use pnet_macros_support::types::*;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::*;
 
 #[packet]
 pub struct Tls {
@@ -10,3 +13,646 @@ pub struct Tls {
     pub payload: Vec<u8>
 }
 
+// https://www.rfc-editor.org/rfc/rfc8446#section-5.1
+pub const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+pub const CONTENT_ALERT: u8 = 21;
+pub const CONTENT_HANDSHAKE: u8 = 22;
+pub const CONTENT_APPLICATION_DATA: u8 = 23;
+pub const CONTENT_HEARTBEAT: u8 = 24;
+
+/// Size of the record header (content type, version, length)
+pub const RECORD_HEADER_LEN: usize = 5;
+/// Largest record payload a peer may send, 2^14 plus the allowed expansion of the ciphertext
+pub const MAX_RECORD_LEN: usize = (1 << 14) + 2048;
+
+// https://www.rfc-editor.org/rfc/rfc8446#section-4
+pub const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+pub const HANDSHAKE_SERVER_HELLO: u8 = 2;
+pub const HANDSHAKE_CERTIFICATE: u8 = 11;
+
+// https://www.iana.org/assignments/tls-extensiontype-values
+pub const EXTENSION_SERVER_NAME: u16 = 0;
+pub const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+pub const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+pub const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+pub const EXTENSION_ALPN: u16 = 16;
+pub const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// GREASE values (RFC 8701) are sent to keep the ecosystem tolerant of unknown values,
+/// they carry no information and are skipped when fingerprinting.
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Returns a human readable name for a TLS protocol version
+pub fn version_name(version: u16) -> &'static str {
+    match version {
+        0x0304 => "TLS 1.3",
+        0x0303 => "TLS 1.2",
+        0x0302 => "TLS 1.1",
+        0x0301 => "TLS 1.0",
+        0x0300 => "SSL 3.0",
+        0x0002 => "SSL 2.0",
+        _ => "Unknown"
+    }
+}
+
+/// Returns the name of a record content type
+pub fn content_type_name(content_type: u8) -> &'static str {
+    match content_type {
+        CONTENT_CHANGE_CIPHER_SPEC => "change_cipher_spec",
+        CONTENT_ALERT => "alert",
+        CONTENT_HANDSHAKE => "handshake",
+        CONTENT_APPLICATION_DATA => "application_data",
+        CONTENT_HEARTBEAT => "heartbeat",
+        _ => "unknown"
+    }
+}
+
+/// Checks whether the bytes start with something that looks like a TLS record header.
+/// Fewer than five bytes are judged on what is available.
+pub fn is_record_header(bytes: &[u8]) -> bool {
+    match bytes {
+        [content_type, ..] if !(CONTENT_CHANGE_CIPHER_SPEC..=CONTENT_HEARTBEAT).contains(content_type) => false,
+        [_, major, ..] if *major != 0x03 => false,
+        [_, _, minor, ..] if *minor > 0x04 => false,
+        [_, _, _, high, low, ..] => (u16::from_be_bytes([*high, *low]) as usize) <= MAX_RECORD_LEN,
+        _ => !bytes.is_empty()
+    }
+}
+
+/// Splits a byte stream into complete records (header included).
+/// Returns the records and the number of bytes they span, anything past that is an incomplete record.
+pub fn split_records(data: &[u8]) -> (Vec<&[u8]>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while data.len() - offset >= RECORD_HEADER_LEN && is_record_header(&data[offset..]) {
+        let length = u16::from_be_bytes([data[offset + 3], data[offset + 4]]) as usize;
+        let end = offset + RECORD_HEADER_LEN + length;
+        if end > data.len() {
+            break;
+        }
+        records.push(&data[offset..end]);
+        offset = end;
+    }
+    (records, offset)
+}
+
+/// Identifies one direction of a TCP connection
+pub type FlowKey = (std::net::Ipv4Addr, u16, std::net::Ipv4Addr, u16);
+
+struct FlowBuffer {
+    /// Bytes of an incomplete record at the end of the last segment
+    pending: Vec<u8>,
+    /// Sequence number the continuation of `pending` has to start at
+    next_seq: u32,
+    /// Sequence number and payload of the last segment that was fed and the records it yielded,
+    /// so asking twice is cheap and retransmissions don't get appended to the buffer
+    last: Option<(u32, Vec<u8>, Vec<Vec<u8>>)>,
+    updated: std::time::Instant
+}
+
+/// Reassembles TLS records that span multiple TCP segments.
+/// Keeps one buffer per flow direction, segments have to arrive in order to be stitched together.
+pub struct TlsReassembler {
+    flows: std::collections::HashMap<FlowKey, FlowBuffer>,
+    timeout: std::time::Duration,
+    max_flows: usize
+}
+
+impl Default for TlsReassembler {
+    fn default() -> Self {
+        TlsReassembler {
+            flows: Default::default(),
+            timeout: std::time::Duration::from_secs(30),
+            max_flows: 4096
+        }
+    }
+}
+
+impl TlsReassembler {
+    /// Feeds the payload of a TCP segment and returns all records that are complete with it
+    pub fn feed(&mut self, flow: FlowKey, seq: u32, payload: &[u8]) -> Vec<Vec<u8>> {
+        if payload.is_empty() {
+            return Vec::new();
+        }
+        let now = std::time::Instant::now();
+        if let Some(buffer) = self.flows.get(&flow) {
+            // A payload changed by a script, or a different segment reusing the sequence number,
+            // is fed like a new one
+            if let Some((_, _, records)) = buffer.last.as_ref().filter(|(last_seq, last_payload, _)| *last_seq == seq && last_payload == payload) {
+                return records.clone();
+            }
+        }
+        self.expire(now);
+
+        let mut data = match self.flows.remove(&flow) {
+            Some(buffer) if !buffer.pending.is_empty() && buffer.next_seq == seq => buffer.pending,
+            _ => Vec::new()
+        };
+        if data.is_empty() && !is_record_header(payload) {
+            // Somewhere in the middle of a record we didn't see the start of
+            return Vec::new();
+        }
+        data.extend_from_slice(payload);
+
+        let (records, consumed) = split_records(&data);
+        let records = records.into_iter().map(<[u8]>::to_vec).collect::<Vec<_>>();
+        let rest = &data[consumed..];
+        let pending = if is_record_header(rest) && rest.len() <= RECORD_HEADER_LEN + MAX_RECORD_LEN {
+            rest.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        if self.flows.len() >= self.max_flows {
+            // Make room by evicting the flow that has been quiet the longest
+            if let Some(oldest) = self.flows.iter().min_by_key(|(_, b)| b.updated).map(|(k, _)| *k) {
+                self.flows.remove(&oldest);
+            }
+        }
+        self.flows.insert(flow, FlowBuffer {
+            pending,
+            next_seq: seq.wrapping_add(payload.len() as u32),
+            last: Some((seq, payload.to_vec(), records.clone())),
+            updated: now
+        });
+        records
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        let timeout = self.timeout;
+        self.flows.retain(|_, buffer| now.duration_since(buffer.updated) < timeout);
+    }
+}
+
+/// Bounds checked reader over the big-endian encoded TLS structures.
+/// Every read returns `None` instead of panicking once the input is exhausted.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+    /// Number of bytes consumed so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+    pub fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos.min(self.buf.len())..]
+    }
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(len)?;
+        let bytes = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(bytes)
+    }
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+    pub fn u24(&mut self) -> Option<u32> {
+        self.bytes(3).map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]))
+    }
+    /// Reads a vector prefixed by a one byte length
+    pub fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+    /// Reads a vector prefixed by a two byte length
+    pub fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+    /// Reads a vector prefixed by a three byte length
+    pub fn vec24(&mut self) -> Option<&'a [u8]> {
+        let len = self.u24()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// A single handshake message contained in a handshake record
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake<'a> {
+    pub msg_type: u8,
+    pub body: &'a [u8]
+}
+
+/// Iterates over all complete handshake messages in the payload of a handshake record.
+/// Iteration stops at the first truncated message.
+pub fn handshakes(payload: &[u8]) -> impl Iterator<Item = Handshake<'_>> {
+    let mut reader = Reader::new(payload);
+    std::iter::from_fn(move || {
+        let msg_type = reader.u8()?;
+        let body = reader.vec24()?;
+        Some(Handshake { msg_type, body })
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    pub extension_type: u16,
+    pub data: Vec<u8>
+}
+
+/// Parses an extension block (without its two byte length prefix)
+pub fn parse_extensions(data: &[u8]) -> Option<Vec<Extension>> {
+    let mut reader = Reader::new(data);
+    let mut extensions = Vec::new();
+    while !reader.is_empty() {
+        let extension_type = reader.u16()?;
+        let data = reader.vec16()?.to_vec();
+        extensions.push(Extension { extension_type, data });
+    }
+    Some(extensions)
+}
+
+/// Parses the body of an ALPN extension into its protocol names
+pub fn parse_alpn(data: &[u8]) -> Option<Vec<String>> {
+    let mut reader = Reader::new(Reader::new(data).vec16()?);
+    let mut protocols = Vec::new();
+    while !reader.is_empty() {
+        protocols.push(String::from_utf8_lossy(reader.vec8()?).into_owned());
+    }
+    Some(protocols)
+}
+
+/// Reads a vector of big-endian u16 values
+fn u16_list(data: &[u8]) -> Option<Vec<u16>> {
+    let mut reader = Reader::new(data);
+    let mut values = Vec::with_capacity(data.len() / 2);
+    while !reader.is_empty() {
+        values.push(reader.u16()?);
+    }
+    Some(values)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientHello {
+    pub legacy_version: u16,
+    pub random: Vec<u8>,
+    pub session_id: Vec<u8>,
+    pub cipher_suites: Vec<u16>,
+    pub compression_methods: Vec<u8>,
+    /// All extensions in the order the client sent them
+    pub extensions: Vec<Extension>
+}
+
+impl ClientHello {
+    /// Parses the body of a ClientHello handshake message
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(body);
+        let legacy_version = reader.u16()?;
+        let random = reader.bytes(32)?.to_vec();
+        let session_id = reader.vec8()?.to_vec();
+        let cipher_suites = u16_list(reader.vec16()?)?;
+        let compression_methods = reader.vec8()?.to_vec();
+        let extensions = if reader.is_empty() {
+            Vec::new()
+        } else {
+            parse_extensions(reader.vec16()?)?
+        };
+        Some(ClientHello {
+            legacy_version,
+            random,
+            session_id,
+            cipher_suites,
+            compression_methods,
+            extensions
+        })
+    }
+    pub fn extension(&self, extension_type: u16) -> Option<&Extension> {
+        self.extensions.iter().find(|e| e.extension_type == extension_type)
+    }
+    /// The host name of the server_name extension
+    pub fn sni(&self) -> Option<String> {
+        let extension = self.extension(EXTENSION_SERVER_NAME)?;
+        let mut list = Reader::new(Reader::new(&extension.data).vec16()?);
+        while !list.is_empty() {
+            let name_type = list.u8()?;
+            let name = list.vec16()?;
+            if name_type == 0 {
+                return Some(String::from_utf8_lossy(name).into_owned());
+            }
+        }
+        None
+    }
+    pub fn supported_groups(&self) -> Vec<u16> {
+        self.extension(EXTENSION_SUPPORTED_GROUPS)
+            .and_then(|e| u16_list(Reader::new(&e.data).vec16()?))
+            .unwrap_or_default()
+    }
+    pub fn ec_point_formats(&self) -> Vec<u8> {
+        self.extension(EXTENSION_EC_POINT_FORMATS)
+            .and_then(|e| Reader::new(&e.data).vec8().map(<[u8]>::to_vec))
+            .unwrap_or_default()
+    }
+    pub fn signature_algorithms(&self) -> Vec<u16> {
+        self.extension(EXTENSION_SIGNATURE_ALGORITHMS)
+            .and_then(|e| u16_list(Reader::new(&e.data).vec16()?))
+            .unwrap_or_default()
+    }
+    pub fn alpn(&self) -> Vec<String> {
+        self.extension(EXTENSION_ALPN)
+            .and_then(|e| parse_alpn(&e.data))
+            .unwrap_or_default()
+    }
+    pub fn supported_versions(&self) -> Vec<u16> {
+        self.extension(EXTENSION_SUPPORTED_VERSIONS)
+            .and_then(|e| u16_list(Reader::new(&e.data).vec8()?))
+            .unwrap_or_default()
+    }
+
+    /// The JA3 fingerprint string
+    /// `SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats`
+    /// https://github.com/salesforce/ja3
+    pub fn ja3(&self) -> String {
+        fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+            values.map(|v| v.to_string()).collect::<Vec<_>>().join("-")
+        }
+        format!("{},{},{},{},{}",
+            self.legacy_version,
+            join(self.cipher_suites.iter().filter(|c| !is_grease(**c))),
+            join(self.extensions.iter().map(|e| e.extension_type).filter(|e| !is_grease(*e))),
+            join(self.supported_groups().iter().filter(|g| !is_grease(**g))),
+            join(self.ec_point_formats().iter()))
+    }
+    /// MD5 digest of the JA3 string, the form JA3 fingerprints are usually shared in
+    pub fn ja3_hash(&self) -> String {
+        to_hex(&Md5::digest(self.ja3().as_bytes()))
+    }
+    /// The JA4 fingerprint, `quic` selects the transport marker (`q` instead of `t`)
+    /// https://github.com/FoxIO-LLC/ja4/blob/main/technical_details/JA4.md
+    pub fn ja4(&self, quic: bool) -> String {
+        fn truncated_hash(input: &str) -> String {
+            if input.is_empty() {
+                return "000000000000".to_string();
+            }
+            to_hex(&Sha256::digest(input.as_bytes()))[..12].to_string()
+        }
+        let version = self.supported_versions().into_iter()
+            .filter(|v| !is_grease(*v))
+            .max()
+            .unwrap_or(self.legacy_version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            0xfeff => "d1",
+            0xfefd => "d2",
+            0xfefc => "d3",
+            _ => "00"
+        };
+        let sni = if self.extension(EXTENSION_SERVER_NAME).is_some() { 'd' } else { 'i' };
+        let alpn = match self.alpn().first().map(|p| p.as_bytes()) {
+            Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => format!("{}{}", *first as char, *last as char),
+            Some([first]) if first.is_ascii_alphanumeric() => format!("{}{}", *first as char, *first as char),
+            // Non alphanumeric values are represented through their hex encoding
+            Some(protocol) if !protocol.is_empty() => {
+                let hex = to_hex(protocol);
+                format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+            },
+            _ => "00".to_string()
+        };
+
+        let ciphers = self.cipher_suites.iter().copied().filter(|c| !is_grease(*c)).collect::<Vec<_>>();
+        let extensions = self.extensions.iter().map(|e| e.extension_type).filter(|e| !is_grease(*e)).collect::<Vec<_>>();
+
+        let mut sorted_ciphers = ciphers.iter().map(|c| format!("{:04x}", c)).collect::<Vec<_>>();
+        sorted_ciphers.sort();
+        let mut sorted_extensions = extensions.iter()
+            .filter(|e| **e != EXTENSION_SERVER_NAME && **e != EXTENSION_ALPN)
+            .map(|e| format!("{:04x}", e))
+            .collect::<Vec<_>>();
+        sorted_extensions.sort();
+        let mut extension_input = sorted_extensions.join(",");
+        let signature_algorithms = self.signature_algorithms();
+        if !extension_input.is_empty() && !signature_algorithms.is_empty() {
+            extension_input.push('_');
+            extension_input.push_str(&signature_algorithms.iter().map(|s| format!("{:04x}", s)).collect::<Vec<_>>().join(","));
+        }
+
+        format!("{}{}{}{:02}{:02}{}_{}_{}",
+            if quic { 'q' } else { 't' },
+            version,
+            sni,
+            ciphers.len().min(99),
+            extensions.len().min(99),
+            alpn,
+            truncated_hash(&sorted_ciphers.join(",")),
+            truncated_hash(&extension_input))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerHello {
+    pub legacy_version: u16,
+    pub random: Vec<u8>,
+    pub session_id: Vec<u8>,
+    pub cipher_suite: u16,
+    pub compression_method: u8,
+    pub extensions: Vec<Extension>
+}
+
+impl ServerHello {
+    /// Parses the body of a ServerHello handshake message
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(body);
+        let legacy_version = reader.u16()?;
+        let random = reader.bytes(32)?.to_vec();
+        let session_id = reader.vec8()?.to_vec();
+        let cipher_suite = reader.u16()?;
+        let compression_method = reader.u8()?;
+        // Extensions are optional in TLS <= 1.2
+        let extensions = if reader.is_empty() {
+            Vec::new()
+        } else {
+            parse_extensions(reader.vec16()?)?
+        };
+        Some(ServerHello {
+            legacy_version,
+            random,
+            session_id,
+            cipher_suite,
+            compression_method,
+            extensions
+        })
+    }
+    pub fn extension(&self, extension_type: u16) -> Option<&Extension> {
+        self.extensions.iter().find(|e| e.extension_type == extension_type)
+    }
+    /// The negotiated version, TLS 1.3 announces it through the supported_versions extension
+    pub fn version(&self) -> u16 {
+        self.extension(EXTENSION_SUPPORTED_VERSIONS)
+            .and_then(|e| Reader::new(&e.data).u16())
+            .unwrap_or(self.legacy_version)
+    }
+    /// The application protocol the server selected, if any
+    pub fn alpn(&self) -> Option<String> {
+        self.extension(EXTENSION_ALPN)
+            .and_then(|e| parse_alpn(&e.data))
+            .and_then(|protocols| protocols.into_iter().next())
+    }
+}
+
+/// Splits the body of a Certificate handshake message into the DER encoded certificates.
+/// Only the TLS <= 1.2 layout is understood, TLS 1.3 sends its certificates encrypted.
+pub fn parse_certificate_chain(body: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut reader = Reader::new(Reader::new(body).vec24()?);
+    let mut chain = Vec::new();
+    while !reader.is_empty() {
+        chain.push(reader.vec24()?.to_vec());
+    }
+    Some(chain)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    pub serial: String,
+    pub sans: Vec<String>,
+    pub not_before: i64,
+    pub not_after: i64,
+    /// Lowercase hex encoded SHA-256 digest of the DER encoding
+    pub fingerprint: String
+}
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(san)) => san.value.general_names.iter().filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::RFC822Name(name) => Some(name.to_string()),
+                GeneralName::URI(name) => Some(name.to_string()),
+                GeneralName::IPAddress(ip) => match ip.len() {
+                    4 => Some(std::net::Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                    16 => Some(std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                    _ => None
+                },
+                _ => None
+            }).collect(),
+            _ => Vec::new()
+        };
+        Some(Certificate {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            sans,
+            not_before: cert.validity().not_before.timestamp(),
+            not_after: cert.validity().not_after.timestamp(),
+            fingerprint: to_hex(&Sha256::digest(der))
+        })
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+
+#[test]
+fn test_server_hello_and_certificate() {
+    use hex_literal::hex;
+    use pnet::packet::Packet;
+    // TLS 1.2 handshake record carrying a ServerHello and a Certificate message
+    let record = hex!("16030302220200005c03031111111111111111111111111111111111111111111111111111111111111111202222222222222222222222222222222222222222222222222222222222222222c02b000014ff01000100000b000201000010000500030268320b0001be0001bb0001b8308201b43082015aa00302010202021234300a06082a8648ce3d04030230253113301106035504030c0a68617270792e74657374310e300c060355040a0c054861727079301e170d3236313031393037343931345a170d3336313031363037343931345a30253113301106035504030c0a68617270792e74657374310e300c060355040a0c0548617270793059301306072a8648ce3d020106082a8648ce3d030107034200047b9b550f5515464b473ef0320c961fb5d7d6666596d7f52c82a8f6110d7b7c0b7b326d7f73b7d2f10998aac5c6639f962f691a8dbcd69700f276619bcd459d88a37a3078301d0603551d0e041604147f2a39f950092100e1b67c56a3573896134a6955301f0603551d230418301680147f2a39f950092100e1b67c56a3573896134a6955300f0603551d130101ff040530030101ff30250603551d11041e301c820a68617270792e74657374820e7777772e68617270792e74657374300a06082a8648ce3d040302034800304502206c3562f37497b13da5cb493bb886654220fda5a04a1f18904760e24963868128022100b1c787e5167e2b87aa796d08c0901a54cd9038507808a0c71fe4510f4b1007c7");
+    let tls = TlsPacket::new(&record).unwrap();
+    let messages = handshakes(tls.payload()).collect::<Vec<_>>();
+    assert_eq!(messages[0].msg_type, HANDSHAKE_SERVER_HELLO);
+
+    let hello = ServerHello::parse(messages[0].body).unwrap();
+    assert_eq!(hello.version(), 0x0303);
+    assert_eq!(hello.cipher_suite, 0xc02b);
+    assert_eq!(hello.alpn(), Some("h2".to_string()));
+    assert_eq!(hello.extensions.iter().map(|e| e.extension_type).collect::<Vec<_>>(), vec![0xff01, 0x000b, 0x0010]);
+
+    assert_eq!(messages[1].msg_type, HANDSHAKE_CERTIFICATE);
+    let chain = parse_certificate_chain(messages[1].body).unwrap();
+    assert_eq!(chain.len(), 1);
+    let cert = Certificate::from_der(&chain[0]).unwrap();
+    assert_eq!(cert.subject, "CN=harpy.test, O=Harpy");
+    assert_eq!(cert.issuer, "CN=harpy.test, O=Harpy");
+    assert_eq!(cert.serial, "12:34");
+    assert_eq!(cert.sans, vec!["harpy.test".to_string(), "www.harpy.test".to_string()]);
+    assert!(cert.not_before < cert.not_after);
+    assert_eq!(cert.fingerprint, "a0f66ff23be12d135baa839190b6cc66356446e7655ae4dd6de342e886e8058f");
+}
+
+#[test]
+fn test_client_hello_fingerprints() {
+    use hex_literal::hex;
+    use pnet::packet::Packet;
+    // ClientHello with GREASE values sprinkled into the cipher suites, extensions, groups and versions
+    let record = hex!("160301008f0100008b0303333333333333333333333333333333333333333333333333333333333333333300000a3a3a13011302c02bc02f010000580a0a00000000000f000d00000a68617270792e7465737400170000000a000a00081a1a001d00170018000b000201000010000e000c02683208687474702f312e31000d00080006040308040401002b0007062a2a03040303");
+    let tls = TlsPacket::new(&record).unwrap();
+    let handshake = handshakes(tls.payload()).next().unwrap();
+    assert_eq!(handshake.msg_type, HANDSHAKE_CLIENT_HELLO);
+
+    let hello = ClientHello::parse(handshake.body).unwrap();
+    assert_eq!(hello.sni(), Some("harpy.test".to_string()));
+    assert_eq!(hello.cipher_suites, vec![0x3a3a, 0x1301, 0x1302, 0xc02b, 0xc02f]);
+    assert_eq!(hello.extensions.iter().map(|e| e.extension_type).collect::<Vec<_>>(), vec![0x0a0a, 0, 23, 10, 11, 16, 13, 43]);
+    assert_eq!(hello.supported_groups(), vec![0x1a1a, 29, 23, 24]);
+    assert_eq!(hello.ec_point_formats(), vec![0]);
+    assert_eq!(hello.alpn(), vec!["h2".to_string(), "http/1.1".to_string()]);
+    assert_eq!(hello.supported_versions(), vec![0x2a2a, 0x0304, 0x0303]);
+
+    assert_eq!(hello.ja3(), "771,4865-4866-49195-49199,0-23-10-11-16-13-43,29-23-24,0");
+    assert_eq!(hello.ja3_hash(), "675eee9b7e52c1b8a8596c3b608a7050");
+    assert_eq!(hello.ja4(false), "t13d0407h2_e00fd9ffaebd_1fdf4de06b7e");
+    assert_eq!(hello.ja4(true), "q13d0407h2_e00fd9ffaebd_1fdf4de06b7e");
+}
+
+#[test]
+fn test_record_reassembly() {
+    use std::net::Ipv4Addr;
+    let flow: FlowKey = (Ipv4Addr::new(192, 168, 0, 2), 51000, Ipv4Addr::new(1, 1, 1, 1), 443);
+    // An alert and a change_cipher_spec record, followed by a handshake record split in the middle
+    let alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+    let ccs = [0x14, 0x03, 0x03, 0x00, 0x01, 0x01];
+    let handshake = [0x16, 0x03, 0x03, 0x00, 0x06, 0x01, 0x00, 0x00, 0x02, 0xaa, 0xbb];
+
+    let mut reassembler = TlsReassembler::default();
+    let mut first = alert.to_vec();
+    first.extend_from_slice(&ccs);
+    first.extend_from_slice(&handshake[..7]);
+    let records = reassembler.feed(flow, 1000, &first);
+    assert_eq!(records, vec![alert.to_vec(), ccs.to_vec()]);
+    // Asking again for the same segment doesn't append it twice
+    assert_eq!(reassembler.feed(flow, 1000, &first), records);
+
+    let records = reassembler.feed(flow, 1000 + first.len() as u32, &handshake[7..]);
+    assert_eq!(records, vec![handshake.to_vec()]);
+
+    // Mid-record segments of unknown flows are ignored
+    assert!(reassembler.feed(flow, 5000, &[0xde, 0xad, 0xbe, 0xef]).is_empty());
+    assert!(!is_record_header(&[0x30, 0x03, 0x03]));
+    assert!(is_record_header(&[0x18, 0x03]));
+}
+
+#[test]
+fn test_record_reassembly_same_seq() {
+    use std::net::Ipv4Addr;
+    let flow: FlowKey = (Ipv4Addr::new(192, 168, 0, 2), 51000, Ipv4Addr::new(1, 1, 1, 1), 443);
+    let first = [0x16, 0x03, 0x03, 0x00, 0x02, 0x01, 0xaa];
+    let second = [0x16, 0x03, 0x03, 0x00, 0x03, 0x01, 0xbb, 0xcc];
+
+    let mut reassembler = TlsReassembler::default();
+    assert_eq!(reassembler.feed(flow, 1000, &first), vec![first.to_vec()]);
+    // A different payload at the same sequence number isn't answered from the cache
+    assert_eq!(reassembler.feed(flow, 1000, &second), vec![second.to_vec()]);
+    assert_eq!(reassembler.feed(flow, 1000, &second), vec![second.to_vec()]);
+}
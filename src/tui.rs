@@ -0,0 +1,506 @@
+//! Live terminal dashboard for `harpy spoof --tui`.
+//!
+//! The spoof loop feeds the `Dashboard` with every frame it handles, a background thread draws it
+//! and handles key presses. Key presses only flip flags in the shared state, which the spoof loop
+//! picks up with the next frame. Quitting stops the session like a signal does.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use pnet::{
+    datalink::MacAddr,
+    packet::{
+        ethernet::{EthernetPacket, EtherTypes},
+        ip::IpNextHeaderProtocols,
+        ipv4::Ipv4Packet,
+        tcp::TcpPacket,
+        udp::UdpPacket,
+        Packet
+    }
+};
+use ratatui::{
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Row, Table},
+    Frame
+};
+
+use crate::{encap::Encapsulation, metrics::METRICS, quic, tls, session::{self, HostRole, PacketHandler, SessionObserver, StopHandle, Verdict}};
+
+/// Number of entries kept in the names, errors and log panels
+const HISTORY: usize = 50;
+const MAX_FLOWS: usize = 4096;
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the session gets to end after quitting before harpy exits without it
+const QUIT_GRACE: Duration = Duration::from_secs(3);
+
+/// A host whose ARP cache harpy poisons
+pub struct HostStatus {
+    pub role: &'static str,
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub arp_replies: u64,
+    pub last_spoofed: Option<Instant>,
+    /// Drop every frame from and to this host instead of forwarding it
+    pub drop_all: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: &'static str,
+    pub src: (Ipv4Addr, u16),
+    pub dst: (Ipv4Addr, u16)
+}
+
+#[derive(Default)]
+struct FlowStats {
+    packets: u64,
+    bytes: u64,
+    last_seen: Option<Instant>
+}
+
+pub struct DashboardState {
+    pub title: String,
+    pub hosts: Vec<HostStatus>,
+    pub selected: usize,
+    /// Frames are dropped instead of forwarded while paused
+    pub paused: bool,
+    /// Set by the `r` key, cleared by the spoof loop once the script is reloaded
+    pub reload_requested: bool,
+    flows: HashMap<FlowKey, FlowStats>,
+    talkers: HashMap<Ipv4Addr, u64>,
+    /// `(kind, client, name)` of recently seen DNS queries and TLS/QUIC SNIs
+    names: VecDeque<(&'static str, Ipv4Addr, String)>,
+    errors: VecDeque<String>,
+    logs: VecDeque<String>,
+    quic: quic::CryptoReassembler,
+    tls: tls::TlsReassembler,
+    /// Stops the session when quitting
+    stop: StopHandle,
+    /// Set once the terminal is handed back, nothing is drawn anymore
+    closed: bool
+}
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, item: T) {
+    if queue.len() >= HISTORY {
+        queue.pop_front();
+    }
+    queue.push_back(item);
+}
+
+/// Returns the name asked for by a DNS query
+pub fn dns_query_name(payload: &[u8]) -> Option<String> {
+    // Only queries (QR unset) with at least one question
+    if payload.len() < 12 || payload[2] & 0x80 != 0 || u16::from_be_bytes([payload[4], payload[5]]) == 0 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut reader = tls::Reader::new(&payload[12..]);
+    loop {
+        let label = reader.vec8()?;
+        if label.is_empty() {
+            break;
+        }
+        // Compression pointers don't appear in the question of a query
+        if label.len() > 63 || labels.len() > 127 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(label).into_owned());
+    }
+    Some(labels.join("."))
+}
+
+/// Returns the server name of a TLS record carrying a ClientHello
+fn client_hello_sni(record: &[u8]) -> Option<String> {
+    if record.first() != Some(&tls::CONTENT_HANDSHAKE) {
+        return None;
+    }
+    tls::handshakes(record.get(tls::RECORD_HEADER_LEN..)?)
+        .find(|handshake| handshake.msg_type == tls::HANDSHAKE_CLIENT_HELLO)
+        .and_then(|handshake| tls::ClientHello::parse(handshake.body))?
+        .sni()
+}
+
+impl DashboardState {
+    fn new(title: String, stop: StopHandle) -> Self {
+        DashboardState {
+            title,
+            hosts: Vec::new(),
+            selected: 0,
+            paused: false,
+            reload_requested: false,
+            flows: HashMap::new(),
+            talkers: HashMap::new(),
+            names: VecDeque::new(),
+            errors: VecDeque::new(),
+            logs: VecDeque::new(),
+            quic: Default::default(),
+            tls: Default::default(),
+            stop,
+            closed: false
+        }
+    }
+
+    fn observe(&mut self, frame: &EthernetPacket) {
+        let encapsulation = Encapsulation::parse(frame);
+        if encapsulation.ethertype != EtherTypes::Ipv4 {
+            return;
+        }
+        let Some(ipv4) = Ipv4Packet::new(encapsulation.payload(frame)) else {
+            return;
+        };
+        let (src, dst) = (ipv4.get_source(), ipv4.get_destination());
+        let size = frame.packet().len() as u64;
+        *self.talkers.entry(src).or_default() += size;
+
+        let key = match ipv4.get_next_level_protocol() {
+            IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4.payload()).map(|tcp| {
+                // ClientHellos with post-quantum key shares span several segments
+                let records = self.tls.feed((src, tcp.get_source(), dst, tcp.get_destination()), tcp.get_sequence(), tcp.payload());
+                if let Some(sni) = records.iter().find_map(|record| client_hello_sni(record)) {
+                    push_bounded(&mut self.names, ("sni", src, sni));
+                }
+                FlowKey { protocol: "tcp", src: (src, tcp.get_source()), dst: (dst, tcp.get_destination()) }
+            }),
+            IpNextHeaderProtocols::Udp => UdpPacket::new(ipv4.payload()).map(|udp| {
+                if udp.get_destination() == 53 {
+                    if let Some(name) = dns_query_name(udp.payload()) {
+                        push_bounded(&mut self.names, ("dns", src, name));
+                    }
+                } else if let Some(sni) = self.quic_sni(udp.payload()) {
+                    push_bounded(&mut self.names, ("quic", src, sni));
+                }
+                FlowKey { protocol: "udp", src: (src, udp.get_source()), dst: (dst, udp.get_destination()) }
+            }),
+            _ => None
+        };
+        let key = key.unwrap_or(FlowKey { protocol: "ip", src: (src, 0), dst: (dst, 0) });
+
+        if self.flows.len() >= MAX_FLOWS && !self.flows.contains_key(&key) {
+            self.flows.retain(|_, flow| flow.last_seen.is_some_and(|seen| seen.elapsed() < FLOW_TIMEOUT));
+            if self.flows.len() >= MAX_FLOWS {
+                return;
+            }
+        }
+        let flow = self.flows.entry(key).or_default();
+        flow.packets += 1;
+        flow.bytes += size;
+        flow.last_seen = Some(Instant::now());
+    }
+
+    fn quic_sni(&mut self, payload: &[u8]) -> Option<String> {
+        let (dcid, plaintext) = quic::decrypt_client_initial(payload).ok()?;
+        let frames = quic::crypto_frames(&plaintext).ok()?;
+        let handshake = self.quic.feed(&dcid, frames)?;
+        if handshake[0] != tls::HANDSHAKE_CLIENT_HELLO {
+            return None;
+        }
+        tls::ClientHello::parse(&handshake[4..])?.sni()
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(8), Constraint::Length(12)])
+            .split(frame.area());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(35), Constraint::Percentage(25)])
+            .split(rows[1]);
+        let left = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3 + self.hosts.len() as u16), Constraint::Min(3)])
+            .split(columns[0]);
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(rows[2]);
+
+        let (state, state_style) = match self.paused {
+            true => ("PAUSED", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+            false => ("forwarding", Style::default())
+        };
+        let status = Paragraph::new(Line::from(vec![
+            Span::raw(format!("{} | ", self.title)),
+            Span::styled(state, state_style),
+            Span::raw(format!(" | dropped {} | tampered {} | q quit, p pause, ↑↓ select, d drop all, r reload",
+                METRICS.packets_dropped.get(), METRICS.packets_tampered.get()))
+        ])).block(Block::default().borders(Borders::ALL).title("harpy"));
+        frame.render_widget(status, rows[0]);
+
+        let hosts = self.hosts.iter().enumerate().map(|(i, host)| {
+            let style = if i == self.selected { Style::default().add_modifier(Modifier::REVERSED) } else { Style::default() };
+            Row::new(vec![
+                host.role.to_string(),
+                host.ip.to_string(),
+                host.mac.to_string(),
+                host.arp_replies.to_string(),
+                host.last_spoofed.map_or("-".to_string(), |t| format!("{}s ago", t.elapsed().as_secs())),
+                if host.drop_all { "DROP ALL".to_string() } else { String::new() }
+            ]).style(style)
+        });
+        let hosts = Table::new(hosts, [Constraint::Length(8), Constraint::Length(15), Constraint::Length(17), Constraint::Length(6), Constraint::Length(9), Constraint::Min(8)])
+            .header(Row::new(vec!["Role", "IP", "MAC", "ARP", "Spoofed", ""]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title("Spoofed hosts"));
+        frame.render_widget(hosts, left[0]);
+
+        let mut talkers = self.talkers.iter().collect::<Vec<_>>();
+        talkers.sort_by_key(|(_, bytes)| std::cmp::Reverse(**bytes));
+        let talkers = talkers.into_iter().take(left[1].height as usize)
+            .map(|(ip, bytes)| ListItem::new(format!("{:<15} {:>10}", ip, format_bytes(*bytes))))
+            .collect::<Vec<_>>();
+        frame.render_widget(List::new(talkers).block(Block::default().borders(Borders::ALL).title("Top talkers")), left[1]);
+
+        let mut flows = self.flows.iter().collect::<Vec<_>>();
+        flows.sort_by_key(|(_, flow)| std::cmp::Reverse(flow.bytes));
+        let flows = flows.into_iter().take(columns[1].height as usize).map(|(key, flow)| Row::new(vec![
+            key.protocol.to_string(),
+            format!("{}:{}", key.src.0, key.src.1),
+            format!("{}:{}", key.dst.0, key.dst.1),
+            flow.packets.to_string(),
+            format_bytes(flow.bytes)
+        ]));
+        let flows = Table::new(flows, [Constraint::Length(4), Constraint::Length(21), Constraint::Length(21), Constraint::Length(7), Constraint::Min(8)])
+            .header(Row::new(vec!["", "Source", "Destination", "Pkts", "Bytes"]).style(Style::default().add_modifier(Modifier::BOLD)))
+            .block(Block::default().borders(Borders::ALL).title("Top flows"));
+        frame.render_widget(flows, columns[1]);
+
+        let names = self.names.iter().rev().take(columns[2].height as usize)
+            .map(|(kind, client, name)| ListItem::new(format!("{:<4} {} {}", kind, client, name)))
+            .collect::<Vec<_>>();
+        frame.render_widget(List::new(names).block(Block::default().borders(Borders::ALL).title("DNS / SNI")), columns[2]);
+
+        let errors = self.errors.iter().rev().map(|e| ListItem::new(e.as_str()).style(Style::default().fg(Color::Red))).collect::<Vec<_>>();
+        frame.render_widget(List::new(errors).block(Block::default().borders(Borders::ALL).title("Script errors")), bottom[0]);
+        let logs = self.logs.iter().rev().map(|l| ListItem::new(l.as_str())).collect::<Vec<_>>();
+        frame.render_widget(List::new(logs).block(Block::default().borders(Borders::ALL).title("Log")), bottom[1]);
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0)
+    }
+}
+
+/// Stops the session and waits for it to hand back the terminal. The session only notices once
+/// the next frame arrives, on a quiet network harpy exits with `code` after `QUIT_GRACE`.
+fn quit(state: &Mutex<DashboardState>, code: i32) {
+    state.lock().unwrap_or_else(|e| e.into_inner()).stop.stop();
+    let deadline = Instant::now() + QUIT_GRACE;
+    while Instant::now() < deadline {
+        if state.lock().unwrap_or_else(|e| e.into_inner()).closed {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    ratatui::restore();
+    crate::fastpath::restore_all();
+    std::process::exit(code);
+}
+
+/// Handle to the dashboard, cheap to clone. A default `Dashboard` is disabled and ignores all
+/// updates, so the spoof loop can feed it unconditionally.
+#[derive(Clone, Default)]
+pub struct Dashboard(Option<Arc<Mutex<DashboardState>>>);
+
+impl Dashboard {
+    /// Takes over the terminal, installs a logger writing into the dashboard and starts drawing.
+    /// Quitting the dashboard stops the session through `stop`.
+    pub fn start(title: String, stop: StopHandle) -> Dashboard {
+        let state = Arc::new(Mutex::new(DashboardState::new(title, stop)));
+        let level = std::env::var("RUST_LOG").ok().and_then(|level| level.parse().ok()).unwrap_or(log::LevelFilter::Info);
+        if log::set_boxed_logger(Box::new(DashboardLogger(state.clone()))).is_ok() {
+            log::set_max_level(level);
+        }
+
+        let ui_state = state.clone();
+        std::thread::spawn(move || {
+            let mut terminal = ratatui::init();
+            loop {
+                let drawn = {
+                    let state = ui_state.lock().unwrap_or_else(|e| e.into_inner());
+                    if state.closed {
+                        return;
+                    }
+                    terminal.draw(|frame| state.draw(frame))
+                };
+                if let Err(e) = drawn {
+                    // The session goes on without the dashboard until it's stopped
+                    Dashboard(Some(ui_state.clone())).stop();
+                    eprintln!("Couldn't draw dashboard: {}", e);
+                    return;
+                }
+                if !event::poll(Duration::from_millis(250)).unwrap_or(false) {
+                    continue;
+                }
+                let Ok(Event::Key(key)) = event::read() else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let mut state = ui_state.lock().unwrap_or_else(|e| e.into_inner());
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => {
+                        drop(state);
+                        quit(&ui_state, 0);
+                        return;
+                    },
+                    KeyCode::Char('p') => state.paused = !state.paused,
+                    KeyCode::Char('r') => state.reload_requested = true,
+                    KeyCode::Char('d') => {
+                        let selected = state.selected;
+                        if let Some(host) = state.hosts.get_mut(selected) {
+                            host.drop_all = !host.drop_all;
+                        }
+                    },
+                    KeyCode::Up => state.selected = state.selected.saturating_sub(1),
+                    KeyCode::Down => state.selected = (state.selected + 1).min(state.hosts.len().saturating_sub(1)),
+                    _ => {}
+                }
+            }
+        });
+        Dashboard(Some(state))
+    }
+
+    /// Hands the terminal back, so errors ending the session are readable
+    pub fn stop(&self) {
+        if let Some(state) = &self.0 {
+            // Holding the lock, the drawing thread can't be in the middle of a frame
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            if !std::mem::replace(&mut state.closed, true) {
+                ratatui::restore();
+            }
+        }
+    }
+
+    fn update<R: Default>(&self, f: impl FnOnce(&mut DashboardState) -> R) -> R {
+        match &self.0 {
+            Some(state) => f(&mut state.lock().unwrap_or_else(|e| e.into_inner())),
+            None => R::default()
+        }
+    }
+
+    pub fn add_host(&self, role: &'static str, ip: Ipv4Addr, mac: MacAddr) {
+        self.update(|state| state.hosts.push(HostStatus { role, ip, mac, arp_replies: 0, last_spoofed: None, drop_all: false }));
+    }
+    /// Records a spoofed ARP reply sent to `ip`
+    pub fn arp_reply(&self, ip: Ipv4Addr) {
+        self.update(|state| {
+            if let Some(host) = state.hosts.iter_mut().find(|host| host.ip == ip) {
+                host.arp_replies += 1;
+                host.last_spoofed = Some(Instant::now());
+            }
+        });
+    }
+    pub fn observe(&self, frame: &EthernetPacket) {
+        self.update(|state| state.observe(frame));
+    }
+    pub fn script_error(&self, error: &str) {
+        self.update(|state| push_bounded(&mut state.errors, error.to_string()));
+    }
+    pub fn is_paused(&self) -> bool {
+        self.update(|state| state.paused)
+    }
+    /// Whether frames between `src` and `dst` have to be dropped, because one of them is set to drop all
+    pub fn drops_all(&self, src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+        self.update(|state| state.hosts.iter().any(|host| host.drop_all && (host.ip == src || host.ip == dst)))
+    }
+    /// Returns true once after a reload of the script was requested
+    pub fn take_reload(&self) -> bool {
+        self.update(|state| std::mem::take(&mut state.reload_requested))
+    }
+}
+
+impl SessionObserver for Dashboard {
+    fn on_mac_resolved(&self, role: HostRole, ip: Ipv4Addr, mac: MacAddr) {
+        self.add_host(role.as_str(), ip, mac);
+    }
+    fn on_arp_reply(&self, ip: Ipv4Addr) {
+        self.arp_reply(ip);
+    }
+    fn on_frame(&self, frame: &session::Frame) {
+        self.observe(frame.packet);
+    }
+}
+
+/// Drops frames while forwarding is paused or one of their hosts is set to drop all
+impl PacketHandler for Dashboard {
+    fn on_packet(&mut self, frame: &session::Frame) -> Verdict {
+        match self.is_paused() || self.drops_all(frame.source(), frame.destination()) {
+            true => Verdict::Drop,
+            false => Verdict::Forward
+        }
+    }
+}
+
+/// Routes log records into the dashboard's log panel, writing to the terminal would tear it apart
+struct DashboardLogger(Arc<Mutex<DashboardState>>);
+
+impl log::Log for DashboardLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            let line = format!("{:<5} {}", record.level(), record.args());
+            push_bounded(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()).logs, line);
+        }
+    }
+    fn flush(&self) {}
+}
+
+
+#[test]
+fn test_dashboard_observe() {
+    use hex_literal::hex;
+    let mut state = DashboardState::new(String::new(), StopHandle::default());
+    // DNS query for 27justin.dev from 192.168.0.38 to 1.1.1.1
+    let frame = [
+        &hex!("e0d55eac1f1b 484efc9d1a60 0800")[..],
+        &hex!("4500003a 00000000 4011 0000 c0a80026 01010101"),
+        &hex!("c35c 0035 0026 0000"),
+        &hex!("0001 0100 0001 0000 0000 0000 0832376a757374696e 03646576 00 0001 0001")
+    ].concat();
+    let frame = EthernetPacket::new(&frame).unwrap();
+    state.observe(&frame);
+
+    assert_eq!(state.names.back(), Some(&("dns", Ipv4Addr::new(192, 168, 0, 38), "27justin.dev".to_string())));
+    assert_eq!(state.flows.len(), 1);
+    assert_eq!(state.talkers[&Ipv4Addr::new(192, 168, 0, 38)], frame.packet().len() as u64);
+
+    // Responses and truncated names are ignored
+    assert_eq!(dns_query_name(&hex!("fc9d 8180 0001 0001 0000 0000 01 61 00")), None);
+    assert_eq!(dns_query_name(&hex!("fc9d 0100 0001 0000 0000 0000 01 61 05 62")), None);
+}
+
+#[test]
+fn test_dashboard_split_client_hello() {
+    use hex_literal::hex;
+    let mut state = DashboardState::new(String::new(), StopHandle::default());
+    // ClientHello for example.com, with nothing but the server_name extension
+    let record = hex!("16 0301 0043 01 00003f 0303
+        0000000000000000000000000000000000000000000000000000000000000000
+        00 0002 1301 01 00 0014 0000 0010 000e 00 000b 6578616d706c652e636f6d");
+    // TCP segment from 192.168.0.38:50012 to 93.184.215.14:443
+    let segment = |seq: u32, payload: &[u8]| [
+        &hex!("e0d55eac1f1b 484efc9d1a60 0800")[..],
+        &hex!("4500"), &(40 + payload.len() as u16).to_be_bytes(), &hex!("00000000 4006 0000 c0a80026 5db8d70e"),
+        &hex!("c35c 01bb"), &seq.to_be_bytes(), &hex!("00000000 5018 ffff 0000 0000"),
+        payload
+    ].concat();
+
+    state.observe(&EthernetPacket::new(&segment(1, &record[..40])).unwrap());
+    assert!(state.names.is_empty());
+    state.observe(&EthernetPacket::new(&segment(41, &record[40..])).unwrap());
+    assert_eq!(state.names.back(), Some(&("sni", Ipv4Addr::new(192, 168, 0, 38), "example.com".to_string())));
+}
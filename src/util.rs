@@ -1,4 +1,5 @@
 use pnet::datalink::NetworkInterface;
+use crate::error::HarpyError;
 use std::{net::{IpAddr, Ipv4Addr}, fs::File, io::{BufReader, BufRead}};
 
 
@@ -12,59 +13,72 @@ pub fn get_gateway_for(interface: &NetworkInterface) -> Option<IpAddr> {
     // If the `Gateway` field is not `00000000`, return it as an `IpAddress`.
     // If the `Gateway` field is `00000000`, return `None`.
     let mut gateway: Option<IpAddr> = None;
-    let file = File::open("/proc/net/route").unwrap();
+    let file = File::open("/proc/net/route").ok()?;
     let mut lines = BufReader::new(file).lines();
     lines.next();
-    for line in lines {
-        let line = line.unwrap();
+    for line in lines.map_while(Result::ok) {
         let parts: Vec<&str> = line.split('\t').collect();
-        if parts[0] == interface.name {
+        if parts.len() > 2 && parts[0] == interface.name {
             let gateway_str = parts[2];
             if gateway_str != "00000000" {
                 // Convert "binary form" aabbccdd where each pair is the hexadecimal representation
                 // of a byte into a ip-address in decimal format
-                let gateway_raw = u32::from_str_radix(gateway_str, 16).unwrap().to_be();
-                gateway = Some(IpAddr::V4(Ipv4Addr::from(gateway_raw)));
+                if let Ok(gateway_raw) = u32::from_str_radix(gateway_str, 16) {
+                    gateway = Some(IpAddr::V4(Ipv4Addr::from(gateway_raw.to_be())));
+                }
             }
         }
     }
     gateway
 }
 
+/// The IPv4 gateway of the interface's default route
+pub fn default_gateway(interface: &NetworkInterface) -> Option<Ipv4Addr> {
+    match get_gateway_for(interface)? {
+        IpAddr::V4(ip) => Some(ip),
+        _ => None
+    }
+}
 
-#[inline]
-pub fn checksum(octets: &[u8]) -> u16 {
-    // Calculate the checksum compliant with RFC 1071
-    // https://tools.ietf.org/html/rfc1071
-    //
-    // I couldn't get the code from the RFC to work, so I looked around a bit and found a similar
-    // algorithm for C on stackoverflow. After porting it to rust, it does seem to work.
-    // Yes, this uses unsafe, but I can't see any way this could ever result in a segfault.
-    // All below used "unsafe" code, is inherently safe due to bounds checking, thus, unless
-    // someone comes up with a safe, faster way to calculate the checksum, this will stay as is.
-    // 
-    unsafe {
-        let mut sum: u32 = 0xffff;
-        let mut addr: *const u16 = octets.as_ptr() as *const u16;
-        let mut len = octets.len();
-        while len > 1 {
-            sum += *addr as u32;
-            addr = addr.add(1);
-            len -= 2;
-            if sum > 0xffff {
-                sum -= 0xffff;
-            }
-        }
+/// Looks up an interface that is up
+pub fn find_interface(name: &str) -> Result<NetworkInterface, HarpyError> {
+    let interface = pnet::datalink::interfaces().into_iter()
+        .find(|iface| iface.name == name)
+        .ok_or_else(|| HarpyError::NoSuchInterface(name.to_string()))?;
+    if !interface.is_up() {
+        return Err(HarpyError::InterfaceDown(interface.name));
+    }
+    Ok(interface)
+}
 
-        if len > 0 {
-            sum += *addr as u32;
-            if sum > 0xffff {
-                sum -= 0xffff;
-            }
-        }
+/// Reads the MTU from /sys/class/net/{interface}/mtu
+pub fn interface_mtu(interface: &NetworkInterface) -> Result<u32, HarpyError> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface.name))
+        .map_err(|e| HarpyError::Mtu(interface.name.clone(), e.to_string()))?
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| HarpyError::Mtu(interface.name.clone(), e.to_string()))
+}
+
+/// The first IPv4 address of an interface
+pub fn interface_ipv4(interface: &NetworkInterface) -> Option<Ipv4Addr> {
+    interface.ips.iter().find_map(|ip| match ip.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        _ => None
+    })
+}
 
-        (!sum as u16).to_be()
+
+/// The Internet checksum of RFC 1071, an odd last byte is padded with zero
+#[inline]
+pub fn checksum(octets: &[u8]) -> u16 {
+    let mut sum: u32 = octets.chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
     }
+    !sum as u16
 }
 
 pub trait Subsequence<O> {
@@ -75,31 +89,29 @@ impl<'a, T, O: AsRef<&'a [T]>> Subsequence<O> for Vec<T>
 where T: PartialEq + 'a {
     fn subsequence(&self, other: O) -> bool
     where T: 'a {
-        let slice = self.as_slice();
         let other = other.as_ref();
-        for i in 0..(self.len() - other.len()) {
-            if slice[i..i+other.len()] == other[..] {
-                return true;
-            }
-        }
-        false
+        other.is_empty() || self.windows(other.len()).any(|window| window == *other)
     }
 }
 
 impl<'a, T, O: Iterator<Item = &'a T>> Subsequence<O> for std::slice::Iter<'_, T>
 where T: PartialEq + Clone + 'a {
     fn subsequence(&self, other: O) -> bool {
-        let slice = self.as_slice();
         let other = other.cloned().collect::<Vec<T>>();
-        for i in 0..(self.len() - other.len()) {
-            if &slice[i..i+other.len()] == &other[..] {
-                return true;
-            }
-        }
-        false
+        // `windows` yields nothing if the needle is longer than the haystack
+        other.is_empty() || self.as_slice().windows(other.len()).any(|window| window == other.as_slice())
     }
 }
 
+/// Decodes a string of hexadecimal digits, whitespace between bytes is ignored
+pub fn from_hex(string: &str) -> Option<Vec<u8>> {
+    let digits = string.chars().filter(|c| !c.is_whitespace()).map(|c| c.to_digit(16).map(|d| d as u8)).collect::<Option<Vec<u8>>>()?;
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    Some(digits.chunks(2).map(|pair| pair[0] << 4 | pair[1]).collect())
+}
+
 
 #[test]
 fn test_subsequence() {
@@ -108,8 +120,18 @@ fn test_subsequence() {
     let response = hex!("484efc9d1a60e0d55eac1f1b080045000051b7100000401100bcc0a8002601010101c35c0035003dc31ecdfd012000010000000000010832376a757374696e03646576000001000100002904d000000000000c000a0008b16ef21ad93bdc8c");
 
     let response_iter = response.iter();
-    assert_eq!(response_iter.subsequence(&mut b"27justin".iter()), true);
-    assert_eq!(response_iter.subsequence(&mut b"not there".iter()), false);
+    assert!(response_iter.subsequence(&mut b"27justin".iter()));
+    assert!(!response_iter.subsequence(&mut b"not there".iter()));
+    // Needles at the very end and longer than the haystack
+    assert!(b"abc".iter().subsequence(&mut b"bc".iter()));
+    assert!(!b"abc".iter().subsequence(&mut b"abcd".iter()));
+}
+
+#[test]
+fn test_from_hex() {
+    assert_eq!(from_hex("00ff 10Ab"), Some(vec![0x00, 0xff, 0x10, 0xab]));
+    assert_eq!(from_hex("abc"), None);
+    assert_eq!(from_hex("zz"), None);
 }
 
 #[test]
@@ -117,5 +139,8 @@ fn test_checksum() {
     use hex_literal::hex;
     let ip_header = hex!("4500003c7b35400040060000c0a800260d20384b");
     assert_eq!(checksum(&ip_header), 0xb94d);
+    // Echo request with an odd length payload of "abc"
+    assert_eq!(checksum(&hex!("0800 0000 0001 0001 616263")), 0x339b);
+    assert_eq!(checksum(&hex!("0800 339b 0001 0001 616263")), 0);
 }
 